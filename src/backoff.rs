@@ -0,0 +1,81 @@
+use crate::codes::Code;
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+/// Backoff tracks consecutive failures, doubling the delay before the next attempt (starting at
+/// the base delay) on each failure, up to a maximum. Each delay is randomly shortened by up to a
+/// quarter, so that clients which failed together (e.g. when a provider went down) don't retry in
+/// lockstep.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            failures: 0,
+        }
+    }
+
+    /// Records a failure, returning the delay to wait before the next attempt.
+    pub fn failure(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.max.max(self.base));
+        self.failures = self.failures.saturating_add(1);
+        jitter(delay)
+    }
+
+    /// Records a failure which retrying soon won't fix, returning the maximum delay; later
+    /// failures also wait the maximum delay, until reset.
+    pub fn permanent_failure(&mut self) -> Duration {
+        self.failures = u32::MAX;
+        jitter(self.max.max(self.base))
+    }
+
+    /// Returns true if the most recent attempt failed.
+    pub fn is_backing_off(&self) -> bool {
+        self.failures > 0
+    }
+
+    /// Records a success (or that the cause of failure has likely gone away), resetting the delay
+    /// to the base delay.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Randomly shortens a delay by up to a quarter.
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(1.0 - fastrand::f64() / 4.0)
+}
+
+/// An error which retrying soon won't fix, e.g. the provider rejecting our credentials, or
+/// config it doesn't support. Such errors aren't retried immediately, & checks failing with one
+/// back off to the maximum delay at once.
+#[derive(Debug)]
+pub struct Permanent {
+    pub code: Code,
+    pub message: String,
+}
+
+impl Display for Permanent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Permanent {}
+
+/// Returns whether the given error is permanent.
+pub fn is_permanent(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Permanent>().is_some()
+}
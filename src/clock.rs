@@ -0,0 +1,76 @@
+//! Detection of wall-clock jumps. Scheduling is based on monotonic time, so is unaffected by the
+//! wall clock being stepped (e.g. by NTP); but times derived from the wall clock (e.g. when the
+//! next check is due, as reported by `rnccd status`) must be recalculated. A forward jump may also
+//! mean the machine was suspended, which monotonic time doesn't count on every platform, so
+//! waits may have run long.
+
+use std::time::{Duration, SystemTime};
+use tokio::{
+    sync::watch,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tracing::warn;
+
+/// How often the wall clock is compared against monotonic time.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The smallest difference between the two that counts as a jump. (Gradual corrections, e.g. by
+/// NTP slewing the clock, are far smaller.)
+const MIN_JUMP: Duration = Duration::from_secs(30);
+
+/// A jump of the wall clock, relative to monotonic time.
+#[derive(Clone, Copy, Default)]
+pub struct Jump {
+    pub forward: bool,
+    pub by: Duration,
+}
+
+/// Starts watching for wall-clock jumps. The returned receiver is notified of each one.
+pub fn spawn() -> watch::Receiver<Jump> {
+    let (tx, rx) = watch::channel(Jump::default());
+    tokio::spawn(async move {
+        let mut interval = time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let (mut last, mut last_wall) = (Instant::now(), SystemTime::now());
+        loop {
+            interval.tick().await;
+            let (now, now_wall) = (Instant::now(), SystemTime::now());
+            let elapsed = now - last;
+            let jump = match now_wall.duration_since(last_wall) {
+                Ok(wall_elapsed) if wall_elapsed >= elapsed => Jump {
+                    forward: true,
+                    by: wall_elapsed - elapsed,
+                },
+                Ok(wall_elapsed) => Jump {
+                    forward: false,
+                    by: elapsed - wall_elapsed,
+                },
+                Err(err) => Jump {
+                    forward: false,
+                    by: elapsed + err.duration(),
+                },
+            };
+            (last, last_wall) = (now, now_wall);
+            if jump.by >= MIN_JUMP {
+                warn!(
+                    forward = jump.forward,
+                    by = ?jump.by,
+                    "Wall clock jumped, recalculating schedules"
+                );
+                if tx.send(jump).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Waits for the next wall-clock jump, returning it. If jumps are no longer watched for, this
+/// never returns.
+pub async fn jumped(jumps: &mut watch::Receiver<Jump>) -> Jump {
+    if jumps.changed().await.is_err() {
+        std::future::pending().await
+    }
+    *jumps.borrow_and_update()
+}
@@ -0,0 +1,271 @@
+//! Updates of Cloudflare DNS records, via Cloudflare's v4 API, authenticated with an API token.
+
+use crate::{
+    backoff::Permanent,
+    codes::Code,
+    config::{CallConfig, CloudflareConfig},
+    lockout::Lockout,
+    provider::{self, CheckFuture, Provider, ReconcileFuture, RecordType, UpdateFuture},
+};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{header::CONTENT_TYPE, Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+use tokio::sync::OnceCell;
+
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// The error code meaning we're making too many requests, & should back off.
+const THROTTLED_CODE: u32 = 971;
+
+/// Cloudflare updates a record via Cloudflare's API.
+pub struct Cloudflare {
+    id: String,
+    account: String,
+    record: String,
+    record_type: RecordType,
+    cfg: CloudflareConfig,
+    zone_id: OnceCell<String>, // looked up on first use, unless configured.
+}
+
+impl Cloudflare {
+    pub fn new(cfg: CloudflareConfig, record_type: RecordType) -> Self {
+        let record = provider::record_name(&cfg.domain, cfg.host());
+        let id = match record_type {
+            RecordType::A => format!("cloudflare:{record}"),
+            RecordType::Aaaa => format!("cloudflare:{record}/AAAA"),
+        };
+        Self {
+            id,
+            account: format!("cloudflare:{}", cfg.domain),
+            record,
+            record_type,
+            zone_id: OnceCell::new_with(cfg.zone_id.clone()),
+            cfg,
+        }
+    }
+
+    /// Points the record at `addr`, with the configured settings, creating it if it doesn't
+    /// exist. Returns false if it already was.
+    async fn set(&self, client: &reqwest::Client, addr: IpAddr) -> Result<bool> {
+        let path = format!("/zones/{}/dns_records", self.zone_id(client).await?);
+        let record_type = self.record_type.to_string();
+        let query = [
+            ("type", record_type.as_str()),
+            ("name", self.record.as_str()),
+        ];
+        let records: Vec<Record> = self.call(client, Method::GET, &path, &query, None).await?;
+        let change = RecordChange {
+            record_type: &record_type,
+            name: &self.record,
+            content: addr.to_string(),
+            ttl: self.cfg.ttl_secs,
+            proxied: self.cfg.proxied,
+        };
+        let Some(record) = records.first() else {
+            let _: Record = self
+                .call(client, Method::POST, &path, &[], Some(&change))
+                .await?;
+            return Ok(true);
+        };
+        if record.content.parse::<IpAddr>().ok() == Some(addr)
+            && self.cfg.ttl_secs.is_none_or(|ttl| ttl == record.ttl)
+            && self
+                .cfg
+                .proxied
+                .is_none_or(|proxied| record.proxied == Some(proxied))
+        {
+            return Ok(false);
+        }
+        let path = format!("{path}/{}", record.id);
+        let _: Record = self
+            .call(client, Method::PATCH, &path, &[], Some(&change))
+            .await?;
+        Ok(true)
+    }
+
+    /// Returns the zone's ID, looking it up by name if it isn't yet known.
+    async fn zone_id(&self, client: &reqwest::Client) -> Result<&str> {
+        let zone_id = self
+            .zone_id
+            .get_or_try_init(|| async {
+                let query = [("name", self.cfg.domain.as_str())];
+                let zones: Vec<Zone> = self
+                    .call(client, Method::GET, "/zones", &query, None)
+                    .await?;
+                zones.into_iter().next().map(|zone| zone.id).ok_or_else(|| {
+                    anyhow!(
+                        "zone {} not found (can the API token read it?)",
+                        self.cfg.domain
+                    )
+                })
+            })
+            .await?;
+        Ok(zone_id)
+    }
+
+    /// Calls the API, returning the call's result.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        client: &reqwest::Client,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<&RecordChange<'_>>,
+    ) -> Result<T> {
+        let mut req = client
+            .request(method.clone(), format!("{API_URL}{path}"))
+            .timeout(self.cfg.calls.timeout())
+            .bearer_auth(self.cfg.api_token.value())
+            .query(query);
+        if let Some(body) = body {
+            req = req
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(body)?);
+        }
+        let resp = req.send().await?;
+        let status = resp.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Lockout {
+                message: format!("{method} {path} got {status}"),
+            }
+            .into());
+        }
+        let body = resp.text().await?;
+        let resp: Response<T> = serde_json::from_str(&body).with_context(|| {
+            format!("{method} {path} got unexpected response ({status}): {body}")
+        })?;
+        if !resp.success {
+            let errors: Vec<_> = resp
+                .errors
+                .iter()
+                .map(|err| format!("{} (error {})", err.message, err.code))
+                .collect();
+            let message = format!("{method} {path} got error: {}", errors.join("; "));
+            if resp.errors.iter().any(|err| err.code == THROTTLED_CODE) {
+                return Err(Lockout { message }.into());
+            }
+            // The token is invalid, or lacks permission.
+            if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+                return Err(Permanent {
+                    code: Code::ProviderBadCredentials,
+                    message,
+                }
+                .into());
+            }
+            return Err(anyhow!(message));
+        }
+        resp.result
+            .ok_or_else(|| anyhow!("{method} {path} got no result"))
+    }
+}
+
+impl Provider for Cloudflare {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    fn endpoint(&self) -> &str {
+        API_URL
+    }
+
+    fn record(&self) -> &str {
+        &self.record
+    }
+
+    fn calls(&self) -> &CallConfig {
+        &self.cfg.calls
+    }
+
+    fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    fn update<'a>(&'a self, client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a> {
+        Box::pin(async move {
+            self.set(client, addr).await?;
+            Ok(())
+        })
+    }
+
+    // The token can be checked read-only, as can finding the zone.
+    fn check_credentials<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        _current_addr: Option<IpAddr>,
+    ) -> CheckFuture<'a> {
+        Box::pin(async move {
+            let token: Token = self
+                .call(client, Method::GET, "/user/tokens/verify", &[], None)
+                .await?;
+            let zone_id = self.zone_id(client).await?;
+            Ok(format!(
+                "verified API token (status {}) & found zone {} ({zone_id})",
+                token.status, self.cfg.domain
+            ))
+        })
+    }
+
+    fn reconcile<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        addr: IpAddr,
+    ) -> Option<ReconcileFuture<'a>> {
+        // Setting the record rewrites it only if it differs from what's configured.
+        if self.cfg.ttl_secs.is_none() && self.cfg.proxied.is_none() {
+            return None;
+        }
+        Some(Box::pin(self.set(client, addr)))
+    }
+}
+
+/// The envelope of every API response.
+#[derive(Deserialize)]
+struct Response<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: u32,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Zone {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: String,
+    content: String,
+    ttl: u32,
+    proxied: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    status: String,
+}
+
+/// The body of a request creating or changing a record.
+#[derive(Serialize)]
+struct RecordChange<'a> {
+    #[serde(rename = "type")]
+    record_type: &'a str,
+    name: &'a str,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxied: Option<bool>,
+}
@@ -0,0 +1,127 @@
+//! Stable, machine-readable codes for failures, given in notifications so that webhook receivers
+//! can branch on the kind of failure without matching error messages (which may change).
+
+use crate::{backoff::Permanent, detect::CaptivePortal, lockout::Lockout};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+/// A failure's code. Codes are never renamed or reused, only added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Code {
+    /// Namecheap rejected the dynamic DNS password.
+    NcBadPassword,
+    /// Namecheap rejected the XML API user or key, or API access isn't enabled.
+    NcBadApiKey,
+    /// Namecheap refused an XML API request from an IP address which isn't whitelisted.
+    NcIpNotWhitelisted,
+    /// Namecheap doesn't know the domain or host, or it isn't in the account.
+    NcNotFound,
+    /// Namecheap returned some other error.
+    NcError,
+    /// A provider rejected our credentials.
+    ProviderBadCredentials,
+    /// A provider doesn't know the record, or it isn't in the account.
+    ProviderNotFound,
+    /// A provider can't make the update, given the config (e.g. its record type).
+    ProviderUnsupported,
+    /// A provider locked out the account.
+    ProviderLockedOut,
+    /// A call to a provider timed out.
+    UpdateTimeout,
+    /// A call to a provider couldn't connect to it.
+    UpdateConnectFailed,
+    /// An update otherwise failed.
+    UpdateFailed,
+    /// Detection timed out.
+    DetectTimeout,
+    /// A detector couldn't be connected to.
+    DetectConnectFailed,
+    /// A detector's request was answered by a captive portal.
+    DetectCaptivePortal,
+    /// Detection otherwise failed.
+    DetectFailed,
+    /// Detectors disagreed about our address.
+    DetectorsDisagreed,
+    /// A resolver still returned the old address after the record's TTL expired.
+    PropagationFailed,
+    /// Namecheap's host records, read back after writing them, differed from those written.
+    NcHostsMismatch,
+}
+
+/// An error with a code, for failures whose code can't be told from the kind of error alone.
+#[derive(Debug)]
+pub struct Coded {
+    pub code: Code,
+    pub message: String,
+}
+
+impl Display for Coded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Coded {}
+
+/// Returns the code of an error from detecting our address.
+pub fn detection(err: &anyhow::Error) -> Code {
+    of(err).unwrap_or_else(|| match transport(err) {
+        Some(Transport::Timeout) => Code::DetectTimeout,
+        Some(Transport::Connect) => Code::DetectConnectFailed,
+        None => Code::DetectFailed,
+    })
+}
+
+/// Returns the code of an error from updating a provider's record.
+pub fn update(err: &anyhow::Error) -> Code {
+    of(err).unwrap_or_else(|| match transport(err) {
+        Some(Transport::Timeout) => Code::UpdateTimeout,
+        Some(Transport::Connect) => Code::UpdateConnectFailed,
+        None => Code::UpdateFailed,
+    })
+}
+
+/// Returns the code given by the error, or any error causing it.
+fn of(err: &anyhow::Error) -> Option<Code> {
+    err.chain().find_map(|err| {
+        if let Some(coded) = err.downcast_ref::<Coded>() {
+            Some(coded.code)
+        } else if let Some(permanent) = err.downcast_ref::<Permanent>() {
+            Some(permanent.code)
+        } else if err.is::<Lockout>() {
+            Some(Code::ProviderLockedOut)
+        } else if err.is::<CaptivePortal>() {
+            Some(Code::DetectCaptivePortal)
+        } else {
+            None
+        }
+    })
+}
+
+enum Transport {
+    Timeout,
+    Connect,
+}
+
+/// Returns how the error's request failed, if it failed to get a response.
+fn transport(err: &anyhow::Error) -> Option<Transport> {
+    err.chain().find_map(|err| {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            if err.is_timeout() {
+                return Some(Transport::Timeout);
+            } else if err.is_connect() {
+                return Some(Transport::Connect);
+            }
+        }
+        let timed_out = err.is::<tokio::time::error::Elapsed>()
+            || err
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::TimedOut);
+        timed_out.then_some(Transport::Timeout)
+    })
+}
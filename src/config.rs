@@ -0,0 +1,1597 @@
+use crate::logging::LogTarget;
+#[cfg(feature = "custom")]
+use crate::pattern::Pattern;
+use crate::provider::RecordType;
+use anyhow::{anyhow, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+use tracing::warn;
+
+/// Config (read-only).
+///
+/// Besides the settings below, `profiles` gives named sets of provider settings, e.g.
+/// `{aggressive: {timeout_secs: 10, retries: 5}}`, to reduce duplication between providers. A
+/// provider's settings (at the top level, or in `backup_providers`) use one with
+/// `profile: <name>`; settings given alongside override the profile's. Profiles are applied as the
+/// config is parsed, so aren't shown by `rnccd config show`.
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    /// The record to update, & how: the `provider` key selects the provider (`namecheap`, the
+    /// default, `cloudflare`, `duckdns`, `noip`, `custom`, or `relay`), whose settings follow.
+    #[serde(flatten)]
+    pub provider: ProviderConfig,
+
+    /// Arbitrary labels (e.g. `site: home`) for this host entry, attached to its logs, metrics,
+    /// notifications, & status. Entries of `hosts` may add their own.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    /// Additional providers to update alongside `provider`, e.g. a backup hostname. Each is
+    /// updated on every change, and its success is tracked independently.
+    #[serde(default)]
+    pub backup_providers: Vec<ProviderConfig>,
+
+    /// The most provider API calls to make per minute, across all hosts (e.g. to stay under an
+    /// account's rate limit). Calls beyond this are queued, with updates of stale records going
+    /// before routine checks. Unlimited if omitted.
+    pub provider_requests_per_minute: Option<u32>,
+
+    /// The most provider API calls to make per minute with each account (e.g. each client's
+    /// Namecheap account), so that one account's calls can't trip another's rate limit.
+    /// Unlimited if omitted.
+    pub account_requests_per_minute: Option<u32>,
+
+    /// Update every provider on the first check after starting, even if the state says they
+    /// already have our address (e.g. to re-assert records after restoring stale state from a
+    /// backup).
+    #[serde(default)]
+    pub update_on_start: bool,
+
+    /// Sync the state file & its directory to disk on each write, so that a crash or power loss
+    /// can't lose or corrupt the state, at the cost of more writes (e.g. to an SD card).
+    #[serde(default)]
+    pub durable_state: bool,
+
+    /// How often to check our IP address, as a number of seconds or with units, e.g. `90s`, `5m`,
+    /// or `1h30m`. Defaults to a minute.
+    pub check_interval: Option<HumanDuration>,
+
+    /// The longest a check (detection, updates, & verification, including retries) may take, e.g.
+    /// `45s`. A check overrunning this is aborted & counted as failed, so that a hang can't delay
+    /// the next check. Defaults to the check interval.
+    pub cycle_timeout: Option<HumanDuration>,
+
+    /// How often to log that rnccd is still running, e.g. `1d`, to show liveness in logs which
+    /// otherwise only get lines when something changes or fails (checks finding nothing to do are
+    /// logged at debug level). If omitted, no such line is logged.
+    pub log_heartbeat: Option<HumanDuration>,
+
+    /// The longest to wait between checks while backing off from repeated failures, e.g. `6h`.
+    /// Failures which retrying soon won't fix (e.g. rejected credentials) wait this long at once.
+    /// Defaults to an hour.
+    pub max_backoff: Option<HumanDuration>,
+
+    /// When to make the first check after starting: `immediately` (the default), or
+    /// `after-interval`, which also skips waiting for the network at startup. Delaying keeps a
+    /// crash-looping daemon from hammering detection services.
+    #[serde(default)]
+    pub first_check: FirstCheck,
+
+    /// When to update records: `on-change` (the default) whenever our address differs from what
+    /// they have, `on-change-stable` only once a new address has lasted `stable_for` (riding out
+    /// brief flaps, e.g. failovers to a backup uplink), `always` on every check, or `never` (e.g.
+    /// to only monitor our address).
+    #[serde(default)]
+    pub update_policy: UpdatePolicy,
+
+    /// With `update_policy: on-change-stable`, how long a new address must last before records
+    /// are updated, e.g. `10m`. Defaults to 5 minutes.
+    pub stable_for: Option<HumanDuration>,
+
+    /// The HTTP service used to detect our IP address. Defaults to ipify.
+    pub detector: Option<DetectorConfig>,
+
+    /// More services to detect our IP address with, alongside `detector`. How they're used when
+    /// more than one is configured is set by `detection`.
+    #[serde(default)]
+    pub detectors: Vec<DetectorConfig>,
+
+    /// How to use more than one detector: `quorum` (the default) asks each on every check, &
+    /// uses the address most agree on, quarantining services which consistently disagree;
+    /// `fallback` asks them in order (`detector` first), using the first address detected, so
+    /// that an outage of one service doesn't stop updates.
+    #[serde(default)]
+    pub detection: DetectionMode,
+
+    /// With `quorum` detection, how many detectors must agree on an address for it to be used
+    /// (e.g. 2, of 3 detectors). If fewer agree, the check fails, rather than updating records
+    /// with an address which may be garbage. If omitted, the address most agree on is used.
+    pub detection_quorum: Option<usize>,
+
+    /// The uplinks (e.g. WAN links) of a host with more than one, each detected over separately,
+    /// by binding requests to the uplink's interface. `uplink_policy` picks whose address is used.
+    /// If set, uplinks replace `detector` & `detectors` for A records.
+    #[serde(default)]
+    pub uplinks: Vec<UplinkConfig>,
+
+    /// How to pick the uplink whose address is used: `primary` (the default) uses the first
+    /// uplink whose detection succeeds, failing over to the next when it fails; `lowest-latency`
+    /// detects over every uplink, using the one which answered fastest.
+    #[serde(default)]
+    pub uplink_policy: UplinkPolicy,
+
+    /// The HTTP service used to detect our IPv6 address, for AAAA records. Defaults to ipify's
+    /// IPv6-only endpoint.
+    pub detector6: Option<DetectorConfig>,
+
+    /// Refuse to start unless every detector is local: a command, or a URL whose host is
+    /// `localhost` or a loopback, private, or link-local address (e.g. a router's status page).
+    /// This guarantees that no third-party service is asked for our address.
+    #[serde(default)]
+    pub no_external_detection: bool,
+
+    /// A URL used to check whether the network is up at startup. Any HTTP response counts as
+    /// success. If omitted, startup waits until IP address detection succeeds instead.
+    pub connectivity_url: Option<String>,
+
+    /// A proxy to use for all HTTP requests, e.g. `http://proxy.example:3128`. If omitted, the
+    /// standard proxy environment variables (`HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY`) are used.
+    /// Either way, hosts listed in `NO_PROXY` are connected to directly.
+    pub proxy: Option<String>,
+
+    /// Ignore the standard proxy environment variables (including `NO_PROXY`).
+    #[serde(default)]
+    pub ignore_proxy_env: bool,
+
+    /// Which IP address family HTTP requests are made over: `prefer-ipv4`, `prefer-ipv6`,
+    /// `ipv4-only`, or `ipv6-only`. If omitted, the system's preference is used. Either way, if
+    /// connecting over the preferred family doesn't succeed quickly, the other is tried in
+    /// parallel (unless disabled).
+    pub address_family: Option<AddressFamily>,
+
+    /// Refuse HTTP requests (including redirects) to hosts other than those of the providers,
+    /// detectors, `connectivity_url`, webhooks, & metrics sinks, as defense in depth against
+    /// requests (& the credentials they carry) being steered elsewhere. Hosts given as IP
+    /// addresses are only checked when redirected to. Can't be used with `proxy`, & proxy
+    /// environment variables are ignored.
+    #[serde(default)]
+    pub restrict_egress: bool,
+
+    /// If given, measure how long each update takes to propagate to public resolvers.
+    pub propagation: Option<PropagationConfig>,
+
+    /// If given, check what the records actually resolve to at startup (& optionally every so
+    /// often), rather than trusting the state to know what the providers have: a record changed
+    /// out-of-band is then updated, even if the state says it already has our address.
+    pub verify_dns: Option<VerifyDnsConfig>,
+
+    /// A low-stakes record updated first on each change, e.g. `canary.example.com` (one of the
+    /// records configured above): the other records are only updated once the canary has been,
+    /// & resolves to the new address. This limits the damage if the provider misbehaves, or the
+    /// detected address is wrong.
+    pub canary: Option<CanaryConfig>,
+
+    /// Webhooks to notify of notable events (e.g. IP changes).
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// How many consecutive failures to update a record (or detect our address) there must be
+    /// before a notification is sent, e.g. 3, so that a single blip doesn't notify. Defaults to 1.
+    pub notify_after_failures: Option<u32>,
+
+    /// Send `started` & `stopped` notifications when the daemon starts, & when it's stopped (by
+    /// SIGTERM or Ctrl-C).
+    #[serde(default)]
+    pub notify_lifecycle: bool,
+
+    /// Rules for which notifications are sent, e.g. to keep a lab's records from paging at night
+    /// while always paging for a mail server's. Each notification is governed by the first rule
+    /// matching its record; notifications matching no rule are always sent.
+    #[serde(default)]
+    pub notification_rules: Vec<NotificationRule>,
+
+    /// Commands to run after a record is updated with a new address, e.g. to restart a VPN or
+    /// re-issue a certificate. They run in order, in the background, with the record in
+    /// `RNCCD_RECORD`, its type in `RNCCD_RECORD_TYPE`, & the old & new addresses in
+    /// `RNCCD_OLD_ADDR` (empty if unknown) & `RNCCD_NEW_ADDR`. A failing hook is logged, but
+    /// doesn't stop later hooks, or fail the update.
+    #[serde(default)]
+    pub on_change: Vec<ExecConfig>,
+
+    /// A dead man's switch (e.g. a healthchecks.io check) to ping after each check, so that its
+    /// monitor alerts if rnccd stops checking, or its checks keep failing.
+    pub healthcheck: Option<HealthcheckConfig>,
+
+    /// The local HTTP API, through which the daemon's status can be read (`GET /v1/status`) & a
+    /// check triggered (`POST /v1/check`).
+    pub http_api: Option<HttpApiConfig>,
+
+    /// Where the daemon writes logs: `stdout` (the default), or (on Unix) `syslog` or `journald`.
+    /// Logs from before the config is loaded go to stdout. `--log-target` overrides this.
+    pub log_target: Option<LogTarget>,
+
+    /// Sinks to write metrics (measurements of each detection & update) to.
+    #[serde(default)]
+    pub metrics: Vec<MetricsConfig>,
+
+    /// A file holding secrets (passwords & tokens), as a map from name to value. Secret values in
+    /// this config can then be given as `{secret: <name>}`, so that this config needn't be kept
+    /// private. A relative path is relative to the directory containing the (first) config file.
+    pub secrets: Option<String>,
+}
+
+impl Config {
+    /// How often to check our IP address.
+    pub fn check_interval(&self) -> Duration {
+        self.check_interval
+            .map_or(Duration::from_secs(60), |interval| interval.0)
+    }
+
+    /// How long a new address must last before records are updated, with
+    /// `update_policy: on-change-stable`.
+    pub fn stable_for(&self) -> Duration {
+        self.stable_for
+            .map_or(Duration::from_secs(5 * 60), |stable_for| stable_for.0)
+    }
+
+    /// The longest to wait between checks while backing off.
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+            .map_or(Duration::from_secs(60 * 60), |max| max.0)
+    }
+
+    /// Returns every configured detector.
+    pub fn detectors(&self) -> Vec<&DetectorConfig> {
+        self.detector.iter().chain(&self.detectors).collect()
+    }
+
+    /// Replaces each reference to a secret with its value, from the secrets file.
+    pub fn resolve_secrets(&mut self, config_path: &Path) -> Result<()> {
+        let secrets_path = self.secrets.clone();
+        resolve_secrets(self.secrets_mut(), secrets_path.as_deref(), config_path)
+    }
+
+    /// Replaces the value of each secret with a placeholder, so that the config can be shown.
+    pub fn redact_secrets(&mut self) {
+        for secret in self.secrets_mut() {
+            *secret = Secret::Value("<redacted>".to_string());
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<&mut Secret> {
+        let mut secrets = self.provider.secrets_mut();
+        for provider in &mut self.backup_providers {
+            secrets.extend(provider.secrets_mut());
+        }
+        secrets.extend(self.metrics.iter_mut().filter_map(MetricsConfig::secret));
+        secrets.extend(
+            self.webhooks
+                .iter_mut()
+                .filter_map(|cfg| cfg.secret.as_mut()),
+        );
+        secrets
+    }
+}
+
+/// Replaces each reference to a secret with its value, from the secrets file at `secrets_path`
+/// (relative to the directory containing the config file at `config_path`).
+fn resolve_secrets(
+    mut secrets: Vec<&mut Secret>,
+    secrets_path: Option<&str>,
+    config_path: &Path,
+) -> Result<()> {
+    if secrets
+        .iter()
+        .all(|secret| matches!(secret, Secret::Value(_)))
+    {
+        return Ok(());
+    }
+
+    let secrets_path = secrets_path
+        .ok_or_else(|| anyhow!("config refers to secrets, but no secrets file is given"))?;
+    let secrets_path = config_path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(secrets_path);
+    let values = read_secrets(&secrets_path)
+        .with_context(|| format!("couldn't read {}", secrets_path.display()))?;
+    for secret in &mut secrets {
+        if let Secret::Ref { secret: name } = secret {
+            let value = values
+                .get(name)
+                .ok_or_else(|| anyhow!("secret {name:?} not found in secrets file"))?;
+            **secret = Secret::Value(value.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Parses a (merged) config, applying profiles. The `provider` key defaults to `namecheap`, as it
+/// predates other providers.
+pub fn parse(mut value: serde_yaml::Value) -> Result<Config> {
+    let profiles = value.get("profiles").cloned().unwrap_or_default();
+    if let Some(serde_yaml::Value::Sequence(backups)) = value.get_mut("backup_providers") {
+        for backup in backups {
+            apply_profile(&profiles, backup)?;
+        }
+    }
+    apply_profile(&profiles, &mut value)?;
+    if let serde_yaml::Value::Mapping(mapping) = &mut value {
+        if !mapping.contains_key("provider") {
+            mapping.insert("provider".into(), "namecheap".into());
+        }
+    }
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Parses a (merged) `relay-server` config, applying profiles to its providers as [`parse`] does to
+/// backup providers.
+#[cfg(feature = "relay")]
+pub fn parse_relay_server(mut value: serde_yaml::Value) -> Result<RelayServerConfig> {
+    let profiles = value.get("profiles").cloned().unwrap_or_default();
+    if let Some(serde_yaml::Value::Sequence(providers)) = value.get_mut("providers") {
+        for provider in providers {
+            apply_profile(&profiles, provider)?;
+        }
+    }
+    Ok(serde_yaml::from_value(value)?)
+}
+
+/// Merges a provider's settings over those of the profile they name, if any.
+fn apply_profile(profiles: &serde_yaml::Value, settings: &mut serde_yaml::Value) -> Result<()> {
+    let serde_yaml::Value::Mapping(mapping) = settings else {
+        return Ok(());
+    };
+    let Some(name) = mapping.remove("profile") else {
+        return Ok(());
+    };
+    let name = name
+        .as_str()
+        .ok_or_else(|| anyhow!("profile must be given by name"))?;
+    let mut merged = profiles
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("profile {name:?} not found"))?;
+    merge(&mut merged, std::mem::take(settings));
+    *settings = merged;
+    Ok(())
+}
+
+/// Merges config `overlay` into `base`: mappings are merged key by key, recursively, while any
+/// other value in `overlay` (including a list) replaces the one in `base`.
+pub fn merge(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn read_secrets(path: &Path) -> Result<HashMap<String, String>> {
+    let file = File::open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if file.metadata()?.permissions().mode() & 0o007 != 0 {
+            warn!(
+                path = %path.display(),
+                "Secrets file is accessible to all users, consider restricting its permissions"
+            );
+        }
+    }
+    Ok(serde_yaml::from_reader(file)?)
+}
+
+/// A secret value (e.g. a password), given either directly, or as a reference (`{secret: <name>}`)
+/// to a value in the secrets file.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Ref { secret: String },
+}
+
+impl Secret {
+    /// Returns the secret's value. References are resolved when the config is loaded.
+    pub fn value(&self) -> &str {
+        match self {
+            Secret::Value(value) => value,
+            Secret::Ref { .. } => unreachable!("secret references are resolved at load time"),
+        }
+    }
+}
+
+/// Config for an IP address detection service: a URL, a command, or a network interface.
+#[derive(Deserialize, Serialize)]
+pub struct DetectorConfig {
+    /// The URL to request, e.g. `https://ifconfig.co/json`. The response may be the address as
+    /// plain text, or a JSON object. A `tcp://host:port` URL instead connects to a TCP echo
+    /// service (e.g. one run with `rnccd echo-server`), for networks which block outbound
+    /// HTTP(S); such connections don't use the proxy. A `stun://host[:port]` URL instead asks a
+    /// STUN server (e.g. `stun://stun.l.google.com:19302`), which is rarely rate-limited. A
+    /// `dns://server[:port]/name` URL instead queries a DNS server which answers with the address
+    /// the query came from: `dns://resolver1.opendns.com/myip.opendns.com`, or
+    /// `dns://ns1-1.akamaitech.net/whoami.akamai.net`.
+    pub url: Option<String>,
+
+    /// A command which prints the address (as plain text, or a JSON object), e.g. to ask a
+    /// router. Can't be used with `--sandbox`.
+    pub exec: Option<ExecConfig>,
+
+    /// A network interface holding our public address, e.g. `eth0` on a router whose WAN
+    /// interface is given the address directly. Its first address which isn't loopback, private,
+    /// link-local, or in the shared address space used by carrier-grade NAT is used.
+    pub interface: Option<String>,
+
+    /// For JSON responses, the field holding the address (a dotted path for nested objects).
+    /// Defaults to `ip`.
+    pub field: Option<String>,
+
+    /// For URLs, how long the service may take to respond, in seconds, before it's considered to
+    /// have failed. Defaults to 30 seconds.
+    pub timeout_secs: Option<u64>,
+}
+
+/// How to use more than one detector.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DetectionMode {
+    #[default]
+    Quorum,
+    Fallback,
+}
+
+/// Config for an uplink.
+#[derive(Deserialize, Serialize)]
+pub struct UplinkConfig {
+    /// The uplink's network interface, e.g. `wan0`. Detection requests are made from its address.
+    pub interface: String,
+
+    /// The HTTP or interface detector used over the uplink. Defaults to ipify.
+    pub detector: Option<DetectorConfig>,
+}
+
+/// How to pick the uplink whose address is used.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UplinkPolicy {
+    #[default]
+    Primary,
+    LowestLatency,
+}
+
+/// Config for running an external command.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ExecConfig {
+    /// The program to run, followed by its arguments, e.g. `[/usr/local/bin/wan-ip, --quiet]`.
+    pub command: Vec<String>,
+
+    /// How long the command may take, in seconds, before it (& any processes it started) is
+    /// killed. Defaults to 30 seconds.
+    pub timeout_secs: Option<u64>,
+
+    /// The directory to run the command in. Defaults to `/`.
+    pub working_dir: Option<String>,
+
+    /// Variables to set in the command's environment. Otherwise, the environment holds only `PATH`
+    /// (& any variables documented for the command's use).
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "exec")]
+impl ExecConfig {
+    /// How long the command may take.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(30))
+    }
+}
+
+/// When to make the first check after starting.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FirstCheck {
+    #[default]
+    Immediately,
+    AfterInterval,
+}
+
+/// When to update records.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdatePolicy {
+    #[default]
+    OnChange,
+    OnChangeStable,
+    Always,
+    Never,
+}
+
+/// A positive duration, which deserializes from a number of seconds or from a string of numbers
+/// with units (`s`, `m`, `h`, or `d`), e.g. `90s` or `1h30m`, & serializes as the latter.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "DurationRepr", into = "String")]
+pub struct HumanDuration(pub Duration);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationRepr {
+    Secs(u64),
+    Text(String),
+}
+
+impl TryFrom<DurationRepr> for HumanDuration {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: DurationRepr) -> Result<Self> {
+        let secs = match repr {
+            DurationRepr::Secs(secs) => secs,
+            DurationRepr::Text(text) => {
+                parse_secs(&text).map_err(|err| anyhow!("invalid duration {text:?}: {err}"))?
+            }
+        };
+        if secs == 0 {
+            return Err(anyhow!("duration must be positive"));
+        }
+        Ok(HumanDuration(Duration::from_secs(secs)))
+    }
+}
+
+impl From<HumanDuration> for String {
+    fn from(duration: HumanDuration) -> String {
+        let mut secs = duration.0.as_secs();
+        let mut text = String::new();
+        for (unit, unit_secs) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+            if secs >= unit_secs {
+                text.push_str(&format!("{}{unit}", secs / unit_secs));
+                secs %= unit_secs;
+            }
+        }
+        text
+    }
+}
+
+/// Parses a number of seconds, optionally given as numbers with units, e.g. `1h30m`.
+fn parse_secs(text: &str) -> Result<u64> {
+    let text = text.trim();
+    if let Ok(secs) = text.parse() {
+        return Ok(secs);
+    }
+    let mut secs: u64 = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(anyhow!("expected a number at {rest:?}"));
+        }
+        let n: u64 = rest[..digits].parse()?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_secs = match &rest[..unit_len] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "" => return Err(anyhow!("missing unit after {n}")),
+            unit => return Err(anyhow!("unknown unit {unit:?}")),
+        };
+        rest = &rest[unit_len..];
+        secs = n
+            .checked_mul(unit_secs)
+            .and_then(|n| secs.checked_add(n))
+            .ok_or_else(|| anyhow!("too long"))?;
+    }
+    Ok(secs)
+}
+
+/// An IP address family preference.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressFamily {
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+/// Config for a provider, selected by the `provider` key.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum ProviderConfig {
+    #[cfg(feature = "namecheap")]
+    Namecheap(NamecheapConfig),
+    #[cfg(feature = "cloudflare")]
+    Cloudflare(CloudflareConfig),
+    #[cfg(feature = "duckdns")]
+    DuckDns(DuckDnsConfig),
+    #[cfg(feature = "noip")]
+    NoIp(NoIpConfig),
+    #[cfg(feature = "custom")]
+    Custom(CustomConfig),
+    #[cfg(feature = "relay")]
+    Relay(RelayConfig),
+}
+
+impl ProviderConfig {
+    /// The domain the (first) record is in.
+    pub fn domain(&self) -> &str {
+        match self {
+            #[cfg(feature = "namecheap")]
+            ProviderConfig::Namecheap(cfg) => &cfg.domain,
+            #[cfg(feature = "cloudflare")]
+            ProviderConfig::Cloudflare(cfg) => &cfg.domain,
+            #[cfg(feature = "duckdns")]
+            ProviderConfig::DuckDns(_) => DuckDnsConfig::DOMAIN,
+            #[cfg(feature = "noip")]
+            ProviderConfig::NoIp(cfg) => cfg.split_hostname().1,
+            #[cfg(feature = "custom")]
+            ProviderConfig::Custom(cfg) => &cfg.domain,
+            #[cfg(feature = "relay")]
+            ProviderConfig::Relay(cfg) => &cfg.domain,
+        }
+    }
+
+    /// The host of the (first) record, where `@` is the bare domain.
+    pub fn host(&self) -> &str {
+        match self {
+            #[cfg(feature = "namecheap")]
+            ProviderConfig::Namecheap(cfg) => cfg.host(),
+            #[cfg(feature = "cloudflare")]
+            ProviderConfig::Cloudflare(cfg) => cfg.host(),
+            #[cfg(feature = "duckdns")]
+            ProviderConfig::DuckDns(cfg) => cfg.subdomain(),
+            #[cfg(feature = "noip")]
+            ProviderConfig::NoIp(cfg) => cfg.split_hostname().0,
+            #[cfg(feature = "custom")]
+            ProviderConfig::Custom(cfg) => cfg.host(),
+            #[cfg(feature = "relay")]
+            ProviderConfig::Relay(cfg) => cfg.host(),
+        }
+    }
+
+    /// The provider's name, as given by the `provider` key, e.g. `namecheap`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "namecheap")]
+            ProviderConfig::Namecheap(_) => "namecheap",
+            #[cfg(feature = "cloudflare")]
+            ProviderConfig::Cloudflare(_) => "cloudflare",
+            #[cfg(feature = "duckdns")]
+            ProviderConfig::DuckDns(_) => "duckdns",
+            #[cfg(feature = "noip")]
+            ProviderConfig::NoIp(_) => "noip",
+            #[cfg(feature = "custom")]
+            ProviderConfig::Custom(_) => "custom",
+            #[cfg(feature = "relay")]
+            ProviderConfig::Relay(_) => "relay",
+        }
+    }
+
+    /// Splits the config into one per record.
+    pub fn per_record(&self) -> Vec<ProviderConfig> {
+        self.per_host().into_iter().map(|(cfg, _)| cfg).collect()
+    }
+
+    /// Splits the config into one per record, each with its host's own labels.
+    pub fn per_host(&self) -> Vec<(ProviderConfig, BTreeMap<String, String>)> {
+        match self {
+            #[cfg(feature = "namecheap")]
+            ProviderConfig::Namecheap(cfg) => cfg
+                .per_host()
+                .into_iter()
+                .map(|(cfg, labels)| (ProviderConfig::Namecheap(cfg), labels))
+                .collect(),
+            #[cfg(feature = "cloudflare")]
+            ProviderConfig::Cloudflare(cfg) => {
+                vec![(ProviderConfig::Cloudflare(cfg.clone()), BTreeMap::new())]
+            }
+            #[cfg(feature = "duckdns")]
+            ProviderConfig::DuckDns(cfg) => {
+                vec![(ProviderConfig::DuckDns(cfg.clone()), BTreeMap::new())]
+            }
+            #[cfg(feature = "noip")]
+            ProviderConfig::NoIp(cfg) => {
+                vec![(ProviderConfig::NoIp(cfg.clone()), BTreeMap::new())]
+            }
+            #[cfg(feature = "custom")]
+            ProviderConfig::Custom(cfg) => {
+                vec![(ProviderConfig::Custom(cfg.clone()), BTreeMap::new())]
+            }
+            #[cfg(feature = "relay")]
+            ProviderConfig::Relay(cfg) => {
+                vec![(ProviderConfig::Relay(cfg.clone()), BTreeMap::new())]
+            }
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<&mut Secret> {
+        match self {
+            #[cfg(feature = "namecheap")]
+            ProviderConfig::Namecheap(cfg) => cfg.secrets_mut(),
+            #[cfg(feature = "cloudflare")]
+            ProviderConfig::Cloudflare(cfg) => vec![&mut cfg.api_token],
+            #[cfg(feature = "duckdns")]
+            ProviderConfig::DuckDns(cfg) => vec![&mut cfg.token],
+            #[cfg(feature = "noip")]
+            ProviderConfig::NoIp(cfg) => vec![&mut cfg.password],
+            #[cfg(feature = "custom")]
+            ProviderConfig::Custom(cfg) => cfg.password.iter_mut().collect(),
+            #[cfg(feature = "relay")]
+            ProviderConfig::Relay(_) => Vec::new(),
+        }
+    }
+}
+
+/// Config for a Namecheap dynamic DNS record.
+#[cfg(feature = "namecheap")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NamecheapConfig {
+    /// The domain to update.
+    pub domain: String,
+
+    /// The host (aka subdomain) to set DNS for. Omit, or specify `@`, to update the bare domain.
+    /// Specify `*` to update the wildcard subdomain.
+    pub host: Option<String>,
+
+    /// More hosts to set DNS for, alongside `host`, sharing these settings. Each is either a host
+    /// name, or a host entry giving its own domain & password (e.g. `{host: vpn, domain:
+    /// example.net, password: ...}`). The shorthand `apex+www+wildcard` (also accepted as `host`)
+    /// expands to `@`, `www`, & `*`. Entries may also use their own account's credentials
+    /// (`password` or `api`). Each host is kept up to date, paused, & reported on separately.
+    #[serde(default)]
+    pub hosts: Vec<HostEntry>,
+
+    /// The TTL to give the record, in seconds (60 to 86400); drift from it (e.g. an edit in
+    /// Namecheap's dashboard) is corrected. Only supported with the XML API: dynamic DNS updates
+    /// leave the TTL as it is.
+    pub ttl_secs: Option<u32>,
+
+    /// Which records to update: `A` (the default), `AAAA`, or `both`. AAAA records can only be
+    /// updated with the XML API.
+    #[serde(default)]
+    pub record_types: RecordTypes,
+
+    /// SRV records to maintain, pointing services (e.g. `_minecraft._tcp`) at the (first) host,
+    /// with the configured port, priority, & weight. Only supported with the XML API.
+    #[serde(default)]
+    pub srv_records: Vec<SrvRecordConfig>,
+
+    /// Read the domain's host records back after writing them, & fail the update (so that it's
+    /// notified & retried) if Namecheap dropped or changed any. Only supported with the XML API.
+    #[serde(default)]
+    pub verify_hosts: bool,
+
+    /// Extra query parameters to send with dynamic DNS updates. A parameter with the same name as
+    /// a standard one (`host`, `domain`, `password`, or `ip`) replaces it, & a null value removes
+    /// it, e.g. to send `Host` rather than `host`.
+    #[serde(default)]
+    pub extra_params: BTreeMap<String, Option<String>>,
+
+    /// Overrides of how calls to Namecheap are made.
+    #[serde(flatten)]
+    pub calls: CallConfig,
+
+    /// The credentials to use, which select the API used to update the record.
+    #[serde(flatten)]
+    pub auth: NamecheapAuth,
+}
+
+/// Config for an SRV record pointing a service at a Namecheap record.
+#[cfg(feature = "namecheap")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SrvRecordConfig {
+    /// The record's name, relative to the domain: the service & protocol, e.g. `_minecraft._tcp`.
+    pub name: String,
+
+    /// The port the service listens on.
+    pub port: u16,
+
+    /// The record's priority, where lower is preferred. Defaults to 0.
+    #[serde(default)]
+    pub priority: u16,
+
+    /// The record's weight, between records of the same priority. Defaults to 0.
+    #[serde(default)]
+    pub weight: u16,
+}
+
+#[cfg(feature = "namecheap")]
+impl NamecheapConfig {
+    /// The (first) host to set DNS for, defaulting to the bare domain.
+    pub fn host(&self) -> &str {
+        let host = match (&self.host, self.hosts.first()) {
+            (Some(host), _) | (None, Some(HostEntry::Host(host))) => host,
+            (None, Some(HostEntry::Entry(entry))) => &entry.host,
+            (None, None) => "@",
+        };
+        match host {
+            STANDARD_HOSTS => "@",
+            host => host,
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<&mut Secret> {
+        let mut secrets = vec![self.auth.secret()];
+        secrets.extend(self.hosts.iter_mut().filter_map(|entry| match entry {
+            HostEntry::Host(_) => None,
+            HostEntry::Entry(entry) => entry.auth.as_mut().map(NamecheapAuth::secret),
+        }));
+        secrets
+    }
+
+    /// Splits the config into one per host, with shorthands expanded & duplicates removed, each
+    /// with the host's own labels.
+    pub fn per_host(&self) -> Vec<(NamecheapConfig, BTreeMap<String, String>)> {
+        let plain = |host: &str| HostConfig {
+            host: host.to_string(),
+            domain: None,
+            auth: None,
+            labels: BTreeMap::new(),
+        };
+        let mut entries: Vec<HostConfig> = Vec::new();
+        for entry in self
+            .host
+            .iter()
+            .cloned()
+            .map(HostEntry::Host)
+            .chain(self.hosts.clone())
+        {
+            match entry {
+                HostEntry::Host(host) if host == STANDARD_HOSTS => {
+                    entries.extend(["@", "www", "*"].map(plain))
+                }
+                HostEntry::Host(host) => entries.push(plain(&host)),
+                HostEntry::Entry(entry) => entries.push(entry),
+            }
+        }
+        if entries.is_empty() {
+            entries.push(plain("@"));
+        }
+
+        let mut cfgs: Vec<(NamecheapConfig, BTreeMap<String, String>)> = Vec::new();
+        for entry in entries {
+            let cfg = NamecheapConfig {
+                domain: entry.domain.unwrap_or_else(|| self.domain.clone()),
+                host: Some(entry.host),
+                hosts: Vec::new(),
+                // SRV records point at the first host only.
+                srv_records: match cfgs.is_empty() {
+                    true => self.srv_records.clone(),
+                    false => Vec::new(),
+                },
+                auth: entry.auth.unwrap_or_else(|| self.auth.clone()),
+                ..self.clone()
+            };
+            if !cfgs
+                .iter()
+                .any(|(other, _)| other.domain == cfg.domain && other.host == cfg.host)
+            {
+                cfgs.push((cfg, entry.labels));
+            }
+        }
+        cfgs
+    }
+}
+
+/// Config for a Cloudflare DNS record.
+#[cfg(feature = "cloudflare")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CloudflareConfig {
+    /// The domain (i.e. zone) to update, e.g. `example.com`.
+    pub domain: String,
+
+    /// The host (aka subdomain) to set DNS for. Omit, or specify `@`, to update the bare domain.
+    pub host: Option<String>,
+
+    /// An API token with the `Zone.DNS: Edit` permission for the zone.
+    pub api_token: Secret,
+
+    /// The zone's ID. If omitted, it's looked up by name, which also requires the token to have
+    /// the `Zone.Zone: Read` permission.
+    pub zone_id: Option<String>,
+
+    /// The TTL to give the record, in seconds (60 to 86400), or 1 for automatic; drift from it is
+    /// corrected.
+    pub ttl_secs: Option<u32>,
+
+    /// Whether the record is proxied through Cloudflare; drift from it is corrected. If omitted,
+    /// existing records are left as they are, & new ones aren't proxied.
+    pub proxied: Option<bool>,
+
+    /// Which records to update: `A` (the default), `AAAA`, or `both`.
+    #[serde(default)]
+    pub record_types: RecordTypes,
+
+    /// Overrides of how calls to Cloudflare are made.
+    #[serde(flatten)]
+    pub calls: CallConfig,
+}
+
+#[cfg(feature = "cloudflare")]
+impl CloudflareConfig {
+    /// The host to set DNS for, defaulting to the bare domain.
+    pub fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or("@")
+    }
+}
+
+/// Config for a DuckDNS record.
+#[cfg(feature = "duckdns")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DuckDnsConfig {
+    /// The subdomain to update, e.g. `myhome` (or `myhome.duckdns.org`).
+    pub subdomain: String,
+
+    /// The account's token, shown on duckdns.org once logged in.
+    pub token: Secret,
+
+    /// Which records to update: `A` (the default), `AAAA`, or `both`.
+    #[serde(default)]
+    pub record_types: RecordTypes,
+
+    /// Overrides of how calls to DuckDNS are made.
+    #[serde(flatten)]
+    pub calls: CallConfig,
+}
+
+#[cfg(feature = "duckdns")]
+impl DuckDnsConfig {
+    /// The domain every DuckDNS subdomain is in.
+    pub const DOMAIN: &'static str = "duckdns.org";
+
+    /// The subdomain to update, without the DuckDNS domain.
+    pub fn subdomain(&self) -> &str {
+        self.subdomain
+            .strip_suffix(".duckdns.org")
+            .unwrap_or(&self.subdomain)
+    }
+}
+
+/// Config for a No-IP record.
+#[cfg(feature = "noip")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NoIpConfig {
+    /// The fully-qualified hostname to update, e.g. `myhome.ddns.net`.
+    pub hostname: String,
+
+    /// The account's username (or email address), or a DDNS key's username.
+    pub username: String,
+
+    /// The password of the account or DDNS key.
+    pub password: Secret,
+
+    /// Which records to update: `A` (the default), `AAAA`, or `both`.
+    #[serde(default)]
+    pub record_types: RecordTypes,
+
+    /// Overrides of how calls to No-IP are made.
+    #[serde(flatten)]
+    pub calls: CallConfig,
+}
+
+#[cfg(feature = "noip")]
+impl NoIpConfig {
+    /// Splits the hostname into its host & domain.
+    pub fn split_hostname(&self) -> (&str, &str) {
+        self.hostname
+            .split_once('.')
+            .unwrap_or(("@", &self.hostname))
+    }
+}
+
+/// Config for a record updated by requesting a URL, for providers without built-in support.
+#[cfg(feature = "custom")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CustomConfig {
+    /// The URL to GET to update the record, e.g.
+    /// `https://example.com/update?host={host}&ip={ip}&token={password}`. The placeholders
+    /// `{ip}`, `{host}`, `{domain}`, `{record}` (the host within the domain), `{username}`, &
+    /// `{password}` are replaced with their (URL-encoded) values.
+    pub url: String,
+
+    /// The domain to update.
+    pub domain: String,
+
+    /// The host (aka subdomain) to set DNS for. Omit, or specify `@`, to update the bare domain.
+    pub host: Option<String>,
+
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+
+    /// How to tell whether an update succeeded.
+    #[serde(default)]
+    pub success: SuccessConfig,
+
+    /// Which records to update: `A` (the default), `AAAA`, or `both`.
+    #[serde(default)]
+    pub record_types: RecordTypes,
+
+    /// Overrides of how calls to the provider are made.
+    #[serde(flatten)]
+    pub calls: CallConfig,
+}
+
+#[cfg(feature = "custom")]
+impl CustomConfig {
+    /// The host to set DNS for, defaulting to the bare domain.
+    pub fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or("@")
+    }
+}
+
+/// How to tell whether a custom provider's update succeeded: every rule given must hold. If none
+/// are given, any 2xx status means success. Bodies are matched with surrounding whitespace
+/// trimmed.
+#[cfg(feature = "custom")]
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct SuccessConfig {
+    /// The status code of a successful response.
+    pub status: Option<u16>,
+
+    /// Text that the body of a successful response contains.
+    pub contains: Option<String>,
+
+    /// A regular expression that the body of a successful response matches, e.g. `^good|^nochg`.
+    /// Only a subset of the usual syntax is supported: literals, `.`, character classes (`[a-z]`,
+    /// `\d`, `\w`, `\s`), `*`, `+`, `?`, `^`, `$`, & `|` between whole alternatives (but not
+    /// groups).
+    pub matches: Option<Pattern>,
+}
+
+/// Config for a record updated through a relay: another rnccd instance, running `rnccd
+/// relay-server`, which holds the provider's credentials & makes the update on our behalf. Calls
+/// to the relay are authenticated with a client certificate, & the relay's certificate is checked
+/// against `ca_cert` alone. Certificates are read at startup (& on reload), so rnccd must be
+/// reloaded once they're renewed.
+#[cfg(feature = "relay")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RelayConfig {
+    /// The relay's URL, e.g. `https://relay.example.net:8443`.
+    pub url: String,
+
+    /// The domain to update.
+    pub domain: String,
+
+    /// The host (aka subdomain) to set DNS for. Omit, or specify `@`, to update the bare domain.
+    pub host: Option<String>,
+
+    /// A PEM file holding the certificate of the CA which issued the relay's certificate.
+    pub ca_cert: String,
+
+    /// A PEM file holding our client certificate (followed by any intermediates).
+    pub client_cert: String,
+
+    /// A PEM file holding our client certificate's private key.
+    pub client_key: String,
+
+    /// Which records to update: `A` (the default), `AAAA`, or `both`.
+    #[serde(default)]
+    pub record_types: RecordTypes,
+
+    /// Overrides of how calls to the relay are made.
+    #[serde(flatten)]
+    pub calls: CallConfig,
+}
+
+#[cfg(feature = "relay")]
+impl RelayConfig {
+    /// The host to set DNS for, defaulting to the bare domain.
+    pub fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or("@")
+    }
+}
+
+/// Config for `rnccd relay-server`, which updates records on behalf of other rnccd instances
+/// (e.g. on routers which can't reach the providers, or shouldn't hold their credentials). As in
+/// the daemon's config, `profiles` gives named sets of provider settings.
+#[cfg(feature = "relay")]
+#[derive(Deserialize, Serialize)]
+pub struct RelayServerConfig {
+    /// A PEM file holding the relay's certificate (followed by any intermediates).
+    pub cert: String,
+
+    /// A PEM file holding the relay's certificate's private key.
+    pub key: String,
+
+    /// A PEM file holding the certificates of the CAs which issue clients' certificates. Only
+    /// clients presenting a certificate issued by one of these (& listed in `clients`) may call.
+    pub client_ca: String,
+
+    /// The clients which may call, & the records each may update.
+    pub clients: Vec<RelayClientConfig>,
+
+    /// The records which may be updated, as for `backup_providers`.
+    pub providers: Vec<ProviderConfig>,
+
+    /// A file holding secrets, as for the daemon's config.
+    pub secrets: Option<String>,
+}
+
+#[cfg(feature = "relay")]
+impl RelayServerConfig {
+    /// Replaces each reference to a secret with its value, from the secrets file.
+    pub fn resolve_secrets(&mut self, config_path: &Path) -> Result<()> {
+        let secrets = self
+            .providers
+            .iter_mut()
+            .flat_map(ProviderConfig::secrets_mut)
+            .collect();
+        resolve_secrets(secrets, self.secrets.as_deref(), config_path)
+    }
+}
+
+/// A client of the relay, identified by its certificate.
+#[cfg(feature = "relay")]
+#[derive(Deserialize, Serialize)]
+pub struct RelayClientConfig {
+    /// A name for the client, used in logs.
+    pub name: String,
+
+    /// The SHA-256 fingerprint of the client's certificate, in hex (colons are optional), e.g. as
+    /// printed by `openssl x509 -noout -fingerprint -sha256`.
+    pub cert_sha256: String,
+
+    /// The records (e.g. `www.example.com`) the client may update.
+    pub records: Vec<String>,
+}
+
+/// The shorthand for the hosts almost everyone wants: the bare domain, `www`, & the wildcard.
+#[cfg(feature = "namecheap")]
+const STANDARD_HOSTS: &str = "apex+www+wildcard";
+
+/// An entry of `hosts`: a host name, or a host with its own settings.
+#[cfg(feature = "namecheap")]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum HostEntry {
+    Host(String),
+    Entry(HostConfig),
+}
+
+/// A host with its own settings, overriding those of the config it's listed in.
+#[cfg(feature = "namecheap")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HostConfig {
+    pub host: String,
+
+    /// The domain the host is in.
+    pub domain: Option<String>,
+
+    /// The host's credentials: a dynamic DNS password (which Namecheap issues per domain), or
+    /// the XML API credentials of the account the domain is in.
+    #[serde(flatten)]
+    pub auth: Option<NamecheapAuth>,
+
+    /// Labels for this host, added to (& overriding) the config's.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Which address records of a host to update.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub enum RecordTypes {
+    #[default]
+    A,
+    #[serde(rename = "AAAA")]
+    Aaaa,
+    #[serde(rename = "both")]
+    Both,
+}
+
+impl RecordTypes {
+    pub fn types(self) -> Vec<RecordType> {
+        match self {
+            RecordTypes::A => vec![RecordType::A],
+            RecordTypes::Aaaa => vec![RecordType::Aaaa],
+            RecordTypes::Both => vec![RecordType::A, RecordType::Aaaa],
+        }
+    }
+}
+
+/// Overrides of how calls to a provider are made, for providers whose latency or reliability
+/// differs from the usual.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CallConfig {
+    /// How long a request to the provider may take, in seconds. Defaults to 30 seconds.
+    pub timeout_secs: Option<u64>,
+
+    /// How many times a failed update is retried before the check fails (& is retried after the
+    /// usual backoff). Defaults to 0.
+    #[serde(default)]
+    pub retries: u32,
+
+    /// How long to wait before the first retry, in seconds, doubling for each further retry.
+    /// Defaults to 5 seconds.
+    pub retry_delay_secs: Option<u64>,
+
+    /// How long to stop calling the provider with an account, in seconds, after the provider locks
+    /// it out (e.g. for making too many requests). Defaults to 3 hours.
+    pub lockout_cooldown_secs: Option<u64>,
+}
+
+impl CallConfig {
+    /// How long a request to the provider may take.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(30))
+    }
+
+    /// How long to wait before the first retry.
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_delay_secs.unwrap_or(5))
+    }
+
+    /// How long to stop calling the provider with an account after it's locked out.
+    pub fn lockout_cooldown(&self) -> Duration {
+        Duration::from_secs(self.lockout_cooldown_secs.unwrap_or(3 * 60 * 60))
+    }
+}
+
+/// Credentials for Namecheap: either a dynamic DNS password (`password: ...`), or XML API
+/// credentials (`api: {...}`).
+#[cfg(feature = "namecheap")]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum NamecheapAuth {
+    /// The dynamic DNS password, for the dynamic DNS API.
+    Password { password: Secret },
+
+    /// Credentials for the XML API, which updates the record by rewriting the domain's host
+    /// records. API access must be enabled for the account, with our address allowlisted.
+    Api { api: NamecheapApiConfig },
+}
+
+#[cfg(feature = "namecheap")]
+impl NamecheapAuth {
+    fn secret(&mut self) -> &mut Secret {
+        match self {
+            NamecheapAuth::Password { password } => password,
+            NamecheapAuth::Api { api } => &mut api.key,
+        }
+    }
+}
+
+/// Config for Namecheap's XML API.
+#[cfg(feature = "namecheap")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NamecheapApiConfig {
+    /// The API user.
+    pub user: String,
+
+    /// The API key.
+    pub key: Secret,
+
+    /// The account whose domain is updated, defaulting to the API user.
+    pub username: Option<String>,
+
+    /// The address to give as the client IP of API calls, which must be allowlisted. Defaults to
+    /// the address an A record is being set to. Namecheap only accepts IPv4 addresses here, so
+    /// this is required to update AAAA records.
+    pub client_ip: Option<Ipv4Addr>,
+
+    /// Use Namecheap's sandbox environment (a separate account, at sandbox.namecheap.com) rather
+    /// than production. Sandbox records aren't served in public DNS, so updates to them aren't
+    /// checked for propagation.
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+#[cfg(feature = "namecheap")]
+impl NamecheapApiConfig {
+    /// The account whose domain is updated.
+    pub fn username(&self) -> &str {
+        self.username.as_deref().unwrap_or(&self.user)
+    }
+}
+
+/// Config for measuring propagation of updates.
+#[derive(Deserialize, Serialize)]
+pub struct PropagationConfig {
+    /// The resolvers to poll, defaulting to Cloudflare's, Google's, & Quad9's. Authoritative
+    /// nameservers may be listed too.
+    #[serde(default = "default_resolvers")]
+    pub resolvers: Vec<IpAddr>,
+
+    /// How long to keep polling for, in seconds, before giving up. Defaults to an hour.
+    pub timeout_secs: Option<u64>,
+
+    /// How long past the TTL of the old record a resolver may keep returning it, in seconds,
+    /// before propagation is considered to have failed. Defaults to 5 minutes.
+    pub grace_secs: Option<u64>,
+}
+
+impl PropagationConfig {
+    /// How long past the TTL of the old record a resolver may keep returning it.
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace_secs.unwrap_or(5 * 60))
+    }
+
+    /// How long to keep polling for before giving up.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(60 * 60))
+    }
+}
+
+/// Config for verifying records in DNS.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VerifyDnsConfig {
+    /// The resolver to query, defaulting to Cloudflare's. An authoritative nameserver for the
+    /// records avoids answers cached from before an update.
+    #[serde(default = "default_resolver")]
+    pub resolver: IpAddr,
+
+    /// How often to verify after startup, e.g. `6h`; this should be longer than the records'
+    /// TTL, or a record may be updated again while resolvers still have the old address. If
+    /// omitted, records are only verified at startup.
+    pub interval: Option<HumanDuration>,
+}
+
+impl Default for VerifyDnsConfig {
+    /// Verifies at startup only, via the default resolver.
+    fn default() -> Self {
+        Self {
+            resolver: default_resolver(),
+            interval: None,
+        }
+    }
+}
+
+/// Config for a dead man's switch.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HealthcheckConfig {
+    /// The URL to ping after each successful check, e.g. `https://hc-ping.com/<uuid>`. Failed
+    /// checks ping `<url>/fail` instead, with the error as the body. Checks skipped while paused
+    /// don't ping. With both A & AAAA records, each type's checks ping.
+    pub url: String,
+
+    /// Also ping `<url>/start` as each check starts, so that the monitor can alert if a check
+    /// hangs, & measure how long checks take.
+    #[serde(default)]
+    pub ping_start: bool,
+}
+
+/// Config for the local HTTP API.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HttpApiConfig {
+    /// The address to listen on, e.g. `127.0.0.1:8053`. It must be a loopback address, since the
+    /// API is unauthenticated.
+    pub listen: SocketAddr,
+}
+
+/// Config for a canary record.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CanaryConfig {
+    /// The canary record, e.g. `canary.example.com`.
+    pub record: String,
+
+    /// The resolver to check the canary with, defaulting to Cloudflare's. An authoritative
+    /// nameserver for the record avoids waiting out answers cached from before the update.
+    #[serde(default = "default_resolver")]
+    pub resolver: IpAddr,
+
+    /// How long to wait for the canary to resolve to the new address, e.g. `2m`, before giving up
+    /// on the check (which counts towards `cycle_timeout`). Defaults to 30 seconds.
+    pub timeout: Option<HumanDuration>,
+}
+
+impl CanaryConfig {
+    /// How long to wait for the canary to resolve to the new address.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+            .map_or(Duration::from_secs(30), |timeout| timeout.0)
+    }
+}
+
+fn default_resolver() -> IpAddr {
+    Ipv4Addr::new(1, 1, 1, 1).into()
+}
+
+fn default_resolvers() -> Vec<IpAddr> {
+    vec![
+        Ipv4Addr::new(1, 1, 1, 1).into(),
+        Ipv4Addr::new(8, 8, 8, 8).into(),
+        Ipv4Addr::new(9, 9, 9, 9).into(),
+    ]
+}
+
+/// Config for a webhook, which is sent each notification as a JSON POST.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    pub url: String,
+
+    /// The body sent: `json` (the default) sends the notification as is, while `slack` & `discord`
+    /// send a message describing it, as accepted by Slack's & Discord's incoming webhooks.
+    #[serde(default)]
+    pub format: WebhookFormat,
+
+    /// A secret shared with the receiver. If given, each request's body is signed with it, in an
+    /// `X-Signature: sha256=<hex-encoded HMAC-SHA256>` header.
+    pub secret: Option<Secret>,
+}
+
+/// The body sent to a webhook.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    #[default]
+    Json,
+    Slack,
+    Discord,
+}
+
+/// A rule for which notifications about some records are sent.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NotificationRule {
+    /// The records the rule applies to, e.g. `www.example.com`; `*.example.com` matches every
+    /// subdomain of `example.com`.
+    pub records: Vec<String>,
+
+    /// The least severe notifications sent: `info` (the default, sending everything), `warning`,
+    /// or `critical`. IP changes are info; detector disagreements & propagation failures are
+    /// warnings; lockouts are critical.
+    #[serde(default)]
+    pub min_severity: Severity,
+
+    /// Hours during which fewer notifications are sent, e.g. overnight.
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl NotificationRule {
+    /// Whether the rule applies to the given record.
+    pub fn matches(&self, record: &str) -> bool {
+        self.records
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => record
+                    .strip_suffix(domain)
+                    .is_some_and(|host| host.ends_with('.')),
+                None => pattern == record,
+            })
+    }
+}
+
+/// The severity of a notification.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Hours, in local time, during which only notifications of at least some severity are sent;
+/// others are dropped.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QuietHours {
+    /// When quiet hours start, e.g. `22:00`.
+    pub start: TimeOfDay,
+
+    /// When quiet hours end, e.g. `07:00`. If before `start`, quiet hours span midnight.
+    pub end: TimeOfDay,
+
+    /// The least severe notifications sent during quiet hours. Defaults to `critical`.
+    #[serde(default = "critical")]
+    pub min_severity: Severity,
+}
+
+fn critical() -> Severity {
+    Severity::Critical
+}
+
+impl QuietHours {
+    /// Whether the given time of day is within quiet hours.
+    pub fn contains(&self, time: TimeOfDay) -> bool {
+        match self.start <= self.end {
+            true => self.start <= time && time < self.end,
+            false => self.start <= time || time < self.end,
+        }
+    }
+}
+
+/// A time of day, to the minute, which (de)serializes as `HH:MM`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    /// Minutes since midnight.
+    pub minutes: u32,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        let parsed = text.split_once(':').and_then(|(hours, minutes)| {
+            let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+            (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+        });
+        let minutes = parsed.ok_or_else(|| anyhow!("invalid time of day {text:?} (want HH:MM)"))?;
+        Ok(TimeOfDay { minutes })
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> String {
+        format!("{:02}:{:02}", time.minutes / 60, time.minutes % 60)
+    }
+}
+
+/// Config for a metrics sink, selected by the `sink` key.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "sink", rename_all = "lowercase")]
+pub enum MetricsConfig {
+    #[cfg(feature = "influxdb")]
+    InfluxDb(InfluxDbConfig),
+    #[cfg(feature = "graphite")]
+    Graphite(GraphiteConfig),
+}
+
+impl MetricsConfig {
+    /// The URL written to, for sinks with one.
+    pub fn url(&self) -> Option<&str> {
+        match *self {
+            #[cfg(feature = "influxdb")]
+            MetricsConfig::InfluxDb(ref cfg) => Some(&cfg.url),
+            #[cfg(feature = "graphite")]
+            MetricsConfig::Graphite(_) => None,
+        }
+    }
+
+    fn secret(&mut self) -> Option<&mut Secret> {
+        match *self {
+            #[cfg(feature = "influxdb")]
+            MetricsConfig::InfluxDb(ref mut cfg) => cfg.token.as_mut(),
+            #[cfg(feature = "graphite")]
+            MetricsConfig::Graphite(_) => None,
+        }
+    }
+}
+
+/// Config for writing metrics to InfluxDB, in line protocol.
+#[cfg(feature = "influxdb")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct InfluxDbConfig {
+    /// Where to write to: either an HTTP(S) write endpoint, including any query parameters (e.g.
+    /// `http://influx:8086/api/v2/write?org=home&bucket=rnccd`), or a UDP listener (e.g.
+    /// `udp://influx:8089`).
+    pub url: String,
+
+    /// An API token, sent with HTTP writes.
+    pub token: Option<Secret>,
+}
+
+/// Config for writing metrics to Graphite, in the plaintext protocol.
+#[cfg(feature = "graphite")]
+#[derive(Clone, Deserialize, Serialize)]
+pub struct GraphiteConfig {
+    /// The host & port of the Graphite (carbon) plaintext listener, e.g. `graphite:2003`.
+    pub address: String,
+
+    /// The prefix of each metric path, defaulting to `rnccd`.
+    pub prefix: Option<String>,
+}
+
+#[cfg(feature = "graphite")]
+impl GraphiteConfig {
+    /// The prefix of each metric path.
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("rnccd")
+    }
+}
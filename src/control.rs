@@ -0,0 +1,166 @@
+//! The control socket, over which `rnccd` subcommands talk to a running daemon. The protocol is
+//! line-based: a client connects, sends a single command line, and receives a single response
+//! line, which is either `ok [<output>]` or `error <message>`.
+
+use crate::{faults::Faults, state::StateUpdate, status::Status, Command};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, watch},
+};
+use tracing::{info, warn, Span};
+
+/// The maximum length of a command line.
+const MAX_COMMAND_LEN: u64 = 1024;
+
+/// How long a client waits for the daemon to respond.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds the control socket at the given path, replacing any stale socket left behind by a
+/// previous run. This is done before sandboxing & dropping privileges.
+pub fn bind(path: &Path) -> io::Result<StdUnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) | Err(_) => (),
+    }
+    let listener = StdUnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Sends a command to the daemon listening on the given control socket, returning its output.
+pub fn send(path: &Path, command: &Command) -> Result<String> {
+    let mut stream = StdUnixStream::connect(path)
+        .with_context(|| format!("couldn't connect to {}", path.display()))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let line = match command {
+        Command::Pause { record } => format!("pause {record}"),
+        Command::Resume { record } => format!("resume {record}"),
+        Command::Status { .. } => "status".to_string(),
+        Command::InjectFailure { injection } => format!("inject-failure {injection}"),
+        Command::Doctor | Command::Config { .. } => {
+            return Err(anyhow!("not a control command"));
+        }
+        #[cfg(feature = "tcp-echo")]
+        Command::EchoServer { .. } => return Err(anyhow!("not a control command")),
+        #[cfg(feature = "relay")]
+        Command::RelayServer { .. } => return Err(anyhow!("not a control command")),
+        #[cfg(feature = "namecheap")]
+        Command::Records { .. } => return Err(anyhow!("not a control command")),
+    };
+    writeln!(stream, "{line}")?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    let response = response.trim_end();
+    match response.split_once(' ').unwrap_or((response, "")) {
+        ("ok", output) => Ok(output.to_string()),
+        ("error", msg) => Err(anyhow!("{msg}")),
+        _ => Err(anyhow!("unexpected response: {response:?}")),
+    }
+}
+
+/// Server is the daemon side of the control socket.
+pub struct Server {
+    listener: UnixListener,
+    paused: HashMap<String, watch::Sender<bool>>,
+    statuses: Vec<watch::Receiver<Status>>,
+    state_updates: mpsc::Sender<(StateUpdate, Span)>,
+    faults: Arc<Faults>,
+}
+
+impl Server {
+    /// Creates a server. `paused` holds the pause switch of the worker for each record, &
+    /// `statuses` the status of each worker.
+    pub fn new(
+        listener: StdUnixListener,
+        paused: HashMap<String, watch::Sender<bool>>,
+        statuses: Vec<watch::Receiver<Status>>,
+        state_updates: mpsc::Sender<(StateUpdate, Span)>,
+        faults: Arc<Faults>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: UnixListener::from_std(listener)?,
+            paused,
+            statuses,
+            state_updates,
+            faults,
+        })
+    }
+
+    /// Runs the server forever. Connections are handled one at a time, which is plenty.
+    pub async fn run(self) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    if let Err(err) = self.handle(stream).await {
+                        warn!(%err, "Couldn't handle control connection");
+                    }
+                }
+                Err(err) => warn!(%err, "Couldn't accept control connection"),
+            }
+        }
+    }
+
+    async fn handle(&self, stream: UnixStream) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut line = String::new();
+        AsyncBufReader::new(read.take(MAX_COMMAND_LEN))
+            .read_line(&mut line)
+            .await?;
+        let response = match self.execute(line.trim_end()).await {
+            Ok(output) if output.is_empty() => "ok".to_string(),
+            Ok(output) => format!("ok {output}"),
+            Err(err) => format!("error {err:#}"),
+        };
+        write.write_all(format!("{response}\n").as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn execute(&self, line: &str) -> Result<String> {
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "pause" => self.set_paused(arg, true).await,
+            "resume" => self.set_paused(arg, false).await,
+            "status" => {
+                let statuses: Vec<_> = self.statuses.iter().map(|rx| rx.borrow().clone()).collect();
+                Ok(serde_json::to_string(&statuses)?)
+            }
+            "inject-failure" => {
+                self.faults.inject(arg.parse()?);
+                Ok(String::new())
+            }
+            _ => Err(anyhow!("unknown command {command:?}")),
+        }
+    }
+
+    async fn set_paused(&self, record: &str, paused: bool) -> Result<String> {
+        let switch = self
+            .paused
+            .get(record)
+            .ok_or_else(|| anyhow!("unknown record {record:?}"))?;
+        self.state_updates
+            .send((
+                StateUpdate::Paused(record.to_string(), paused),
+                Span::current(),
+            ))
+            .await
+            .map_err(|_| anyhow!("state writer is gone"))?;
+        switch.send_replace(paused);
+        info!(record, paused, "Changed pause state via control socket");
+        Ok(String::new())
+    }
+}
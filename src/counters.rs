@@ -0,0 +1,69 @@
+//! Cumulative counts of each provider's updates & failed updates, & when it last changed its
+//! record. Counters are persisted in the state, so that metrics & `rnccd status` don't reset to
+//! zero whenever the daemon restarts.
+
+use crate::status;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A provider's counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Totals {
+    /// Successful updates.
+    pub updates: u64,
+
+    /// Failed update attempts (including those later retried).
+    pub failures: u64,
+
+    /// When the last successful update was made, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_change: Option<u64>,
+}
+
+/// Counters is a handle to every provider's counters, shared by all workers.
+#[derive(Clone, Default)]
+pub struct Counters {
+    totals: Arc<Mutex<BTreeMap<String, Totals>>>, // keyed by provider ID.
+}
+
+impl Counters {
+    /// Creates a handle to the given (persisted) counters.
+    pub fn new(totals: BTreeMap<String, Totals>) -> Self {
+        Self {
+            totals: Arc::new(Mutex::new(totals)),
+        }
+    }
+
+    /// Returns the counters of the provider with the given ID.
+    pub fn get(&self, id: &str) -> Totals {
+        self.totals
+            .lock()
+            .unwrap()
+            .get(id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Counts an update attempt by the provider with the given ID, returning its counters.
+    pub fn count(&self, id: &str, success: bool) -> Totals {
+        let mut totals = self.totals.lock().unwrap();
+        let totals = totals.entry(id.to_string()).or_default();
+        match success {
+            true => {
+                totals.updates += 1;
+                totals.last_change = Some(status::unix_time(SystemTime::now()));
+            }
+            false => totals.failures += 1,
+        }
+        *totals
+    }
+
+    /// Returns every provider's counters, to be persisted.
+    pub fn all(&self) -> BTreeMap<String, Totals> {
+        self.totals.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,154 @@
+//! Updates of records by requesting a templated URL, as accepted by many routers & small dynamic
+//! DNS services, so that providers without built-in support can be used.
+
+use crate::{
+    backoff::Permanent,
+    codes::Code,
+    config::{CallConfig, CustomConfig},
+    lockout::Lockout,
+    provider::{self, Provider, RecordType, UpdateFuture},
+};
+use anyhow::{anyhow, Result};
+use reqwest::StatusCode;
+use std::{fmt::Write, net::IpAddr};
+
+/// Custom updates a record by requesting the configured URL.
+pub struct Custom {
+    id: String,
+    record: String,
+    record_type: RecordType,
+    cfg: CustomConfig,
+}
+
+impl Custom {
+    pub fn new(cfg: CustomConfig, record_type: RecordType) -> Self {
+        let record = provider::record_name(&cfg.domain, cfg.host());
+        let id = match record_type {
+            RecordType::A => format!("custom:{record}"),
+            RecordType::Aaaa => format!("custom:{record}/AAAA"),
+        };
+        Self {
+            id,
+            record,
+            record_type,
+            cfg,
+        }
+    }
+
+    /// Returns the URL to request to point the record at `addr`.
+    fn url(&self, addr: IpAddr) -> Result<String> {
+        let mut url = String::new();
+        let mut rest = self.cfg.url.as_str();
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("URL has an unclosed placeholder"))?;
+            let value = match &rest[start + 1..start + len] {
+                "ip" => addr.to_string(),
+                "host" => self.cfg.host().to_string(),
+                "domain" => self.cfg.domain.clone(),
+                "record" => self.record.clone(),
+                "username" => self.cfg.username.clone().unwrap_or_default(),
+                "password" => self
+                    .cfg
+                    .password
+                    .as_ref()
+                    .map_or_else(String::new, |password| password.value().to_string()),
+                name => return Err(anyhow!("URL has unknown placeholder {{{name}}}")),
+            };
+            url.push_str(&rest[..start]);
+            url.push_str(&encode(&value));
+            rest = &rest[start + len + 1..];
+        }
+        url.push_str(rest);
+        Ok(url)
+    }
+
+    async fn update_address(&self, client: &reqwest::Client, addr: IpAddr) -> Result<()> {
+        // Errors are stripped of the request URL, since it may hold the password.
+        let resp = client
+            .get(self.url(addr)?)
+            .timeout(self.cfg.calls.timeout())
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        let status = resp.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Lockout {
+                message: format!("update request got {status}"),
+            }
+            .into());
+        }
+        let body = resp.text().await.map_err(reqwest::Error::without_url)?;
+        let body = body.trim();
+
+        let success = &self.cfg.success;
+        let status_ok = match success.status {
+            Some(want) => status.as_u16() == want,
+            None => status.is_success(),
+        };
+        let contains_ok = success
+            .contains
+            .as_ref()
+            .is_none_or(|text| body.contains(text));
+        let matches_ok = success
+            .matches
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(body));
+        if !(status_ok && contains_ok && matches_ok) {
+            let message = format!("update request didn't succeed ({status}): {body}");
+            if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+                return Err(Permanent {
+                    code: Code::ProviderBadCredentials,
+                    message,
+                }
+                .into());
+            }
+            return Err(anyhow!(message));
+        }
+        Ok(())
+    }
+}
+
+impl Provider for Custom {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.cfg.url
+    }
+
+    fn record(&self) -> &str {
+        &self.record
+    }
+
+    fn calls(&self) -> &CallConfig {
+        &self.cfg.calls
+    }
+
+    fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    fn update<'a>(&'a self, client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a> {
+        Box::pin(self.update_address(client, addr))
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so that a value can be placed anywhere
+/// in a URL.
+fn encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,609 @@
+use crate::{
+    config::{DetectionMode, DetectorConfig, ExecConfig},
+    nat64::{self, Prefix},
+};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{header::CONTENT_TYPE, StatusCode, Url};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "ipify")]
+pub const IPIFY_URL: &str = "https://api.ipify.org";
+#[cfg(feature = "ipify")]
+pub const IPIFY_IPV6_URL: &str = "https://api6.ipify.org";
+
+/// The URL scheme of detectors which use the TCP echo protocol, e.g. `tcp://echo.example:7777`.
+const TCP_ECHO_SCHEME: &str = "tcp://";
+
+/// The URL scheme of detectors which query a DNS server which answers with the address a query
+/// came from, e.g. `dns://resolver1.opendns.com/myip.opendns.com`.
+const DNS_SCHEME: &str = "dns://";
+
+/// The URL scheme of detectors which ask a STUN server, e.g. `stun://stun.l.google.com:19302`.
+const STUN_SCHEME: &str = "stun://";
+
+/// The pseudo-URL scheme identifying exec detectors, e.g. `exec:/usr/local/bin/wan-ip`.
+const EXEC_SCHEME: &str = "exec:";
+
+/// The pseudo-URL scheme identifying interface detectors, e.g. `interface:eth0`.
+const INTERFACE_SCHEME: &str = "interface:";
+
+/// The JSON field holding the address, if the config doesn't say.
+const DEFAULT_FIELD: &str = "ip";
+
+/// A detector is quarantined when its trust score falls below `QUARANTINE_BELOW`, & released once
+/// it rises above `RELEASE_ABOVE`. Scores are in thousandths.
+const QUARANTINE_BELOW: u16 = 500;
+const RELEASE_ABOVE: u16 = 800;
+
+/// Detectors detects our IP address using one or more detectors. With more than one, either:
+///  * every detector is asked, & the address most of them agree on is used (if enough agree to
+///    make a quorum, when one is required). Each detector's trust score tracks how often it
+///    agrees; a detector which consistently disagrees (or fails while others succeed) is
+///    quarantined, & its answer only used if no trusted detector answers.
+///  * or, detectors are asked in order, until one answers.
+pub struct Detectors {
+    detectors: Vec<Arc<Detector>>,
+    mode: DetectionMode,
+    quorum: Option<usize>, // how many detectors must agree, if more than the most that do.
+    trust: Mutex<BTreeMap<String, Trust>>, // keyed by URL.
+    source: Mutex<Option<String>>, // the URL of the detector last used, if falling back.
+}
+
+/// The trust in a detector, as persisted in the state.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trust {
+    /// An exponentially-weighted moving average of whether the detector agreed with the address
+    /// used, in thousandths.
+    pub score: u16,
+    pub quarantined: bool,
+}
+
+impl Default for Trust {
+    fn default() -> Self {
+        Self {
+            score: 1000,
+            quarantined: false,
+        }
+    }
+}
+
+impl Detectors {
+    /// Creates the configured detectors (defaulting to ipify), with their trust as of the last
+    /// run.
+    pub fn new(
+        cfgs: &[&DetectorConfig],
+        mode: DetectionMode,
+        quorum: Option<usize>,
+        mut trust: BTreeMap<String, Trust>,
+    ) -> Result<Self> {
+        let detectors = match cfgs {
+            [] => vec![Detector::new(None)?],
+            cfgs => cfgs
+                .iter()
+                .map(|cfg| Detector::new(Some(cfg)))
+                .collect::<Result<_>>()?,
+        };
+        match (mode, quorum) {
+            (_, None) => (),
+            (DetectionMode::Fallback, Some(_)) => {
+                return Err(anyhow!("a detection quorum needs quorum detection"))
+            }
+            (DetectionMode::Quorum, Some(quorum)) if quorum == 0 || quorum > detectors.len() => {
+                return Err(anyhow!(
+                    "a detection quorum must be between 1 & the number of detectors ({})",
+                    detectors.len()
+                ))
+            }
+            (DetectionMode::Quorum, Some(_)) => (),
+        }
+        trust.retain(|url, _| detectors.iter().any(|detector| detector.url == *url));
+        Ok(Self {
+            detectors: detectors.into_iter().map(Arc::new).collect(),
+            mode,
+            quorum,
+            trust: Mutex::new(trust),
+            source: Mutex::new(None),
+        })
+    }
+
+    pub fn detectors(&self) -> impl Iterator<Item = &Detector> {
+        self.detectors.iter().map(Arc::as_ref)
+    }
+
+    /// Returns each detector's trust, for persisting.
+    pub fn trust(&self) -> BTreeMap<String, Trust> {
+        self.trust.lock().unwrap().clone()
+    }
+
+    pub async fn current_address(&self, client: &reqwest::Client) -> Result<Ipv4Addr> {
+        Ok(self.detect(client).await?.addr)
+    }
+
+    /// Detects our address, also returning the answers of any detectors which disagreed.
+    pub async fn detect(&self, client: &reqwest::Client) -> Result<Detection> {
+        if let [detector] = &self.detectors[..] {
+            return Ok(Detection {
+                addr: detector.current_address(client).await?,
+                disagreements: BTreeMap::new(),
+            });
+        }
+        if let DetectionMode::Fallback = self.mode {
+            return Ok(Detection {
+                addr: self.first_answer(client).await?,
+                disagreements: BTreeMap::new(),
+            });
+        }
+
+        let mut tasks = JoinSet::new();
+        for (i, detector) in self.detectors.iter().enumerate() {
+            let (detector, client) = (Arc::clone(detector), client.clone());
+            tasks.spawn(async move { (i, detector.current_address(&client).await) });
+        }
+        let mut results: Vec<_> = self.detectors.iter().map(|_| None).collect();
+        while let Some(rslt) = tasks.join_next().await {
+            let (i, rslt) = rslt?;
+            results[i] = Some(rslt);
+        }
+        let results: Vec<_> = results.into_iter().flatten().collect();
+
+        let mut trust = self.trust.lock().unwrap();
+        let quarantined: Vec<_> = self
+            .detectors
+            .iter()
+            .map(|detector| {
+                trust
+                    .get(&detector.url)
+                    .is_some_and(|trust| trust.quarantined)
+            })
+            .collect();
+        let addr = plurality(&results, |i| !quarantined[i])
+            .or_else(|| plurality(&results, |i| quarantined[i]));
+        let Some(addr) = addr else {
+            // Every detector failing is likely our network's fault, not theirs: trust is unchanged.
+            return Err(every_failed(
+                self.detectors
+                    .iter()
+                    .zip(&results)
+                    .filter_map(|(detector, rslt)| {
+                        Some((detector.url.as_str(), rslt.as_ref().err()?))
+                    }),
+            ));
+        };
+
+        // Without a quorum, we can't tell which detectors are wrong: trust is unchanged.
+        let votes = results
+            .iter()
+            .filter(|rslt| rslt.as_ref().is_ok_and(|detected| *detected == addr))
+            .count();
+        if let Some(quorum) = self.quorum.filter(|&quorum| votes < quorum) {
+            let detected: Vec<_> = self
+                .detectors
+                .iter()
+                .zip(&results)
+                .filter_map(|(detector, rslt)| {
+                    Some(format!("{}: {}", detector.url, rslt.as_ref().ok()?))
+                })
+                .collect();
+            return Err(anyhow!(
+                "only {votes} of {} detectors agreed on {addr}, short of the quorum of {quorum} \
+                 (detected {})",
+                self.detectors.len(),
+                detected.join("; ")
+            ));
+        }
+
+        let mut disagreements = BTreeMap::new();
+        for (detector, rslt) in self.detectors.iter().zip(&results) {
+            let agreed = match rslt {
+                Ok(detected) if *detected == addr => true,
+                Ok(detected) => {
+                    debug!(
+                        detector = detector.url,
+                        ?detected,
+                        ?addr,
+                        "Detector disagreed"
+                    );
+                    disagreements.insert(detector.url.clone(), *detected);
+                    false
+                }
+                Err(err) => {
+                    debug!(
+                        detector = detector.url,
+                        err = format!("{err:#}"),
+                        "Detector failed"
+                    );
+                    false
+                }
+            };
+            let trust = trust.entry(detector.url.clone()).or_default();
+            trust.score = (u32::from(trust.score) * 9 / 10 + if agreed { 100 } else { 0 }) as u16;
+            if !trust.quarantined && trust.score < QUARANTINE_BELOW {
+                trust.quarantined = true;
+                warn!(
+                    detector = detector.url,
+                    score = trust.score,
+                    "Quarantining detector, which has consistently disagreed with the others (or \
+                     failed)"
+                );
+            } else if trust.quarantined && trust.score > RELEASE_ABOVE {
+                trust.quarantined = false;
+                info!(
+                    detector = detector.url,
+                    score = trust.score,
+                    "Detector is trusted again"
+                );
+            }
+        }
+        Ok(Detection {
+            addr,
+            disagreements,
+        })
+    }
+
+    /// Asks each detector in turn, returning the first address detected.
+    async fn first_answer(&self, client: &reqwest::Client) -> Result<Ipv4Addr> {
+        let mut errs = Vec::new();
+        for detector in &self.detectors {
+            match detector.current_address(client).await {
+                Ok(addr) => {
+                    let mut source = self.source.lock().unwrap();
+                    if source.as_deref() != Some(&detector.url) {
+                        info!(
+                            detector = detector.url,
+                            "Detecting IP address with detector"
+                        );
+                        *source = Some(detector.url.clone());
+                    }
+                    return Ok(addr);
+                }
+                Err(err) => {
+                    debug!(
+                        detector = detector.url,
+                        err = format!("{err:#}"),
+                        "Detector failed, falling back to the next"
+                    );
+                    errs.push((detector.url.as_str(), err));
+                }
+            }
+        }
+        Err(every_failed(errs.iter().map(|(url, err)| (*url, err))))
+    }
+}
+
+/// Returns the error of a detection for which every detector failed, given each detector's URL &
+/// error. If any hit a captive portal, the error is (also) a `CaptivePortal`.
+fn every_failed<'a>(errs: impl Iterator<Item = (&'a str, &'a anyhow::Error)>) -> anyhow::Error {
+    let mut portal = None;
+    let mut msgs = Vec::new();
+    for (url, err) in errs {
+        portal = portal.or_else(|| captive_portal(err).cloned());
+        msgs.push(format!("{url}: {err:#}"));
+    }
+    let msg = format!("every detector failed: {}", msgs.join("; "));
+    match portal {
+        Some(portal) => anyhow::Error::new(portal).context(msg),
+        None => anyhow!(msg),
+    }
+}
+
+/// The result of a detection.
+pub struct Detection {
+    /// The address used.
+    pub addr: Ipv4Addr,
+
+    /// The addresses detected instead by detectors which disagreed, keyed by URL.
+    pub disagreements: BTreeMap<String, Ipv4Addr>,
+}
+
+/// Returns the address detected by the most detectors among those selected by `include` (given
+/// each detector's index), if any. Ties go to the detector configured first.
+fn plurality(results: &[Result<Ipv4Addr>], include: impl Fn(usize) -> bool) -> Option<Ipv4Addr> {
+    let addrs: Vec<_> = results
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| include(i))
+        .filter_map(|(_, rslt)| rslt.as_ref().ok())
+        .collect();
+    let votes = |addr| addrs.iter().filter(|&&other| *other == addr).count();
+    let mut best: Option<(Ipv4Addr, usize)> = None;
+    for &&addr in &addrs {
+        if best.is_none_or(|(_, best_votes)| votes(addr) > best_votes) {
+            best = Some((addr, votes(addr)));
+        }
+    }
+    best.map(|(addr, _)| addr)
+}
+
+/// Detector detects our current IP address in one of the ways `DetectorConfig` describes: by
+/// asking an HTTP service, which may respond with the address as plain text or, e.g.
+/// `{"ip": "192.0.2.1"}`, as JSON; or, by the URL's scheme, a TCP echo service (`tcp://`), a STUN
+/// server (`stun://`), a DNS server (`dns://`), or a network interface (`interface:`). Otherwise,
+/// a command may be run, which prints the address as an HTTP service would.
+pub struct Detector {
+    url: String,
+    field: String,
+    exec: Option<ExecConfig>,
+    timeout: Option<Duration>,
+}
+
+impl Detector {
+    /// Creates the configured IPv6 detector, defaulting to ipify's IPv6-only endpoint.
+    pub fn new6(cfg: Option<&DetectorConfig>) -> Result<Self> {
+        match cfg {
+            Some(cfg) => Self::new(Some(cfg)),
+            #[cfg(feature = "ipify")]
+            None => Ok(Self {
+                url: IPIFY_IPV6_URL.to_string(),
+                field: DEFAULT_FIELD.to_string(),
+                exec: None,
+                timeout: None,
+            }),
+            #[cfg(not(feature = "ipify"))]
+            None => Err(anyhow!("no IPv6 detector configured")),
+        }
+    }
+
+    /// Creates the configured detector, defaulting to ipify.
+    pub fn new(cfg: Option<&DetectorConfig>) -> Result<Self> {
+        match cfg {
+            Some(cfg) => {
+                // Exec & interface detectors are identified by their command or interface, in place
+                // of a URL.
+                let url = match (&cfg.url, &cfg.exec, &cfg.interface) {
+                    (Some(url), None, None) => url.clone(),
+                    (None, Some(exec), None) => format!("{EXEC_SCHEME}{}", exec.command.join(" ")),
+                    (None, None, Some(interface)) => format!("{INTERFACE_SCHEME}{interface}"),
+                    _ => {
+                        return Err(anyhow!(
+                            "a detector needs exactly one of url, exec, & interface"
+                        ))
+                    }
+                };
+                #[cfg(not(feature = "tcp-echo"))]
+                if url.starts_with(TCP_ECHO_SCHEME) {
+                    return Err(anyhow!("detector {url} needs the \"tcp-echo\" feature"));
+                }
+                #[cfg(not(feature = "stun"))]
+                if url.starts_with(STUN_SCHEME) {
+                    return Err(anyhow!("detector {url} needs the \"stun\" feature"));
+                }
+                #[cfg(not(feature = "exec"))]
+                if url.starts_with(EXEC_SCHEME) {
+                    return Err(anyhow!("detector {url} needs the \"exec\" feature"));
+                }
+                Ok(Self {
+                    url,
+                    field: cfg.field.as_deref().unwrap_or(DEFAULT_FIELD).to_string(),
+                    exec: cfg.exec.clone(),
+                    timeout: cfg.timeout_secs.map(Duration::from_secs),
+                })
+            }
+            #[cfg(feature = "ipify")]
+            None => Ok(Self {
+                url: IPIFY_URL.to_string(),
+                field: DEFAULT_FIELD.to_string(),
+                exec: None,
+                timeout: None,
+            }),
+            #[cfg(not(feature = "ipify"))]
+            None => Err(anyhow!("no detector configured")),
+        }
+    }
+
+    /// The URL of the detection service.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Whether the detector is reached over HTTP(S).
+    pub fn is_http(&self) -> bool {
+        !self.url.starts_with(TCP_ECHO_SCHEME)
+            && !self.url.starts_with(STUN_SCHEME)
+            && !self.url.starts_with(DNS_SCHEME)
+            && !self.url.starts_with(INTERFACE_SCHEME)
+            && self.exec.is_none()
+    }
+
+    /// Whether the detector reads a network interface's addresses.
+    pub fn is_interface(&self) -> bool {
+        self.url.starts_with(INTERFACE_SCHEME)
+    }
+
+    /// Whether the detector is local: a command, a network interface, or a service on our own
+    /// network.
+    pub fn is_local(&self) -> bool {
+        if self.exec.is_some() || self.url.starts_with(INTERFACE_SCHEME) {
+            return true;
+        }
+        let Some(host) = Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+        else {
+            return false;
+        };
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(addr) => is_local_addr(addr),
+            Err(_) => host == "localhost",
+        }
+    }
+
+    /// Whether the detector runs a command.
+    pub fn is_exec(&self) -> bool {
+        self.exec.is_some()
+    }
+
+    pub async fn current_address(&self, client: &reqwest::Client) -> Result<Ipv4Addr> {
+        match self.detect(client, false).await? {
+            IpAddr::V4(addr) => Ok(addr),
+            // Only look for the network's NAT64 prefix if the address might need it.
+            addr => normalize(addr, nat64::prefix().await),
+        }
+    }
+
+    /// Detects our IPv6 address.
+    pub async fn current_address6(&self, client: &reqwest::Client) -> Result<Ipv6Addr> {
+        match self.detect(client, true).await? {
+            IpAddr::V6(addr) if addr.to_ipv4_mapped().is_none() => Ok(addr),
+            addr => Err(anyhow!("detected {addr}, which isn't an IPv6 address")),
+        }
+    }
+
+    /// Detects our address, of either family (though STUN, DNS, & interface detectors, which
+    /// could find either, are told which is wanted).
+    async fn detect(&self, client: &reqwest::Client, ipv6: bool) -> Result<IpAddr> {
+        #[cfg(feature = "tcp-echo")]
+        if let Some(addr) = self.url.strip_prefix(TCP_ECHO_SCHEME) {
+            return crate::echo::detect(addr).await;
+        }
+        #[cfg(feature = "stun")]
+        if let Some(server) = self.url.strip_prefix(STUN_SCHEME) {
+            return crate::stun::detect(server, ipv6).await;
+        }
+        if let Some(target) = self.url.strip_prefix(DNS_SCHEME) {
+            return crate::dns::detect(target, ipv6).await;
+        }
+        if let Some(name) = self.url.strip_prefix(INTERFACE_SCHEME) {
+            return crate::interface::detect(name, ipv6);
+        }
+        #[cfg(feature = "exec")]
+        if let Some(exec) = &self.exec {
+            let output = crate::exec::run(exec, &[]).await?;
+            return self.parse(output.trim(), output.trim_start().starts_with('{'));
+        }
+        let mut req = client.get(&self.url);
+        if let Some(timeout) = self.timeout {
+            req = req.timeout(timeout);
+        }
+        let resp = req.send().await?;
+        if let Some(portal) = CaptivePortal::of_response(&self.url, &resp) {
+            return Err(portal.into());
+        }
+        if resp.status() != StatusCode::OK {
+            return Err(anyhow!("unexpected status code: {}", resp.status()));
+        }
+        let is_json = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.contains("json"));
+        let body = resp.text().await?;
+        let body = body.trim();
+        if body.starts_with('<') {
+            return Err(CaptivePortal {
+                reason: "got an HTML page".to_string(),
+            }
+            .into());
+        }
+
+        // Some services send JSON labelled as text, so sniff the body too.
+        self.parse(body, is_json || body.starts_with('{'))
+    }
+
+    /// Parses a response, which is either the address as plain text, or a JSON object.
+    fn parse(&self, body: &str, is_json: bool) -> Result<IpAddr> {
+        let addr = if is_json {
+            json_field(body, &self.field)?
+        } else {
+            body.to_string()
+        };
+        addr.parse()
+            .with_context(|| format!("couldn't parse address {addr:?}"))
+    }
+}
+
+/// An error meaning a detector's request was answered by a captive portal (e.g. a hotel's Wi-Fi
+/// login page), rather than by the detector: we aren't connected to the internet yet.
+#[derive(Clone, Debug)]
+pub struct CaptivePortal {
+    pub reason: String,
+}
+
+impl CaptivePortal {
+    /// Returns the captive portal a response to a request for `url` came from, if it looks like
+    /// it came from one: it was redirected to another host, or asks us to authenticate to the
+    /// network.
+    fn of_response(url: &str, resp: &reqwest::Response) -> Option<Self> {
+        if resp.status() == StatusCode::NETWORK_AUTHENTICATION_REQUIRED {
+            return Some(Self {
+                reason: format!("got {}", resp.status()),
+            });
+        }
+        if resp.status().is_redirection() {
+            return Some(Self {
+                reason: format!("got unfollowed redirect ({})", resp.status()),
+            });
+        }
+        let requested_host = Url::parse(url).ok()?.host_str()?.to_string();
+        let host = resp.url().host_str()?;
+        (host != requested_host).then(|| Self {
+            reason: format!("was redirected to {host}"),
+        })
+    }
+}
+
+impl Display for CaptivePortal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "looks like a captive portal: {}", self.reason)
+    }
+}
+
+impl Error for CaptivePortal {}
+
+/// Returns the captive portal that caused the given error, if any.
+pub fn captive_portal(err: &anyhow::Error) -> Option<&CaptivePortal> {
+    err.downcast_ref()
+}
+
+/// Returns the string at the given field of a JSON object. Fields of nested objects are given as
+/// dotted paths, e.g. `data.ip`.
+fn json_field(body: &str, field: &str) -> Result<String> {
+    let mut value: &serde_json::Value = &serde_json::from_str(body)?;
+    for key in field.split('.') {
+        value = value
+            .get(key)
+            .ok_or_else(|| anyhow!("response has no field {field:?}"))?;
+    }
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("field {field:?} of response is not a string"))
+}
+
+/// Whether an address is a loopback, private, or link-local address.
+pub fn is_local_addr(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_loopback() || addr.is_private() || addr.is_link_local(),
+        IpAddr::V6(addr) => {
+            // Unique local (fc00::/7) & link-local (fe80::/10) addresses.
+            let segment = addr.segments()[0];
+            addr.is_loopback() || segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// Converts a detected address to the IPv4 address to publish. Detectors reached over IPv6 may
+/// report our IPv4 address as an IPv4-mapped (`::ffff:a.b.c.d`) or NAT64-synthesized
+/// (`64:ff9b::a.b.c.d`, or with the network's own NAT64 prefix, if given) IPv6 address, which are
+/// unwrapped; other IPv6 addresses can't be published in an A record, so are rejected.
+pub fn normalize(addr: IpAddr, nat64: Option<Prefix>) -> Result<Ipv4Addr> {
+    match addr {
+        IpAddr::V4(addr) => Ok(addr),
+        IpAddr::V6(addr) => addr
+            .to_ipv4_mapped()
+            .or_else(|| Prefix::WELL_KNOWN.extract(addr))
+            .or_else(|| nat64.and_then(|prefix| prefix.extract(addr)))
+            .ok_or_else(|| {
+                anyhow!("detected IPv6 address {addr}, but only IPv4 addresses can be published")
+            }),
+    }
+}
@@ -0,0 +1,211 @@
+//! A minimal DNS client, for asking a specific resolver what a record currently resolves to. (The
+//! system resolver can't be pointed at a particular server, and caches.) Also used for detection
+//! of our IP address via servers which answer with the address a query came from, e.g. OpenDNS's
+//! `myip.opendns.com`.
+
+use crate::nat64;
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::{self, UdpSocket},
+    time,
+};
+
+/// How long to wait for a response from a resolver.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PORT: u16 = 53;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// An A (or AAAA) record returned by a resolver.
+pub struct Record {
+    pub addr: IpAddr,
+    pub ttl: Duration,
+}
+
+/// Asks the given resolver for the A records of `name`, recursively.
+pub async fn query_a(resolver: IpAddr, name: &str) -> Result<Vec<Record>> {
+    query((resolver, PORT).into(), name, TYPE_A).await
+}
+
+/// Asks the given resolver for the AAAA records of `name`, recursively.
+pub async fn query_aaaa(resolver: IpAddr, name: &str) -> Result<Vec<Record>> {
+    query((resolver, PORT).into(), name, TYPE_AAAA).await
+}
+
+/// Detects our address by asking the server at `target` (a `host[:port]/name`, e.g.
+/// `resolver1.opendns.com/myip.opendns.com`) for the A (or, with `ipv6`, AAAA) records of `name`,
+/// which the server answers with the address the query came from.
+pub async fn detect(target: &str, ipv6: bool) -> Result<IpAddr> {
+    let url = Url::parse(&format!("dns://{target}"))
+        .with_context(|| format!("couldn't parse DNS detector {target:?}"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("DNS detector {target:?} has no server"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = url.path().trim_start_matches('/');
+    if name.is_empty() {
+        return Err(anyhow!("DNS detector {target:?} has no name to query"));
+    }
+    let server = net::lookup_host((host, url.port().unwrap_or(PORT)))
+        .await?
+        .find(|addr| addr.is_ipv6() == ipv6)
+        .ok_or_else(|| {
+            let family = if ipv6 { "IPv6" } else { "IPv4" };
+            anyhow!("{host} has no {family} address")
+        })?;
+    let record_type = if ipv6 { TYPE_AAAA } else { TYPE_A };
+    let records = query(server, name, record_type).await?;
+    match records.as_slice() {
+        [record] => Ok(record.addr),
+        [] => Err(anyhow!("{host} has no records for {name}")),
+        _ => Err(anyhow!("{host} has more than one record for {name}")),
+    }
+}
+
+/// Asks the resolver at `server` for the records of `name` of the given type, recursively.
+async fn query(server: SocketAddr, name: &str, record_type: u16) -> Result<Vec<Record>> {
+    let id = fastrand::u16(..);
+    let query = build_query(id, name, record_type)?;
+    let server = nat64::reachable(server).await?;
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+    socket.send(&query).await?;
+
+    let mut buf = [0u8; 1232];
+    time::timeout(QUERY_TIMEOUT, async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // Ignore responses to other queries (e.g. late responses to a previous query).
+            if n >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                return parse_response(&buf[..n], record_type);
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("timed out waiting for {}", server.ip()))?
+}
+
+fn build_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend(id.to_be_bytes());
+    query.extend(0x0100u16.to_be_bytes()); // flags: recursion desired.
+    query.extend(1u16.to_be_bytes()); // one question.
+    query.extend([0; 6]); // no answer, authority, or additional records.
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("invalid name {name:?}"));
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(record_type.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn parse_response(msg: &[u8], record_type: u16) -> Result<Vec<Record>> {
+    let mut r = Reader { msg, pos: 2 };
+    let flags = r.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(anyhow!("response is not a response"));
+    }
+    if flags & 0x0200 != 0 {
+        return Err(anyhow!("response is truncated"));
+    }
+    match flags & 0x000f {
+        0 => (),
+        3 => return Ok(Vec::new()), // NXDOMAIN
+        rcode => return Err(anyhow!("resolver returned error (RCODE {rcode})")),
+    }
+    let questions = r.u16()?;
+    let answers = r.u16()?;
+    r.pos += 4; // authority & additional record counts.
+
+    for _ in 0..questions {
+        r.skip_name()?;
+        r.pos += 4; // type & class.
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        r.skip_name()?;
+        let (rtype, class, ttl, len) = (r.u16()?, r.u16()?, r.u32()?, r.u16()?);
+        let data = r.bytes(usize::from(len))?;
+        // Any CNAMEs in the chain are followed by the resolver, so the A (or AAAA) records are
+        // all we need.
+        if rtype != record_type || class != CLASS_IN {
+            continue;
+        }
+        let addr: IpAddr = match (rtype, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(data), _) => Ipv4Addr::from(data).into(),
+            (TYPE_AAAA, _, Ok(data)) => Ipv6Addr::from(data).into(),
+            _ => continue,
+        };
+        records.push(Record {
+            addr,
+            ttl: Duration::from_secs(ttl.into()),
+        });
+    }
+    Ok(records)
+}
+
+/// Reader reads big-endian values from a DNS message.
+struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos + n;
+        let bytes = self
+            .msg
+            .get(self.pos..end)
+            .ok_or_else(|| anyhow!("response is too short"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Skips a (possibly compressed) name.
+    fn skip_name(&mut self) -> Result<()> {
+        loop {
+            match self.u8()? {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => {
+                    // A pointer to a name elsewhere, which ends this one.
+                    self.u8()?;
+                    return Ok(());
+                }
+                len => {
+                    self.bytes(usize::from(len))?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The `doctor` command, which runs a battery of checks of the environment rnccd runs in and
+//! prints a pass/fail report, with hints on how to fix any failures.
+
+use crate::{
+    detect::{self, Detectors},
+    dns,
+    provider::{Provider, RecordType},
+};
+use anyhow::{anyhow, Context, Result};
+use reqwest::{header::DATE, Url};
+use std::{ffi::OsStr, net::IpAddr, path::Path, sync::Arc, time::SystemTime};
+use tempfile::NamedTempFile;
+use tokio::net;
+
+/// The maximum difference between our clock & a server's clock that is considered sane.
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// A single check's outcome.
+struct Check {
+    name: String,
+    result: Result<String>,
+    hint: &'static str,
+}
+
+/// Runs all checks, printing a report. Returns true if every check passed.
+pub async fn run(
+    client: &reqwest::Client,
+    providers: &[Arc<dyn Provider>],
+    detectors: &Detectors,
+    resolver: IpAddr,
+    state_path: Option<&OsStr>,
+) -> bool {
+    let mut checks = Vec::new();
+
+    for provider in providers {
+        checks.push(Check {
+            name: format!("Resolve endpoint of {}", provider.id()),
+            result: resolve(provider.endpoint()).await,
+            hint: "check your DNS configuration (e.g. /etc/resolv.conf) & network connectivity",
+        });
+
+        checks.push(Check {
+            name: format!("Credentials for {}", provider.id()),
+            result: check_credentials(client, provider.as_ref(), resolver).await,
+            hint: "check the password/API key/token in the config file",
+        });
+    }
+
+    for detector in detectors.detectors() {
+        checks.push(Check {
+            name: format!("Detect IP address via {}", detector.url()),
+            result: detector
+                .current_address(client)
+                .await
+                .map(|addr| format!("detected {addr}")),
+            hint: "check network connectivity, and that outbound HTTPS is allowed",
+        });
+    }
+
+    checks.push(Check {
+        name: "Write state directory".to_string(),
+        result: match state_path {
+            Some(state_path) => check_state_dir(state_path),
+            None => Ok("skipped, as the state is kept in memory".to_string()),
+        },
+        hint: "make sure the directory containing the state file exists & is writable by the \
+               user rnccd runs as",
+    });
+
+    checks.push(Check {
+        name: "Clock sanity".to_string(),
+        result: match detectors.detectors().find(|detector| detector.is_http()) {
+            Some(detector) => check_clock(client, detector.url()).await,
+            None => Ok("skipped, as no detector uses HTTP".to_string()),
+        },
+        hint: "make sure the system clock is synchronized, e.g. via NTP",
+    });
+
+    let mut ok = true;
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => println!("[PASS] {}: {detail}", check.name),
+            Err(err) => {
+                ok = false;
+                println!("[FAIL] {}: {err:#}", check.name);
+                println!("       hint: {}", check.hint);
+            }
+        }
+    }
+    ok
+}
+
+async fn resolve(url: &str) -> Result<String> {
+    let url = Url::parse(url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("URL {url} has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<_> = net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("couldn't resolve {host}"))?
+        .map(|addr| addr.ip().to_string())
+        .collect();
+    Ok(format!("{host} resolves to {}", addrs.join(", ")))
+}
+
+/// Checks credentials without changing the record. The record's current address (which the check
+/// may re-set it to) is looked up via a public resolver, since neither state (which may be stale)
+/// nor the system's resolver (which may answer with a LAN or DNS64 address) can be trusted.
+async fn check_credentials(
+    client: &reqwest::Client,
+    provider: &dyn Provider,
+    resolver: IpAddr,
+) -> Result<String> {
+    let current_addr = public_addr(provider, resolver).await;
+    let rslt = provider
+        .check_credentials(client, current_addr.as_ref().ok().copied())
+        .await;
+    match (rslt, current_addr) {
+        (Ok(detail), _) => Ok(format!("accepted ({detail})")),
+        (Err(err), Err(why)) => Err(anyhow!("{err:#} ({why:#})")),
+        (Err(err), Ok(_)) => Err(err),
+    }
+}
+
+/// Looks up the record's address in public DNS: its only address, if that's a public one. Wildcard
+/// records can't be looked up, since any name they match would do.
+async fn public_addr(provider: &dyn Provider, resolver: IpAddr) -> Result<IpAddr> {
+    let record = provider.record();
+    if record.starts_with("*.") {
+        return Err(anyhow!("{record} is a wildcard record"));
+    }
+    let record_type = provider.record_type();
+    let records = match record_type {
+        RecordType::A => dns::query_a(resolver, record).await,
+        RecordType::Aaaa => dns::query_aaaa(resolver, record).await,
+    };
+    let records = records.with_context(|| format!("couldn't look up {record} via {resolver}"))?;
+    let addr = match records[..] {
+        [ref only] => only.addr,
+        [] => return Err(anyhow!("{record} has no {record_type} record")),
+        _ => return Err(anyhow!("{record} has more than one {record_type} record")),
+    };
+    if detect::is_local_addr(addr) {
+        return Err(anyhow!("{record} resolves to a private address, {addr}"));
+    }
+    Ok(addr)
+}
+
+fn check_state_dir(state_path: &OsStr) -> Result<String> {
+    let dir = match Path::new(state_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    NamedTempFile::new_in(dir)
+        .with_context(|| format!("couldn't create a file in {}", dir.display()))?;
+    Ok(format!("{} is writable", dir.display()))
+}
+
+/// Compares our clock against the `Date` header of a response from the detection service.
+async fn check_clock(client: &reqwest::Client, url: &str) -> Result<String> {
+    let resp = client.head(url).send().await?;
+    let server_time = resp
+        .headers()
+        .get(DATE)
+        .ok_or_else(|| anyhow!("server didn't send a Date header"))?
+        .to_str()?;
+    let server_time = httpdate::parse_http_date(server_time)?;
+    let now = SystemTime::now();
+    let skew = now
+        .duration_since(server_time)
+        .or_else(|_| server_time.duration_since(now))
+        .unwrap_or_default()
+        .as_secs();
+    if skew > MAX_CLOCK_SKEW_SECS {
+        return Err(anyhow!("local clock differs from {url}'s by {skew}s"));
+    }
+    Ok(format!("within {skew}s of {url}"))
+}
@@ -0,0 +1,97 @@
+//! Updates of DuckDNS records, via DuckDNS's update API.
+
+use crate::{
+    backoff::Permanent,
+    codes::Code,
+    config::{CallConfig, DuckDnsConfig},
+    provider::{self, Provider, RecordType, UpdateFuture},
+};
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+
+const UPDATE_URL: &str = "https://www.duckdns.org/update";
+
+/// DuckDns updates a record via DuckDNS's API.
+pub struct DuckDns {
+    id: String,
+    record: String,
+    record_type: RecordType,
+    cfg: DuckDnsConfig,
+}
+
+impl DuckDns {
+    pub fn new(cfg: DuckDnsConfig, record_type: RecordType) -> Self {
+        let record = provider::record_name(DuckDnsConfig::DOMAIN, cfg.subdomain());
+        let id = match record_type {
+            RecordType::A => format!("duckdns:{record}"),
+            RecordType::Aaaa => format!("duckdns:{record}/AAAA"),
+        };
+        Self {
+            id,
+            record,
+            record_type,
+            cfg,
+        }
+    }
+
+    async fn update_address(&self, client: &reqwest::Client, addr: IpAddr) -> Result<()> {
+        let addr_param = match addr {
+            IpAddr::V4(_) => "ip",
+            IpAddr::V6(_) => "ipv6",
+        };
+        // Errors are stripped of the request URL, since it holds the token.
+        let resp = client
+            .get(UPDATE_URL)
+            .timeout(self.cfg.calls.timeout())
+            .query(&[
+                ("domains", self.cfg.subdomain()),
+                ("token", self.cfg.token.value()),
+                (addr_param, &addr.to_string()),
+            ])
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        let status = resp.status();
+
+        // This API answers with a bare OK or KO, without saying what was wrong.
+        let body = resp.text().await.map_err(reqwest::Error::without_url)?;
+        match body.trim() {
+            "OK" => Ok(()),
+            "KO" => Err(Permanent {
+                code: Code::ProviderBadCredentials,
+                message: "update request was rejected (are the subdomain & token right?)"
+                    .to_string(),
+            }
+            .into()),
+            body => Err(anyhow!(
+                "update request got unexpected response ({status}): {body}"
+            )),
+        }
+    }
+}
+
+impl Provider for DuckDns {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn endpoint(&self) -> &str {
+        UPDATE_URL
+    }
+
+    fn record(&self) -> &str {
+        &self.record
+    }
+
+    fn calls(&self) -> &CallConfig {
+        &self.cfg.calls
+    }
+
+    fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    fn update<'a>(&'a self, client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a> {
+        Box::pin(self.update_address(client, addr))
+    }
+}
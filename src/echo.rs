@@ -0,0 +1,66 @@
+//! A minimal TCP "echo" protocol for detecting our IP address, for networks where outbound HTTP(S)
+//! is blocked but other TCP connections are allowed. The client connects, & the server replies
+//! with the address it sees the connection coming from, as text, then closes the connection.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tracing::{debug, info};
+
+/// How long a request (on either side) may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum length of a response.
+const MAX_RESPONSE_LEN: u64 = 64;
+
+/// Asks the server at `addr` (a `host:port`) for our address. Connections are made directly,
+/// ignoring any proxy.
+pub async fn detect(addr: &str) -> Result<IpAddr> {
+    let resp = time::timeout(TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut resp = String::new();
+        (&mut stream)
+            .take(MAX_RESPONSE_LEN)
+            .read_to_string(&mut resp)
+            .await?;
+        anyhow::Ok(resp)
+    })
+    .await
+    .map_err(|_| anyhow!("{addr} didn't respond within {TIMEOUT:?}"))??;
+    let resp = resp.trim();
+    resp.parse()
+        .with_context(|| format!("couldn't parse address {resp:?}"))
+}
+
+/// Runs a server on the given address forever, replying to each connection with its source
+/// address.
+pub async fn serve(listen: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("couldn't listen on {listen}"))?;
+    info!(%listen, "Serving TCP echo detection");
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                debug!(%err, "Couldn't accept connection");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let resp = format!("{}\n", peer.ip());
+            match time::timeout(TIMEOUT, stream.write_all(resp.as_bytes())).await {
+                Ok(Ok(())) => debug!(%peer, "Replied with source address"),
+                Ok(Err(err)) => debug!(%peer, %err, "Couldn't reply"),
+                Err(_) => debug!(%peer, "Timed out replying"),
+            }
+        });
+    }
+}
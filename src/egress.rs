@@ -0,0 +1,94 @@
+//! Restriction of outbound HTTP requests to the hosts the config says we talk to, as defense in
+//! depth against requests being steered elsewhere (e.g. by a redirect, or a templated URL) along
+//! with the credentials they carry.
+
+use crate::{config::Config, detect::Detector, HttpSettings};
+use reqwest::{redirect, Url};
+use std::{collections::BTreeSet, iter, sync::Arc};
+
+/// The most redirects followed, as by the HTTP client's default policy.
+const MAX_REDIRECTS: usize = 10;
+
+/// The hosts HTTP requests may be made to.
+pub struct Allowlist {
+    hosts: BTreeSet<String>,
+}
+
+impl Allowlist {
+    /// Derives the allowlist from the config: the hosts of the providers' endpoints, the
+    /// detectors (including uplinks'), the connectivity check, webhooks, the healthcheck, & metrics
+    /// sinks. The providers are created with `http`, i.e. the settings the allowlist is for.
+    pub fn new(cfg: &Config, http: &HttpSettings) -> Self {
+        let mut urls: Vec<String> = crate::providers(cfg, http)
+            .iter()
+            .map(|provider| provider.endpoint().to_string())
+            .collect();
+        let detectors = cfg.detectors();
+        let detectors = match detectors.is_empty() {
+            true => vec![Detector::new(None)],
+            false => detectors.into_iter().map(Some).map(Detector::new).collect(),
+        };
+        let uplink_detectors = cfg
+            .uplinks
+            .iter()
+            .map(|uplink| Detector::new(uplink.detector.as_ref()));
+        urls.extend(
+            detectors
+                .into_iter()
+                .chain(uplink_detectors)
+                .chain(iter::once(Detector::new6(cfg.detector6.as_ref())))
+                .flatten()
+                .filter(Detector::is_http)
+                .map(|detector| detector.url().to_string()),
+        );
+        urls.extend(cfg.connectivity_url.clone());
+        urls.extend(cfg.webhooks.iter().map(|webhook| webhook.url.clone()));
+        urls.extend(
+            cfg.healthcheck
+                .iter()
+                .map(|healthcheck| healthcheck.url.clone()),
+        );
+        urls.extend(
+            cfg.metrics
+                .iter()
+                .filter_map(|sink| sink.url().map(str::to_string)),
+        );
+
+        // URLs which can't be parsed (e.g. templated hosts) allow nothing.
+        let hosts = urls
+            .iter()
+            .filter_map(|url| Url::parse(url).ok()?.host_str().map(normalize))
+            .collect();
+        Self { hosts }
+    }
+
+    /// Whether requests may be made to `host`.
+    pub fn allows(&self, host: &str) -> bool {
+        self.hosts.contains(&normalize(host))
+    }
+
+    /// Returns a redirect policy which follows only redirects to allowed hosts.
+    pub fn redirect_policy(self: Arc<Self>) -> redirect::Policy {
+        redirect::Policy::custom(move |attempt| {
+            let host = attempt.url().host_str().unwrap_or_default().to_string();
+            if !self.allows(&host) {
+                attempt.error(format!(
+                    "refusing redirect to {host}, which isn't allowlisted"
+                ))
+            } else if attempt.previous().len() > MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        })
+    }
+}
+
+/// Normalizes a host for comparison: lowercases it, & strips brackets from IPv6 addresses & any
+/// trailing dot.
+fn normalize(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
@@ -0,0 +1,124 @@
+//! Output to the Windows Event Log. Warnings, errors, & notable events (those logged with the
+//! `NOTABLE_TARGET` target, e.g. IP changes) are written to the Application log, under the
+//! `rnccd` event source, so that they show up in Event Viewer alongside the service's status.
+
+use std::{
+    ffi::{c_void, OsStr},
+    fmt::{self, Write},
+    iter,
+    os::windows::ffi::OsStrExt,
+    ptr,
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// The event source that events are written under.
+const SOURCE: &str = "rnccd";
+
+const EVENTLOG_ERROR_TYPE: u16 = 0x0001;
+const EVENTLOG_WARNING_TYPE: u16 = 0x0002;
+const EVENTLOG_INFORMATION_TYPE: u16 = 0x0004;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn RegisterEventSourceW(server_name: *const u16, source_name: *const u16) -> isize;
+    fn ReportEventW(
+        event_log: isize,
+        event_type: u16,
+        category: u16,
+        event_id: u32,
+        user_sid: *const c_void,
+        num_strings: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        raw_data: *const c_void,
+    ) -> i32;
+}
+
+/// EventLog is a tracing layer which writes events to the Windows Event Log.
+pub struct EventLog {
+    handle: isize,
+}
+
+impl EventLog {
+    /// Registers the event source, returning None if that fails (in which case there's nowhere to
+    /// report the failure but stderr).
+    ///
+    /// For Event Viewer to show messages without a "description cannot be found" preamble, the
+    /// source can be registered in the registry once, e.g. with PowerShell's
+    /// `New-EventLog -LogName Application -Source rnccd`.
+    pub fn new() -> Option<Self> {
+        let source = wide(SOURCE);
+        // SAFETY: a NULL server name means the local computer; source is NUL-terminated.
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        if handle == 0 {
+            eprintln!(
+                "Couldn't register event source: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some(Self { handle })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for EventLog {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = *metadata.level();
+        let event_type = if level == Level::ERROR {
+            EVENTLOG_ERROR_TYPE
+        } else if level == Level::WARN {
+            EVENTLOG_WARNING_TYPE
+        } else if metadata.target() == crate::NOTABLE_TARGET {
+            EVENTLOG_INFORMATION_TYPE
+        } else {
+            return;
+        };
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let message = wide(&format!("{}{}", visitor.message, visitor.fields));
+        let strings = [message.as_ptr()];
+        // SAFETY: handle is a registered event source; strings holds one NUL-terminated string.
+        // Failures are ignored, since there's nowhere better to report them.
+        unsafe {
+            ReportEventW(
+                self.handle,
+                event_type,
+                0,
+                0,
+                ptr::null(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+}
+
+/// MessageVisitor collects an event's message, and its other fields as `key=value` pairs.
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+/// Encodes a string as a NUL-terminated UTF-16 string.
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
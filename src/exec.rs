@@ -0,0 +1,101 @@
+//! Running external commands (e.g. exec detectors), such that a misbehaving command can't stall
+//! rnccd: each runs with a timeout, a restricted environment, & size-limited output.
+
+use crate::config::ExecConfig;
+use anyhow::{anyhow, Context, Result};
+use std::{
+    env,
+    process::{self, Stdio},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    time,
+};
+
+/// The most output (on each of stdout & stderr) captured from a command.
+const MAX_OUTPUT_LEN: usize = 64 * 1024;
+
+/// Runs the configured command to completion, with `vars` (as well as `PATH` & the configured
+/// variables) in its environment, returning its standard output. A command which fails, times
+/// out, or writes too much output is an error.
+pub async fn run(cfg: &ExecConfig, vars: &[(&str, String)]) -> Result<String> {
+    let (program, args) = cfg
+        .command
+        .split_first()
+        .ok_or_else(|| anyhow!("command is empty"))?;
+    let mut command = process::Command::new(program);
+    command
+        .args(args)
+        .env_clear()
+        .envs(env::var_os("PATH").map(|path| ("PATH", path)))
+        .envs(&cfg.env)
+        .envs(vars.iter().map(|(name, value)| (name, value)))
+        .current_dir(cfg.working_dir.as_deref().unwrap_or("/"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Run the command in its own process group, so that any processes it starts are killed along
+    // with it.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = Command::from(command)
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("couldn't run {program:?}"))?;
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+
+    let rslt = time::timeout(cfg.timeout(), async {
+        let (stdout, stderr, status) =
+            tokio::try_join!(read_limited(stdout), read_limited(stderr), async {
+                Ok(child.wait().await?)
+            })?;
+        anyhow::Ok((stdout, stderr, status))
+    })
+    .await;
+    let (stdout, stderr, status) = match rslt {
+        Ok(Ok(rslt)) => rslt,
+        Ok(Err(err)) => {
+            kill_group(&child);
+            return Err(err);
+        }
+        Err(_) => {
+            kill_group(&child);
+            return Err(anyhow!(
+                "{program:?} didn't finish within {:?}",
+                cfg.timeout()
+            ));
+        }
+    };
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(anyhow!("{program:?} failed ({status}): {}", stderr.trim()));
+    }
+    String::from_utf8(stdout).with_context(|| format!("{program:?} wrote non-UTF-8 output"))
+}
+
+/// Reads a child's output, failing if it's longer than `MAX_OUTPUT_LEN`.
+async fn read_limited(output: Option<impl AsyncRead + Unpin>) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(output) = output {
+        output
+            .take(MAX_OUTPUT_LEN as u64 + 1)
+            .read_to_end(&mut buf)
+            .await?;
+    }
+    if buf.len() > MAX_OUTPUT_LEN {
+        return Err(anyhow!("command wrote more than {MAX_OUTPUT_LEN} bytes"));
+    }
+    Ok(buf)
+}
+
+/// Kills the child's process group. (The child itself is also killed when dropped.)
+fn kill_group(child: &tokio::process::Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: kill has no memory-safety preconditions.
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+    }
+    #[cfg(not(unix))]
+    let _ = child;
+}
@@ -0,0 +1,98 @@
+//! Failure injection, for checking that alerting & monitoring fire on failures without waiting for
+//! a real outage. Failures are injected via the hidden `--inject-failure` flag, or the
+//! `inject-failure` control command.
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tracing::warn;
+
+/// An operation which can be made to fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Operation {
+    /// IP address detection.
+    Detection,
+    /// Provider updates.
+    Update,
+    /// State file writes.
+    State,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Detection => "detection",
+            Operation::Update => "update",
+            Operation::State => "state",
+        })
+    }
+}
+
+/// A request to fail the next `count` attempts at an operation, written `<operation>[:<count>]`.
+#[derive(Clone, Copy, Debug)]
+pub struct Injection {
+    pub operation: Operation,
+    pub count: u32,
+}
+
+impl FromStr for Injection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (operation, count) = match s.split_once(':') {
+            Some((operation, count)) => (operation, count.parse()?),
+            None => (s, 1),
+        };
+        let operation = Operation::from_str(operation, false).map_err(|err| anyhow!(err))?;
+        Ok(Self { operation, count })
+    }
+}
+
+impl Display for Injection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.operation, self.count)
+    }
+}
+
+/// Faults tracks how many more attempts at each operation should fail.
+#[derive(Default)]
+pub struct Faults {
+    detection: AtomicU32,
+    update: AtomicU32,
+    state: AtomicU32,
+}
+
+impl Faults {
+    /// Fails the next `injection.count` attempts at the operation, in addition to any already
+    /// injected.
+    pub fn inject(&self, injection: Injection) {
+        warn!(
+            operation = %injection.operation,
+            count = injection.count,
+            "Injecting failures"
+        );
+        self.counter(injection.operation)
+            .fetch_add(injection.count, Ordering::Relaxed);
+    }
+
+    /// Called before each attempt at an operation: returns an error if the attempt should fail.
+    pub fn check(&self, operation: Operation) -> Result<()> {
+        let counter = self.counter(operation);
+        match counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)) {
+            Ok(_) => Err(anyhow!("injected {operation} failure")),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn counter(&self, operation: Operation) -> &AtomicU32 {
+        match operation {
+            Operation::Detection => &self.detection,
+            Operation::Update => &self.update,
+            Operation::State => &self.state,
+        }
+    }
+}
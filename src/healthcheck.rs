@@ -0,0 +1,70 @@
+//! Pings of a dead man's switch (e.g. a healthchecks.io check) after each check, so that an
+//! external monitor alerts if rnccd dies, hangs, or keeps failing.
+
+use crate::config::HealthcheckConfig;
+use anyhow::Result;
+use std::time::Duration;
+use tracing::warn;
+
+/// How long a ping may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most of an error's message sent with a failure ping.
+const MAX_MESSAGE_LEN: usize = 10 * 1024;
+
+/// Healthcheck pings the configured URL.
+pub struct Healthcheck {
+    client: reqwest::Client,
+    cfg: HealthcheckConfig,
+}
+
+impl Healthcheck {
+    pub fn new(client: reqwest::Client, cfg: HealthcheckConfig) -> Self {
+        Self { client, cfg }
+    }
+
+    /// Pings `<url>/start`, if configured to, as a check starts.
+    pub async fn start(&self) {
+        if self.cfg.ping_start {
+            self.ping("/start", String::new()).await;
+        }
+    }
+
+    /// Pings `<url>` after a successful check, or `<url>/fail` (with the error) after a failed one.
+    pub async fn finish(&self, rslt: &Result<()>) {
+        match rslt {
+            Ok(()) => self.ping("", String::new()).await,
+            Err(err) => {
+                let mut message = format!("{err:#}");
+                if message.len() > MAX_MESSAGE_LEN {
+                    let end = (0..=MAX_MESSAGE_LEN)
+                        .rev()
+                        .find(|&i| message.is_char_boundary(i))
+                        .unwrap_or_default();
+                    message.truncate(end);
+                }
+                self.ping("/fail", message).await
+            }
+        }
+    }
+
+    /// Pings the URL with the given suffix. Failures are only logged: a monitor which can't be
+    /// reached will alert by itself.
+    async fn ping(&self, suffix: &str, body: String) {
+        let url = format!("{}{suffix}", self.cfg.url.trim_end_matches('/'));
+        let rslt = self
+            .client
+            .post(&url)
+            .timeout(TIMEOUT)
+            .body(body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(err) = rslt {
+            warn!(
+                err = format!("{:#}", err.without_url()),
+                "Couldn't ping healthcheck"
+            );
+        }
+    }
+}
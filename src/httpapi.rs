@@ -0,0 +1,163 @@
+//! The local HTTP API, through which dashboards & scripts can observe & poke the daemon without
+//! parsing its logs or speaking the control socket's protocol. It listens only on loopback
+//! addresses, & has two endpoints:
+//!
+//! - `GET /v1/status`: each record's status as JSON, as given by `rnccd status --output json`.
+//! - `POST /v1/check`: checks every record now, rather than waiting out the interval. Responds
+//!   with `202 Accepted` at once, without waiting for the checks.
+//!
+//! Web pages the user visits can also reach loopback addresses, so requests are refused unless
+//! their `Host` is this server (a loopback address or `localhost`, with its port), which defeats
+//! DNS rebinding; & `POST`s are refused if they carry an `Origin`, as browsers' requests do.
+
+use crate::status::Status;
+use anyhow::{anyhow, Context, Result};
+use hyper::{
+    header::{CONTENT_TYPE, HOST, ORIGIN},
+    http::uri::Authority,
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{debug, info};
+
+/// The longest a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server serves the HTTP API.
+pub struct Server {
+    listener: TcpListener,
+    port: u16, // the port listened on, which requests' Host must give.
+    statuses: Vec<watch::Receiver<Status>>,
+    check_requests: Arc<watch::Sender<()>>,
+}
+
+impl Server {
+    /// Creates a server listening on `listen`, which must be a loopback address. `statuses` holds
+    /// the status of each worker, & `check_requests` is signalled to make workers check now.
+    pub async fn bind(
+        listen: SocketAddr,
+        statuses: Vec<watch::Receiver<Status>>,
+        check_requests: Arc<watch::Sender<()>>,
+    ) -> Result<Self> {
+        if !listen.ip().is_loopback() {
+            return Err(anyhow!(
+                "{listen} isn't a loopback address: the HTTP API is unauthenticated, so may only \
+                 listen locally"
+            ));
+        }
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("couldn't listen on {listen}"))?;
+        let port = listener.local_addr()?.port();
+        Ok(Self {
+            listener,
+            port,
+            statuses,
+            check_requests,
+        })
+    }
+
+    /// Runs the server forever.
+    pub async fn run(self) {
+        let server = Arc::new(self);
+        if let Ok(listen) = server.listener.local_addr() {
+            info!(%listen, "Serving HTTP API");
+        }
+        loop {
+            let (stream, peer) = match server.listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    debug!(%err, "Couldn't accept HTTP API connection");
+                    continue;
+                }
+            };
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let service = service_fn(|req| {
+                    let server = Arc::clone(&server);
+                    async move { Ok::<_, Infallible>(server.handle(req)) }
+                });
+                let rslt = Http::new()
+                    .http1_only(true)
+                    .http1_keep_alive(false)
+                    .http1_header_read_timeout(REQUEST_TIMEOUT)
+                    .serve_connection(stream, service)
+                    .await;
+                if let Err(err) = rslt {
+                    debug!(%peer, %err, "HTTP API connection failed");
+                }
+            });
+        }
+    }
+
+    fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if let Err(err) = self.check_source(&req) {
+            debug!(%err, "Refused HTTP API request");
+            return response(StatusCode::FORBIDDEN, "text/plain", format!("{err}\n"));
+        }
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/v1/status") => {
+                let statuses: Vec<_> = self.statuses.iter().map(|rx| rx.borrow().clone()).collect();
+                match serde_json::to_string(&statuses) {
+                    Ok(json) => response(StatusCode::OK, "application/json", json),
+                    Err(err) => response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "text/plain",
+                        format!("{err}\n"),
+                    ),
+                }
+            }
+            (&Method::POST, "/v1/check") => {
+                info!("Check requested via HTTP API");
+                self.check_requests.send_replace(());
+                response(StatusCode::ACCEPTED, "text/plain", "checking\n".to_string())
+            }
+            (_, "/v1/status" | "/v1/check") => response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "text/plain",
+                "method not allowed\n".to_string(),
+            ),
+            _ => response(
+                StatusCode::NOT_FOUND,
+                "text/plain",
+                "not found\n".to_string(),
+            ),
+        }
+    }
+
+    /// Checks that a request wasn't made by a web page: that its `Host` is this server, & that
+    /// it's not a `POST` with an `Origin`.
+    fn check_source(&self, req: &Request<Body>) -> Result<()> {
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok()?.parse::<Authority>().ok())
+            .ok_or_else(|| anyhow!("Host is missing or invalid"))?;
+        let name = host.host().trim_start_matches('[').trim_end_matches(']');
+        let is_local = name.eq_ignore_ascii_case("localhost")
+            || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+        if !is_local || host.port_u16().unwrap_or(80) != self.port {
+            return Err(anyhow!("Host {host} isn't this server"));
+        }
+        if req.method() == Method::POST && req.headers().contains_key(ORIGIN) {
+            return Err(anyhow!("POSTs from web pages aren't allowed"));
+        }
+        Ok(())
+    }
+}
+
+fn response(status: StatusCode, content_type: &'static str, body: String) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, content_type.parse().unwrap());
+    resp
+}
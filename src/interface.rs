@@ -0,0 +1,111 @@
+//! Detection of our IP address from the addresses of a local network interface, for hosts (e.g.
+//! routers) given the public address directly.
+
+use crate::detect;
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+
+/// Returns the first public address, of the given family, of the named interface.
+pub fn detect(name: &str, ipv6: bool) -> Result<IpAddr> {
+    let addrs = addresses(name)?;
+    addrs
+        .iter()
+        .copied()
+        .filter(|addr| addr.is_ipv6() == ipv6)
+        .find(|&addr| is_public(addr))
+        .ok_or_else(|| {
+            let family = if ipv6 { "IPv6" } else { "IPv4" };
+            let addrs: Vec<_> = addrs.iter().map(IpAddr::to_string).collect();
+            anyhow!(
+                "interface {name} has no public {family} address (has [{}])",
+                addrs.join(", ")
+            )
+        })
+}
+
+/// Returns an address, of the given family, of the named interface to make requests from: the
+/// first address which isn't link-local (which would need the interface's scope to be usable).
+pub fn local_address(name: &str, ipv6: bool) -> Result<IpAddr> {
+    addresses(name)?
+        .into_iter()
+        .filter(|addr| addr.is_ipv6() == ipv6)
+        .find(|addr| match addr {
+            IpAddr::V4(addr) => !addr.is_link_local(),
+            IpAddr::V6(addr) => !addr.is_unicast_link_local(),
+        })
+        .ok_or_else(|| {
+            let family = if ipv6 { "IPv6" } else { "IPv4" };
+            anyhow!("interface {name} has no {family} address")
+        })
+}
+
+/// Whether an address is public: not unspecified, local, or (for IPv4) in the shared address
+/// space (100.64.0.0/10) used by carrier-grade NAT.
+fn is_public(addr: IpAddr) -> bool {
+    let is_shared = match addr {
+        IpAddr::V4(addr) => addr.octets()[0] == 100 && addr.octets()[1] & 0xc0 == 64,
+        IpAddr::V6(_) => false,
+    };
+    !addr.is_unspecified() && !detect::is_local_addr(addr) && !is_shared
+}
+
+/// Returns the addresses of the named interface.
+#[cfg(unix)]
+fn addresses(name: &str) -> Result<Vec<IpAddr>> {
+    use std::{
+        ffi::CStr,
+        io,
+        net::{Ipv4Addr, Ipv6Addr},
+        ptr,
+    };
+
+    let mut ifaddrs = ptr::null_mut();
+    // SAFETY: getifaddrs has no memory-safety preconditions; we check the result.
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut found = false;
+    let mut addrs = Vec::new();
+    let mut next = ifaddrs;
+    while !next.is_null() {
+        // SAFETY: next is an entry of the list getifaddrs returned, which isn't yet freed.
+        let ifaddr = unsafe { &*next };
+        next = ifaddr.ifa_next;
+        // SAFETY: each entry's name is a valid C string.
+        if unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+        found = true;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: a non-null ifa_addr points to a sockaddr, of the type given by its family.
+        unsafe {
+            match i32::from((*ifaddr.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*ifaddr.ifa_addr.cast::<libc::sockaddr_in>();
+                    addrs.push(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into());
+                }
+                libc::AF_INET6 => {
+                    let addr = &*ifaddr.ifa_addr.cast::<libc::sockaddr_in6>();
+                    addrs.push(Ipv6Addr::from(addr.sin6_addr.s6_addr).into());
+                }
+                _ => (),
+            }
+        }
+    }
+    // SAFETY: ifaddrs was returned by getifaddrs, & nothing refers to it any more.
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    if !found {
+        return Err(anyhow!("there's no interface named {name}"));
+    }
+    Ok(addrs)
+}
+
+#[cfg(not(unix))]
+fn addresses(_name: &str) -> Result<Vec<IpAddr>> {
+    Err(anyhow!(
+        "interface detection isn't supported on this platform"
+    ))
+}
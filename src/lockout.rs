@@ -0,0 +1,69 @@
+//! Cool-downs after a provider locks us out (e.g. for abuse, or making too many requests). A
+//! lockout applies to the whole account, so while an account is cooling down no worker calls the
+//! provider with it: retrying early would only extend the lockout. Cool-downs are persisted in the
+//! state, so that restarting doesn't cut them short.
+
+use crate::status;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// An error meaning the provider has temporarily locked out the account the call was made with.
+#[derive(Debug)]
+pub struct Lockout {
+    /// The provider's explanation.
+    pub message: String,
+}
+
+impl Display for Lockout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "provider locked out the account: {}", self.message)
+    }
+}
+
+impl Error for Lockout {}
+
+/// Returns the lockout that caused the given error, if any.
+pub fn cause(err: &anyhow::Error) -> Option<&Lockout> {
+    err.downcast_ref()
+}
+
+/// Cooldowns is a handle to the cool-downs in effect, shared by all workers.
+#[derive(Clone, Default)]
+pub struct Cooldowns {
+    until: Arc<Mutex<BTreeMap<String, u64>>>, // seconds since the Unix epoch, keyed by account.
+}
+
+impl Cooldowns {
+    /// Creates a handle to the given (persisted) cool-downs.
+    pub fn new(until: BTreeMap<String, u64>) -> Self {
+        Self {
+            until: Arc::new(Mutex::new(until)),
+        }
+    }
+
+    /// Returns how much longer the given account is cooling down for, if it is.
+    pub fn remaining(&self, account: &str) -> Option<Duration> {
+        let until = *self.until.lock().unwrap().get(account)?;
+        let remaining = until.saturating_sub(status::unix_time(SystemTime::now()));
+        (remaining > 0).then(|| Duration::from_secs(remaining))
+    }
+
+    /// Starts a cool-down of the given account, lasting `duration`. Returns when it ends, & every
+    /// cool-down still in effect, to be persisted.
+    pub fn start(&self, account: &str, duration: Duration) -> (u64, BTreeMap<String, u64>) {
+        let now = status::unix_time(SystemTime::now());
+        let end = now.saturating_add(duration.as_secs());
+        let mut until = self.until.lock().unwrap();
+        until.retain(|_, &mut until| until > now);
+        let end = *until
+            .entry(account.to_string())
+            .and_modify(|until| *until = (*until).max(end))
+            .or_insert(end);
+        (end, until.clone())
+    }
+}
@@ -0,0 +1,215 @@
+//! Log output, as text (for people) or JSON (for log shippers, e.g. Promtail), to stdout or the
+//! system log. In JSON, each event is a single-line object holding its timestamp, level, target, &
+//! message, along with its fields & those of the spans it's in (e.g. `domain`, `host`, &
+//! `provider`), with the innermost winning.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::LevelFilter,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        writer::BoxMakeWriter,
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+/// Log formats.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Where logs go.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    /// Standard output.
+    #[default]
+    Stdout,
+    /// The syslog daemon, via `/dev/log` (Unix only).
+    Syslog,
+    /// The systemd journal (Unix only).
+    Journald,
+}
+
+type Output = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Logs is the handle to the global subscriber, through which where logs go can be changed once
+/// the config (which may say) is loaded.
+pub struct Logs {
+    format: LogFormat,
+    output: reload::Handle<Output, Registry>,
+}
+
+/// Installs the global subscriber, logging in the given format to the given target.
+pub fn init(format: LogFormat, target: LogTarget) -> Result<Logs> {
+    let (output, handle) = reload::Layer::new(output(format, target)?);
+    let subscriber = tracing_subscriber::registry()
+        .with(output)
+        .with(LevelFilter::INFO);
+    #[cfg(windows)]
+    let subscriber = subscriber.with(crate::eventlog::EventLog::new());
+    subscriber.init();
+    Ok(Logs {
+        format,
+        output: handle,
+    })
+}
+
+impl Logs {
+    /// Sends logs to the given target from now on.
+    pub fn set_target(&self, target: LogTarget) -> Result<()> {
+        self.output.reload(output(self.format, target)?)?;
+        Ok(())
+    }
+}
+
+/// Creates the layer writing logs in the given format to the given target. The system log records
+/// each message's time & priority, so those aren't repeated in text messages.
+fn output(format: LogFormat, target: LogTarget) -> Result<Output> {
+    let writer = match target {
+        LogTarget::Stdout => BoxMakeWriter::new(std::io::stdout),
+        #[cfg(unix)]
+        LogTarget::Syslog => BoxMakeWriter::new(crate::syslog::SystemLog::syslog()?),
+        #[cfg(unix)]
+        LogTarget::Journald => BoxMakeWriter::new(crate::syslog::SystemLog::journal()?),
+        #[cfg(not(unix))]
+        LogTarget::Syslog | LogTarget::Journald => {
+            return Err(anyhow::anyhow!(
+                "syslog & journald are only supported on Unix"
+            ))
+        }
+    };
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    Ok(match (format, target) {
+        (LogFormat::Text, LogTarget::Stdout) => layer
+            .event_format(tracing_subscriber::fmt::format().with_target(false))
+            .boxed(),
+        (LogFormat::Text, _) => layer
+            .with_ansi(false)
+            .event_format(
+                tracing_subscriber::fmt::format()
+                    .with_target(false)
+                    .with_level(false)
+                    .without_time(),
+            )
+            .boxed(),
+        (LogFormat::Json, _) => layer
+            .with_ansi(false)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .boxed(),
+    })
+}
+
+/// Formats events as JSON objects.
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let mut obj = Map::new();
+        obj.insert("timestamp".to_string(), timestamp.into());
+        obj.insert("level".to_string(), meta.level().as_str().into());
+        obj.insert("target".to_string(), meta.target().into());
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<JsonFields>>() {
+                    obj.extend(parse(&fields.fields));
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut obj));
+        writeln!(writer, "{}", Value::Object(obj))
+    }
+}
+
+/// Formats spans' fields as JSON objects, so that they can be merged into their events'.
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut obj = Map::new();
+        fields.record(&mut JsonVisitor(&mut obj));
+        write!(writer, "{}", Value::Object(obj))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut obj = parse(&current.fields);
+        fields.record(&mut JsonVisitor(&mut obj));
+        current.fields = Value::Object(obj).to_string();
+        Ok(())
+    }
+}
+
+/// Parses fields formatted by JsonFields.
+fn parse(fields: &str) -> Map<String, Value> {
+    serde_json::from_str(fields).unwrap_or_default()
+}
+
+/// Records fields into a JSON object. Addresses, durations, & the like are recorded as strings.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
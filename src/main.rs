@@ -1,172 +1,1476 @@
-use anyhow::{anyhow, Result};
-use clap::Parser;
+mod backoff;
+mod clock;
+#[cfg(feature = "cloudflare")]
+mod cloudflare;
+mod codes;
+mod config;
+#[cfg(unix)]
+mod control;
+mod counters;
+#[cfg(feature = "custom")]
+mod custom;
+mod detect;
+mod dns;
+mod doctor;
+#[cfg(feature = "duckdns")]
+mod duckdns;
+#[cfg(feature = "tcp-echo")]
+mod echo;
+mod egress;
+#[cfg(windows)]
+mod eventlog;
+#[cfg(feature = "exec")]
+mod exec;
+mod faults;
+mod healthcheck;
+#[cfg(feature = "http-api")]
+mod httpapi;
+mod interface;
+mod lockout;
+mod logging;
+mod metrics;
+#[cfg(feature = "namecheap")]
+mod namecheap;
+mod nat64;
+mod netmon;
+#[cfg(feature = "noip")]
+mod noip;
+mod notify;
+mod paths;
+#[cfg(feature = "custom")]
+mod pattern;
+mod privileges;
+mod propagation;
+mod provider;
+mod ratelimit;
+#[cfg(feature = "namecheap")]
+mod records;
+#[cfg(feature = "relay")]
+mod relay;
+mod resolver;
+mod sandbox;
+mod state;
+mod status;
+#[cfg(feature = "stun")]
+mod stun;
+mod supervisor;
+#[cfg(unix)]
+mod syslog;
+mod systemd;
+mod uplinks;
+mod watchdog;
+mod worker;
+#[cfg(feature = "namecheap")]
+mod xml;
+
+use crate::{
+    config::{AddressFamily, Config, FirstCheck, ProviderConfig, VerifyDnsConfig},
+    counters::Counters,
+    detect::{Detector, Detectors},
+    faults::{Faults, Injection},
+    healthcheck::Healthcheck,
+    lockout::Cooldowns,
+    notify::{Event, Notification},
+    provider::{Provider, RecordType},
+    state::{State, StateUpdate},
+    status::{ProviderStatus, Status},
+    supervisor::{Heartbeat, Supervisor, SUPERVISE_INTERVAL},
+    uplinks::Uplinks,
+    watchdog::Watchdog,
+    worker::{CanaryGate, Channels, Shared, Worker},
+};
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 use reqwest::{
     header::{HeaderMap, HeaderValue, USER_AGENT},
-    StatusCode,
+    NoProxy, Proxy,
 };
-use serde_derive::{Deserialize, Serialize};
-use tracing::{error, info};
 use std::{
+    collections::{BTreeMap, HashMap},
     ffi::{OsStr, OsString},
     fs::File,
-    io,
-    net::Ipv4Addr,
+    iter,
+    net::IpAddr,
     path::Path,
+    process,
+    sync::Arc,
     time::Duration,
 };
-use tempfile::NamedTempFile;
-use tokio::time::{self, MissedTickBehavior};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+#[cfg(not(any(
+    feature = "namecheap",
+    feature = "cloudflare",
+    feature = "duckdns",
+    feature = "noip",
+    feature = "custom",
+    feature = "relay"
+)))]
+compile_error!("at least one provider feature must be enabled (e.g. \"namecheap\")");
+
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("at least one TLS backend feature must be enabled (\"rustls\" or \"native-tls\")");
+
+#[cfg(unix)]
+type ControlListener = std::os::unix::net::UnixListener;
+#[cfg(not(unix))]
+type ControlListener = std::convert::Infallible;
+
+/// The tracing target of notable events (e.g. IP changes), which are written to OS-level logs
+/// (e.g. the Windows Event Log) along with warnings & errors.
+const NOTABLE_TARGET: &str = "rnccd::notable";
+
+/// The longest to wait, when stopping, for workers to finish their in-flight checks. Workers still
+/// checking are then aborted, which is safe: providers not yet updated are still believed to have
+/// the old address.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest to wait, when stopping, for the `stopped` notification to be delivered.
+const STOP_NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many supervisor intervals may pass without the supervisor making progress before the
+/// watchdog exits the process.
+const WATCHDOG_INTERVALS: u32 = 6;
 
 /// A simple Namecheap Dynamic DNS client.
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
-    /// The config file to use (read-only).
+    /// The config file to use (read-only). May be given more than once, in which case the files are
+    /// merged, with settings in later files overriding those in earlier ones. If omitted, the first
+    /// of `$XDG_CONFIG_HOME/rnccd/config.yaml` & `/etc/rnccd/config.yaml` (on Windows,
+    /// `%PROGRAMDATA%\rnccd\config.yaml`) which exists is used.
     #[arg(long, value_name = "FILE")]
-    config: OsString,
+    config: Vec<OsString>,
 
-    /// The state file to use (read/write).
+    /// The state file to use (read/write). If omitted, the first of
+    /// `$XDG_STATE_HOME/rnccd/state.yaml` & `/var/lib/rnccd/state.yaml` (on Windows,
+    /// `%PROGRAMDATA%\rnccd\state.yaml`) which exists is used, else the first whose directory
+    /// exists. `none` keeps the state in memory only (e.g. on a read-only filesystem): what the
+    /// records have is then looked up in DNS at startup.
     #[arg(long, value_name = "FILE")]
-    state: OsString,
+    state: Option<OsString>,
+
+    /// The control socket to listen on (when running as a daemon) or connect to (when running a
+    /// command). Unix only.
+    #[arg(long, value_name = "FILE")]
+    control_socket: Option<OsString>,
+
+    /// The user to switch to after reading the config & state files. If `--group` is not given,
+    /// the user's primary group is used.
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+
+    /// The group to switch to after reading the config & state files.
+    #[arg(long, value_name = "GROUP")]
+    group: Option<String>,
+
+    /// Sandbox the process after startup, restricting filesystem access to the config file & the
+    /// state file's directory, and denying syscalls that rnccd never needs. Supported on Linux
+    /// (Landlock & seccomp) and OpenBSD (unveil & pledge).
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Fail the next COUNT (default 1) attempts at an operation (detection, update, or state), to
+    /// check that alerting works. May be given more than once.
+    #[arg(long, value_name = "OPERATION[:COUNT]", hide = true)]
+    inject_failure: Vec<Injection>,
+
+    /// Detect our IP address as usual, but never change records: instead, each check reports
+    /// (in the logs & `rnccd status`) what it would have done about each record.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Check (& if necessary update) each record once, then exit, rather than running as a
+    /// daemon: e.g. to run from cron or a systemd timer. Exits with 0 if every check succeeded,
+    /// or 1 if any failed.
+    #[arg(long, conflicts_with = "control_socket")]
+    once: bool,
+
+    /// How to format logs: `text` for people, or `json` (one object per line, with each event's
+    /// fields, e.g. `domain`, `host`, `provider`, `old_addr`, & `new_addr`) for log shippers.
+    #[arg(long, value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+
+    /// Where to write logs: `stdout`, or (on Unix) `syslog` or `journald`. Overrides the config's
+    /// `log_target`.
+    #[arg(long, value_enum)]
+    log_target: Option<logging::LogTarget>,
+
+    /// A command to run. If omitted, run the daemon.
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Config (read-only).
-#[derive(Deserialize)]
-struct Config {
-    /// The domain to update.
-    domain: String,
+/// Commands. Other than `doctor`, `config`, `records`, `echo-server`, & `relay-server`, these are
+/// sent to a running daemon over its control socket.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check the environment (DNS, connectivity, credentials, state directory, clock) and report
+    /// problems. Records keep their addresses: where credentials can only be checked by re-setting
+    /// a record to its current address, that's only done if a public resolver gives it a single,
+    /// public address.
+    Doctor,
+
+    /// Inspect the config.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Inspect a domain's records, as Namecheap has them (read-only). Needs XML API credentials
+    /// for the domain in the config.
+    #[cfg(feature = "namecheap")]
+    Records {
+        #[command(subcommand)]
+        command: RecordsCommand,
+    },
+
+    /// Run a TCP echo server, which replies to each connection with its source address, for use
+    /// as a `tcp://` detector by rnccd instances on networks which block outbound HTTP(S).
+    #[cfg(feature = "tcp-echo")]
+    EchoServer {
+        /// The address to listen on, e.g. `0.0.0.0:7777`.
+        #[arg(long)]
+        listen: std::net::SocketAddr,
+    },
+
+    /// Run a relay, which updates records on behalf of rnccd instances using the `relay` provider
+    /// (e.g. on routers which can't reach the providers). The config file gives the relay's
+    /// certificate, its clients, & the records they may update, rather than the daemon's settings.
+    #[cfg(feature = "relay")]
+    RelayServer {
+        /// The address to listen on, e.g. `0.0.0.0:8443`.
+        #[arg(long)]
+        listen: std::net::SocketAddr,
+    },
+
+    /// Pause updates for a record (e.g. `www.example.com`) until it is resumed. Survives restarts.
+    Pause { record: String },
 
-    /// The host (aka subdomain) to set DNS for. Omit, or specify `@`, to update the bare domain.
-    /// Specify `*` to update the wildcard subdomain.
-    host: Option<String>,
+    /// Resume updates for a paused record, checking it immediately.
+    Resume { record: String },
 
-    /// The dynamic DNS password.
-    password: String,
+    /// Show the status of each record: the detected & provider-confirmed addresses, the last
+    /// success & error, and when the next check is due.
+    Status {
+        #[arg(long, value_enum, default_value_t = status::Output::Table)]
+        output: status::Output,
+    },
+
+    /// Fail the next COUNT (default 1) attempts at an operation (detection, update, or state), to
+    /// check that alerting works.
+    #[command(hide = true)]
+    InjectFailure {
+        #[arg(value_name = "OPERATION[:COUNT]")]
+        injection: Injection,
+    },
 }
 
-// State (read/write).
-#[derive(Default, Serialize, Deserialize)]
-struct State {
-    /// Our current conception of what Namecheap thinks our IP address is.
-    addr: Option<Ipv4Addr>,
+/// Config commands.
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective config (after merging config files & resolving secrets), as YAML.
+    Show {
+        /// Replace the value of each secret with a placeholder, e.g. to include the config in a
+        /// bug report.
+        #[arg(long)]
+        redacted: bool,
+    },
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .event_format(tracing_subscriber::fmt::format().with_target(false))
-        .init();
+/// Records commands.
+#[cfg(feature = "namecheap")]
+#[derive(Subcommand)]
+pub enum RecordsCommand {
+    /// List the domain's host records.
+    List {
+        /// The domain, e.g. `example.com`.
+        domain: String,
+
+        #[arg(long, value_enum, default_value_t = status::Output::Table)]
+        output: status::Output,
+    },
+
+    /// Start managing an existing record: add its host to the domain's provider in the (first)
+    /// config file, & note its current address in the state, so that it isn't needlessly updated.
+    /// The config file is rewritten, so comments in it are lost. rnccd must be stopped first, as it
+    /// would overwrite the state: adoption is refused if it answers on `--control-socket`.
+    Adopt {
+        /// The domain, e.g. `example.com`.
+        domain: String,
+
+        /// The host (aka subdomain), e.g. `www`, or `@` for the bare domain.
+        host: String,
+    },
+}
+
+fn main() {
     let args = Args::parse();
+    let logs = logging::init(args.log_format, args.log_target.unwrap_or_default())
+        .expect("Couldn't set up logging");
+    match &args.command {
+        Some(Command::Doctor) => process::exit(run_doctor(&args)),
+        Some(Command::Config { command }) => process::exit(run_config_command(&args, command)),
+        #[cfg(feature = "namecheap")]
+        Some(Command::Records { command }) => process::exit(run_records_command(&args, command)),
+        #[cfg(feature = "tcp-echo")]
+        Some(Command::EchoServer { listen }) => process::exit(run_echo_server(*listen)),
+        #[cfg(feature = "relay")]
+        Some(Command::RelayServer { listen }) => process::exit(run_relay_server(&args, *listen)),
+        Some(command) => process::exit(run_command(args.control_socket.as_deref(), command)),
+        None => (),
+    }
+    let config_paths = paths::config(&args.config).expect("Couldn't find config file");
+    let state_path = paths::state(args.state.as_deref()).expect("Couldn't find state file");
 
     // Parse config & state files.
-    let cfg: Config = {
-        let config_file = File::open(args.config).expect("Couldn't open config file");
-        serde_yaml::from_reader(config_file).expect("Couldn't parse config file")
-    };
-    let mut state: State = match File::open(&args.state) {
-        Ok(state_file) => serde_yaml::from_reader(state_file).expect("Couldn't parse state file"),
-        Err(err) if err.kind() == io::ErrorKind::NotFound => {
-            let state = State::default();
-            update_state(&args.state, &state)
-                .await
-                .expect("Couldn't write initial state file");
+    let mut cfg = load_config(&config_paths);
+    if let (None, Some(target)) = (args.log_target, cfg.log_target) {
+        logs.set_target(target).expect("Couldn't set up logging");
+    }
+    let state = match &state_path {
+        Some(state_path) => {
+            let state =
+                state::load(state_path, cfg.durable_state).expect("Couldn't load state file");
+            info!("Loaded state: {state}");
             state
         }
-        Err(err) => panic!("Couldn't read state file: {}", err),
+        // Without a state file, we don't know what the records have, so look them up in DNS.
+        None => {
+            info!("Keeping state in memory only, will verify records in DNS");
+            cfg.verify_dns.get_or_insert_with(VerifyDnsConfig::default);
+            State::default()
+        }
     };
 
-    // Create an HTTP client.
-    let client = reqwest::Client::builder()
-        .default_headers(HeaderMap::from_iter([(
-            USER_AGENT,
-            HeaderValue::from_str(&format!("rnccd {}", env!("CARGO_PKG_VERSION")))
-                .expect("Couldn't create default HTTP headers"),
-        )]))
-        .timeout(Duration::from_secs(30))
+    // Bind the control socket while we still have the privileges to do so.
+    let control_listener = args.control_socket.as_deref().map(|path| {
+        #[cfg(unix)]
+        return control::bind(Path::new(path)).expect("Couldn't bind control socket");
+        #[cfg(not(unix))]
+        panic!("Control sockets are not supported on this platform");
+    });
+
+    // Drop privileges & sandbox ourselves before starting the async runtime, so that all of its
+    // threads inherit the sandbox.
+    privileges::drop(args.user.as_deref(), args.group.as_deref())
+        .expect("Couldn't drop privileges");
+    #[cfg(not(feature = "http-api"))]
+    if cfg.http_api.is_some() {
+        panic!("http_api needs the \"http-api\" feature");
+    }
+    #[cfg(not(feature = "exec"))]
+    if !cfg.on_change.is_empty() {
+        panic!("on_change hooks need the \"exec\" feature");
+    }
+    if args.sandbox {
+        if detectors(&cfg, &state)
+            .detectors()
+            .any(|detector| detector.is_exec())
+        {
+            panic!("Exec detectors can't be used with --sandbox, which forbids running commands");
+        }
+        if !cfg.on_change.is_empty() {
+            panic!("on_change hooks can't be used with --sandbox, which forbids running commands");
+        }
+        let config_paths: Vec<_> = config_paths.iter().map(Path::new).collect();
+        sandbox::apply(&config_paths, state_path.as_deref().map(Path::new))
+            .expect("Couldn't sandbox process");
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
         .build()
-        .expect("Couldn't create HTTP client");
-
-    // Main loop: check IP every now and then, update if necessary.
-    info!("Starting: will check & update IP every 60s");
-    let mut interval = time::interval(Duration::from_secs(60));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-    let mut namecheap_addr = state.addr; // namecheap_addr stores our belief about what Namecheap thinks our IP is.
-    loop {
-        interval.tick().await;
-
-        // Figure out what our current IP is.
-        let current_addr = match current_address(&client).await {
-            Ok(addr) => addr,
+        .expect("Couldn't create async runtime")
+        .block_on(run(
+            &args,
+            &config_paths,
+            state_path,
+            cfg,
+            state,
+            control_listener,
+        ));
+}
+
+/// Loads the config, merging the given files in order.
+fn load_config(config_paths: &[OsString]) -> Config {
+    try_load_config(config_paths).unwrap_or_else(|err| panic!("Couldn't load config: {err:#}"))
+}
+
+/// Loads the config, merging the given files in order, or returns why it couldn't be.
+fn try_load_config(config_paths: &[OsString]) -> anyhow::Result<Config> {
+    let mut cfg = config::parse(merge_config(config_paths)?).context("couldn't parse config")?;
+    cfg.resolve_secrets(Path::new(&config_paths[0]))
+        .context("couldn't resolve secrets")?;
+    Ok(cfg)
+}
+
+/// Loads the `relay-server` command's config, as for [`try_load_config`].
+#[cfg(feature = "relay")]
+fn try_load_relay_server_config(
+    config_paths: &[OsString],
+) -> anyhow::Result<config::RelayServerConfig> {
+    let mut cfg =
+        config::parse_relay_server(merge_config(config_paths)?).context("couldn't parse config")?;
+    cfg.resolve_secrets(Path::new(&config_paths[0]))
+        .context("couldn't resolve secrets")?;
+    Ok(cfg)
+}
+
+/// Reads the given config files, & merges them in order.
+fn merge_config(config_paths: &[OsString]) -> anyhow::Result<serde_yaml::Value> {
+    let mut merged = serde_yaml::Value::Null;
+    for config_path in config_paths {
+        let display = Path::new(config_path).display();
+        let config_file =
+            File::open(config_path).with_context(|| format!("couldn't open {display}"))?;
+        let value = serde_yaml::from_reader(config_file)
+            .with_context(|| format!("couldn't parse {display}"))?;
+        config::merge(&mut merged, value);
+    }
+    Ok(merged)
+}
+
+/// Runs the `doctor` command, returning the process exit code.
+fn run_doctor(args: &Args) -> i32 {
+    let paths = paths::config(&args.config)
+        .and_then(|config_paths| Ok((config_paths, paths::state(args.state.as_deref())?)));
+    let (config_paths, state_path) = match paths {
+        Ok(paths) => paths,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return 2;
+        }
+    };
+    let cfg = load_config(&config_paths);
+    let state = match &state_path {
+        Some(state_path) => state::read(state_path).unwrap_or_else(|err| {
+            eprintln!("Couldn't read state file, ignoring it: {err:#}");
+            State::default()
+        }),
+        None => State::default(),
+    };
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't create async runtime")
+        .block_on(async {
+            let http = HttpSettings::new(&cfg);
+            let client = http.client(None);
+            let detectors = detectors(&cfg, &state);
+            // The resolver records are verified with, if configured, likely has the freshest
+            // answers.
+            let resolver = cfg.verify_dns.clone().unwrap_or_default().resolver;
+            match doctor::run(
+                &client,
+                &providers(&cfg, &http),
+                &detectors,
+                resolver,
+                state_path.as_deref(),
+            )
+            .await
+            {
+                true => 0,
+                false => 1,
+            }
+        })
+}
+
+/// Runs a `config` command, returning the process exit code.
+fn run_config_command(args: &Args, command: &ConfigCommand) -> i32 {
+    let config_paths = match paths::config(&args.config) {
+        Ok(config_paths) => config_paths,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return 2;
+        }
+    };
+    let mut cfg = load_config(&config_paths);
+    match *command {
+        ConfigCommand::Show { redacted } => {
+            if redacted {
+                cfg.redact_secrets();
+            }
+            match serde_yaml::to_string(&cfg) {
+                Ok(yaml) => {
+                    print!("{yaml}");
+                    0
+                }
+                Err(err) => {
+                    eprintln!("Error: {err:#}");
+                    1
+                }
+            }
+        }
+    }
+}
+
+/// Runs a `records` command, returning the process exit code.
+#[cfg(feature = "namecheap")]
+fn run_records_command(args: &Args, command: &RecordsCommand) -> i32 {
+    let config_paths = match paths::config(&args.config) {
+        Ok(config_paths) => config_paths,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return 2;
+        }
+    };
+    let cfg = load_config(&config_paths);
+    let (RecordsCommand::List { domain, .. } | RecordsCommand::Adopt { domain, .. }) = command;
+    let api_cfg = iter::once(&cfg.provider)
+        .chain(&cfg.backup_providers)
+        .flat_map(ProviderConfig::per_record)
+        .find_map(|provider| match provider {
+            ProviderConfig::Namecheap(nc_cfg) if nc_cfg.domain == *domain => {
+                let config::NamecheapAuth::Api { ref api } = nc_cfg.auth else {
+                    return None;
+                };
+                Some((api.client_ip, nc_cfg))
+            }
+            _ => None,
+        });
+    let Some((client_ip, api_cfg)) = api_cfg else {
+        eprintln!("Error: no XML API credentials are configured for {domain}");
+        return 2;
+    };
+    let rslt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't create async runtime")
+        .block_on(async {
+            // Namecheap needs to be told the (allowlisted) address we're calling from.
+            let client = HttpSettings::new(&cfg).client(None);
+            let client_ip = match client_ip {
+                Some(client_ip) => client_ip,
+                None => {
+                    detectors(&cfg, &State::default())
+                        .current_address(&client)
+                        .await?
+                }
+            };
+            namecheap::list_hosts(&client, &api_cfg, client_ip).await
+        })
+        .and_then(|hosts| match command {
+            RecordsCommand::List { output, .. } => records::render(&hosts, *output),
+            RecordsCommand::Adopt { host, .. } => {
+                // The daemon keeps its state in memory, so would overwrite what's adopted.
+                #[cfg(unix)]
+                if let Some(control_socket) = &args.control_socket {
+                    let status = Command::Status {
+                        output: status::Output::Table,
+                    };
+                    if control::send(Path::new(control_socket), &status).is_ok() {
+                        return Err(anyhow::anyhow!(
+                            "rnccd is running (it answers on {}): stop it before adopting records",
+                            Path::new(control_socket).display()
+                        ));
+                    }
+                }
+                let state_path = paths::state(args.state.as_deref())?.ok_or_else(|| {
+                    anyhow::anyhow!("--state none has no state to adopt records into")
+                })?;
+                records::adopt(&config_paths, &state_path, domain, host, &hosts)
+            }
+        });
+    match rslt {
+        Ok(out) => {
+            println!("{out}");
+            0
+        }
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            1
+        }
+    }
+}
+
+/// Runs the `echo-server` command, returning the process exit code (if it stops).
+#[cfg(feature = "tcp-echo")]
+fn run_echo_server(listen: std::net::SocketAddr) -> i32 {
+    let rslt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't create async runtime")
+        .block_on(echo::serve(listen));
+    if let Err(err) = rslt {
+        eprintln!("Error: {err:#}");
+    }
+    1
+}
+
+/// Runs the `relay-server` command, returning the process exit code (if it stops).
+#[cfg(feature = "relay")]
+fn run_relay_server(args: &Args, listen: std::net::SocketAddr) -> i32 {
+    let cfg = paths::config(&args.config).and_then(|config_paths| {
+        try_load_relay_server_config(&config_paths).context("couldn't load config")
+    });
+    let cfg = match cfg {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return 2;
+        }
+    };
+    let http = HttpSettings {
+        proxy: None,
+        ignore_proxy_env: false,
+        address_family: None,
+        allowlist: None,
+    };
+    let rslt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't create async runtime")
+        .block_on(relay::serve(cfg, listen, http));
+    match rslt {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            1
+        }
+    }
+}
+
+/// Sends a command to the running daemon, returning the process exit code.
+fn run_command(control_socket: Option<&OsStr>, command: &Command) -> i32 {
+    #[cfg(unix)]
+    {
+        let Some(control_socket) = control_socket else {
+            eprintln!("--control-socket is required to send commands to the daemon");
+            return 2;
+        };
+        let rslt = control::send(Path::new(control_socket), command);
+        let rslt = match command {
+            Command::Status { output } => rslt.and_then(|json| status::render(&json, *output)),
+            _ => rslt,
+        };
+        match rslt {
+            Ok(output) => {
+                if !output.is_empty() {
+                    println!("{output}");
+                }
+                0
+            }
             Err(err) => {
-                error!(%err, "Couldn't get current IP address");
-                continue;
+                eprintln!("Error: {err:#}");
+                1
             }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (control_socket, command);
+        eprintln!("Control sockets are not supported on this platform");
+        2
+    }
+}
+
+async fn run(
+    args: &Args,
+    config_paths: &[OsString],
+    state_path: Option<OsString>,
+    cfg: Config,
+    mut state: State,
+    control_listener: Option<ControlListener>,
+) {
+    let (injections, dry_run, once) = (&args.inject_failure, args.dry_run, args.once);
+    let reload_refusal = reload_refusal(args, state_path.is_none());
+    // Create an HTTP client.
+    let http = HttpSettings::new(&cfg);
+    let client = http.client(None);
+    let metrics = metrics::spawn(client.clone(), &cfg.metrics);
+    let faults = Arc::new(Faults::default());
+    for &injection in injections {
+        faults.inject(injection);
+    }
+
+    // Start the state writer, which persists changes reported by the workers.
+    let (state_updates, state_updates_rx) = mpsc::channel(16);
+    let providers = providers(&cfg, &http);
+    if let Some(canary) = &cfg.canary {
+        if !providers
+            .iter()
+            .any(|provider| provider.record() == canary.record)
+        {
+            panic!(
+                "Canary {} isn't one of the configured records",
+                canary.record
+            );
+        }
+    }
+    let detector = Arc::new(detectors(&cfg, &state));
+    let detector6 = providers
+        .iter()
+        .any(|provider| provider.record_type() == RecordType::Aaaa)
+        .then(|| Arc::new(detector6(&cfg)));
+    if !cfg.uplinks.is_empty() && matches!(cfg.address_family, Some(AddressFamily::Ipv6Only)) {
+        panic!("Uplinks detect our IPv4 address over IPv4, so can't be used with ipv6-only");
+    }
+    let uplinks = (!cfg.uplinks.is_empty()).then(|| Arc::new(uplinks(&cfg, http.clone())));
+    log_summary(
+        &cfg,
+        &providers,
+        &detector,
+        uplinks.as_deref(),
+        detector6.as_deref(),
+        state_path.as_deref(),
+    );
+    check_ipv4(&cfg, &providers).await;
+    if dry_run {
+        info!("Dry run: records won't be changed");
+    }
+    if cfg.update_on_start {
+        info!("update_on_start is set, will update every provider on the first check");
+    }
+
+    // Each host entry gets a worker per type of record it has, since each type's address is
+    // detected separately. A host's workers share a pause switch.
+    let worker_span = |entry: &HostEntry, record_type: RecordType| {
+        let span = info_span!(
+            "worker",
+            domain = entry.cfg.domain(),
+            host = entry.cfg.host(),
+            provider = entry.cfg.name(),
+            record_type = field::Empty,
+            labels = field::Empty,
+        );
+        if record_type != RecordType::A {
+            span.record("record_type", record_type.to_string());
+        }
+        if !entry.labels.is_empty() {
+            let labels: Vec<_> = entry
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            span.record("labels", labels.join(","));
+        }
+        span
+    };
+    let mut pause_switches = HashMap::new();
+    let mut workers = Vec::new();
+    let mut statuses = Vec::new();
+    let entries = host_entries(&cfg, &http);
+    // State written before pending addresses were kept per provider has one for every provider
+    // of each type of record.
+    let legacy_pending = [
+        state.pending_addr.take().map(IpAddr::V4),
+        state.pending_addr6.take().map(IpAddr::V6),
+    ];
+    for addr in legacy_pending.into_iter().flatten() {
+        for provider in &providers {
+            if provider.record_type().matches(addr) {
+                state
+                    .pending_addrs
+                    .entry(provider.id().to_string())
+                    .or_insert(addr);
+            }
+        }
+    }
+    let first_a = providers
+        .iter()
+        .find(|provider| provider.record_type() == RecordType::A)
+        .map(|provider| provider.id().to_string());
+    for (entry, record_type) in entries
+        .iter()
+        .flat_map(|entry| [(entry, RecordType::A), (entry, RecordType::Aaaa)])
+    {
+        let providers: Vec<_> = entry
+            .providers
+            .iter()
+            .filter(|provider| provider.record_type() == record_type)
+            .cloned()
+            .collect();
+        if providers.is_empty() {
+            continue;
+        }
+        // State written before per-provider tracking only knows the first A provider's address.
+        // If updating on start, the addresses in state are ignored, so that the first check
+        // updates every provider.
+        let provider_addrs: Vec<_> = providers
+            .iter()
+            .map(|provider| {
+                if cfg.update_on_start {
+                    return None;
+                }
+                let addr = state.provider_addrs.get(provider.id()).copied();
+                let legacy_addr = state
+                    .addr
+                    .filter(|_| first_a.as_deref() == Some(provider.id()));
+                addr.or(legacy_addr.map(IpAddr::V4))
+            })
+            .collect();
+        let detected_addr = match record_type {
+            RecordType::A => state.detected_addr.map(IpAddr::V4),
+            RecordType::Aaaa => state.detected_addr6.map(IpAddr::V6),
         };
+        let pending_addr = providers
+            .iter()
+            .find_map(|provider| state.pending_addrs.get(provider.id()))
+            .copied();
+        let record = providers[0].record().to_string();
+        let paused = pause_switches
+            .entry(record.clone())
+            .or_insert_with(|| watch::channel(state.paused.contains(&record)).0)
+            .subscribe();
+        let (status, status_rx) = watch::channel(Status {
+            record: record.clone(),
+            labels: entry.labels.clone(),
+            providers: providers
+                .iter()
+                .zip(&provider_addrs)
+                .map(|(provider, &addr)| ProviderStatus {
+                    id: provider.id().to_string(),
+                    addr,
+                    totals: state
+                        .counters
+                        .get(provider.id())
+                        .copied()
+                        .unwrap_or_default(),
+                    update_latency_ms: None,
+                })
+                .collect(),
+            detected_addr,
+            detection_latency_ms: None,
+            paused: state.paused.contains(&record),
+            last_success: None,
+            last_error: None,
+            recent_errors: Vec::new(),
+            next_check: None,
+            plan: Vec::new(),
+        });
+        statuses.push(status_rx);
+        let channels = Channels {
+            paused,
+            status: Arc::new(status),
+        };
+        workers.push((
+            worker_span(entry, record_type),
+            providers,
+            provider_addrs,
+            pending_addr,
+            channels,
+            entry.labels.clone(),
+        ));
+    }
+    // Updates of the canary's type of record wait on its worker, if other workers have that type.
+    let canary_gate = cfg.canary.as_ref().map(|canary| {
+        let gated = [RecordType::A, RecordType::Aaaa]
+            .into_iter()
+            .filter(|&record_type| {
+                let of_type: Vec<&Vec<Arc<dyn Provider>>> = workers
+                    .iter()
+                    .map(|(_, providers, ..)| providers)
+                    .filter(|providers| providers[0].record_type() == record_type)
+                    .collect();
+                of_type.len() > 1
+                    && of_type
+                        .iter()
+                        .flat_map(|providers| providers.iter())
+                        .any(|provider| provider.record() == canary.record)
+            })
+            .collect();
+        Arc::new(CanaryGate::new(gated))
+    });
+    let cooldowns = Cooldowns::new(state.cooldowns.clone());
+    let counters = Counters::new(state.counters.clone());
+    let notifier = notify::spawn(
+        client.clone(),
+        cfg.webhooks.clone(),
+        cfg.notification_rules.clone(),
+        state.notifications.clone(),
+        state_updates.clone(),
+        metrics.clone(),
+    );
+    let state_writer = tokio::spawn(state::run_writer(
+        state_path,
+        state,
+        state_updates_rx,
+        Arc::clone(&faults),
+        cfg.durable_state,
+    ));
 
-        // Update IP in Namecheap if it differs.
-        if Some(current_addr) != namecheap_addr {
-            info!(old_addr = ?namecheap_addr, new_addr = ?current_addr, "Detected new IP, updating");
-            if let Err(err) = update_address(&client, &cfg, current_addr).await {
-                error!(%err, "Couldn't update IP address");
-                continue;
+    let statuses_for_systemd = statuses.clone();
+
+    // Start the HTTP API, if configured.
+    let (check_requests_tx, check_requests) = watch::channel(());
+    let check_requests_tx = Arc::new(check_requests_tx);
+    #[cfg(feature = "http-api")]
+    if let Some(api) = &cfg.http_api {
+        let server =
+            httpapi::Server::bind(api.listen, statuses.clone(), Arc::clone(&check_requests_tx))
+                .await
+                .expect("Couldn't start HTTP API");
+        tokio::spawn(server.run());
+    }
+
+    // Start the control server, if requested.
+    #[cfg(unix)]
+    if let Some(listener) = control_listener {
+        let server = control::Server::new(
+            listener,
+            pause_switches,
+            statuses,
+            state_updates.clone(),
+            Arc::clone(&faults),
+        )
+        .expect("Couldn't start control server");
+        tokio::spawn(server.run());
+    }
+    #[cfg(not(unix))]
+    let _ = (control_listener, pause_switches, statuses);
+
+    // Wait for the network to come up, so that the first check doesn't fail if we're racing it at
+    // boot. If the first check is delayed, the network has that long to come up anyway; if
+    // running once, failing fast (until the next run) is more predictable than waiting.
+    let mut network_changes = netmon::spawn();
+    let check_interval = cfg.check_interval();
+    let delay_first_check = cfg.first_check == FirstCheck::AfterInterval && !once;
+    let max_backoff = cfg.max_backoff();
+    let stable_for = cfg.stable_for();
+    if !delay_first_check && !once {
+        netmon::wait_for_network(
+            &client,
+            &detector,
+            cfg.connectivity_url.as_deref(),
+            &mut network_changes,
+        )
+        .await;
+    }
+
+    let mut accounts: Vec<_> = providers
+        .iter()
+        .map(|provider| provider.account())
+        .collect();
+    accounts.sort_unstable();
+    accounts.dedup();
+    let healthcheck = cfg
+        .healthcheck
+        .map(|healthcheck| Arc::new(Healthcheck::new(client.clone(), healthcheck)));
+    let (stop_tx, stop) = watch::channel(false);
+    let shared = Shared {
+        client,
+        detector,
+        uplinks,
+        detector6,
+        detections: Arc::default(),
+        state_updates,
+        network_changes,
+        check_requests,
+        stop,
+        clock_jumps: clock::spawn(),
+        faults,
+        metrics,
+        notifier,
+        rate_limiter: ratelimit::spawn(
+            cfg.provider_requests_per_minute,
+            cfg.account_requests_per_minute,
+            &accounts,
+        ),
+        cooldowns,
+        counters,
+        // Measurements can take far longer than a single check, so aren't made if running once.
+        propagation: cfg.propagation.filter(|_| !once).map(Arc::new),
+        verify_dns: cfg.verify_dns.map(Arc::new),
+        canary: cfg.canary.map(Arc::new),
+        canary_gate,
+        healthcheck,
+        dry_run,
+        delay_first_check,
+        max_backoff,
+        cycle_timeout: cfg.cycle_timeout.map(|timeout| timeout.0),
+        log_heartbeat: cfg.log_heartbeat.map(|interval| interval.0),
+        on_change: Arc::new(cfg.on_change),
+        notify_after_failures: cfg.notify_after_failures.unwrap_or(1).max(1),
+        update_policy: cfg.update_policy,
+        stable_for,
+    };
+    if once {
+        info!("Checking IP once");
+        // Workers run at once, since a canary's holds up the others' updates.
+        let mut runs = JoinSet::new();
+        for (span, providers, provider_addrs, pending_addr, channels, labels) in workers {
+            let worker = Worker::new(
+                shared.clone(),
+                providers.into_iter().zip(provider_addrs).collect(),
+                check_interval,
+                pending_addr,
+                Heartbeat::unsupervised(),
+                channels,
+                labels,
+            );
+            runs.spawn(worker.run_once().instrument(span));
+        }
+        let mut failed = false;
+        while let Some(rslt) = runs.join_next().await {
+            failed |= !matches!(rslt, Ok(Ok(())));
+        }
+
+        // Once everything else has let go of the state writer (after the notifier tries to
+        // deliver any notifications), it has written all state updates.
+        drop(shared);
+        if let Err(err) = state_writer.await {
+            error!(%err, "State writer failed");
+            failed = true;
+        }
+        process::exit(if failed { 1 } else { 0 });
+    }
+
+    // Start a supervised worker per domain/host entry: each checks IP every now and then, and
+    // updates if necessary.
+    info!(
+        "Starting: will check & update IP every {}s",
+        check_interval.as_secs()
+    );
+    let lifecycle_notification =
+        |event| Notification::new(event, providers[0].record().to_string(), cfg.labels.clone());
+    if cfg.notify_lifecycle {
+        shared
+            .notifier
+            .notify(lifecycle_notification(Event::Started {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            }));
+    }
+    if let Some(timeout) = systemd::watchdog_timeout() {
+        // The supervisor pets the watchdog (& so pings systemd's) once per interval.
+        if timeout < SUPERVISE_INTERVAL * 2 {
+            warn!(
+                ?timeout,
+                "systemd's watchdog timeout (WatchdogSec) is too short to be reliably met, it \
+                 should be at least {:?}",
+                SUPERVISE_INTERVAL * 2
+            );
+        }
+    }
+    tokio::spawn(systemd::report(statuses_for_systemd));
+    let watchdog = Watchdog::start(SUPERVISE_INTERVAL * WATCHDOG_INTERVALS);
+    let mut supervisor = Supervisor::new(watchdog);
+    for (span, providers, provider_addrs, pending_addr, channels, labels) in workers {
+        let shared = shared.clone();
+        let (mut provider_addrs, mut pending_addr) = (Some(provider_addrs), pending_addr);
+        supervisor.add(span, move |heartbeat| {
+            // A restarted worker can't trust the addresses it was started with, since the previous
+            // incarnation may have died mid-update; start from scratch instead.
+            let addrs = provider_addrs
+                .take()
+                .unwrap_or_else(|| vec![None; providers.len()]);
+            Worker::new(
+                shared.clone(),
+                providers.iter().cloned().zip(addrs).collect(),
+                check_interval,
+                pending_addr.take(),
+                heartbeat,
+                channels.clone(),
+                labels.clone(),
+            )
+        });
+    }
+    #[cfg(unix)]
+    tokio::spawn(forward_check_signals(check_requests_tx));
+    let rslt = tokio::select! {
+        rslt = supervisor.run() => rslt,
+        () = stop_requested() => {
+            info!("Stopping");
+            systemd::notify("STOPPING=1");
+            stop_workers(supervisor, &stop_tx, &shared.state_updates).await;
+            if cfg.notify_lifecycle {
+                shared
+                    .notifier
+                    .notify_and_wait(lifecycle_notification(Event::Stopped), STOP_NOTIFY_TIMEOUT)
+                    .await;
             }
-            namecheap_addr = Some(current_addr);
+            info!("Stopped");
+            process::exit(0);
+        }
+        () = reload_requested(config_paths, reload_refusal) => {
+            // Make sure the new process starts from the latest state. It reports being ready again
+            // after its first checks.
+            systemd::notify("RELOADING=1");
+            stop_workers(supervisor, &stop_tx, &shared.state_updates).await;
+            info!("Restarting with the new config");
+            let err = reexec();
+            error!(err = format!("{err:#}"), "Couldn't restart with the new config, exiting");
+            process::exit(1);
         }
+    };
+    if let Err(err) = rslt {
+        error!(err = format!("{err:#}"), "Supervisor failed, exiting");
+        process::exit(1);
+    }
+}
 
-        // Update state on disk if it differs.
-        if Some(current_addr) != state.addr {
-            let new_state = State {
-                addr: Some(current_addr),
-            };
-            if let Err(err) = update_state(&args.state, &new_state).await {
-                error!(%err, "Couldn't write state file");
+/// Stops the workers, letting their in-flight checks finish (for up to `STOP_TIMEOUT`), then waits
+/// for the state writer to write everything they reported.
+async fn stop_workers(
+    supervisor: Supervisor,
+    stop: &watch::Sender<bool>,
+    state_updates: &mpsc::Sender<(StateUpdate, Span)>,
+) {
+    stop.send_replace(true);
+    supervisor.stop(STOP_TIMEOUT).await;
+    let (flushed, flushed_rx) = oneshot::channel();
+    if state_updates
+        .send((StateUpdate::Flush(flushed), Span::current()))
+        .await
+        .is_ok()
+    {
+        let _ = flushed_rx.await;
+    }
+}
+
+/// Returns why the config can't be reloaded (by re-executing rnccd), if it can't be.
+fn reload_refusal(args: &Args, state_in_memory: bool) -> Option<&'static str> {
+    if args.sandbox {
+        Some("--sandbox forbids running commands")
+    } else if args.user.is_some() || args.group.is_some() {
+        Some("privileges were dropped (--user/--group), so the config may no longer be readable")
+    } else if state_in_memory {
+        Some("the state is kept in memory only (--state none), so would be lost")
+    } else {
+        None
+    }
+}
+
+/// Waits for a request to reload the config (SIGHUP, on Unix) with a valid config: an invalid one
+/// is logged & ignored. If the config can't be reloaded (`refusal` says why), requests are logged
+/// & ignored too.
+async fn reload_requested(config_paths: &[OsString], refusal: Option<&str>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sighup = signal(SignalKind::hangup()).expect("Couldn't listen for SIGHUP");
+        loop {
+            sighup.recv().await;
+            if let Some(refusal) = refusal {
+                error!("Can't reload config, as {refusal}; restart rnccd instead");
                 continue;
             }
-            state = new_state;
+            info!("Reloading config");
+            match try_load_config(config_paths) {
+                Ok(_) => return,
+                Err(err) => error!(
+                    err = format!("{err:#}"),
+                    "Couldn't load new config, keeping the current one"
+                ),
+            }
         }
     }
+    #[cfg(not(unix))]
+    {
+        let _ = (config_paths, refusal);
+        std::future::pending().await
+    }
 }
 
-async fn current_address(client: &reqwest::Client) -> Result<Ipv4Addr> {
-    let resp = client.get("https://api.ipify.org").send().await?;
-    if resp.status() != StatusCode::OK {
-        return Err(anyhow!("unexpected status code: {}", resp.status()));
+/// Replaces the process with a fresh rnccd, with the same arguments (less any failure injections,
+/// which were for this process), which then loads the (new) config & the state from disk. Returns
+/// only on failure.
+fn reexec() -> anyhow::Error {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let mut args = Vec::new();
+        let mut all_args = std::env::args_os().skip(1);
+        while let Some(arg) = all_args.next() {
+            match arg.to_str() {
+                Some("--inject-failure") => {
+                    all_args.next();
+                }
+                Some(arg) if arg.starts_with("--inject-failure=") => (),
+                _ => args.push(arg),
+            }
+        }
+        match std::env::current_exe() {
+            Ok(exe) => process::Command::new(exe).args(args).exec().into(),
+            Err(err) => anyhow::Error::new(err).context("couldn't find our executable"),
+        }
     }
-    Ok(resp.text().await?.parse()?)
+    #[cfg(not(unix))]
+    anyhow::anyhow!("restarting is not supported on this platform")
 }
 
-async fn update_address(client: &reqwest::Client, cfg: &Config, addr: Ipv4Addr) -> Result<()> {
-    let resp = client
-        .get("https://dynamicdns.park-your-domain.com/update")
-        .query(&[
-            ("host", cfg.host.as_deref().unwrap_or("@")),
-            ("domain", &cfg.domain),
-            ("password", &cfg.password),
-            ("ip", &addr.to_string()),
-        ])
-        .send()
-        .await?;
+/// Makes workers check now each time SIGUSR1 is received, e.g. after records were changed by hand.
+#[cfg(unix)]
+async fn forward_check_signals(check_requests: Arc<watch::Sender<()>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr1 = signal(SignalKind::user_defined1()).expect("Couldn't listen for SIGUSR1");
+    while sigusr1.recv().await.is_some() {
+        info!("Got SIGUSR1, checking now");
+        check_requests.send_replace(());
+    }
+}
 
-    // This API always returns 200 OK, and communicates errors via an unschema'ed XML document in
-    // the body. I don't want to depend on an entire XML parser, so look for an error count of 0 to
-    // communicate success.
-    let body = resp.text().await?;
-    if body.contains("<ErrCount>0</ErrCount>") {
-        return Ok(());
+/// Waits for a request to stop: SIGTERM (on Unix), or Ctrl-C.
+async fn stop_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
     }
-    Err(anyhow!("update request got error: {}", body))
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-async fn update_state(state_path: &OsStr, state: &State) -> Result<()> {
-    let state_path = Path::new(state_path);
-    let dir = state_path.parent().ok_or_else(|| {
-        anyhow!(
-            "couldn't determine parent directory of {}",
-            state_path.display()
-        )
-    })?;
-    let temp_file = NamedTempFile::new_in(dir)?;
-    serde_yaml::to_writer(&temp_file, state)?;
-    temp_file.persist(state_path)?;
-    Ok(())
+/// Logs a summary of the effective config, without secrets, so that logs attached to bug reports
+/// show what rnccd was doing.
+fn log_summary(
+    cfg: &Config,
+    providers: &[Arc<dyn Provider>],
+    detectors: &Detectors,
+    uplinks: Option<&Uplinks>,
+    detector6: Option<&Detector>,
+    state_path: Option<&OsStr>,
+) {
+    let providers: Vec<_> = providers.iter().map(|provider| provider.id()).collect();
+    // Uplinks replace the detectors.
+    let detectors: Vec<_> = match uplinks {
+        Some(uplinks) => uplinks.detectors().collect(),
+        None => detectors.detectors().collect(),
+    };
+    let detectors: Vec<_> = detectors
+        .into_iter()
+        .chain(detector6)
+        .map(|detector| detector.url())
+        .collect();
+    let labels: Vec<_> = cfg.labels.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        domain = cfg.provider.domain(),
+        host = cfg.provider.host(),
+        providers = providers.join(","),
+        detectors = detectors.join(","),
+        uplinks = cfg
+            .uplinks
+            .iter()
+            .map(|uplink| uplink.interface.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+        check_interval_secs = cfg.check_interval().as_secs(),
+        state = %state_path.map_or("none".into(), |path| Path::new(path).display().to_string()),
+        labels = labels.join(","),
+        webhooks = cfg.webhooks.len(),
+        metrics_sinks = cfg.metrics.len(),
+        proxy = cfg.proxy.is_some(),
+        "Effective config"
+    );
+}
+
+/// Checks that IPv4 is usable if it's needed, i.e. if any records are A records, or IPv4 is the
+/// only family requests may be made over. On an IPv6-only host, IPv4 addresses can only be reached
+/// (& our IPv4 address detected) via NAT64, with the translator's address as ours.
+async fn check_ipv4(cfg: &Config, providers: &[Arc<dyn Provider>]) {
+    let ipv4_only = matches!(cfg.address_family, Some(AddressFamily::Ipv4Only));
+    let a_records = providers
+        .iter()
+        .any(|provider| provider.record_type() == RecordType::A);
+    if !(ipv4_only || a_records) || nat64::has_ipv4_route() {
+        return;
+    }
+    if ipv4_only {
+        warn!("address_family is ipv4-only, but this host is IPv6-only (it has no IPv4 route)");
+        return;
+    }
+    match nat64::prefix().await {
+        Some(prefix) => info!(
+            %prefix,
+            "This host is IPv6-only, reaching IPv4 via NAT64: A records will be given the NAT64 \
+             translator's address"
+        ),
+        None => warn!(
+            "This host is IPv6-only (it has no IPv4 route) & its network has no NAT64, so our \
+             IPv4 address can't be detected for A records; set record_types to aaaa if this \
+             persists"
+        ),
+    }
+}
+
+/// The settings HTTP clients are built with, kept apart from the config so that clients can also
+/// be built later, e.g. bound to an uplink's current address.
+#[derive(Clone)]
+struct HttpSettings {
+    proxy: Option<String>,
+    ignore_proxy_env: bool,
+    address_family: Option<AddressFamily>,
+    allowlist: Option<Arc<egress::Allowlist>>,
+}
+
+impl HttpSettings {
+    fn new(cfg: &Config) -> Self {
+        let mut settings = Self {
+            proxy: cfg.proxy.clone(),
+            ignore_proxy_env: cfg.ignore_proxy_env,
+            address_family: cfg.address_family,
+            allowlist: None,
+        };
+        if cfg.restrict_egress {
+            // Requests through a proxy are resolved by the proxy, so can't be checked against the
+            // allowlist.
+            if cfg.proxy.is_some() {
+                panic!("restrict_egress can't be used with a proxy");
+            }
+            settings.allowlist = Some(Arc::new(egress::Allowlist::new(cfg, &settings)));
+        }
+        settings
+    }
+
+    /// Builds a client, which makes requests from `local_addr` if given.
+    fn client(&self, local_addr: Option<IpAddr>) -> reqwest::Client {
+        self.builder(local_addr)
+            .build()
+            .expect("Couldn't create HTTP client")
+    }
+
+    /// Returns a builder for a client, as for [`HttpSettings::client`], for callers which need
+    /// more (e.g. their own certificates).
+    fn builder(&self, local_addr: Option<IpAddr>) -> reqwest::ClientBuilder {
+        let client = reqwest::Client::builder();
+        #[cfg(feature = "native-tls")]
+        let client = client.use_native_tls();
+        #[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+        let client = client.use_rustls_tls();
+        let allowlist = &self.allowlist;
+        let client = match (&self.proxy, self.ignore_proxy_env || allowlist.is_some()) {
+            (Some(proxy), ignore_env) => {
+                let proxy = Proxy::all(proxy).expect("Couldn't parse proxy URL");
+                client.proxy(if ignore_env {
+                    proxy
+                } else {
+                    proxy.no_proxy(NoProxy::from_env())
+                })
+            }
+            (None, true) => client.no_proxy(),
+            (None, false) => client, // reqwest uses the environment's proxy settings by default.
+        };
+        let client = match (self.address_family, allowlist) {
+            (None, None) => client,
+            (family, allowlist) => {
+                client.dns_resolver(Arc::new(resolver::Resolver::new(family, allowlist.clone())))
+            }
+        };
+        let client = match allowlist {
+            Some(allowlist) => client.redirect(Arc::clone(allowlist).redirect_policy()),
+            None => client,
+        };
+        client
+            .local_address(local_addr)
+            .default_headers(HeaderMap::from_iter([(
+                USER_AGENT,
+                HeaderValue::from_str(&format!("rnccd {}", env!("CARGO_PKG_VERSION")))
+                    .expect("Couldn't create default HTTP headers"),
+            )]))
+            .timeout(Duration::from_secs(30))
+    }
+}
+
+/// Creates the detectors used to find our IP address, with their trust as of the last run.
+fn detectors(cfg: &Config, state: &State) -> Detectors {
+    let detectors = Detectors::new(
+        &cfg.detectors(),
+        cfg.detection,
+        cfg.detection_quorum,
+        state.detector_trust.clone(),
+    )
+    .expect("Couldn't create detectors");
+    detectors
+        .detectors()
+        .for_each(|detector| ensure_local(cfg, detector));
+    detectors
+}
+
+/// Creates the uplinks our IPv4 address is detected over.
+fn uplinks(cfg: &Config, http: HttpSettings) -> Uplinks {
+    let uplinks =
+        Uplinks::new(&cfg.uplinks, cfg.uplink_policy, http).expect("Couldn't create uplinks");
+    uplinks
+        .detectors()
+        .for_each(|detector| ensure_local(cfg, detector));
+    uplinks
+}
+
+/// Creates the detector used to find our IPv6 address.
+fn detector6(cfg: &Config) -> Detector {
+    let detector = Detector::new6(cfg.detector6.as_ref()).expect("Couldn't create IPv6 detector");
+    ensure_local(cfg, &detector);
+    detector
+}
+
+/// Exits if the detector isn't local, but no_external_detection is set.
+fn ensure_local(cfg: &Config, detector: &Detector) {
+    if cfg.no_external_detection && !detector.is_local() {
+        panic!(
+            "no_external_detection is set, but detector {} isn't local",
+            detector.url()
+        );
+    }
+}
+
+/// A host entry, which gets workers of its own.
+struct HostEntry {
+    cfg: ProviderConfig,
+    /// The config's labels, & the entry's own.
+    labels: BTreeMap<String, String>,
+    /// The entry's providers: its own, & those of the backup providers for its record.
+    providers: Vec<Arc<dyn Provider>>,
+}
+
+/// Returns the host entries: the primary provider's, then those of any backup providers whose
+/// records none of the primary provider's have. (Backup providers of the same record as a primary
+/// one join its entry.)
+fn host_entries(cfg: &Config, http: &HttpSettings) -> Vec<HostEntry> {
+    let mut entries: Vec<HostEntry> = Vec::new();
+    for (i, provider_cfg) in iter::once(&cfg.provider)
+        .chain(&cfg.backup_providers)
+        .enumerate()
+    {
+        for (entry, labels) in provider_cfg.per_host() {
+            let mut providers = provider::new(&entry, http).expect("Couldn't create providers");
+            if i > 0 {
+                providers.retain(|backup| {
+                    let same_record = |entry: &&mut HostEntry| {
+                        entry
+                            .providers
+                            .iter()
+                            .any(|provider| provider.record() == backup.record())
+                    };
+                    match entries.iter_mut().find(same_record) {
+                        Some(entry) => {
+                            entry.providers.push(Arc::clone(backup));
+                            false
+                        }
+                        None => true,
+                    }
+                });
+                if providers.is_empty() {
+                    continue;
+                }
+            }
+            let mut all_labels = cfg.labels.clone();
+            all_labels.extend(labels);
+            entries.push(HostEntry {
+                cfg: entry,
+                labels: all_labels,
+                providers,
+            });
+        }
+    }
+    entries
+}
+
+/// Creates the providers to update: the primary provider, then any backup providers, each split
+/// into a provider per record.
+fn providers(cfg: &Config, http: &HttpSettings) -> Vec<Arc<dyn Provider>> {
+    iter::once(&cfg.provider)
+        .chain(&cfg.backup_providers)
+        .flat_map(ProviderConfig::per_record)
+        .flat_map(|cfg| provider::new(&cfg, http).expect("Couldn't create providers"))
+        .collect()
 }
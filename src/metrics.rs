@@ -0,0 +1,291 @@
+//! Metrics: workers record measurements of detections & updates, which are sent to the metrics
+//! task & written to each configured sink.
+
+// Without any sink features, measurements are built but never read.
+#![cfg_attr(
+    not(any(feature = "influxdb", feature = "graphite")),
+    allow(dead_code, unused_variables)
+)]
+
+use crate::config::MetricsConfig;
+use anyhow::Result;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// How many measurements may be queued for the metrics task before new ones are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// A single measurement, e.g. of one detection or one update.
+pub struct Measurement {
+    pub name: &'static str,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(&'static str, Value)>,
+    pub time: SystemTime,
+}
+
+/// The value of a field of a measurement.
+#[derive(Clone, Copy)]
+pub enum Value {
+    Bool(bool),
+    Float(f64),
+}
+
+impl Measurement {
+    pub fn new(
+        name: &'static str,
+        tags: Vec<(String, String)>,
+        fields: Vec<(&'static str, Value)>,
+    ) -> Self {
+        Self {
+            name,
+            tags,
+            fields,
+            time: SystemTime::now(),
+        }
+    }
+
+    /// A measurement of an operation (e.g. `detection`) on a record, which took `duration` & did
+    /// or didn't succeed.
+    pub fn operation(
+        name: &'static str,
+        tags: Vec<(String, String)>,
+        success: bool,
+        duration: Duration,
+    ) -> Self {
+        Self::new(
+            name,
+            tags,
+            vec![
+                ("success", Value::Bool(success)),
+                ("duration_seconds", Value::Float(duration.as_secs_f64())),
+            ],
+        )
+    }
+}
+
+/// Metrics is a handle used to record measurements. Recording never blocks: if the metrics task
+/// falls behind, measurements are dropped.
+#[derive(Clone)]
+pub struct Metrics {
+    tx: Option<mpsc::Sender<Measurement>>,
+}
+
+impl Metrics {
+    pub fn record(&self, measurement: Measurement) {
+        if let Some(tx) = &self.tx {
+            if tx.try_send(measurement).is_err() {
+                warn!("Metrics queue is full, dropping measurement");
+            }
+        }
+    }
+}
+
+/// Starts the metrics task, writing to the configured sinks. If there are none, measurements are
+/// discarded without starting a task.
+pub fn spawn(client: reqwest::Client, cfgs: &[MetricsConfig]) -> Metrics {
+    let sinks: Vec<_> = cfgs.iter().map(|cfg| Sink::new(cfg, &client)).collect();
+    if sinks.is_empty() {
+        return Metrics { tx: None };
+    }
+    let (tx, mut rx) = mpsc::channel(QUEUE_LEN);
+    tokio::spawn(async move {
+        while let Some(measurement) = rx.recv().await {
+            for sink in &sinks {
+                if let Err(err) = sink.write(&measurement).await {
+                    warn!(
+                        sink = sink.name(),
+                        err = format!("{err:#}"),
+                        "Couldn't write metrics"
+                    );
+                }
+            }
+        }
+    });
+    Metrics { tx: Some(tx) }
+}
+
+enum Sink {
+    #[cfg(feature = "influxdb")]
+    InfluxDb(influxdb::Sink),
+    #[cfg(feature = "graphite")]
+    Graphite(graphite::Sink),
+}
+
+impl Sink {
+    #[cfg_attr(not(feature = "influxdb"), allow(unused_variables))]
+    fn new(cfg: &MetricsConfig, client: &reqwest::Client) -> Self {
+        match *cfg {
+            #[cfg(feature = "influxdb")]
+            MetricsConfig::InfluxDb(ref cfg) => {
+                Sink::InfluxDb(influxdb::Sink::new(cfg.clone(), client.clone()))
+            }
+            #[cfg(feature = "graphite")]
+            MetricsConfig::Graphite(ref cfg) => Sink::Graphite(graphite::Sink::new(cfg.clone())),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "influxdb")]
+            Sink::InfluxDb(_) => "influxdb",
+            #[cfg(feature = "graphite")]
+            Sink::Graphite(_) => "graphite",
+        }
+    }
+
+    async fn write(&self, measurement: &Measurement) -> Result<()> {
+        match *self {
+            #[cfg(feature = "influxdb")]
+            Sink::InfluxDb(ref sink) => sink.write(measurement).await,
+            #[cfg(feature = "graphite")]
+            Sink::Graphite(ref sink) => sink.write(measurement).await,
+        }
+    }
+}
+
+#[cfg(feature = "influxdb")]
+mod influxdb {
+    use super::{Measurement, Value};
+    use crate::config::InfluxDbConfig;
+    use anyhow::{anyhow, Context, Result};
+    use reqwest::header::AUTHORIZATION;
+    use std::{fmt::Write, time::SystemTime};
+    use tokio::net::{self, UdpSocket};
+
+    /// Sink writes measurements in InfluxDB line protocol, either by HTTP POST (e.g. to
+    /// `http://influx:8086/api/v2/write?org=home&bucket=rnccd`), or as UDP datagrams (e.g. to
+    /// `udp://influx:8089`).
+    pub struct Sink {
+        cfg: InfluxDbConfig,
+        client: reqwest::Client,
+    }
+
+    impl Sink {
+        pub fn new(cfg: InfluxDbConfig, client: reqwest::Client) -> Self {
+            Self { cfg, client }
+        }
+
+        pub async fn write(&self, measurement: &Measurement) -> Result<()> {
+            let line = line(measurement)?;
+            match self.cfg.url.strip_prefix("udp://") {
+                Some(addr) => {
+                    let addr = net::lookup_host(addr)
+                        .await?
+                        .next()
+                        .ok_or_else(|| anyhow!("{addr} has no addresses"))?;
+                    let bind_addr = if addr.is_ipv4() {
+                        "0.0.0.0:0"
+                    } else {
+                        "[::]:0"
+                    };
+                    let socket = UdpSocket::bind(bind_addr).await?;
+                    socket.send_to(line.as_bytes(), addr).await?;
+                }
+                None => {
+                    let mut req = self.client.post(&self.cfg.url).body(line);
+                    if let Some(token) = &self.cfg.token {
+                        req = req.header(AUTHORIZATION, format!("Token {}", token.value()));
+                    }
+                    req.send()
+                        .await?
+                        .error_for_status()
+                        .context("InfluxDB rejected write")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Formats a measurement as a line of line protocol, with a nanosecond timestamp.
+    fn line(measurement: &Measurement) -> Result<String> {
+        let mut line = format!("rnccd_{}", escape(measurement.name, ", "));
+        for (key, value) in &measurement.tags {
+            write!(line, ",{}={}", escape(key, ",= "), escape(value, ",= "))?;
+        }
+        for (i, (key, value)) in measurement.fields.iter().enumerate() {
+            let sep = if i == 0 { ' ' } else { ',' };
+            write!(line, "{sep}{}=", escape(key, ",= "))?;
+            match value {
+                Value::Bool(value) => write!(line, "{value}")?,
+                Value::Float(value) => write!(line, "{value}")?,
+            }
+        }
+        let timestamp = measurement.time.duration_since(SystemTime::UNIX_EPOCH)?;
+        writeln!(line, " {}", timestamp.as_nanos())?;
+        Ok(line)
+    }
+
+    /// Backslash-escapes the given characters (and backslashes) in a name, tag, or field key.
+    fn escape(s: &str, chars: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if c == '\\' || chars.contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+}
+
+#[cfg(feature = "graphite")]
+mod graphite {
+    use super::{Measurement, Value};
+    use crate::config::GraphiteConfig;
+    use anyhow::Result;
+    use std::{fmt::Write, time::SystemTime};
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    /// Sink writes measurements to Graphite in the plaintext protocol, over TCP. Each field of a
+    /// measurement is written as its own metric, with a path built from the prefix, the
+    /// measurement name, & the tag values, e.g. `rnccd.detection.www_example_com.success`.
+    pub struct Sink {
+        cfg: GraphiteConfig,
+    }
+
+    impl Sink {
+        pub fn new(cfg: GraphiteConfig) -> Self {
+            Self { cfg }
+        }
+
+        pub async fn write(&self, measurement: &Measurement) -> Result<()> {
+            let lines = lines(self.cfg.prefix(), measurement)?;
+            let mut stream = TcpStream::connect(&self.cfg.address).await?;
+            stream.write_all(lines.as_bytes()).await?;
+            stream.shutdown().await?;
+            Ok(())
+        }
+    }
+
+    /// Formats a measurement as lines of the plaintext protocol, with a seconds timestamp.
+    fn lines(prefix: &str, measurement: &Measurement) -> Result<String> {
+        let mut path = format!("{prefix}.{}", sanitize(measurement.name));
+        for (_, value) in &measurement.tags {
+            write!(path, ".{}", sanitize(value))?;
+        }
+        let timestamp = measurement
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let mut lines = String::new();
+        for (key, value) in &measurement.fields {
+            let value = match *value {
+                Value::Bool(value) => f64::from(u8::from(value)),
+                Value::Float(value) => value,
+            };
+            writeln!(lines, "{path}.{} {value} {timestamp}", sanitize(key))?;
+        }
+        Ok(lines)
+    }
+
+    /// Replaces characters which have meaning in a metric path (e.g. the dots of a record name).
+    fn sanitize(s: &str) -> String {
+        s.chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+}
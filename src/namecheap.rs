@@ -0,0 +1,850 @@
+use crate::{
+    backoff::Permanent,
+    codes::{Code, Coded},
+    config::{CallConfig, NamecheapAuth, NamecheapConfig},
+    lockout::Lockout,
+    provider::{self, CheckFuture, Provider, ReconcileFuture, RecordType, UpdateFuture},
+    xml,
+};
+use anyhow::{anyhow, Context, Result};
+use reqwest::StatusCode;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+};
+use tracing::{info, warn};
+
+pub use api::Host;
+
+const UPDATE_URL: &str = "https://dynamicdns.park-your-domain.com/update";
+
+/// Phrases of error messages meaning that Namecheap has locked out the account (e.g. for abuse, or
+/// making too many requests), rather than rejected the request. Namecheap doesn't document
+/// dedicated error numbers for these.
+const LOCKOUT_PHRASES: &[&str] = &[
+    "too many requests",
+    "too many attempts",
+    "too many failed",
+    "temporarily blocked",
+    "temporarily locked",
+    "account is locked",
+    "abuse",
+];
+
+/// Namecheap updates a record via Namecheap's dynamic DNS API, or its XML API.
+pub struct Namecheap {
+    id: String,
+    account: String,
+    record: String,
+    record_type: RecordType,
+    cfg: NamecheapConfig,
+}
+
+impl Namecheap {
+    pub fn new(cfg: NamecheapConfig, record_type: RecordType) -> Self {
+        let record = provider::record_name(&cfg.domain, cfg.host());
+        let id = match cfg.auth {
+            NamecheapAuth::Api { ref api } if api.sandbox => format!("namecheap-sandbox:{record}"),
+            _ => format!("namecheap:{record}"),
+        };
+        // Dynamic DNS passwords are per-domain; API credentials are per-user.
+        let account = match cfg.auth {
+            NamecheapAuth::Password { .. } => format!("namecheap:{}", cfg.domain),
+            NamecheapAuth::Api { ref api } if api.sandbox => {
+                format!("namecheap-sandbox-api:{}", api.user)
+            }
+            NamecheapAuth::Api { ref api } => format!("namecheap-api:{}", api.user),
+        };
+        // IDs of A records predate AAAA support, so are left unqualified.
+        let id = match record_type {
+            RecordType::A => id,
+            RecordType::Aaaa => format!("{id}/AAAA"),
+        };
+        if matches!(cfg.auth, NamecheapAuth::Password { .. }) && cfg.ttl_secs.is_some() {
+            warn!(
+                record,
+                "Ignoring ttl_secs: dynamic DNS updates can't set the TTL"
+            );
+        }
+        if matches!(cfg.auth, NamecheapAuth::Password { .. }) && cfg.verify_hosts {
+            warn!(
+                record,
+                "Ignoring verify_hosts: dynamic DNS updates don't write host records"
+            );
+        }
+        if matches!(cfg.auth, NamecheapAuth::Password { .. }) && !cfg.srv_records.is_empty() {
+            warn!(
+                record,
+                "Ignoring srv_records: dynamic DNS updates can't set SRV records"
+            );
+        }
+        match (record_type, &cfg.auth) {
+            (RecordType::A, _) => (),
+            (RecordType::Aaaa, NamecheapAuth::Password { .. }) => warn!(
+                record,
+                "Can't update AAAA record: dynamic DNS updates only support A records, use the \
+                 XML API instead"
+            ),
+            (RecordType::Aaaa, NamecheapAuth::Api { api }) if api.client_ip.is_none() => warn!(
+                record,
+                "Can't update AAAA record: api.client_ip must be set, as Namecheap only accepts \
+                 IPv4 client addresses"
+            ),
+            (RecordType::Aaaa, NamecheapAuth::Api { .. }) => (),
+        }
+        Self {
+            id,
+            account,
+            record,
+            record_type,
+            cfg,
+        }
+    }
+}
+
+impl Provider for Namecheap {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    fn endpoint(&self) -> &str {
+        match self.cfg.auth {
+            NamecheapAuth::Password { .. } => UPDATE_URL,
+            NamecheapAuth::Api { ref api } => api::url(api),
+        }
+    }
+
+    fn record(&self) -> &str {
+        &self.record
+    }
+
+    fn calls(&self) -> &CallConfig {
+        &self.cfg.calls
+    }
+
+    fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    fn update<'a>(&'a self, client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a> {
+        match self.cfg.auth {
+            NamecheapAuth::Password { ref password } => Box::pin(async move {
+                let IpAddr::V4(addr) = addr else {
+                    return Err(Permanent {
+                        code: Code::ProviderUnsupported,
+                        message: "dynamic DNS updates only support A records".to_string(),
+                    }
+                    .into());
+                };
+                update_address(client, &self.cfg, password.value(), addr).await
+            }),
+            NamecheapAuth::Api { ref api } => Box::pin(async move {
+                api::update(client, &self.cfg, api, addr).await?;
+                Ok(())
+            }),
+        }
+    }
+
+    fn check_credentials<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        current_addr: Option<IpAddr>,
+    ) -> CheckFuture<'a> {
+        match self.cfg.auth {
+            NamecheapAuth::Password { .. } => provider::check_by_update(self, client, current_addr),
+            // The API can be checked read-only, by listing the domain's records.
+            NamecheapAuth::Api { ref api } => Box::pin(async move {
+                let client_ip = match (api.client_ip, current_addr) {
+                    (Some(client_ip), _) => client_ip,
+                    (None, Some(addr)) => api::client_ip(api, addr)?,
+                    (None, None) => {
+                        return Err(anyhow!(
+                            "api.client_ip must be set to check credentials, as {}'s current \
+                             address isn't known",
+                            self.cfg.domain
+                        ))
+                    }
+                };
+                let hosts = api::get_hosts(client, &self.cfg, api, client_ip).await?;
+                Ok(format!(
+                    "listed {} host records of {}",
+                    hosts.hosts.len(),
+                    self.cfg.domain
+                ))
+            }),
+        }
+    }
+
+    fn reconcile<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        addr: IpAddr,
+    ) -> Option<ReconcileFuture<'a>> {
+        match self.cfg.auth {
+            // Updating rewrites the records only if they differ from what's configured.
+            NamecheapAuth::Api { ref api }
+                if self.cfg.ttl_secs.is_some() || !self.cfg.srv_records.is_empty() =>
+            {
+                Some(Box::pin(api::update(client, &self.cfg, api, addr)))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_sandbox(&self) -> bool {
+        matches!(self.cfg.auth, NamecheapAuth::Api { ref api } if api.sandbox)
+    }
+}
+
+async fn update_address(
+    client: &reqwest::Client,
+    cfg: &NamecheapConfig,
+    password: &str,
+    addr: Ipv4Addr,
+) -> Result<()> {
+    let addr_param = addr.to_string();
+    let mut params = BTreeMap::from([
+        ("host", Some(cfg.host())),
+        ("domain", Some(cfg.domain.as_str())),
+        ("password", Some(password)),
+        ("ip", Some(addr_param.as_str())),
+    ]);
+    // Extra parameters replace (or, if null, remove) standard ones of the same name.
+    for (name, value) in &cfg.extra_params {
+        params.insert(name, value.as_deref());
+    }
+    let params: Vec<_> = params
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect();
+    // Errors are stripped of the request URL, since it holds the password.
+    let resp = client
+        .get(UPDATE_URL)
+        .timeout(cfg.calls.timeout())
+        .query(&params)
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)?;
+    if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Lockout {
+            message: format!("update request got {}", resp.status()),
+        }
+        .into());
+    }
+
+    // This API always returns 200 OK, and communicates errors via an unschema'ed XML document in
+    // the body.
+    let body = resp.text().await.map_err(reqwest::Error::without_url)?;
+    let resp = InterfaceResponse::parse(&body)
+        .with_context(|| format!("update request got unexpected response: {body}"))?;
+    if resp.err_count > 0 || !resp.errors.is_empty() {
+        return Err(UpdateError::new(&resp).into());
+    }
+    if !resp.done {
+        return Err(anyhow!("update request wasn't done: {body}"));
+    }
+
+    // The response also echoes the IP that was applied, which can differ from the one we sent if
+    // the account overrides it, or a proxy rewrote the request.
+    match resp.ip {
+        Some(applied_addr) if applied_addr.parse() == Ok(addr) => {
+            info!(
+                host = cfg.host(),
+                domain = cfg.domain,
+                %applied_addr,
+                "Namecheap applied IP"
+            );
+        }
+        Some(applied_addr) => {
+            return Err(anyhow!(
+                "Namecheap applied IP {applied_addr}, rather than the requested {addr}"
+            ));
+        }
+        None => warn!(
+            host = cfg.host(),
+            domain = cfg.domain,
+            "Namecheap didn't say which IP it applied"
+        ),
+    }
+    Ok(())
+}
+
+/// A response from the dynamic DNS API: an `<interface-response>` document.
+struct InterfaceResponse {
+    err_count: u32,
+    errors: Vec<String>, // the text of each <ErrN> element, in order.
+    response_number: Option<String>,
+    response_string: Option<String>,
+    ip: Option<String>,
+    done: bool,
+}
+
+impl InterfaceResponse {
+    fn parse(body: &str) -> Result<Self> {
+        let doc = xml::parse(body)?;
+        if doc.name != "interface-response" {
+            return Err(anyhow!("expected <interface-response>, got <{}>", doc.name));
+        }
+        let err_count = doc
+            .child_text("ErrCount")
+            .ok_or_else(|| anyhow!("response has no ErrCount"))?;
+        let err_count = err_count
+            .parse()
+            .with_context(|| format!("couldn't parse ErrCount {err_count:?}"))?;
+        let errors = doc.child("errors").map_or_else(Vec::new, |errors| {
+            errors
+                .children
+                .iter()
+                .filter(|err| err.name.starts_with("Err"))
+                .map(|err| err.text().to_string())
+                .collect()
+        });
+        let response = doc
+            .child("responses")
+            .and_then(|responses| responses.child("response"));
+        let response_text = |name| Some(response?.child_text(name)?.to_string());
+        Ok(Self {
+            err_count,
+            errors,
+            response_number: response_text("ResponseNumber"),
+            response_string: response_text("ResponseString"),
+            ip: doc.child_text("IP").map(str::to_string),
+            done: doc
+                .child_text("Done")
+                .is_some_and(|done| done.eq_ignore_ascii_case("true")),
+        })
+    }
+}
+
+/// Lists the domain's host records, via the XML API. `client_ip` is the address we're making
+/// requests from.
+pub async fn list_hosts(
+    client: &reqwest::Client,
+    cfg: &NamecheapConfig,
+    client_ip: Ipv4Addr,
+) -> Result<Vec<Host>> {
+    let NamecheapAuth::Api { ref api } = cfg.auth else {
+        return Err(anyhow!("listing records needs XML API credentials"));
+    };
+    Ok(api::get_hosts(client, cfg, api, client_ip).await?.hosts)
+}
+
+/// An error response from the dynamic DNS API, classified by its first error & the number of its
+/// first response, which identifies the error more reliably.
+#[derive(Debug)]
+enum UpdateError {
+    /// The password is wrong, or isn't the domain's dynamic DNS password.
+    BadPassword(String),
+    /// The domain isn't in the account, or dynamic DNS isn't enabled for it.
+    DomainNotFound(String),
+    /// The host has no record to update.
+    HostNotFound(String),
+    /// Namecheap has locked out the account.
+    LockedOut(String),
+    /// Any other error, or a response which couldn't be understood.
+    Other(String),
+}
+
+impl UpdateError {
+    fn new(resp: &InterfaceResponse) -> Self {
+        let Some(err) = resp.errors.first() else {
+            return Self::Other(format!(
+                "update request got {} errors, but didn't say what they were",
+                resp.err_count
+            ));
+        };
+        let number = resp.response_number.as_deref();
+        let message = match (number, &resp.response_string) {
+            (Some(number), Some(string)) => {
+                format!("update request got error: {err} (response {number}: {string})")
+            }
+            (Some(number), None) => format!("update request got error: {err} (response {number})"),
+            (None, _) => format!("update request got error: {err}"),
+        };
+        let lower = err.to_lowercase();
+        match number {
+            _ if is_lockout(err) => Self::LockedOut(message),
+            Some("304156") => Self::BadPassword(message),
+            Some("316153") => Self::DomainNotFound(message),
+            Some("380091") => Self::HostNotFound(message),
+            _ if lower.contains("password") => Self::BadPassword(message),
+            _ if lower.contains("domain") && lower.contains("not") => Self::DomainNotFound(message),
+            _ if lower.contains("not found") => Self::HostNotFound(message),
+            _ => Self::Other(message),
+        }
+    }
+}
+
+impl From<UpdateError> for anyhow::Error {
+    /// Converts the error, such that retrying is given up on for errors which won't go away
+    /// until the config is fixed.
+    fn from(err: UpdateError) -> Self {
+        match err {
+            UpdateError::BadPassword(message) => error(Code::NcBadPassword, message),
+            UpdateError::DomainNotFound(message) | UpdateError::HostNotFound(message) => {
+                error(Code::NcNotFound, message)
+            }
+            UpdateError::LockedOut(message) => Lockout { message }.into(),
+            UpdateError::Other(message) => error(Code::NcError, message),
+        }
+    }
+}
+
+/// Returns an error with the given code, which is permanent if the code means that Namecheap
+/// won't accept the request until the config (or the account) is fixed.
+fn error(code: Code, message: String) -> anyhow::Error {
+    match code {
+        Code::NcBadPassword | Code::NcBadApiKey | Code::NcIpNotWhitelisted | Code::NcNotFound => {
+            Permanent { code, message }.into()
+        }
+        _ => Coded { code, message }.into(),
+    }
+}
+
+/// Whether the given error message means that the account is locked out.
+fn is_lockout(message: &str) -> bool {
+    let message = message.to_lowercase();
+    LOCKOUT_PHRASES
+        .iter()
+        .any(|phrase| message.contains(phrase))
+}
+
+/// Returns the code of an XML API error number, if it has a more specific code than `NC_ERROR`.
+fn error_number_code(number: &str) -> Option<Code> {
+    match number {
+        "1010101" | "1010102" | "1011102" | "2011166" => Some(Code::NcBadApiKey),
+        "1011150" => Some(Code::NcIpNotWhitelisted),
+        "2016166" | "2019166" => Some(Code::NcNotFound),
+        _ => None,
+    }
+}
+
+/// Updates via the XML API, which has no call to update a single record: instead, the domain's
+/// host records are read, & written back with the record's address replaced.
+mod api {
+    use crate::{
+        codes::{Code, Coded},
+        config::{NamecheapApiConfig, NamecheapConfig, SrvRecordConfig},
+        lockout::Lockout,
+        provider,
+        xml::{self, Element},
+    };
+    use anyhow::{anyhow, Context, Result};
+    use reqwest::StatusCode;
+    use serde_derive::Serialize;
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+        sync::{Arc, LazyLock, Mutex},
+    };
+    use tracing::warn;
+
+    const URL: &str = "https://api.namecheap.com/xml.response";
+    const SANDBOX_URL: &str = "https://api.sandbox.namecheap.com/xml.response";
+
+    /// The TTL of a newly-created record.
+    const DEFAULT_TTL: &str = "1799";
+
+    /// How many times a domain's host records may change while we're updating them before we
+    /// give up.
+    const MAX_CONFLICTS: u32 = 3;
+
+    pub fn url(api: &NamecheapApiConfig) -> &'static str {
+        match api.sandbox {
+            true => SANDBOX_URL,
+            false => URL,
+        }
+    }
+
+    /// A domain's host records, as returned by `getHosts`.
+    #[derive(Clone, PartialEq, Eq)]
+    pub struct Hosts {
+        pub email_type: Option<String>,
+        pub hosts: Vec<Host>,
+    }
+
+    #[derive(Clone, PartialEq, Eq, Serialize)]
+    pub struct Host {
+        pub name: String,
+        #[serde(rename = "type")]
+        pub record_type: String,
+        pub address: String,
+        pub mx_pref: Option<String>,
+        pub ttl: Option<String>,
+    }
+
+    /// Returns the client IP to give API calls made while setting a record to `addr`.
+    pub fn client_ip(api: &NamecheapApiConfig, addr: IpAddr) -> Result<Ipv4Addr> {
+        match (api.client_ip, addr) {
+            (Some(client_ip), _) => Ok(client_ip),
+            (None, IpAddr::V4(addr)) => Ok(addr),
+            (None, IpAddr::V6(_)) => Err(anyhow!(
+                "api.client_ip must be set to update AAAA records, as Namecheap only accepts IPv4 \
+                 client addresses"
+            )),
+        }
+    }
+
+    /// Points the record (of the type matching `addr`) at `addr`, with the configured TTL.
+    /// Returns false if it already was.
+    pub async fn update(
+        client: &reqwest::Client,
+        cfg: &NamecheapConfig,
+        api: &NamecheapApiConfig,
+        addr: IpAddr,
+    ) -> Result<bool> {
+        let client_ip = client_ip(api, addr)?;
+        let lock = domain_lock(cfg, api);
+        let _guard = lock.lock().await;
+        let mut read = get_hosts(client, cfg, api, client_ip).await?;
+        let mut conflicts = 0;
+        loop {
+            let Some(hosts) = with_record(cfg, &read, addr) else {
+                return Ok(false);
+            };
+
+            // setHosts replaces all of the domain's records, so writing records read before
+            // someone else changed them would undo the change. The API can't make the write
+            // conditional, so check for changes just before writing, & start over if there were.
+            let fresh = get_hosts(client, cfg, api, client_ip).await?;
+            if fresh != read {
+                conflicts += 1;
+                if conflicts > MAX_CONFLICTS {
+                    return Err(anyhow!(
+                        "{}'s host records kept changing while updating them",
+                        cfg.domain
+                    ));
+                }
+                warn!(
+                    domain = cfg.domain,
+                    "Host records changed while updating them, retrying"
+                );
+                read = fresh;
+                continue;
+            }
+            set_hosts(client, cfg, api, client_ip, hosts.clone()).await?;
+            if cfg.verify_hosts {
+                let written = get_hosts(client, cfg, api, client_ip).await?;
+                check_written(cfg, &hosts, &written)?;
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Returns the lock on the domain's host records, held while reading, modifying, & writing
+    /// them so that our own updates of its records (e.g. of its A & AAAA records) don't overwrite
+    /// each other.
+    fn domain_lock(cfg: &NamecheapConfig, api: &NamecheapApiConfig) -> Arc<tokio::sync::Mutex<()>> {
+        static LOCKS: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+            LazyLock::new(Mutex::default);
+        let key = format!("{}:{}:{}", url(api), api.username(), cfg.domain);
+        Arc::clone(LOCKS.lock().unwrap().entry(key).or_default())
+    }
+
+    /// Returns the domain's host records with the record (of the type matching `addr`) pointed
+    /// at `addr`, with the configured TTL, & the configured SRV records pointed at the record, or
+    /// None if they already are.
+    fn with_record(cfg: &NamecheapConfig, read: &Hosts, addr: IpAddr) -> Option<Hosts> {
+        let record_type = match addr {
+            IpAddr::V4(_) => "A",
+            IpAddr::V6(_) => "AAAA",
+        };
+        let is_record = |host: &Host| host.name == cfg.host() && host.record_type == record_type;
+        let records: Vec<_> = read.hosts.iter().filter(|host| is_record(host)).collect();
+        let wanted_ttl = cfg.ttl_secs.map(|ttl| ttl.to_string());
+        let record_ok = match records[..] {
+            [record] => {
+                record.address.parse::<IpAddr>().ok() == Some(addr)
+                    && (wanted_ttl.is_none() || record.ttl == wanted_ttl)
+            }
+            _ => false,
+        };
+        let target = format!("{}.", provider::record_name(&cfg.domain, cfg.host()));
+        let srv_ok = cfg.srv_records.iter().all(|srv| {
+            match read
+                .hosts
+                .iter()
+                .filter(|host| host.name == srv.name && host.record_type == "SRV")
+                .collect::<Vec<_>>()[..]
+            {
+                [host] => {
+                    is_srv(host, srv, &target) && (wanted_ttl.is_none() || host.ttl == wanted_ttl)
+                }
+                _ => false,
+            }
+        });
+        if record_ok && srv_ok {
+            return None;
+        }
+
+        // Replace the record's A (or AAAA) records, if any, with one for the address, with the
+        // configured TTL, else keeping the existing one.
+        let ttl = wanted_ttl
+            .clone()
+            .or_else(|| records.first().and_then(|host| host.ttl.clone()));
+        let mut hosts = read.clone();
+        hosts.hosts.retain(|host| !is_record(host));
+        hosts.hosts.push(Host {
+            name: cfg.host().to_string(),
+            record_type: record_type.to_string(),
+            address: addr.to_string(),
+            mx_pref: None,
+            ttl: Some(ttl.unwrap_or_else(|| DEFAULT_TTL.to_string())),
+        });
+
+        // Likewise replace each service's SRV records with one pointing at the record.
+        for srv in &cfg.srv_records {
+            let is_service = |host: &Host| host.name == srv.name && host.record_type == "SRV";
+            let ttl = wanted_ttl.clone().or_else(|| {
+                hosts
+                    .hosts
+                    .iter()
+                    .find(|host| is_service(host))
+                    .and_then(|host| host.ttl.clone())
+            });
+            hosts.hosts.retain(|host| !is_service(host));
+            hosts.hosts.push(Host {
+                name: srv.name.clone(),
+                record_type: "SRV".to_string(),
+                address: format!("{} {} {} {target}", srv.priority, srv.weight, srv.port),
+                mx_pref: None,
+                ttl: Some(ttl.unwrap_or_else(|| DEFAULT_TTL.to_string())),
+            });
+        }
+        Some(hosts)
+    }
+
+    /// Returns whether an SRV host record (whose address is `priority weight port target`) is the
+    /// configured one, pointing at `target`.
+    fn is_srv(host: &Host, srv: &SrvRecordConfig, target: &str) -> bool {
+        let fields: Vec<_> = host.address.split_whitespace().collect();
+        let [priority, weight, port, host_target] = fields[..] else {
+            return false;
+        };
+        priority.parse() == Ok(srv.priority)
+            && weight.parse() == Ok(srv.weight)
+            && port.parse() == Ok(srv.port)
+            && host_target
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(target.trim_end_matches('.'))
+    }
+
+    /// Checks that the host records read back after writing them are those written (give or take
+    /// case & trailing dots), returning an error listing any which were dropped or changed.
+    fn check_written(cfg: &NamecheapConfig, wrote: &Hosts, read: &Hosts) -> Result<()> {
+        let describe = |host: &Host| {
+            let mut desc = format!("{} {} {}", host.name, host.record_type, host.address);
+            if let Some(mx_pref) = &host.mx_pref {
+                desc.push_str(&format!(" (MXPref {mx_pref})"));
+            }
+            if let Some(ttl) = &host.ttl {
+                desc.push_str(&format!(" (TTL {ttl})"));
+            }
+            desc
+        };
+        let key = |host: &Host| {
+            (
+                host.name.to_lowercase(),
+                host.record_type.to_uppercase(),
+                host.address.trim_end_matches('.').to_lowercase(),
+                host.mx_pref.clone(),
+                host.ttl.clone(),
+            )
+        };
+        let mut unexpected: Vec<_> = read.hosts.iter().collect();
+        let mut missing = Vec::new();
+        for host in &wrote.hosts {
+            match unexpected.iter().position(|read| key(read) == key(host)) {
+                Some(i) => {
+                    unexpected.swap_remove(i);
+                }
+                None => missing.push(describe(host)),
+            }
+        }
+        if missing.is_empty() && unexpected.is_empty() {
+            return Ok(());
+        }
+        let unexpected: Vec<_> = unexpected.into_iter().map(describe).collect();
+        Err(Coded {
+            code: Code::NcHostsMismatch,
+            message: format!(
+                "{}'s host records didn't read back as written: missing [{}], unexpected [{}]",
+                cfg.domain,
+                missing.join(", "),
+                unexpected.join(", ")
+            ),
+        }
+        .into())
+    }
+
+    /// Replaces the domain's host records.
+    async fn set_hosts(
+        client: &reqwest::Client,
+        cfg: &NamecheapConfig,
+        api: &NamecheapApiConfig,
+        client_ip: Ipv4Addr,
+        hosts: Hosts,
+    ) -> Result<()> {
+        let mut params = domain_params(cfg)?;
+        params.extend(
+            hosts
+                .email_type
+                .map(|email_type| ("EmailType".to_string(), email_type)),
+        );
+        for (i, host) in hosts.hosts.into_iter().enumerate() {
+            let i = i + 1;
+            params.push((format!("HostName{i}"), host.name));
+            params.push((format!("RecordType{i}"), host.record_type));
+            params.push((format!("Address{i}"), host.address));
+            params.extend(host.mx_pref.map(|mx_pref| (format!("MXPref{i}"), mx_pref)));
+            params.extend(host.ttl.map(|ttl| (format!("TTL{i}"), ttl)));
+        }
+        let resp = call(
+            client,
+            cfg,
+            api,
+            client_ip,
+            "namecheap.domains.dns.setHosts",
+            params,
+        )
+        .await?;
+        let result = resp.descendants("DomainDNSSetHostsResult");
+        let is_success = result.first().and_then(|result| result.attr("IsSuccess"));
+        if is_success != Some("true") {
+            return Err(anyhow!(
+                "setHosts didn't succeed (IsSuccess {is_success:?})"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the domain's host records. `client_ip` is the address we're making requests from.
+    pub async fn get_hosts(
+        client: &reqwest::Client,
+        cfg: &NamecheapConfig,
+        api: &NamecheapApiConfig,
+        client_ip: Ipv4Addr,
+    ) -> Result<Hosts> {
+        let params = domain_params(cfg)?;
+        let resp = call(
+            client,
+            cfg,
+            api,
+            client_ip,
+            "namecheap.domains.dns.getHosts",
+            params,
+        )
+        .await?;
+        let result = resp.descendants("DomainDNSGetHostsResult");
+        let result = result
+            .first()
+            .ok_or_else(|| anyhow!("getHosts response has no result"))?;
+        let hosts = result
+            .descendants("host")
+            .iter()
+            .map(|host| {
+                let attr = |name| {
+                    host.attr(name)
+                        .map(str::to_string)
+                        .ok_or_else(|| anyhow!("getHosts returned a host without {name}"))
+                };
+                Ok(Host {
+                    name: attr("Name")?,
+                    record_type: attr("Type")?,
+                    address: attr("Address")?,
+                    mx_pref: attr("MXPref")
+                        .ok()
+                        .filter(|_| host.attr("Type") == Some("MX")),
+                    ttl: attr("TTL").ok(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Hosts {
+            email_type: result.attr("EmailType").map(str::to_string),
+            hosts,
+        })
+    }
+
+    /// Returns the parameters identifying the domain: its second-level domain & TLD.
+    fn domain_params(cfg: &NamecheapConfig) -> Result<Vec<(String, String)>> {
+        let (sld, tld) = cfg
+            .domain
+            .split_once('.')
+            .ok_or_else(|| anyhow!("domain {:?} has no TLD", cfg.domain))?;
+        Ok(vec![
+            ("SLD".to_string(), sld.to_string()),
+            ("TLD".to_string(), tld.to_string()),
+        ])
+    }
+
+    /// Calls an API command, returning the response document if the call succeeded. Parameters are
+    /// POSTed, so that the API key doesn't appear in URLs (e.g. in error messages).
+    async fn call(
+        client: &reqwest::Client,
+        cfg: &NamecheapConfig,
+        api: &NamecheapApiConfig,
+        client_ip: Ipv4Addr,
+        command: &str,
+        params: Vec<(String, String)>,
+    ) -> Result<Element> {
+        let mut form = vec![
+            ("ApiUser".to_string(), api.user.clone()),
+            ("ApiKey".to_string(), api.key.value().to_string()),
+            ("UserName".to_string(), api.username().to_string()),
+            ("ClientIp".to_string(), client_ip.to_string()),
+            ("Command".to_string(), command.to_string()),
+        ];
+        form.extend(params);
+        let resp = client
+            .post(url(api))
+            .timeout(cfg.calls.timeout())
+            .form(&form)
+            .send()
+            .await?;
+        if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Lockout {
+                message: format!("{command} got {}", resp.status()),
+            }
+            .into());
+        }
+        let body = resp.error_for_status()?.text().await?;
+
+        let resp = xml::parse(&body)
+            .with_context(|| format!("{command} got unexpected response: {body}"))?;
+        if resp.name != "ApiResponse" {
+            return Err(anyhow!("{command} got unexpected response: {body}"));
+        }
+        if resp.attr("Status") != Some("OK") {
+            let elements = resp.descendants("Error");
+            let errors: Vec<_> = elements.iter().map(|err| describe(err)).collect();
+            if errors.is_empty() {
+                return Err(anyhow!("{command} got unexpected response: {body}"));
+            }
+            if errors.iter().any(|err| super::is_lockout(err)) {
+                return Err(Lockout {
+                    message: format!("{command} got error: {}", errors.join("; ")),
+                }
+                .into());
+            }
+            let code = elements
+                .iter()
+                .find_map(|err| super::error_number_code(err.attr("Number")?))
+                .unwrap_or(Code::NcError);
+            let message = format!("{command} got error: {}", errors.join("; "));
+            return Err(super::error(code, message));
+        }
+        Ok(resp)
+    }
+
+    /// Describes an API `<Error Number="...">message</Error>` element.
+    fn describe(err: &Element) -> String {
+        match err.attr("Number") {
+            Some(number) => format!("{} (error {number})", err.text()),
+            None => err.text().to_string(),
+        }
+    }
+}
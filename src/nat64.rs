@@ -0,0 +1,112 @@
+//! Support for IPv6-only hosts, which reach IPv4 addresses (if at all) via NAT64: a translator
+//! which embeds IPv4 addresses in an IPv6 prefix, discovered via DNS64 (RFC 7050). Names with only
+//! A records then resolve to synthesized AAAA records, so HTTP requests work as is; this module is
+//! for the rest, which are given IPv4 addresses directly (e.g. resolvers), & for explaining why
+//! IPv4 operations fail on hosts without NAT64.
+
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+use tokio::net;
+use tracing::debug;
+
+/// The name which only has A records (RFC 7050), so whose AAAA records were synthesized by DNS64.
+const DISCOVERY_NAME: &str = "ipv4only.arpa";
+
+/// The addresses of `DISCOVERY_NAME`.
+const DISCOVERY_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// An address whose route is looked up to check for IPv4 connectivity. Nothing is sent to it.
+const ROUTE_PROBE: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+
+/// A NAT64 prefix. Only 96-bit prefixes (e.g. the well-known `64:ff9b::/96`), which embed the IPv4
+/// address in the last 32 bits, are supported; they're by far the most common.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prefix([u16; 6]);
+
+impl Prefix {
+    /// The NAT64 well-known prefix (RFC 6052), `64:ff9b::/96`.
+    pub const WELL_KNOWN: Prefix = Prefix([0x64, 0xff9b, 0, 0, 0, 0]);
+
+    /// Returns the IPv6 address through which the given IPv4 address is reached.
+    pub fn synthesize(self, addr: Ipv4Addr) -> Ipv6Addr {
+        let [a, b, c, d, e, f] = self.0;
+        let addr = u32::from(addr);
+        Ipv6Addr::new(a, b, c, d, e, f, (addr >> 16) as u16, addr as u16)
+    }
+
+    /// Returns the IPv4 address embedded in the given address, if it has this prefix.
+    pub fn extract(self, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+        let [.., a, b, c, d] = addr.octets();
+        (addr.segments()[..6] == self.0).then(|| Ipv4Addr::new(a, b, c, d))
+    }
+}
+
+impl Display for Prefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{}/96", Ipv6Addr::new(a, b, c, d, e, g, 0, 0))
+    }
+}
+
+/// Whether the host has a route to the IPv4 internet. If not, it's IPv6-only.
+pub fn has_ipv4_route() -> bool {
+    // Connecting a UDP socket only looks up the route, which fails if there isn't one.
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .is_ok_and(|socket| socket.connect(ROUTE_PROBE).is_ok())
+}
+
+/// Discovers the network's NAT64 prefix via DNS64, returning None if it has none.
+pub async fn prefix() -> Option<Prefix> {
+    let addrs = match net::lookup_host((DISCOVERY_NAME, 0)).await {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            debug!(%err, "Couldn't look up {DISCOVERY_NAME}, assuming no NAT64");
+            return None;
+        }
+    };
+    addrs
+        .filter_map(|addr| match addr.ip() {
+            IpAddr::V6(addr) => Some(addr),
+            IpAddr::V4(_) => None,
+        })
+        .find(|addr| {
+            let [.., a, b, c, d] = addr.octets();
+            DISCOVERY_ADDRS.contains(&Ipv4Addr::new(a, b, c, d))
+        })
+        .map(|addr| {
+            let [a, b, c, d, e, f, ..] = addr.segments();
+            Prefix([a, b, c, d, e, f])
+        })
+}
+
+/// Returns the address to send to in order to reach `addr`: `addr` itself, unless it's an IPv4
+/// address & the host is IPv6-only, in which case it's reached via NAT64 (or can't be).
+pub async fn reachable(addr: SocketAddr) -> Result<SocketAddr> {
+    let SocketAddr::V4(addr4) = addr else {
+        return Ok(addr);
+    };
+    if has_ipv4_route() {
+        return Ok(addr);
+    }
+    match prefix().await {
+        Some(prefix) => Ok((prefix.synthesize(*addr4.ip()), addr4.port()).into()),
+        None => Err(anyhow!(
+            "can't reach {}: this host is IPv6-only (it has no IPv4 route) & its network has no \
+             NAT64; use an IPv6 address instead",
+            addr.ip()
+        )),
+    }
+}
+
+/// Adds to an error from an IPv4 operation why it failed, if it's because the host is IPv6-only
+/// & there's no NAT64.
+pub async fn explain(err: anyhow::Error) -> anyhow::Error {
+    if has_ipv4_route() || prefix().await.is_some() {
+        return err;
+    }
+    err.context("this host is IPv6-only (it has no IPv4 route) & its network has no NAT64")
+}
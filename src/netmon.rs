@@ -0,0 +1,202 @@
+use crate::{backoff::Backoff, detect::Detectors};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::{
+    sync::watch,
+    time::{self, Instant},
+};
+use tracing::{info, warn};
+
+/// How long to wait for the network to settle after a change before signalling it. Changes tend
+/// to arrive in bursts (e.g. link up, then address assigned, then routes added).
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// The minimum & maximum delays between checks while waiting for the network at startup.
+const MIN_NETWORK_WAIT_DELAY: Duration = Duration::from_secs(1);
+const MAX_NETWORK_WAIT_DELAY: Duration = Duration::from_secs(15);
+
+/// How long to wait for the network at startup before giving up & starting anyway.
+const NETWORK_WAIT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Starts monitoring the network for changes (links going up/down, addresses or routes being
+/// added/removed). The returned receiver is notified after each (settled) change.
+///
+/// Monitoring is best-effort: if it isn't supported on this platform, or can't be started, the
+/// returned receiver's sender is dropped.
+pub fn spawn() -> watch::Receiver<()> {
+    let (tx, rx) = watch::channel(());
+    #[cfg(target_os = "linux")]
+    match linux::NetlinkMonitor::new() {
+        Ok(monitor) => {
+            tokio::spawn(monitor.run(tx));
+        }
+        Err(err) => warn!(%err, "Couldn't monitor network for changes"),
+    }
+    #[cfg(not(target_os = "linux"))]
+    drop(tx);
+    rx
+}
+
+/// Waits for the next network change. If network changes can't be monitored, this never returns.
+pub async fn changed(network_changes: &mut watch::Receiver<()>) {
+    if network_changes.changed().await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Waits (with a short backoff) until the network is available, as judged by a request to
+/// `connectivity_url` succeeding or, if that is not given, by IP address detection succeeding.
+/// This avoids a failed first check (and a long wait for the next one) when starting at boot,
+/// before the network is fully up. Gives up after a while, since workers can cope with the network
+/// being down anyway.
+pub async fn wait_for_network(
+    client: &reqwest::Client,
+    detector: &Detectors,
+    connectivity_url: Option<&str>,
+    network_changes: &mut watch::Receiver<()>,
+) {
+    let give_up_at = Instant::now() + NETWORK_WAIT_TIMEOUT;
+    let mut backoff = Backoff::new(MIN_NETWORK_WAIT_DELAY, MAX_NETWORK_WAIT_DELAY);
+    loop {
+        network_changes.mark_unchanged();
+        let err = match check_network(client, detector, connectivity_url).await {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        if backoff.is_backing_off() && Instant::now() >= give_up_at {
+            warn!(
+                err = format!("{err:#}"),
+                "Network still unavailable, starting anyway"
+            );
+            return;
+        }
+        if !backoff.is_backing_off() {
+            info!(
+                err = format!("{err:#}"),
+                "Waiting for network to become available"
+            );
+        }
+        tokio::select! {
+            _ = time::sleep(backoff.failure()) => (),
+            _ = changed(network_changes) => (),
+        }
+    }
+}
+
+async fn check_network(
+    client: &reqwest::Client,
+    detector: &Detectors,
+    connectivity_url: Option<&str>,
+) -> Result<()> {
+    match connectivity_url {
+        Some(url) => client.get(url).send().await.map(|_| ())?,
+        None => detector.current_address(client).await.map(|_| ())?,
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::SETTLE_TIME;
+    use std::{
+        io, mem,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+    use tokio::{io::unix::AsyncFd, sync::watch, time};
+    use tracing::{debug, warn};
+
+    /// NetlinkMonitor listens for route netlink multicast messages.
+    pub struct NetlinkMonitor {
+        fd: AsyncFd<OwnedFd>,
+    }
+
+    impl NetlinkMonitor {
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: socket has no memory-safety preconditions; we check the result.
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                    libc::NETLINK_ROUTE,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: fd is a freshly-created socket which nothing else owns.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            // SAFETY: sockaddr_nl is a plain C struct, for which all-zeroes is a valid value.
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = (libc::RTMGRP_LINK
+                | libc::RTMGRP_IPV4_IFADDR
+                | libc::RTMGRP_IPV6_IFADDR
+                | libc::RTMGRP_IPV4_ROUTE
+                | libc::RTMGRP_IPV6_ROUTE) as u32;
+            // SAFETY: addr is a valid sockaddr_nl, and we pass its true size.
+            let rslt = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if rslt < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self {
+                fd: AsyncFd::new(fd)?,
+            })
+        }
+
+        pub async fn run(self, tx: watch::Sender<()>) {
+            loop {
+                if let Err(err) = self.recv().await {
+                    warn!(%err, "Couldn't receive network changes, no longer monitoring");
+                    return;
+                }
+
+                // Let the network settle, swallowing any further changes in the meantime.
+                let settled = time::sleep(SETTLE_TIME);
+                tokio::pin!(settled);
+                loop {
+                    tokio::select! {
+                        _ = &mut settled => break,
+                        rslt = self.recv() => if rslt.is_err() { break },
+                    }
+                }
+
+                debug!("Network changed");
+                tx.send_replace(());
+            }
+        }
+
+        /// Receives (and discards) a message. We don't care what changed, only that something did.
+        async fn recv(&self) -> io::Result<()> {
+            let mut buf = [0u8; 8192];
+            loop {
+                let mut guard = self.fd.readable().await?;
+                match guard.try_io(|fd| {
+                    // SAFETY: buf is valid for writes of buf.len() bytes.
+                    let n = unsafe {
+                        libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0)
+                    };
+                    if n < 0 {
+                        let err = io::Error::last_os_error();
+                        // ENOBUFS means we fell behind & messages were dropped; but we already
+                        // know something changed.
+                        if err.raw_os_error() != Some(libc::ENOBUFS) {
+                            return Err(err);
+                        }
+                    }
+                    Ok(())
+                }) {
+                    Ok(rslt) => return rslt,
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Updates of No-IP records, via No-IP's (dyndns2-style) update API.
+
+use crate::{
+    backoff::Permanent,
+    codes::Code,
+    config::{CallConfig, NoIpConfig},
+    lockout::Lockout,
+    provider::{Provider, RecordType, UpdateFuture},
+};
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+
+const UPDATE_URL: &str = "https://dynupdate.no-ip.com/nic/update";
+
+/// NoIp updates a record via No-IP's API.
+pub struct NoIp {
+    id: String,
+    account: String,
+    record_type: RecordType,
+    cfg: NoIpConfig,
+}
+
+impl NoIp {
+    pub fn new(cfg: NoIpConfig, record_type: RecordType) -> Self {
+        let id = match record_type {
+            RecordType::A => format!("noip:{}", cfg.hostname),
+            RecordType::Aaaa => format!("noip:{}/AAAA", cfg.hostname),
+        };
+        Self {
+            id,
+            account: format!("noip:{}", cfg.username),
+            record_type,
+            cfg,
+        }
+    }
+
+    async fn update_address(&self, client: &reqwest::Client, addr: IpAddr) -> Result<()> {
+        let addr_param = match addr {
+            IpAddr::V4(_) => "myip",
+            IpAddr::V6(_) => "myipv6",
+        };
+        let resp = client
+            .get(UPDATE_URL)
+            .timeout(self.cfg.calls.timeout())
+            .basic_auth(&self.cfg.username, Some(self.cfg.password.value()))
+            .query(&[
+                ("hostname", self.cfg.hostname.as_str()),
+                (addr_param, &addr.to_string()),
+            ])
+            .send()
+            .await?;
+        let status = resp.status();
+
+        // This API answers with a code, followed (on success) by the address applied.
+        let body = resp.text().await?;
+        let body = body.trim();
+        let (code, applied_addr) = body.split_once(' ').unwrap_or((body, ""));
+        let permanent = |code, message: &str| {
+            Err(Permanent {
+                code,
+                message: message.to_string(),
+            }
+            .into())
+        };
+        match code {
+            "good" | "nochg" => (),
+            "abuse" => {
+                return Err(Lockout {
+                    message: "No-IP blocked updates of the hostname for abuse".to_string(),
+                }
+                .into())
+            }
+            "911" => return Err(anyhow!("No-IP had a server error, & asks to retry later")),
+            "nohost" => return permanent(Code::ProviderNotFound, "hostname isn't in the account"),
+            "badauth" => {
+                return permanent(
+                    Code::ProviderBadCredentials,
+                    "username or password is wrong",
+                )
+            }
+            "badagent" => {
+                return permanent(Code::ProviderUnsupported, "No-IP blocked our user agent")
+            }
+            "!donator" => {
+                return permanent(Code::ProviderUnsupported, "update needs a paid account")
+            }
+            _ => {
+                return Err(anyhow!(
+                    "update request got unexpected response ({status}): {body}"
+                ))
+            }
+        }
+
+        // The address applied can differ from the one we sent if a proxy rewrote the request.
+        let applied_addr = applied_addr.trim();
+        if !applied_addr.is_empty() && applied_addr.parse() != Ok(addr) {
+            return Err(anyhow!(
+                "No-IP applied IP {applied_addr}, rather than the requested {addr}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Provider for NoIp {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn account(&self) -> &str {
+        &self.account
+    }
+
+    fn endpoint(&self) -> &str {
+        UPDATE_URL
+    }
+
+    fn record(&self) -> &str {
+        &self.cfg.hostname
+    }
+
+    fn calls(&self) -> &CallConfig {
+        &self.cfg.calls
+    }
+
+    fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    fn update<'a>(&'a self, client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a> {
+        Box::pin(self.update_address(client, addr))
+    }
+}
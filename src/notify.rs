@@ -0,0 +1,529 @@
+//! Notifications: notable events (e.g. IP changes) are sent to the notifier task, which delivers
+//! them to each configured webhook as a JSON POST. Undelivered notifications are kept in a bounded
+//! queue, persisted in the state, & retried until they're delivered.
+
+use crate::{
+    backoff::Backoff,
+    codes::Code,
+    config::{NotificationRule, Severity, TimeOfDay, WebhookConfig, WebhookFormat},
+    metrics::{Measurement, Metrics, Value},
+    state::StateUpdate,
+    status,
+};
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{info, info_span, warn, Instrument, Span};
+
+/// How many notifications may be sent to the notifier task before it has picked them up; beyond
+/// this, new ones are dropped.
+const CHANNEL_LEN: usize = 16;
+
+/// How many undelivered notifications are kept; beyond this, the oldest are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// The minimum & maximum delays before retrying undelivered notifications.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// The header carrying the signature of a webhook's body, if it has a secret.
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// The longest message Discord accepts.
+const MAX_DISCORD_LEN: usize = 2000;
+
+/// An event worth notifying about.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The record was updated to a new address.
+    IpChanged {
+        old_addr: Option<IpAddr>,
+        new_addr: IpAddr,
+    },
+
+    /// A resolver still returned the old address for the record after its TTL expired.
+    PropagationFailed {
+        resolver: IpAddr,
+        expected_addr: Ipv4Addr,
+    },
+
+    /// The provider locked out the account used to update the record, so calls with the account
+    /// are suspended until `until` (in seconds since the Unix epoch). Updates will be missed
+    /// meanwhile; the lockout may need to be resolved with the provider.
+    LockedOut {
+        account: String,
+        reason: String,
+        until: u64,
+    },
+
+    /// Detectors disagreed about our address, which may mean split routing or a hijacked
+    /// detector. `disagreements` maps each disagreeing detector's URL to the address it detected,
+    /// instead of `addr`. Sent when the disagreements change, rather than on every check.
+    DetectorsDisagreed {
+        addr: Ipv4Addr,
+        disagreements: BTreeMap<String, Ipv4Addr>,
+    },
+
+    /// Updating the record with a provider failed. Sent when updates start failing (after
+    /// `notify_after_failures` consecutive failures), or fail differently (i.e. with another
+    /// code), rather than on every failure.
+    UpdateFailed { provider: String, error: String },
+
+    /// Detecting our address failed. Sent when detection starts failing, or fails differently.
+    DetectionFailed { error: String },
+
+    /// The daemon started.
+    Started { version: String },
+
+    /// The daemon is stopping.
+    Stopped,
+}
+
+impl Event {
+    /// How severe the event is, for notification rules.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Event::IpChanged { .. } | Event::Started { .. } | Event::Stopped => Severity::Info,
+            Event::PropagationFailed { .. }
+            | Event::DetectorsDisagreed { .. }
+            | Event::UpdateFailed { .. }
+            | Event::DetectionFailed { .. } => Severity::Warning,
+            Event::LockedOut { .. } => Severity::Critical,
+        }
+    }
+
+    /// The event's name, as given in its `event` field.
+    fn name(&self) -> &'static str {
+        match self {
+            Event::IpChanged { .. } => "ip_changed",
+            Event::PropagationFailed { .. } => "propagation_failed",
+            Event::LockedOut { .. } => "locked_out",
+            Event::DetectorsDisagreed { .. } => "detectors_disagreed",
+            Event::UpdateFailed { .. } => "update_failed",
+            Event::DetectionFailed { .. } => "detection_failed",
+            Event::Started { .. } => "started",
+            Event::Stopped => "stopped",
+        }
+    }
+
+    /// The code of a failure event whose code doesn't depend on the error, if any.
+    fn code(&self) -> Option<Code> {
+        match self {
+            Event::PropagationFailed { .. } => Some(Code::PropagationFailed),
+            Event::LockedOut { .. } => Some(Code::ProviderLockedOut),
+            Event::DetectorsDisagreed { .. } => Some(Code::DetectorsDisagreed),
+            Event::IpChanged { .. }
+            | Event::UpdateFailed { .. }
+            | Event::DetectionFailed { .. }
+            | Event::Started { .. }
+            | Event::Stopped => None,
+        }
+    }
+}
+
+/// A notification of an event about a record, as delivered to webhooks.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    #[serde(flatten)]
+    pub event: Event,
+    pub record: String,
+    /// The failure's code, if the event is a failure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<Code>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub time: u64, // seconds since the Unix epoch.
+}
+
+impl Notification {
+    pub fn new(event: Event, record: String, labels: BTreeMap<String, String>) -> Self {
+        Self {
+            code: event.code(),
+            event,
+            record,
+            labels,
+            time: status::unix_time(SystemTime::now()),
+        }
+    }
+
+    /// Sets the code of a failure whose code depends on the error.
+    pub fn with_code(self, code: Code) -> Self {
+        Self {
+            code: Some(code),
+            ..self
+        }
+    }
+
+    /// Describes the notification in a sentence or two, for chat messages.
+    fn summary(&self) -> String {
+        let record = &self.record;
+        let mut summary = match &self.event {
+            Event::IpChanged {
+                old_addr: Some(old_addr),
+                new_addr,
+            } => format!("{record} changed from {old_addr} to {new_addr}"),
+            Event::IpChanged {
+                old_addr: None,
+                new_addr,
+            } => format!("{record} set to {new_addr}"),
+            Event::PropagationFailed {
+                resolver,
+                expected_addr,
+            } => format!(
+                "{resolver} still doesn't resolve {record} to {expected_addr}, after its TTL expired"
+            ),
+            Event::LockedOut {
+                account,
+                reason,
+                until,
+            } => format!(
+                "{account} is locked out until {}, so {record} won't be updated: {reason}",
+                httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(*until))
+            ),
+            Event::DetectorsDisagreed {
+                addr,
+                disagreements,
+            } => {
+                let disagreements: Vec<_> = disagreements
+                    .iter()
+                    .map(|(url, addr)| format!("{url} detected {addr}"))
+                    .collect();
+                format!(
+                    "Detectors disagreed about our address (using {addr} for {record}): {}",
+                    disagreements.join(", ")
+                )
+            }
+            Event::UpdateFailed { provider, error } => {
+                format!("Couldn't update {record} with {provider}: {error}")
+            }
+            Event::DetectionFailed { error } => {
+                format!("Couldn't detect our address for {record}: {error}")
+            }
+            Event::Started { version } => format!("rnccd {version} started, updating {record}"),
+            Event::Stopped => format!("rnccd stopped, no longer updating {record}"),
+        };
+        if let Some(code) = self.code {
+            let code = serde_json::to_value(code).expect("Couldn't serialize code");
+            let _ = write!(summary, " [{}]", code.as_str().unwrap_or_default());
+        }
+        if !self.labels.is_empty() {
+            let labels: Vec<_> = self
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            let _ = write!(summary, " ({})", labels.join(", "));
+        }
+        summary
+    }
+}
+
+/// Notifier is a handle used to send notifications. Sending never blocks: if the notifier task
+/// falls behind, notifications are dropped.
+#[derive(Clone)]
+pub struct Notifier {
+    tx: Option<mpsc::Sender<(Notification, Option<oneshot::Sender<()>>)>>,
+    metrics: Metrics,
+}
+
+impl Notifier {
+    pub fn notify(&self, notification: Notification) {
+        if let Some(tx) = &self.tx {
+            if tx.try_send((notification, None)).is_err() {
+                warn!("Notifier is busy, dropping notification");
+                self.metrics.record(dropped("notifier_busy"));
+            }
+        }
+    }
+
+    /// Sends a notification, & waits (for at most `timeout`) until delivery has been attempted,
+    /// e.g. before exiting. A notification which couldn't be delivered stays queued, as usual.
+    pub async fn notify_and_wait(&self, notification: Notification, timeout: Duration) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let (done_tx, done_rx) = oneshot::channel();
+        let _ = time::timeout(timeout, async {
+            if tx.send((notification, Some(done_tx))).await.is_ok() {
+                let _ = done_rx.await;
+            }
+        })
+        .await;
+    }
+}
+
+/// A notification awaiting delivery. The queue of these is persisted in the state, so that
+/// notifications sent while a webhook is unavailable survive restarts.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Queued {
+    pub notification: Notification,
+
+    /// The URLs of the webhooks the notification hasn't yet been delivered to.
+    pub pending: BTreeSet<String>,
+}
+
+/// Starts the notifier task, delivering `queued` & then new notifications (unless the rules drop
+/// them) to the configured webhooks, & persisting undelivered notifications via `state_updates`.
+/// If there are no webhooks, notifications are discarded (as are any queued ones).
+pub fn spawn(
+    client: reqwest::Client,
+    webhooks: Vec<WebhookConfig>,
+    rules: Vec<NotificationRule>,
+    queued: Vec<Queued>,
+    state_updates: mpsc::Sender<(StateUpdate, Span)>,
+    metrics: Metrics,
+) -> Notifier {
+    // Notifications for webhooks which are no longer configured can never be delivered.
+    let queue = queued
+        .iter()
+        .cloned()
+        .filter_map(|mut queued| {
+            queued
+                .pending
+                .retain(|url| webhooks.iter().any(|webhook| webhook.url == *url));
+            (!queued.pending.is_empty()).then_some(queued)
+        })
+        .collect();
+    let (tx, rx) = mpsc::channel(CHANNEL_LEN);
+    let tx = (!webhooks.is_empty()).then_some(tx);
+    let task = Task {
+        client,
+        webhooks,
+        rules,
+        queue,
+        persisted: queued,
+        rx,
+        attempted: Vec::new(),
+        state_updates,
+        metrics: metrics.clone(),
+        backoff: Backoff::new(MIN_RETRY_DELAY, MAX_RETRY_DELAY),
+    };
+    tokio::spawn(task.run().instrument(info_span!("notifier")));
+    Notifier { tx, metrics }
+}
+
+struct Task {
+    client: reqwest::Client,
+    webhooks: Vec<WebhookConfig>,
+    rules: Vec<NotificationRule>,
+    queue: VecDeque<Queued>,
+    persisted: Vec<Queued>, // the queue as last sent to the state writer.
+    rx: mpsc::Receiver<(Notification, Option<oneshot::Sender<()>>)>,
+    attempted: Vec<oneshot::Sender<()>>, // to signal once the queue's delivery is attempted.
+    state_updates: mpsc::Sender<(StateUpdate, Span)>,
+    metrics: Metrics,
+    backoff: Backoff,
+}
+
+impl Task {
+    /// Runs the notifier task. Returns once all notifiers have been dropped.
+    async fn run(mut self) {
+        loop {
+            self.persist().await;
+            if self.queue.is_empty() {
+                // Nothing's left to deliver (e.g. the notifications were suppressed).
+                for done in self.attempted.drain(..) {
+                    let _ = done.send(());
+                }
+                match self.rx.recv().await {
+                    Some(notification) => self.enqueue(notification),
+                    None => return,
+                }
+                continue;
+            }
+            while let Ok(notification) = self.rx.try_recv() {
+                self.enqueue(notification);
+            }
+            let delivered = self.deliver_queued().await;
+            for done in self.attempted.drain(..) {
+                let _ = done.send(());
+            }
+            if delivered {
+                self.backoff.reset();
+                continue;
+            }
+
+            // Retry after a delay, queuing (& persisting) any notifications sent meanwhile.
+            self.persist().await;
+            let retry = time::sleep(self.backoff.failure());
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    () = &mut retry => break,
+                    notification = self.rx.recv() => match notification {
+                        Some(notification) => {
+                            self.enqueue(notification);
+                            self.persist().await;
+                        }
+                        None => return,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Adds a notification to the queue, for delivery to every webhook. If the queue is full, the
+    /// oldest notification is dropped.
+    fn enqueue(&mut self, (notification, done): (Notification, Option<oneshot::Sender<()>>)) {
+        self.attempted.extend(done);
+        if let Some(reason) = self.suppression(&notification, local_time(SystemTime::now())) {
+            info!(
+                event = notification.event.name(),
+                record = notification.record,
+                reason,
+                "Not sending notification"
+            );
+            self.metrics.record(dropped(reason));
+            return;
+        }
+        let pending = self.webhooks.iter().map(|w| w.url.clone()).collect();
+        self.queue.push_back(Queued {
+            notification,
+            pending,
+        });
+        if self.queue.len() > QUEUE_LEN {
+            self.queue.pop_front();
+            warn!("Notification queue is full, dropped the oldest notification");
+            self.metrics.record(dropped("queue_full"));
+        }
+    }
+
+    /// Returns why the rule governing a notification's record drops it at the given time of day,
+    /// if it does.
+    fn suppression(&self, notification: &Notification, time: TimeOfDay) -> Option<&'static str> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(&notification.record))?;
+        let severity = notification.event.severity();
+        if severity < rule.min_severity {
+            return Some("below_min_severity");
+        }
+        let quiet_hours = rule.quiet_hours.as_ref()?;
+        (quiet_hours.contains(time) && severity < quiet_hours.min_severity).then_some("quiet_hours")
+    }
+
+    /// Attempts to deliver the queued notifications to each webhook, in order. Delivery to a
+    /// webhook stops at its first failure, so that each webhook receives notifications in order.
+    /// Returns true if every notification was delivered.
+    async fn deliver_queued(&mut self) -> bool {
+        let mut delivered = true;
+        for webhook in &self.webhooks {
+            let queued = self.queue.iter_mut();
+            for queued in queued.filter(|queued| queued.pending.contains(&webhook.url)) {
+                if let Err(err) = deliver(&self.client, webhook, &queued.notification).await {
+                    warn!(
+                        url = webhook.url,
+                        err = format!("{err:#}"),
+                        "Couldn't deliver notification, will retry"
+                    );
+                    delivered = false;
+                    break;
+                }
+                queued.pending.remove(&webhook.url);
+            }
+        }
+        self.queue.retain(|queued| !queued.pending.is_empty());
+        delivered
+    }
+
+    /// Sends the queue to the state writer, if it has changed since it was last sent.
+    async fn persist(&mut self) {
+        if self.queue.iter().eq(&self.persisted) {
+            return;
+        }
+        self.persisted = self.queue.iter().cloned().collect();
+        self.metrics.record(Measurement::new(
+            "notification_queue",
+            Vec::new(),
+            vec![("length", Value::Float(self.queue.len() as f64))],
+        ));
+        let update = StateUpdate::Notifications(self.persisted.clone());
+        let _ = self.state_updates.send((update, Span::current())).await;
+    }
+}
+
+/// Returns the local time of day at the given time. (Without a portable way to find the local
+/// time zone, this is UTC on platforms other than Unix.)
+fn local_time(time: SystemTime) -> TimeOfDay {
+    #[cfg(unix)]
+    {
+        let secs = status::unix_time(time) as libc::time_t;
+        // SAFETY: tm is a plain C struct, for which all-zeroes is a valid value.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        // SAFETY: both pointers are valid for the duration of the call; we check the result.
+        if !unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+            return TimeOfDay {
+                minutes: (tm.tm_hour * 60 + tm.tm_min) as u32,
+            };
+        }
+    }
+    TimeOfDay {
+        minutes: (status::unix_time(time) % (24 * 60 * 60) / 60) as u32,
+    }
+}
+
+/// A measurement of a notification being dropped, for the given reason.
+fn dropped(reason: &str) -> Measurement {
+    Measurement::new(
+        "notifications_dropped",
+        vec![("reason".to_string(), reason.to_string())],
+        vec![("count", Value::Float(1.0))],
+    )
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    webhook: &WebhookConfig,
+    notification: &Notification,
+) -> Result<()> {
+    let body = match webhook.format {
+        WebhookFormat::Json => serde_json::to_vec(notification)?,
+        WebhookFormat::Slack => serde_json::to_vec(&serde_json::json!({
+            "text": notification.summary(),
+        }))?,
+        WebhookFormat::Discord => {
+            let mut content = notification.summary();
+            if content.chars().count() > MAX_DISCORD_LEN {
+                content = content.chars().take(MAX_DISCORD_LEN - 1).collect();
+                content.push('…');
+            }
+            serde_json::to_vec(&serde_json::json!({ "content": content }))?
+        }
+    };
+    let mut req = client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(secret) = &webhook.secret {
+        req = req.header(SIGNATURE_HEADER, sign(secret.value(), &body));
+    }
+    req.body(body).send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Signs a body with a secret, returning `sha256=<hex-encoded HMAC-SHA256 of the body>`. The
+/// receiver authenticates a request by computing the same over the body it received, & comparing
+/// (in constant time) against the header.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    let mut signature = String::from("sha256=");
+    for byte in mac.finalize().into_bytes() {
+        let _ = write!(signature, "{byte:02x}");
+    }
+    signature
+}
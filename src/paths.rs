@@ -0,0 +1,120 @@
+//! Discovery of the config & state files when they aren't given on the command line. The user's
+//! own locations (per the XDG base directory spec) are searched before system-wide ones.
+
+use anyhow::{anyhow, Result};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+const CONFIG_FILE: &str = "config.yaml";
+const STATE_FILE: &str = "state.yaml";
+
+/// The `--state` which keeps the state in memory only, e.g. on a read-only filesystem.
+const NO_STATE: &str = "none";
+
+/// Returns the config files to use: `args` if any are given, else the first default location
+/// which exists.
+pub fn config(args: &[OsString]) -> Result<Vec<OsString>> {
+    if !args.is_empty() {
+        return Ok(args.to_vec());
+    }
+    let candidates = candidates(config_dirs(), CONFIG_FILE);
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(vec![path.clone().into_os_string()]),
+        None => Err(anyhow!(
+            "--config not given, and no config file found at {}",
+            list(&candidates)
+        )),
+    }
+}
+
+/// Returns the state file to use: `arg` if given, else the first default location which exists,
+/// else the first default location whose directory exists (where the state file will be created).
+/// Returns None if `arg` is `none`, in which case the state is kept in memory only.
+pub fn state(arg: Option<&OsStr>) -> Result<Option<OsString>> {
+    match arg {
+        Some(arg) if arg == NO_STATE => return Ok(None),
+        Some(arg) => return Ok(Some(arg.to_owned())),
+        None => (),
+    }
+    let candidates = candidates(state_dirs(), STATE_FILE);
+    let existing = candidates.iter().find(|path| path.is_file());
+    let path = existing.or_else(|| {
+        candidates
+            .iter()
+            .find(|path| path.parent().is_some_and(Path::is_dir))
+    });
+    match path {
+        Some(path) => Ok(Some(path.clone().into_os_string())),
+        None => Err(anyhow!(
+            "--state not given, and no directory for a state file found at {}",
+            list(&candidates)
+        )),
+    }
+}
+
+fn candidates(dirs: Vec<PathBuf>, file: &str) -> Vec<PathBuf> {
+    dirs.into_iter().map(|dir| dir.join(file)).collect()
+}
+
+fn list(paths: &[PathBuf]) -> String {
+    let paths: Vec<_> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    paths.join(", ")
+}
+
+#[cfg(unix)]
+fn config_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    dirs.extend(xdg_dir("XDG_CONFIG_HOME", ".config"));
+    dirs.push(PathBuf::from("/etc/rnccd"));
+    dirs
+}
+
+#[cfg(unix)]
+fn state_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    dirs.extend(xdg_dir("XDG_STATE_HOME", ".local/state"));
+    dirs.push(PathBuf::from("/var/lib/rnccd"));
+    dirs
+}
+
+/// Returns the rnccd directory within an XDG base directory: `$<var>/rnccd` if set (to an absolute
+/// path, per the spec), else `$HOME/<default>/rnccd`.
+#[cfg(unix)]
+fn xdg_dir(var: &str, default: &str) -> Option<PathBuf> {
+    let base = match env::var_os(var).map(PathBuf::from) {
+        Some(base) if base.is_absolute() => base,
+        _ => PathBuf::from(env::var_os("HOME")?).join(default),
+    };
+    Some(base.join("rnccd"))
+}
+
+#[cfg(windows)]
+fn config_dirs() -> Vec<PathBuf> {
+    program_data_dir().into_iter().collect()
+}
+
+#[cfg(windows)]
+fn state_dirs() -> Vec<PathBuf> {
+    program_data_dir().into_iter().collect()
+}
+
+#[cfg(windows)]
+fn program_data_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(env::var_os("PROGRAMDATA")?).join("rnccd"))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn config_dirs() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn state_dirs() -> Vec<PathBuf> {
+    Vec::new()
+}
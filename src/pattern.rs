@@ -0,0 +1,287 @@
+//! A small regular expression matcher, for matching provider responses. I don't want to depend on
+//! an entire regex engine, so it supports only as much of the usual syntax as that needs: literals,
+//! `.`, character classes (`[a-z]`, `[^0-9]`, `\d`, `\w`, `\s`, & their negations `\D`, `\W`, &
+//! `\S`), the quantifiers `*`, `+`, & `?`, the anchors `^` & `$`, & `|` between whole alternatives.
+//! Groups aren't supported, nor are other escapes of letters or digits (e.g. `\b`).
+
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{iter::Peekable, str::Chars};
+
+/// A compiled pattern, which (de)serializes as its source.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern {
+    source: String,
+    branches: Vec<Branch>,
+}
+
+/// An alternative of a pattern: a sequence of pieces, optionally anchored at either end.
+#[derive(Clone, Default)]
+struct Branch {
+    anchored_start: bool,
+    anchored_end: bool,
+    pieces: Vec<Piece>,
+}
+
+/// An atom, repeated between `min` & `max` times.
+#[derive(Clone)]
+struct Piece {
+    atom: Atom,
+    min: usize,
+    max: usize,
+}
+
+#[derive(Clone)]
+enum Atom {
+    Any,
+    Char(char),
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Self> {
+        let mut branches = vec![Branch::default()];
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            let branch = branches.last_mut().unwrap();
+            let atom = match c {
+                '^' if branch.pieces.is_empty() && !branch.anchored_start => {
+                    branch.anchored_start = true;
+                    continue;
+                }
+                '$' if matches!(chars.peek(), None | Some('|')) => {
+                    branch.anchored_end = true;
+                    continue;
+                }
+                '|' => {
+                    branches.push(Branch::default());
+                    continue;
+                }
+                '*' | '+' | '?' => {
+                    let piece = branch
+                        .pieces
+                        .last_mut()
+                        .filter(|piece| (piece.min, piece.max) == (1, 1))
+                        .ok_or_else(|| anyhow!("{c:?} doesn't follow something to repeat"))?;
+                    (piece.min, piece.max) = match c {
+                        '*' => (0, usize::MAX),
+                        '+' => (1, usize::MAX),
+                        _ => (0, 1),
+                    };
+                    continue;
+                }
+                '(' | ')' | '{' | '}' => return Err(anyhow!("{c:?} isn't supported")),
+                '.' => Atom::Any,
+                '\\' => escape(chars.next().ok_or_else(|| anyhow!("trailing \\"))?)?,
+                '[' => class(&mut chars)?,
+                c => Atom::Char(c),
+            };
+            branch.pieces.push(Piece {
+                atom,
+                min: 1,
+                max: 1,
+            });
+        }
+        Ok(Self {
+            source: source.to_string(),
+            branches,
+        })
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.branches.iter().any(|branch| {
+            let starts = match branch.anchored_start {
+                true => 0..=0,
+                false => 0..=text.len(),
+            };
+            starts
+                .into_iter()
+                .any(|start| branch.matches(&branch.pieces, &text[start..]))
+        })
+    }
+}
+
+impl Branch {
+    /// Whether `pieces` match a prefix of `text` (all of it, if anchored at the end).
+    fn matches(&self, pieces: &[Piece], text: &[char]) -> bool {
+        let Some((piece, rest)) = pieces.split_first() else {
+            return !self.anchored_end || text.is_empty();
+        };
+        let mut n = 0;
+        while n < piece.max && n < text.len() && piece.atom.matches(text[n]) {
+            n += 1;
+        }
+        // Match greedily, backtracking as needed.
+        (piece.min..=n)
+            .rev()
+            .any(|n| self.matches(rest, &text[n..]))
+    }
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Char(want) => c == *want,
+            Atom::Class { ranges, negated } => {
+                ranges
+                    .iter()
+                    .any(|&(start, end)| (start..=end).contains(&c))
+                    != *negated
+            }
+        }
+    }
+}
+
+/// Parses a character class, following its opening `[`.
+fn class(chars: &mut Peekable<Chars>) -> Result<Atom> {
+    let negated = chars.next_if_eq(&'^').is_some();
+    let mut ranges = Vec::new();
+    loop {
+        let start = match chars.next().ok_or_else(|| anyhow!("unclosed ["))? {
+            ']' if !ranges.is_empty() => return Ok(Atom::Class { ranges, negated }),
+            '\\' => match escape(chars.next().ok_or_else(|| anyhow!("unclosed ["))?)? {
+                Atom::Class { negated: true, .. } => {
+                    return Err(anyhow!("\\D, \\W, & \\S aren't supported within [...]"));
+                }
+                Atom::Class { ranges: class, .. } => {
+                    ranges.extend(class);
+                    continue;
+                }
+                Atom::Char(c) => c,
+                Atom::Any => unreachable!("escapes never match any character"),
+            },
+            c => c,
+        };
+        // A `-` ending the class is a literal, rather than making a range.
+        let is_range = chars.peek() == Some(&'-') && chars.clone().nth(1) != Some(']');
+        let end = match is_range {
+            true => {
+                chars.next();
+                chars.next().ok_or_else(|| anyhow!("unclosed ["))?
+            }
+            false => start,
+        };
+        ranges.push((start, end));
+    }
+}
+
+/// Returns the atom matched by an escape sequence, `\c`. Escaped punctuation is a literal.
+fn escape(c: char) -> Result<Atom> {
+    let class = |ranges: &[(char, char)]| Atom::Class {
+        ranges: ranges.to_vec(),
+        negated: c.is_ascii_uppercase(),
+    };
+    Ok(match c {
+        'd' | 'D' => class(&[('0', '9')]),
+        'w' | 'W' => class(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+        's' | 'S' => class(&[(' ', ' '), ('\t', '\r')]),
+        'n' => Atom::Char('\n'),
+        't' => Atom::Char('\t'),
+        c if c.is_alphanumeric() => return Err(anyhow!("\\{c} isn't supported")),
+        c => Atom::Char(c),
+    })
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        Pattern::new(&source).map_err(|err| anyhow!("invalid pattern {source:?}: {err}"))
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> String {
+        pattern.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn literals() {
+        assert!(is_match("good", "status: good"));
+        assert!(!is_match("good", "status: bad"));
+        assert!(is_match("a.c", "abc"));
+        assert!(is_match(r"a\.c", "a.c"));
+        assert!(!is_match(r"a\.c", "abc"));
+    }
+
+    #[test]
+    fn anchors() {
+        assert!(is_match("^good", "good: yes"));
+        assert!(!is_match("^good", "not good"));
+        assert!(is_match("good$", "not good"));
+        assert!(!is_match("good$", "good: no"));
+        assert!(is_match("^$", ""));
+        assert!(!is_match("^$", "x"));
+        // Anchors elsewhere are literals.
+        assert!(is_match("a^b", "a^b"));
+        assert!(is_match("a$b", "a$b"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(is_match("^[a-c]+$", "abcba"));
+        assert!(!is_match("^[a-c]+$", "abcd"));
+        assert!(is_match("^[^0-9]+$", "abc"));
+        assert!(!is_match("^[^0-9]+$", "ab1"));
+        assert!(is_match("^[a-]$", "-"));
+        assert!(is_match(r"^\d+$", "0123"));
+        assert!(!is_match(r"^\d+$", "01a"));
+        assert!(is_match(r"^\w\s\w$", "a\tb"));
+        assert!(is_match(r"^[\d.]+$", "10.0.0.1"));
+    }
+
+    #[test]
+    fn negated_classes() {
+        assert!(is_match(r"^\D+$", "abc"));
+        assert!(!is_match(r"^\D+$", "a1"));
+        assert!(is_match(r"^\W$", "-"));
+        assert!(!is_match(r"^\W$", "_"));
+        assert!(is_match(r"^\S+$", "nospace"));
+        assert!(!is_match(r"^\S+$", "a space"));
+    }
+
+    #[test]
+    fn alternatives() {
+        assert!(is_match("^good$|^nochg", "good"));
+        assert!(is_match("^good$|^nochg", "nochg 1.2.3.4"));
+        assert!(!is_match("^good$|^nochg", "good 1.2.3.4"));
+        assert!(!is_match("^good$|^nochg", "badauth"));
+    }
+
+    #[test]
+    fn backtracking() {
+        assert!(is_match("^a*ab$", "aaab"));
+        assert!(is_match("^.*b.*c$", "abbbc"));
+        assert!(is_match("^a?a$", "a"));
+        assert!(is_match(r"^\d+\.\d+$", "12.34"));
+        assert!(!is_match("^a+b$", "aaa"));
+    }
+
+    #[test]
+    fn invalid() {
+        for pattern in [
+            "*a", "a**", "(a)", "a{2}", r"\b", r"\x41", r"[\D]", "[a", "a\\",
+        ] {
+            assert!(
+                Pattern::new(pattern).is_err(),
+                "{pattern:?} should be invalid"
+            );
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::Result;
+
+/// Drops privileges, switching to the given user and/or group. If a user is given without a
+/// group, the user's primary group is used. Supplementary groups are always cleared.
+///
+/// This must be called before any other threads are started, and before sandboxing (which may
+/// forbid changing IDs).
+#[cfg(unix)]
+pub fn drop(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    unix::drop(user, group)
+}
+
+#[cfg(not(unix))]
+pub fn drop(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "dropping privileges is not supported on this platform"
+    ))
+}
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{anyhow, Context, Result};
+    use std::{ffi::CString, io, mem, ptr};
+    use tracing::info;
+
+    /// The initial size of the buffer passed to getpwnam_r/getgrnam_r; doubled as needed.
+    const INITIAL_BUF_LEN: usize = 1024;
+
+    struct User {
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+    }
+
+    pub fn drop(user: Option<&str>, group: Option<&str>) -> Result<()> {
+        let user = user
+            .map(|user| {
+                lookup_user(user).with_context(|| format!("couldn't look up user {user:?}"))
+            })
+            .transpose()?;
+        let gid = match (group, &user) {
+            (Some(group), _) => {
+                lookup_group(group).with_context(|| format!("couldn't look up group {group:?}"))?
+            }
+            (None, Some(user)) => user.gid,
+            (None, None) => return Ok(()),
+        };
+
+        // Having re-executed ourselves (to reload the config), we've already dropped privileges.
+        // SAFETY: getuid/getgid have no memory-safety preconditions.
+        let (uid, current_gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if uid != 0 && current_gid == gid && user.as_ref().is_none_or(|user| user.uid == uid) {
+            return Ok(());
+        }
+
+        // Groups must be changed first: once we've given up our user, we can no longer change them.
+        // SAFETY: we pass a pointer to a single gid, along with a length of 1.
+        check(unsafe { libc::setgroups(1, &gid) })
+            .context("couldn't clear supplementary groups")?;
+        // SAFETY: setgid/setuid have no memory-safety preconditions.
+        check(unsafe { libc::setgid(gid) }).context("couldn't set group")?;
+        if let Some(user) = &user {
+            check(unsafe { libc::setuid(user.uid) }).context("couldn't set user")?;
+
+            // Paranoia: make sure we can't get root back.
+            if user.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(anyhow!("regained root after dropping privileges"));
+            }
+        }
+
+        // SAFETY: getuid/getgid have no memory-safety preconditions.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        info!(uid, gid, "Dropped privileges");
+        Ok(())
+    }
+
+    fn lookup_user(name: &str) -> Result<User> {
+        let c_name = CString::new(name)?;
+        let mut buf_len = INITIAL_BUF_LEN;
+        loop {
+            let mut buf = vec![0 as libc::c_char; buf_len];
+            // SAFETY: passwd is a plain C struct, for which all-zeroes is a valid value.
+            let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            // SAFETY: all pointers are valid for the duration of the call, & buf_len is buf's true
+            // length.
+            let rslt = unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf_len,
+                    &mut result,
+                )
+            };
+            match rslt {
+                0 if result.is_null() => return Err(anyhow!("no such user")),
+                0 => {
+                    return Ok(User {
+                        uid: pwd.pw_uid,
+                        gid: pwd.pw_gid,
+                    })
+                }
+                libc::ERANGE => buf_len *= 2,
+                errno => return Err(io::Error::from_raw_os_error(errno).into()),
+            }
+        }
+    }
+
+    fn lookup_group(name: &str) -> Result<libc::gid_t> {
+        let c_name = CString::new(name)?;
+        let mut buf_len = INITIAL_BUF_LEN;
+        loop {
+            let mut buf = vec![0 as libc::c_char; buf_len];
+            // SAFETY: group is a plain C struct, for which all-zeroes is a valid value.
+            let mut grp: libc::group = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            // SAFETY: all pointers are valid for the duration of the call, & buf_len is buf's true
+            // length.
+            let rslt = unsafe {
+                libc::getgrnam_r(
+                    c_name.as_ptr(),
+                    &mut grp,
+                    buf.as_mut_ptr(),
+                    buf_len,
+                    &mut result,
+                )
+            };
+            match rslt {
+                0 if result.is_null() => return Err(anyhow!("no such group")),
+                0 => return Ok(grp.gr_gid),
+                libc::ERANGE => buf_len *= 2,
+                errno => return Err(io::Error::from_raw_os_error(errno).into()),
+            }
+        }
+    }
+
+    fn check(rslt: libc::c_int) -> io::Result<()> {
+        if rslt < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
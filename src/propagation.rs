@@ -0,0 +1,160 @@
+//! Measurement of how long an update takes to propagate: after an update, public resolvers are
+//! polled until each returns the new address. A resolver still returning the old address after
+//! the old record's TTL (plus a grace period) has expired is reported as a propagation failure.
+
+use crate::{
+    backoff::Backoff,
+    config::PropagationConfig,
+    dns,
+    metrics::{Measurement, Metrics},
+    notify::{Event, Notification, Notifier},
+    worker::Shared,
+};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::Instant};
+use tracing::{debug, error, info, warn, Instrument, Span};
+
+/// The minimum & maximum delays between polls of the resolvers.
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Starts measuring propagation of `record`'s update to `addr`, in the current span. The
+/// propagation delay to each resolver is logged, & recorded as a metric tagged with `tags` & the
+/// resolver. Propagation failures are notified, with `labels`.
+pub fn spawn(
+    cfg: Arc<PropagationConfig>,
+    shared: &Shared,
+    tags: Vec<(String, String)>,
+    labels: BTreeMap<String, String>,
+    record: String,
+    addr: Ipv4Addr,
+) -> JoinHandle<()> {
+    let propagation = Propagation {
+        cfg,
+        metrics: shared.metrics.clone(),
+        notifier: shared.notifier.clone(),
+        tags,
+        labels,
+        record,
+        addr,
+    };
+    tokio::spawn(propagation.measure().instrument(Span::current()))
+}
+
+/// Propagation is the propagation of a single update to a record.
+struct Propagation {
+    cfg: Arc<PropagationConfig>,
+    metrics: Metrics,
+    notifier: Notifier,
+    tags: Vec<(String, String)>,
+    labels: BTreeMap<String, String>,
+    record: String,
+    addr: Ipv4Addr,
+}
+
+impl Propagation {
+    async fn measure(self) {
+        let (record, addr) = (&self.record, self.addr);
+        let start = Instant::now();
+        let mut pending: Vec<_> = self
+            .cfg
+            .resolvers
+            .iter()
+            .map(|&resolver| Pending {
+                resolver,
+                deadline: None,
+                failed: false,
+            })
+            .collect();
+        let mut backoff = Backoff::new(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL);
+        loop {
+            let mut still_pending = Vec::new();
+            for mut p in pending {
+                let records = match dns::query_a(p.resolver, record).await {
+                    Ok(records) => records,
+                    Err(err) => {
+                        debug!(
+                            record,
+                            resolver = %p.resolver,
+                            err = format!("{err:#}"),
+                            "Couldn't query resolver"
+                        );
+                        still_pending.push(p);
+                        continue;
+                    }
+                };
+                if records.iter().any(|r| r.addr == addr) {
+                    let delay = start.elapsed();
+                    info!(record, resolver = %p.resolver, ?delay, "Update propagated to resolver");
+                    self.record_measurement(p.resolver, true, delay);
+                    continue;
+                }
+
+                // The resolver may return the old record until its TTL (as of the first time we
+                // saw it) expires, plus some grace for slow authoritative updates.
+                let now = Instant::now();
+                let ttl = records.iter().map(|r| r.ttl).max().unwrap_or_default();
+                let deadline = *p.deadline.get_or_insert(now + ttl + self.cfg.grace());
+                if now >= deadline && !p.failed {
+                    p.failed = true;
+                    error!(
+                        target: crate::NOTABLE_TARGET,
+                        record,
+                        resolver = %p.resolver,
+                        expected = ?addr,
+                        returned = ?records.iter().map(|r| r.addr).collect::<Vec<_>>(),
+                        "Propagation failed: resolver still returns old address after TTL & grace \
+                         period"
+                    );
+                    self.notifier.notify(Notification::new(
+                        Event::PropagationFailed {
+                            resolver: p.resolver,
+                            expected_addr: addr,
+                        },
+                        record.clone(),
+                        self.labels.clone(),
+                    ));
+                }
+                still_pending.push(p);
+            }
+            pending = still_pending;
+            if pending.is_empty() {
+                return;
+            }
+
+            if start.elapsed() >= self.cfg.timeout() {
+                let resolvers: Vec<_> = pending.iter().map(|p| p.resolver).collect();
+                for &resolver in &resolvers {
+                    self.record_measurement(resolver, false, start.elapsed());
+                }
+                warn!(
+                    record,
+                    ?resolvers,
+                    timeout = ?self.cfg.timeout(),
+                    "Update hasn't propagated to all resolvers, no longer measuring"
+                );
+                return;
+            }
+            tokio::time::sleep(backoff.failure()).await;
+        }
+    }
+
+    fn record_measurement(&self, resolver: IpAddr, success: bool, delay: Duration) {
+        let mut tags = self.tags.clone();
+        tags.push(("resolver".to_string(), resolver.to_string()));
+        self.metrics
+            .record(Measurement::operation("propagation", tags, success, delay));
+    }
+}
+
+/// Pending tracks a resolver which hasn't yet returned the new address.
+struct Pending {
+    resolver: IpAddr,
+    deadline: Option<Instant>, // when the resolver should stop returning the old address.
+    failed: bool,              // whether propagation failure has been reported.
+}
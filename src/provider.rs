@@ -0,0 +1,210 @@
+use crate::{
+    config::{CallConfig, ProviderConfig},
+    HttpSettings,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::{self, Display, Formatter},
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+};
+
+pub type UpdateFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+pub type CheckFuture<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+pub type ReconcileFuture<'a> = Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>>;
+
+/// Provider is a dynamic DNS provider, responsible for keeping a single record up to date.
+pub trait Provider: Send + Sync {
+    /// Identifies the provider & record, e.g. `namecheap:www.example.com`. This is used in logs,
+    /// and to key per-provider state.
+    fn id(&self) -> &str;
+
+    /// Identifies the account the provider's calls are made with, e.g. `namecheap:example.com`. A
+    /// lockout by the provider applies to every record updated with the account. Defaults to the
+    /// provider's ID.
+    fn account(&self) -> &str {
+        self.id()
+    }
+
+    /// The URL of the provider's API endpoint.
+    fn endpoint(&self) -> &str;
+
+    /// The fully-qualified name of the record, e.g. `www.example.com`.
+    fn record(&self) -> &str;
+
+    /// How calls to the provider are made. Requests are expected to honor the timeout; failed
+    /// updates are retried by the caller.
+    fn calls(&self) -> &CallConfig;
+
+    /// The type of record updated, which determines the family of the addresses it's given.
+    fn record_type(&self) -> RecordType {
+        RecordType::A
+    }
+
+    /// Updates the record to point at the given address.
+    fn update<'a>(&'a self, client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a>;
+
+    /// Checks that the provider accepts our credentials, without changing the record, returning
+    /// a description of how. `current_addr` is the address the record currently points at in
+    /// public DNS, if that's known & public. By default, this "updates" the record to its current
+    /// address, which is a no-op, & so can't be done if it isn't known.
+    fn check_credentials<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        current_addr: Option<IpAddr>,
+    ) -> CheckFuture<'a> {
+        check_by_update(self, client, current_addr)
+    }
+
+    /// Corrects any drift of the record's configured settings (e.g. its TTL, if edited by hand),
+    /// given that it should point at `addr`. The future returns true if anything was corrected.
+    /// Returns None if there are no settings to enforce, which is the default.
+    fn reconcile<'a>(
+        &'a self,
+        _client: &'a reqwest::Client,
+        _addr: IpAddr,
+    ) -> Option<ReconcileFuture<'a>> {
+        None
+    }
+
+    /// Whether the provider is a test environment, whose records aren't served in public DNS.
+    fn is_sandbox(&self) -> bool {
+        false
+    }
+}
+
+/// Checks that a provider accepts our credentials by "updating" the record to its current
+/// address. Without one, the check fails rather than risk changing the record.
+pub fn check_by_update<'a, P: Provider + ?Sized>(
+    provider: &'a P,
+    client: &'a reqwest::Client,
+    current_addr: Option<IpAddr>,
+) -> CheckFuture<'a> {
+    Box::pin(async move {
+        let current_addr = current_addr.ok_or_else(|| {
+            anyhow!(
+                "can't verify credentials without modifying {}, as its current address isn't known",
+                provider.record()
+            )
+        })?;
+        provider.update(client, current_addr).await?;
+        Ok(format!(
+            "re-set {} to its current address, {current_addr}",
+            provider.record()
+        ))
+    })
+}
+
+/// Creates the providers described by the given config: one per type of record to update. Any
+/// clients of their own (e.g. a relay's) are built from `http`.
+pub fn new(cfg: &ProviderConfig, http: &HttpSettings) -> Result<Vec<Arc<dyn Provider>>> {
+    #[cfg(not(feature = "relay"))]
+    let _ = http; // Only relays have clients of their own.
+    Ok(match cfg {
+        #[cfg(feature = "namecheap")]
+        ProviderConfig::Namecheap(cfg) => cfg
+            .record_types
+            .types()
+            .into_iter()
+            .map(|record_type| -> Arc<dyn Provider> {
+                Arc::new(crate::namecheap::Namecheap::new(cfg.clone(), record_type))
+            })
+            .collect(),
+        #[cfg(feature = "cloudflare")]
+        ProviderConfig::Cloudflare(cfg) => cfg
+            .record_types
+            .types()
+            .into_iter()
+            .map(|record_type| -> Arc<dyn Provider> {
+                Arc::new(crate::cloudflare::Cloudflare::new(cfg.clone(), record_type))
+            })
+            .collect(),
+        #[cfg(feature = "duckdns")]
+        ProviderConfig::DuckDns(cfg) => cfg
+            .record_types
+            .types()
+            .into_iter()
+            .map(|record_type| -> Arc<dyn Provider> {
+                Arc::new(crate::duckdns::DuckDns::new(cfg.clone(), record_type))
+            })
+            .collect(),
+        #[cfg(feature = "noip")]
+        ProviderConfig::NoIp(cfg) => cfg
+            .record_types
+            .types()
+            .into_iter()
+            .map(|record_type| -> Arc<dyn Provider> {
+                Arc::new(crate::noip::NoIp::new(cfg.clone(), record_type))
+            })
+            .collect(),
+        #[cfg(feature = "custom")]
+        ProviderConfig::Custom(cfg) => cfg
+            .record_types
+            .types()
+            .into_iter()
+            .map(|record_type| -> Arc<dyn Provider> {
+                Arc::new(crate::custom::Custom::new(cfg.clone(), record_type))
+            })
+            .collect(),
+        #[cfg(feature = "relay")]
+        ProviderConfig::Relay(cfg) => {
+            let client = crate::relay::Relay::client(cfg, http)?;
+            cfg.record_types
+                .types()
+                .into_iter()
+                .map(|record_type| -> Arc<dyn Provider> {
+                    Arc::new(crate::relay::Relay::new(
+                        cfg.clone(),
+                        record_type,
+                        client.clone(),
+                    ))
+                })
+                .collect()
+        }
+    })
+}
+
+/// A type of address record.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    /// An IPv4 address record.
+    A,
+    /// An IPv6 address record.
+    Aaaa,
+}
+
+impl RecordType {
+    /// Whether the given address can be published in a record of this type.
+    pub fn matches(self, addr: IpAddr) -> bool {
+        match self {
+            RecordType::A => addr.is_ipv4(),
+            RecordType::Aaaa => addr.is_ipv6(),
+        }
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RecordType::A => "A",
+            RecordType::Aaaa => "AAAA",
+        })
+    }
+}
+
+/// Returns the fully-qualified name of `host` within `domain`, where `@` is the bare domain.
+#[cfg(any(
+    feature = "namecheap",
+    feature = "cloudflare",
+    feature = "duckdns",
+    feature = "custom",
+    feature = "relay"
+))]
+pub fn record_name(domain: &str, host: &str) -> String {
+    match host {
+        "@" => domain.to_string(),
+        host => format!("{host}.{domain}"),
+    }
+}
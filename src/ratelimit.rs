@@ -0,0 +1,135 @@
+//! Scheduling of provider calls under a requests-per-minute budget shared by all workers, & one per
+//! account, so that many hosts on one account don't trip the provider's rate limits (nor starve
+//! other accounts' calls). When calls must wait, those updating stale records go before routine
+//! ones.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{self, Instant},
+};
+use tracing::debug;
+
+/// Waits longer than this for the rate limiter are logged.
+const NOTABLE_WAIT: Duration = Duration::from_secs(1);
+
+/// The priority of a provider call.
+#[derive(Clone, Copy, Debug)]
+pub enum Priority {
+    /// A call updating a record which doesn't have our address.
+    Stale = 0,
+    /// Any other call, e.g. a check for drift of a record's settings.
+    Routine = 1,
+}
+
+type Scheduler = mpsc::UnboundedSender<(Priority, oneshot::Sender<()>)>;
+
+/// RateLimiter is a handle used to wait for permission to make a provider call.
+#[derive(Clone)]
+pub struct RateLimiter {
+    all: Option<Scheduler>,
+    accounts: Arc<HashMap<String, Scheduler>>,
+}
+
+impl RateLimiter {
+    /// Waits until a call of the given priority may be made with the given account.
+    pub async fn acquire(&self, account: &str, priority: Priority) {
+        let start = Instant::now();
+        for scheduler in [self.accounts.get(account), self.all.as_ref()] {
+            let Some(scheduler) = scheduler else {
+                continue;
+            };
+            let (grant_tx, grant_rx) = oneshot::channel();
+            if scheduler.send((priority, grant_tx)).is_ok() {
+                let _ = grant_rx.await;
+            }
+        }
+        let waited = start.elapsed();
+        if waited > NOTABLE_WAIT {
+            debug!(
+                account,
+                ?priority,
+                ?waited,
+                "Waited for provider rate limit"
+            );
+        }
+    }
+}
+
+/// Starts the schedulers: one for all calls, if there's a budget for them, & one for each of the
+/// given accounts, if there's a budget per account. Without budgets, calls are never delayed.
+pub fn spawn(
+    requests_per_minute: Option<u32>,
+    account_requests_per_minute: Option<u32>,
+    accounts: &[&str],
+) -> RateLimiter {
+    let accounts = match account_requests_per_minute {
+        Some(_) => accounts
+            .iter()
+            .filter_map(|&account| {
+                let scheduler = spawn_scheduler(account_requests_per_minute)?;
+                Some((account.to_string(), scheduler))
+            })
+            .collect(),
+        None => HashMap::new(),
+    };
+    RateLimiter {
+        all: spawn_scheduler(requests_per_minute),
+        accounts: Arc::new(accounts),
+    }
+}
+
+/// Starts a scheduler, if there's a budget.
+fn spawn_scheduler(requests_per_minute: Option<u32>) -> Option<Scheduler> {
+    let budget = requests_per_minute.filter(|&budget| budget > 0)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(schedule(budget, rx));
+    Some(tx)
+}
+
+/// Grants requests from a token bucket holding up to a minute's budget, which is refilled evenly
+/// over the minute. Returns once all handles have been dropped.
+async fn schedule(budget: u32, mut rx: mpsc::UnboundedReceiver<(Priority, oneshot::Sender<()>)>) {
+    let refill_interval = Duration::from_secs(60) / budget;
+    let mut tokens = budget;
+    let mut last_refill = Instant::now();
+    let mut queues: [VecDeque<oneshot::Sender<()>>; 2] = Default::default();
+    loop {
+        let refills = last_refill.elapsed().as_nanos() / refill_interval.as_nanos();
+        let refills = u32::try_from(refills).unwrap_or(u32::MAX);
+        tokens = tokens.saturating_add(refills).min(budget);
+        last_refill += refill_interval * refills.min(budget);
+        if tokens == budget {
+            // A full bucket doesn't accumulate credit.
+            last_refill = Instant::now();
+        }
+
+        // Grant waiting requests, highest priority first, while there are tokens.
+        while tokens > 0 {
+            let Some(grant) = queues.iter_mut().find_map(VecDeque::pop_front) else {
+                break;
+            };
+            // The requester may have given up (e.g. its worker was restarted).
+            if grant.send(()).is_ok() {
+                tokens -= 1;
+            }
+        }
+
+        let rslt = if queues.iter().all(VecDeque::is_empty) {
+            rx.recv().await
+        } else {
+            tokio::select! {
+                rslt = rx.recv() => rslt,
+                () = time::sleep_until(last_refill + refill_interval) => continue,
+            }
+        };
+        let Some((priority, grant)) = rslt else {
+            return;
+        };
+        queues[priority as usize].push_back(grant);
+    }
+}
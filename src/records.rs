@@ -0,0 +1,218 @@
+//! Listing of a domain's records, as the provider has them, so that what rnccd sees can be checked
+//! before it's told to manage more records; & adoption of existing records, so that they're
+//! managed from then on.
+
+use crate::{
+    config::{Config, NamecheapConfig, ProviderConfig},
+    namecheap::Host,
+    provider::{self, Provider, RecordType},
+    state,
+    status::Output,
+    HttpSettings,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::Write,
+    iter,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+use tempfile::NamedTempFile;
+
+/// Formats a domain's host records.
+pub fn render(hosts: &[Host], output: Output) -> Result<String> {
+    Ok(match output {
+        Output::Table => table(hosts),
+        Output::Json => serde_json::to_string_pretty(hosts)?,
+    })
+}
+
+/// Formats host records as a table, with a row per record.
+fn table(hosts: &[Host]) -> String {
+    let mut rows = vec![["NAME", "TYPE", "TTL", "MX PREF", "ADDRESS"].map(String::from)];
+    for host in hosts {
+        rows.push([
+            host.name.clone(),
+            host.record_type.clone(),
+            host.ttl.clone().unwrap_or_else(|| "-".to_string()),
+            host.mx_pref.clone().unwrap_or_else(|| "-".to_string()),
+            host.address.clone(),
+        ]);
+    }
+
+    // Pad every column but the last (which may be long) to its widest cell.
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let lines: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .enumerate()
+                .map(|(i, (cell, width))| match i {
+                    4 => cell.clone(),
+                    _ => format!("{cell:width$}"),
+                })
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Adopts the `host` records of `domain`, given the domain's host records: adds the host to the
+/// domain's provider in the first config file (unless it's already managed), & notes the records'
+/// addresses in the state. Returns a description of what was done. The daemon mustn't be running,
+/// as it would overwrite the state.
+pub fn adopt(
+    config_paths: &[OsString],
+    state_path: &OsStr,
+    domain: &str,
+    host: &str,
+    hosts: &[Host],
+) -> Result<String> {
+    let record = provider::record_name(domain, host);
+    let addrs: Vec<(RecordType, IpAddr)> = hosts
+        .iter()
+        .filter(|existing| existing.name == host)
+        .filter_map(|existing| {
+            let record_type = match existing.record_type.as_str() {
+                "A" => RecordType::A,
+                "AAAA" => RecordType::Aaaa,
+                _ => return None,
+            };
+            Some((record_type, existing.address.parse().ok()?))
+        })
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("{record} has no A or AAAA record"));
+    }
+
+    let mut out = Vec::new();
+    if providers(&crate::load_config(config_paths), domain, host)?.is_empty() {
+        let config_path = Path::new(&config_paths[0]);
+        let text = fs::read_to_string(config_path)?;
+        let mut value = serde_yaml::from_str(&text)?;
+        add_host(&mut value, domain, host)
+            .with_context(|| format!("couldn't add {host} to {}", config_path.display()))?;
+        replace_file(config_path, &serde_yaml::to_string(&value)?)
+            .with_context(|| format!("couldn't write {}", config_path.display()))?;
+        out.push(format!(
+            "Added {host} to {domain}'s hosts in {}",
+            config_path.display()
+        ));
+        let has_comments = text
+            .lines()
+            .any(|line| line.trim_start().starts_with('#') || line.contains(" #"));
+        if has_comments {
+            out.push(format!(
+                "Warning: {} was rewritten, & the comments in it were lost",
+                config_path.display()
+            ));
+        }
+    } else {
+        out.push(format!("{record} is already in the config"));
+    }
+
+    let cfg = crate::load_config(config_paths);
+    let providers = providers(&cfg, domain, host)?;
+    if providers.is_empty() {
+        return Err(anyhow!(
+            "{record} still isn't in the config (does a later config file override hosts?)"
+        ));
+    }
+    let mut state = state::read(state_path)?;
+    for provider in providers {
+        let addr = addrs
+            .iter()
+            .find(|&&(record_type, _)| record_type == provider.record_type());
+        if let Some(&(_, addr)) = addr {
+            state.provider_addrs.insert(provider.id().to_string(), addr);
+            out.push(format!("Noted that {} has {addr}", provider.id()));
+        }
+    }
+    state::update_state(state_path, &state, cfg.durable_state)?;
+    Ok(out.join("\n"))
+}
+
+/// Returns the configured Namecheap providers of the given domain & host.
+fn providers(cfg: &Config, domain: &str, host: &str) -> Result<Vec<Arc<dyn Provider>>> {
+    let http = HttpSettings::new(cfg);
+    let mut providers = Vec::new();
+    for provider in iter::once(&cfg.provider)
+        .chain(&cfg.backup_providers)
+        .flat_map(ProviderConfig::per_record)
+        .filter(|provider| {
+            let is_host = |cfg: &NamecheapConfig| cfg.domain == domain && cfg.host() == host;
+            matches!(provider, ProviderConfig::Namecheap(cfg) if is_host(cfg))
+        })
+    {
+        providers.extend(provider::new(&provider, &http)?);
+    }
+    Ok(providers)
+}
+
+/// Replaces a file's contents, keeping its permissions. The new contents are written to a temporary
+/// file which is then renamed over it, so the file is never left half-written.
+fn replace_file(path: &Path, contents: &str) -> Result<()> {
+    // Replace a symlink's target, rather than the symlink.
+    let path = fs::canonicalize(path)?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("couldn't determine parent directory of {}", path.display()))?;
+    let mut temp_file = NamedTempFile::new_in(dir)?;
+    temp_file.write_all(contents.as_bytes())?;
+    temp_file
+        .as_file()
+        .set_permissions(fs::metadata(&path)?.permissions())?;
+    temp_file.persist(&path)?;
+    Ok(())
+}
+
+/// Adds a host to the hosts of the domain's Namecheap provider (at the top level, or among the
+/// backup providers) in a config file.
+fn add_host(cfg: &mut serde_yaml::Value, domain: &str, host: &str) -> Result<()> {
+    let is_provider = |value: &serde_yaml::Value| {
+        value.get("domain").and_then(serde_yaml::Value::as_str) == Some(domain)
+            && value
+                .get("provider")
+                .and_then(serde_yaml::Value::as_str)
+                .is_none_or(|provider| provider == "namecheap")
+    };
+    let provider = if is_provider(cfg) {
+        cfg
+    } else {
+        cfg.get_mut("backup_providers")
+            .and_then(serde_yaml::Value::as_sequence_mut)
+            .and_then(|backups| backups.iter_mut().find(|backup| is_provider(backup)))
+            .ok_or_else(|| anyhow!("there's no Namecheap provider for {domain}"))?
+    };
+    let provider = provider
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("provider isn't a map"))?;
+
+    // Without a host, the first of the hosts is the provider's record, rather than the bare
+    // domain: keep it the bare domain.
+    let has_hosts = provider
+        .get("hosts")
+        .and_then(serde_yaml::Value::as_sequence)
+        .is_some_and(|hosts| !hosts.is_empty());
+    if !provider.contains_key("host") && !has_hosts {
+        provider.insert("host".into(), "@".into());
+    }
+    provider
+        .entry("hosts".into())
+        .or_insert_with(|| serde_yaml::Value::Sequence(Vec::new()))
+        .as_sequence_mut()
+        .ok_or_else(|| anyhow!("hosts isn't a list"))?
+        .push(host.into());
+    Ok(())
+}
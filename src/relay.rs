@@ -0,0 +1,439 @@
+//! Updates relayed through a central rnccd instance, for hosts (e.g. routers) which can't reach
+//! the providers, or shouldn't hold their credentials. The `relay` provider sends each update to
+//! the relay, which checks that the caller may update the record & makes the update with its own
+//! provider config. Both sides authenticate with certificates (i.e. mutual TLS): the relay knows
+//! its clients by their certificates' fingerprints.
+//!
+//! The protocol is a single call: `POST /v1/update` with a JSON body of `record`, `record_type`
+//! (`A` or `AAAA`), & `addr`. Success is any 2xx status; a failure's body gives its `error`, its
+//! `code` (if any), & whether it's `permanent` (i.e. retrying won't help).
+
+use crate::{
+    backoff::Permanent,
+    codes::{self, Code, Coded},
+    config::{CallConfig, ProviderConfig, RelayConfig, RelayServerConfig},
+    lockout::Lockout,
+    provider::{self, Provider, RecordType, UpdateFuture},
+    HttpSettings,
+};
+use anyhow::{anyhow, Context, Result};
+use hyper::{
+    body::HttpBody, header::CONTENT_TYPE, server::conn::Http, service::service_fn, Body, Method,
+    Request, Response, StatusCode,
+};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible, fmt::Write, fs, io::BufReader, net::IpAddr, net::SocketAddr, sync::Arc,
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::Mutex, time};
+use tokio_rustls::{
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{debug, info, warn};
+
+/// The path updates are sent to.
+const UPDATE_PATH: &str = "/v1/update";
+
+/// The longest a client may take to connect & send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum length of a request's body.
+const MAX_BODY_LEN: usize = 4096;
+
+#[derive(Deserialize, Serialize)]
+struct UpdateRequest {
+    record: String,
+    record_type: String,
+    addr: IpAddr,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ErrorResponse {
+    error: String,
+    code: Option<Code>,
+    #[serde(default)]
+    permanent: bool,
+}
+
+/// Relay updates a record by asking a relay to.
+pub struct Relay {
+    id: String,
+    record: String,
+    record_type: RecordType,
+    cfg: RelayConfig,
+    client: reqwest::Client,
+}
+
+impl Relay {
+    /// Creates a relay provider, which makes its calls with `client` (see [`Relay::client`]).
+    pub fn new(cfg: RelayConfig, record_type: RecordType, client: reqwest::Client) -> Self {
+        let record = provider::record_name(&cfg.domain, cfg.host());
+        let id = match record_type {
+            RecordType::A => format!("relay:{record}"),
+            RecordType::Aaaa => format!("relay:{record}/AAAA"),
+        };
+        Self {
+            id,
+            record,
+            record_type,
+            cfg,
+            client,
+        }
+    }
+
+    /// Builds a client which trusts only the relay's CA, & presents our certificate. It's built
+    /// once, at startup (the certificates may not be readable once sandboxed), with the same
+    /// settings as other clients, e.g. the proxy.
+    pub fn client(cfg: &RelayConfig, http: &HttpSettings) -> Result<reqwest::Client> {
+        let read = |path: &str| fs::read(path).with_context(|| format!("couldn't read {path}"));
+        let ca_cert = reqwest::Certificate::from_pem(&read(&cfg.ca_cert)?)
+            .with_context(|| format!("couldn't parse {}", cfg.ca_cert))?;
+        let mut identity = read(&cfg.client_cert)?;
+        identity.extend(read(&cfg.client_key)?);
+        let identity = reqwest::Identity::from_pem(&identity)
+            .with_context(|| format!("couldn't parse {} & {}", cfg.client_cert, cfg.client_key))?;
+        Ok(http
+            .builder(None)
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(ca_cert)
+            .identity(identity)
+            .build()?)
+    }
+
+    async fn update_address(&self, addr: IpAddr) -> Result<()> {
+        let req = UpdateRequest {
+            record: self.record.clone(),
+            record_type: self.record_type.to_string(),
+            addr,
+        };
+        let resp = self
+            .client
+            .post(format!(
+                "{}{UPDATE_PATH}",
+                self.cfg.url.trim_end_matches('/')
+            ))
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&req)?)
+            .timeout(self.cfg.calls.timeout())
+            .send()
+            .await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = resp.text().await?;
+        let Ok(err) = serde_json::from_str::<ErrorResponse>(&body) else {
+            return Err(anyhow!("relay refused update ({status}): {}", body.trim()));
+        };
+        let message = format!("relay refused update ({status}): {}", err.error);
+        Err(match (status, err.code) {
+            (StatusCode::TOO_MANY_REQUESTS, _) => Lockout { message }.into(),
+            (StatusCode::FORBIDDEN, _) => Permanent {
+                code: Code::ProviderBadCredentials,
+                message,
+            }
+            .into(),
+            (StatusCode::NOT_FOUND, _) => Permanent {
+                code: Code::ProviderNotFound,
+                message,
+            }
+            .into(),
+            (_, Some(code)) if err.permanent => Permanent { code, message }.into(),
+            (_, Some(code)) => Coded { code, message }.into(),
+            (_, None) => anyhow!(message),
+        })
+    }
+}
+
+impl Provider for Relay {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.cfg.url
+    }
+
+    fn record(&self) -> &str {
+        &self.record
+    }
+
+    fn calls(&self) -> &CallConfig {
+        &self.cfg.calls
+    }
+
+    fn record_type(&self) -> RecordType {
+        self.record_type
+    }
+
+    // Calls are made with our own client, which holds our certificate.
+    fn update<'a>(&'a self, _client: &'a reqwest::Client, addr: IpAddr) -> UpdateFuture<'a> {
+        Box::pin(self.update_address(addr))
+    }
+}
+
+/// Server makes updates on behalf of its clients.
+struct Server {
+    clients: Vec<Client>,
+    providers: Vec<(Arc<dyn Provider>, Mutex<()>)>, // each with a lock serializing its updates.
+    http: reqwest::Client,
+}
+
+struct Client {
+    name: String,
+    fingerprint: String,
+    records: Vec<String>,
+}
+
+/// Runs a relay on the given address forever, making updates with clients built from `http`.
+pub async fn serve(cfg: RelayServerConfig, listen: SocketAddr, http: HttpSettings) -> Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config(&cfg)?));
+    let server = Arc::new(Server::new(cfg, &http)?);
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("couldn't listen on {listen}"))?;
+    info!(%listen, "Serving relayed updates");
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                debug!(%err, "Couldn't accept connection");
+                continue;
+            }
+        };
+        let (acceptor, server) = (acceptor.clone(), Arc::clone(&server));
+        tokio::spawn(async move {
+            let tls = match time::timeout(REQUEST_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls)) => tls,
+                Ok(Err(err)) => return debug!(%peer, %err, "TLS handshake failed"),
+                Err(_) => return debug!(%peer, "Timed out during TLS handshake"),
+            };
+            let cert = tls.get_ref().1.peer_certificates().and_then(<[_]>::first);
+            let fingerprint = cert.map(|cert| fingerprint(&cert.0)).unwrap_or_default();
+            let Some(i) = server
+                .clients
+                .iter()
+                .position(|c| c.fingerprint == fingerprint)
+            else {
+                return warn!(%peer, fingerprint, "Refused client with unknown certificate");
+            };
+            let service = service_fn(|req| {
+                let server = Arc::clone(&server);
+                async move { Ok::<_, Infallible>(server.handle(i, req).await) }
+            });
+            let rslt = Http::new()
+                .http1_only(true)
+                .http1_keep_alive(false)
+                .http1_header_read_timeout(REQUEST_TIMEOUT)
+                .serve_connection(tls, service)
+                .await;
+            if let Err(err) = rslt {
+                debug!(%peer, %err, "Connection failed");
+            }
+        });
+    }
+}
+
+/// Returns the relay's TLS config, which requires clients to present a certificate issued by the
+/// client CA.
+fn tls_config(cfg: &RelayServerConfig) -> Result<ServerConfig> {
+    let certs = read_certs(&cfg.cert)?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(
+        fs::File::open(&cfg.key).with_context(|| format!("couldn't open {}", cfg.key))?,
+    ))?
+    .into_iter()
+    .find_map(|item| match item {
+        rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+        _ => None,
+    })
+    .ok_or_else(|| anyhow!("{} holds no private key", cfg.key))?;
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(&cfg.client_ca)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("couldn't use a certificate in {}", cfg.client_ca))?;
+    }
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        .with_single_cert(certs, key)
+        .context("couldn't use certificate & key")
+}
+
+/// Reads the certificates in a PEM file, of which there must be at least one.
+fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = fs::File::open(path).with_context(|| format!("couldn't open {path}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("couldn't parse {path}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("{path} holds no certificates"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Returns the SHA-256 fingerprint of a (DER-encoded) certificate, in lowercase hex.
+fn fingerprint(cert: &[u8]) -> String {
+    let mut hex = String::new();
+    for byte in Sha256::digest(cert) {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+impl Server {
+    fn new(cfg: RelayServerConfig, http: &HttpSettings) -> Result<Self> {
+        let mut providers = Vec::new();
+        for provider_cfg in cfg.providers.iter().flat_map(ProviderConfig::per_record) {
+            providers.extend(
+                provider::new(&provider_cfg, http)?
+                    .into_iter()
+                    .map(|provider| (provider, Mutex::new(()))),
+            );
+        }
+        let clients = cfg
+            .clients
+            .into_iter()
+            .map(|client| {
+                let fingerprint = client.cert_sha256.replace(':', "").to_ascii_lowercase();
+                if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(anyhow!(
+                        "client {}'s cert_sha256 isn't a SHA-256 fingerprint",
+                        client.name
+                    ));
+                }
+                for record in &client.records {
+                    if !providers.iter().any(|(p, _)| p.record() == record) {
+                        warn!(
+                            client = client.name,
+                            record, "Client may update an unknown record"
+                        );
+                    }
+                }
+                Ok(Client {
+                    name: client.name,
+                    fingerprint,
+                    records: client.records,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            clients,
+            providers,
+            http: http.client(None),
+        })
+    }
+
+    /// Handles a request from the client with the given index.
+    async fn handle(&self, client: usize, req: Request<Body>) -> Response<Body> {
+        let client = &self.clients[client];
+        if req.method() != Method::POST || req.uri().path() != UPDATE_PATH {
+            return error_response(StatusCode::NOT_FOUND, "no such endpoint", None, true);
+        }
+        let req: UpdateRequest = match read_body(req.into_body())
+            .await
+            .and_then(|body| Ok(serde_json::from_slice(&body)?))
+        {
+            Ok(req) => req,
+            Err(err) => {
+                let message = format!("bad request: {err:#}");
+                return error_response(StatusCode::BAD_REQUEST, &message, None, true);
+            }
+        };
+        let UpdateRequest {
+            record,
+            record_type,
+            addr,
+        } = &req;
+
+        if !client.records.contains(record) {
+            warn!(
+                client = client.name,
+                record, "Refused update of record client may not update"
+            );
+            let message = format!("client may not update {record}");
+            return error_response(StatusCode::FORBIDDEN, &message, None, true);
+        }
+        let Some((provider, lock)) = self.providers.iter().find(|(provider, _)| {
+            provider.record() == record && provider.record_type().to_string() == *record_type
+        }) else {
+            let message = format!("relay has no {record_type} record {record}");
+            return error_response(StatusCode::NOT_FOUND, &message, None, true);
+        };
+        if !provider.record_type().matches(*addr) {
+            let message = format!("{addr} can't be published in an {record_type} record");
+            return error_response(StatusCode::BAD_REQUEST, &message, None, true);
+        }
+
+        let _guard = lock.lock().await;
+        match provider.update(&self.http, *addr).await {
+            Ok(()) => {
+                info!(
+                    target: crate::NOTABLE_TARGET,
+                    client = client.name,
+                    provider = provider.id(),
+                    %addr,
+                    "Relayed update"
+                );
+                Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .expect("Couldn't build response")
+            }
+            Err(err) => {
+                let message = format!("{err:#}");
+                warn!(
+                    client = client.name,
+                    provider = provider.id(),
+                    err = message,
+                    "Couldn't relay update"
+                );
+                let permanent = err.chain().any(|err| err.is::<Permanent>());
+                let status = match err.chain().any(|err| err.is::<Lockout>()) {
+                    true => StatusCode::TOO_MANY_REQUESTS,
+                    false => StatusCode::BAD_GATEWAY,
+                };
+                error_response(status, &message, Some(codes::update(&err)), permanent)
+            }
+        }
+    }
+}
+
+/// Reads a request's body, failing if it's longer than `MAX_BODY_LEN`.
+async fn read_body(mut body: Body) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > MAX_BODY_LEN {
+            return Err(anyhow!("body is longer than {MAX_BODY_LEN} bytes"));
+        }
+    }
+    Ok(buf)
+}
+
+fn error_response(
+    status: StatusCode,
+    error: &str,
+    code: Option<Code>,
+    permanent: bool,
+) -> Response<Body> {
+    let body = ErrorResponse {
+        error: error.to_string(),
+        code,
+        permanent,
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(&body).expect("Couldn't serialize response"),
+        ))
+        .expect("Couldn't build response")
+}
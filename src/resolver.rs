@@ -0,0 +1,59 @@
+//! A DNS resolver for the HTTP client which applies an address family preference, & the egress
+//! allowlist. The HTTP client tries addresses in the order given, falling back to the next family
+//! if the first is slow to connect ("happy eyeballs"), so ordering addresses expresses a
+//! preference, and filtering them disables a family entirely.
+
+use crate::{config::AddressFamily, egress::Allowlist};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio::net;
+
+pub struct Resolver {
+    family: Option<AddressFamily>,
+    allowlist: Option<Arc<Allowlist>>,
+}
+
+impl Resolver {
+    pub fn new(family: Option<AddressFamily>, allowlist: Option<Arc<Allowlist>>) -> Self {
+        Self { family, allowlist }
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let family = self.family;
+        let allowlist = self.allowlist.clone();
+        Box::pin(async move {
+            if allowlist.is_some_and(|allowlist| !allowlist.allows(name.as_str())) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "refusing to connect to {}, which isn't allowlisted",
+                        name.as_str()
+                    ),
+                )
+                .into());
+            }
+            let addrs = net::lookup_host((name.as_str(), 0)).await?;
+            let mut addrs: Vec<SocketAddr> = match family {
+                Some(AddressFamily::Ipv4Only) => addrs.filter(SocketAddr::is_ipv4).collect(),
+                Some(AddressFamily::Ipv6Only) => addrs.filter(SocketAddr::is_ipv6).collect(),
+                _ => addrs.collect(),
+            };
+            match family {
+                Some(AddressFamily::PreferIpv4) => addrs.sort_by_key(SocketAddr::is_ipv6),
+                Some(AddressFamily::PreferIpv6) => addrs.sort_by_key(SocketAddr::is_ipv4),
+                _ => (),
+            }
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no addresses of the allowed family", name.as_str()),
+                )
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
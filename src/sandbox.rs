@@ -0,0 +1,206 @@
+use anyhow::Result;
+use std::path::Path;
+
+/// Sandboxes the process, restricting what it can do from here on out so that a compromise (e.g.
+/// of the HTTP stack) can't trivially read other secrets or persist itself. After this is called,
+/// the config files may still be read, but the only files which may be written are those in the
+/// directory containing the state file, if any.
+///
+/// This must be called before any other threads are started, so that they inherit the sandbox.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub fn apply(config_paths: &[&Path], state_path: Option<&Path>) -> Result<()> {
+    linux::landlock(config_paths, state_path.map(state_dir))?;
+    linux::seccomp()
+}
+
+#[cfg(all(feature = "sandbox", target_os = "openbsd"))]
+pub fn apply(config_paths: &[&Path], state_path: Option<&Path>) -> Result<()> {
+    for config_path in config_paths {
+        openbsd::unveil(config_path, "r")?;
+    }
+    if let Some(state_path) = state_path {
+        openbsd::unveil(state_dir(state_path), "rwc")?;
+    }
+    for path in openbsd::RESOLVER_PATHS {
+        openbsd::unveil(Path::new(path), "r")?;
+    }
+    openbsd::lock_unveil()?;
+    openbsd::pledge("stdio inet dns rpath wpath cpath unix")
+}
+
+#[cfg(not(all(feature = "sandbox", any(target_os = "linux", target_os = "openbsd"))))]
+pub fn apply(_config_paths: &[&Path], _state_path: Option<&Path>) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "sandboxing is not supported on this platform, or by this build"
+    ))
+}
+
+/// Returns the directory containing the state file, which is where new state files are written.
+#[cfg(all(feature = "sandbox", any(target_os = "linux", target_os = "openbsd")))]
+fn state_dir(state_path: &Path) -> &Path {
+    match state_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+mod linux {
+    use anyhow::{anyhow, Context, Result};
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+    use std::{collections::BTreeMap, env, path::Path};
+    use tracing::warn;
+
+    /// Paths which may be read (but not written) after sandboxing. These are needed for DNS
+    /// resolution (resolver config, and NSS modules loaded by the system resolver).
+    const READ_ONLY_PATHS: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/dev/urandom"];
+
+    /// Syscalls which are denied after sandboxing. We never need these, but they are useful for
+    /// an attacker trying to run other programs, escape, or persist.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    const DENIED_SYSCALLS: &[i64] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_reboot,
+    ];
+
+    /// Restricts filesystem access with Landlock: read-write access to the state directory (if
+    /// any), and read-only access to the config files & a few system paths. This is best-effort,
+    /// since older kernels don't support Landlock.
+    pub fn landlock(config_paths: &[&Path], state_dir: Option<&Path>) -> Result<()> {
+        let abi = ABI::V3;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))?
+            .create()?
+            .add_rules(path_beneath_rules(
+                READ_ONLY_PATHS,
+                AccessFs::from_read(abi),
+            ))?
+            .add_rules(path_beneath_rules(config_paths, AccessFs::from_read(abi)))?
+            .add_rules(path_beneath_rules(state_dir, AccessFs::from_all(abi)))?
+            .restrict_self()
+            .context("couldn't apply Landlock ruleset")?;
+        if status.ruleset != RulesetStatus::FullyEnforced {
+            warn!(
+                status = ?status.ruleset,
+                "Landlock is not fully supported by this kernel, filesystem sandboxing is limited"
+            );
+        }
+        Ok(())
+    }
+
+    /// Denies a list of dangerous syscalls with seccomp.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub fn seccomp() -> Result<()> {
+        let arch = TargetArch::try_from(env::consts::ARCH)?;
+        let rules = DENIED_SYSCALLS
+            .iter()
+            .map(|&syscall| (syscall, Vec::new()))
+            .collect::<BTreeMap<_, _>>();
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            arch,
+        )?;
+        let program: BpfProgram = filter.try_into()?;
+        seccompiler::apply_filter(&program)
+            .map_err(|err| anyhow!("couldn't apply seccomp filter: {err}"))
+    }
+
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )))]
+    pub fn seccomp() -> Result<()> {
+        warn!(
+            arch = env::consts::ARCH,
+            "seccomp filtering is not supported on this architecture"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "sandbox", target_os = "openbsd"))]
+mod openbsd {
+    use anyhow::{anyhow, Result};
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path, ptr};
+
+    /// Paths read by the resolver (& the local time zone, for notifications' quiet hours), which
+    /// must remain visible after unveiling.
+    pub const RESOLVER_PATHS: &[&str] = &[
+        "/etc/resolv.conf",
+        "/etc/hosts",
+        "/etc/localtime",
+        "/usr/share/zoneinfo",
+    ];
+
+    pub fn unveil(path: &Path, permissions: &str) -> Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let c_permissions = CString::new(permissions)?;
+        // SAFETY: both arguments are valid NUL-terminated strings.
+        if unsafe { libc::unveil(c_path.as_ptr(), c_permissions.as_ptr()) } < 0 {
+            return Err(anyhow!(
+                "couldn't unveil {}: {}",
+                path.display(),
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Disallows further calls to unveil.
+    pub fn lock_unveil() -> Result<()> {
+        // SAFETY: unveil explicitly accepts two NULL arguments.
+        if unsafe { libc::unveil(ptr::null(), ptr::null()) } < 0 {
+            return Err(anyhow!(
+                "couldn't lock unveil: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn pledge(promises: &str) -> Result<()> {
+        let c_promises = CString::new(promises)?;
+        // SAFETY: promises is a valid NUL-terminated string; NULL execpromises leaves them as-is.
+        if unsafe { libc::pledge(c_promises.as_ptr(), ptr::null()) } < 0 {
+            return Err(anyhow!("couldn't pledge: {}", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
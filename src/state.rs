@@ -0,0 +1,290 @@
+use crate::{
+    counters::Totals,
+    detect::Trust,
+    faults::{Faults, Operation},
+    notify::Queued,
+};
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{OsStr, OsString},
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+use tempfile::NamedTempFile;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn, Span};
+
+// State (read/write).
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    /// Our current conception of what the providers think our IP address is, i.e. the last
+    /// address confirmed by every provider.
+    pub addr: Option<Ipv4Addr>,
+
+    /// The last address confirmed by each provider, keyed by provider ID.
+    #[serde(default)]
+    pub provider_addrs: BTreeMap<String, IpAddr>,
+
+    /// The last address we detected, whether or not the providers have it.
+    pub detected_addr: Option<Ipv4Addr>,
+
+    /// The addresses we've detected but haven't yet managed to get providers to accept, keyed by
+    /// provider ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pending_addrs: BTreeMap<String, IpAddr>,
+
+    /// An address pending for every provider of A records, as written before pending addresses
+    /// were kept per provider. It's moved to `pending_addrs` at startup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_addr: Option<Ipv4Addr>,
+
+    /// Like `addr`, `detected_addr`, & `pending_addr`, but for providers of AAAA records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr6: Option<Ipv6Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected_addr6: Option<Ipv6Addr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_addr6: Option<Ipv6Addr>,
+
+    /// The records (e.g. `www.example.com`) for which updates are paused.
+    #[serde(default)]
+    pub paused: BTreeSet<String>,
+
+    /// The trust in each detector, keyed by URL, if more than one is configured.
+    #[serde(default)]
+    pub detector_trust: BTreeMap<String, Trust>,
+
+    /// When each account's cool-down after being locked out by its provider ends, in seconds since
+    /// the Unix epoch, keyed by account.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cooldowns: BTreeMap<String, u64>,
+
+    /// Each provider's cumulative counters, keyed by provider ID.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub counters: BTreeMap<String, Totals>,
+
+    /// Notifications which haven't yet been delivered to every webhook, oldest first.
+    #[serde(default)]
+    pub notifications: Vec<Queued>,
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.detected_addr {
+            Some(addr) => write!(f, "detected {addr}")?,
+            None => write!(f, "nothing detected yet")?,
+        }
+        let provider_addr = self
+            .addr
+            .map_or_else(|| "an unknown address".to_string(), |addr| addr.to_string());
+        let pending = |ipv4| {
+            self.pending_addrs
+                .values()
+                .any(|addr: &IpAddr| addr.is_ipv4() == ipv4)
+        };
+        match self.pending_addr.is_some() || pending(true) {
+            true => write!(f, ", provider still has {provider_addr}, update pending")?,
+            false => write!(f, ", provider has {provider_addr}")?,
+        }
+        if let Some(addr) = self.detected_addr6 {
+            write!(f, "; detected IPv6 {addr}")?;
+            if self.pending_addr6.is_some() || pending(false) {
+                write!(f, ", update pending")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A change to the state, sent by a worker to the state writer. Addresses are about the providers
+/// of records of their family (i.e. A or AAAA records).
+pub enum StateUpdate {
+    /// The given address was detected.
+    Detected(IpAddr),
+
+    /// The providers with the given IDs (a worker's) are all known to have the given address.
+    Addr(Vec<String>, IpAddr),
+
+    /// The provider with the given ID is known to have the given address.
+    ProviderAddr(String, IpAddr),
+
+    /// The providers with the given IDs need to be updated to the given address.
+    Pending(Vec<String>, IpAddr),
+
+    /// Updates for the given record were paused (true) or resumed (false).
+    Paused(String, bool),
+
+    /// The trust in the detectors changed.
+    DetectorTrust(BTreeMap<String, Trust>),
+
+    /// The cool-downs of locked-out accounts changed.
+    Cooldowns(BTreeMap<String, u64>),
+
+    /// The providers' counters changed.
+    Counters(BTreeMap<String, Totals>),
+
+    /// The queue of undelivered notifications changed.
+    Notifications(Vec<Queued>),
+    /// A request to be told once every earlier update has been written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Runs the state writer, which owns the state: it applies updates sent by workers, and writes
+/// the state to disk whenever it changes. Each update comes with the span of the worker cycle that
+/// sent it, which is used for any logging about the update. Without a state file, the state is
+/// only kept in memory. Returns once all senders have been dropped.
+pub async fn run_writer(
+    state_path: Option<OsString>,
+    mut state: State,
+    mut updates: mpsc::Receiver<(StateUpdate, Span)>,
+    faults: Arc<Faults>,
+    durable: bool,
+) {
+    while let Some((update, span)) = updates.recv().await {
+        let _guard = span.enter();
+        let mut new_state = state.clone();
+        match update {
+            StateUpdate::Addr(ids, addr) => {
+                match addr {
+                    IpAddr::V4(addr) => new_state.addr = Some(addr),
+                    IpAddr::V6(addr) => new_state.addr6 = Some(addr),
+                }
+                for id in ids {
+                    new_state.pending_addrs.remove(&id);
+                }
+            }
+            StateUpdate::ProviderAddr(id, addr) => {
+                new_state.provider_addrs.insert(id, addr);
+            }
+            StateUpdate::Detected(IpAddr::V4(addr)) => new_state.detected_addr = Some(addr),
+            StateUpdate::Detected(IpAddr::V6(addr)) => new_state.detected_addr6 = Some(addr),
+            StateUpdate::Pending(ids, addr) => {
+                new_state
+                    .pending_addrs
+                    .extend(ids.into_iter().map(|id| (id, addr)));
+            }
+            StateUpdate::Paused(record, true) => {
+                new_state.paused.insert(record);
+            }
+            StateUpdate::Paused(record, false) => {
+                new_state.paused.remove(&record);
+            }
+            StateUpdate::DetectorTrust(trust) => new_state.detector_trust = trust,
+            StateUpdate::Cooldowns(cooldowns) => new_state.cooldowns = cooldowns,
+            StateUpdate::Counters(counters) => new_state.counters = counters,
+            StateUpdate::Notifications(notifications) => new_state.notifications = notifications,
+            StateUpdate::Flush(flushed) => {
+                let _ = flushed.send(());
+                continue;
+            }
+        }
+        if new_state == state {
+            continue;
+        }
+        let Some(state_path) = &state_path else {
+            state = new_state;
+            continue;
+        };
+
+        // If the write fails, we'll try again when the next update arrives.
+        let rslt = faults.check(Operation::State);
+        if let Err(err) = rslt.and_then(|()| update_state(state_path, &new_state, durable)) {
+            error!(%err, "Couldn't write state file");
+            continue;
+        }
+        state = new_state;
+    }
+}
+
+/// Loads the state from disk. A missing state file is created with the default state. A corrupt
+/// state file is moved aside (to `<state file>.corrupt-<unix timestamp>`) & replaced with the
+/// default state; since the default state doesn't know what the providers have, the first check
+/// will then re-assert our IP. `durable` is as for `update_state`.
+pub fn load(state_path: &OsStr, durable: bool) -> Result<State> {
+    let err = match File::open(state_path) {
+        Ok(state_file) => match serde_yaml::from_reader(state_file) {
+            Ok(state) => return Ok(state),
+            Err(err) => err,
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let state = State::default();
+            update_state(state_path, &state, durable)?;
+            return Ok(state);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let mut corrupt_path = state_path.to_owned();
+    corrupt_path.push(format!(".corrupt-{timestamp}"));
+    fs::rename(state_path, &corrupt_path)?;
+    warn!(
+        %err,
+        corrupt_path = %Path::new(&corrupt_path).display(),
+        "Couldn't parse state file, moved it aside & starting from scratch"
+    );
+    let state = State::default();
+    update_state(state_path, &state, durable)?;
+    Ok(state)
+}
+
+/// Reads the state from disk, without creating or moving aside the state file. A missing state
+/// file is read as the default state.
+pub fn read(state_path: &OsStr) -> Result<State> {
+    match File::open(state_path) {
+        Ok(state_file) => Ok(serde_yaml::from_reader(state_file)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes the state to disk, atomically replacing the state file. If `durable`, the new file & the
+/// directory containing it are synced before & after the replacement, so that a crash or power
+/// loss (e.g. of an SBC's flaky SD card) can't leave an empty or missing state file, at the cost
+/// of extra writes.
+pub fn update_state(state_path: &OsStr, state: &State, durable: bool) -> Result<()> {
+    let state_path = Path::new(state_path);
+    let dir = state_path.parent().ok_or_else(|| {
+        anyhow!(
+            "couldn't determine parent directory of {}",
+            state_path.display()
+        )
+    })?;
+    let temp_file = NamedTempFile::new_in(dir)?;
+    serde_yaml::to_writer(&temp_file, state)?;
+    if durable {
+        temp_file.as_file().sync_all()?;
+    }
+    temp_file.persist(state_path)?;
+    if durable {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Syncs a directory, making renames within it durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    // An empty parent means the current directory.
+    let dir = match dir.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => dir,
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be synced on this platform: renames are made durable by the filesystem.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
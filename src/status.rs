@@ -0,0 +1,261 @@
+//! Workers' status, as reported by `rnccd status`: each worker publishes its status, which the
+//! control server sends to clients as JSON.
+
+use crate::counters::Totals;
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+/// How many of a worker's latest errors its status keeps.
+pub const RECENT_ERRORS: usize = 10;
+
+/// A worker's status. Times are in seconds since the Unix epoch.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub record: String,
+    /// The labels of the record's host entry.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    pub providers: Vec<ProviderStatus>,
+    pub detected_addr: Option<IpAddr>,
+    /// How long the latest detection took, in milliseconds.
+    #[serde(default)]
+    pub detection_latency_ms: Option<u64>,
+    pub paused: bool,
+    pub last_success: Option<u64>,
+    pub last_error: Option<ErrorStatus>,
+    /// The latest errors, oldest first, up to `RECENT_ERRORS` of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recent_errors: Vec<ErrorStatus>,
+    pub next_check: Option<u64>,
+    /// In dry-run mode, what the latest check would have done.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plan: Vec<Plan>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProviderStatus {
+    pub id: String,
+    /// The address the provider is known to have, if any.
+    pub addr: Option<IpAddr>,
+    #[serde(default)]
+    pub totals: Totals,
+    /// How long the latest update took, in milliseconds.
+    #[serde(default)]
+    pub update_latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ErrorStatus {
+    pub time: u64,
+    pub message: String,
+}
+
+/// What a dry-run check would have done about a provider's record, & why.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Plan {
+    pub provider: String,
+    pub record: String,
+    /// The addresses the record resolves to, if it could be resolved.
+    pub dns_addrs: Option<Vec<IpAddr>>,
+    /// The address the provider is known to have, if any.
+    pub known_addr: Option<IpAddr>,
+    pub detected_addr: IpAddr,
+    pub action: Action,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    None,
+    Update,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::None => "none",
+            Action::Update => "update",
+        })
+    }
+}
+
+/// The output format of `rnccd status`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Output {
+    Table,
+    Json,
+}
+
+/// Returns the given duration in (whole) milliseconds.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Returns the given time in seconds since the Unix epoch.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Renders statuses (as JSON, from the daemon) in the given format. (Like the rest of the control
+/// socket client, this is Unix-only.)
+#[cfg(unix)]
+pub fn render(json: &str, output: Output) -> anyhow::Result<String> {
+    let statuses: Vec<Status> = serde_json::from_str(json)?;
+    Ok(match output {
+        Output::Table => table(&statuses, unix_time(SystemTime::now())),
+        Output::Json => serde_json::to_string_pretty(&statuses)?,
+    })
+}
+
+/// Formats statuses as a table, with a row per provider.
+#[cfg(unix)]
+fn table(statuses: &[Status], now: u64) -> String {
+    let mut rows = vec![[
+        "RECORD",
+        "LABELS",
+        "PROVIDER",
+        "DETECTED",
+        "DETECTION TIME",
+        "CONFIRMED",
+        "UPDATES",
+        "UPDATE TIME",
+        "LAST CHANGE",
+        "LAST SUCCESS",
+        "NEXT CHECK",
+        "LAST ERROR",
+    ]
+    .map(String::from)];
+    for status in statuses {
+        let next_check = match (status.paused, status.next_check) {
+            (true, _) => "paused".to_string(),
+            (false, time) => or_dash(time.map(|time| relative(time, now))),
+        };
+        let last_error = status
+            .last_error
+            .as_ref()
+            .map(|err| format!("{}: {}", relative(err.time, now), err.message));
+
+        // Cells about the record as a whole are given only in its first row.
+        let labels: Vec<_> = status
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        let mut record_cells = Some([
+            status.record.clone(),
+            or_dash((!labels.is_empty()).then(|| labels.join(","))),
+            or_dash(status.detected_addr),
+            or_dash(status.detection_latency_ms.map(latency)),
+            or_dash(status.last_success.map(|time| relative(time, now))),
+            next_check,
+            or_dash(last_error),
+        ]);
+        for provider in &status.providers {
+            let [record, labels, detected, detection_time, last_success, next_check, last_error] =
+                record_cells.take().unwrap_or_default();
+            let totals = provider.totals;
+            rows.push([
+                record,
+                labels,
+                provider.id.clone(),
+                detected,
+                detection_time,
+                or_dash(provider.addr),
+                format!("{} ({} failed)", totals.updates, totals.failures),
+                or_dash(provider.update_latency_ms.map(latency)),
+                or_dash(totals.last_change.map(|time| relative(time, now))),
+                last_success,
+                next_check,
+                last_error,
+            ]);
+        }
+    }
+
+    // Pad every column but the last (which may be long) to its widest cell.
+    let mut widths = [0; 12];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let lines: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .enumerate()
+                .map(|(i, (cell, width))| match i {
+                    11 => cell.clone(),
+                    _ => format!("{cell:width$}"),
+                })
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        })
+        .collect();
+    let mut out = lines.join("\n");
+
+    // In dry-run mode, follow with what each record's latest check would have done.
+    for status in statuses {
+        for plan in &status.plan {
+            let dns_addrs = plan.dns_addrs.as_ref().map(|addrs| {
+                let addrs: Vec<_> = addrs.iter().map(IpAddr::to_string).collect();
+                addrs.join(",")
+            });
+            out.push_str(&format!(
+                "\ndry run: {} ({}): dns {}, known {}, detected {}: would {}",
+                plan.record,
+                plan.provider,
+                or_dash(dns_addrs),
+                or_dash(plan.known_addr),
+                plan.detected_addr,
+                match plan.action {
+                    Action::None => "do nothing".to_string(),
+                    Action::Update => format!("update to {}", plan.detected_addr),
+                }
+            ));
+        }
+    }
+    out
+}
+
+#[cfg(unix)]
+fn or_dash(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+/// Formats a latency, e.g. `250ms` or `1.2s`.
+#[cfg(unix)]
+fn latency(ms: u64) -> String {
+    match ms {
+        0..=999 => format!("{ms}ms"),
+        _ => format!("{:.1}s", ms as f64 / 1000.0),
+    }
+}
+
+/// Formats a time relative to now, e.g. `5m ago` or `in 30s`.
+#[cfg(unix)]
+fn relative(time: u64, now: u64) -> String {
+    let (secs, past) = match time <= now {
+        true => (now - time, true),
+        false => (time - now, false),
+    };
+    let amount = match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    };
+    match past {
+        true => format!("{amount} ago"),
+        false => format!("in {amount}"),
+    }
+}
@@ -0,0 +1,153 @@
+//! Detection of our IP address via STUN (RFC 8489): a binding request sent to a STUN server (e.g.
+//! one of the many public servers run for WebRTC) is answered with the address it came from.
+//! Unlike most HTTP detection services, public STUN servers rarely rate-limit.
+
+use crate::nat64;
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+use tokio::{
+    net::{self, UdpSocket},
+    time,
+};
+
+/// The port STUN servers listen on, if the URL doesn't say.
+const DEFAULT_PORT: u16 = 3478;
+
+/// How long to wait for a response to the first request. Requests are sent over UDP, so may be
+/// lost: each unanswered request is retransmitted, waiting twice as long as the last time.
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+
+/// How many requests to send before giving up.
+const MAX_REQUESTS: u32 = 5;
+
+/// The maximum length of a response.
+const MAX_RESPONSE_LEN: usize = 548;
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const ERROR_CODE: u16 = 0x0009;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Asks the STUN server at `server` (a `host[:port]`) for our address, of the given family.
+pub async fn detect(server: &str, ipv6: bool) -> Result<IpAddr> {
+    let url = Url::parse(&format!("stun://{server}"))
+        .with_context(|| format!("couldn't parse STUN server {server:?}"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("STUN server {server:?} has no host"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+    let server_addr = net::lookup_host((host, port))
+        .await?
+        .find(|addr| addr.is_ipv6() == ipv6)
+        .ok_or_else(|| {
+            let family = if ipv6 { "IPv6" } else { "IPv4" };
+            anyhow!("{host} has no {family} address")
+        })?;
+    let server_addr = nat64::reachable(server_addr).await?;
+    let local_addr: IpAddr = match server_addr.is_ipv6() {
+        true => Ipv6Addr::UNSPECIFIED.into(),
+        false => Ipv4Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local_addr, 0)).await?;
+    socket.connect(server_addr).await?;
+
+    let mut transaction_id = [0; 12];
+    transaction_id.fill_with(|| fastrand::u8(..));
+    let mut request = Vec::with_capacity(20);
+    request.extend(BINDING_REQUEST.to_be_bytes());
+    request.extend(0u16.to_be_bytes()); // no attributes.
+    request.extend(MAGIC_COOKIE.to_be_bytes());
+    request.extend(transaction_id);
+
+    let mut rto = INITIAL_RTO;
+    let mut buf = [0; MAX_RESPONSE_LEN];
+    for _ in 0..MAX_REQUESTS {
+        socket.send(&request).await?;
+        let deadline = time::Instant::now() + rto;
+        // Responses to other (e.g. earlier) requests are ignored.
+        while let Ok(len) = time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            if let Some(addr) = parse_response(&buf[..len?], &transaction_id)? {
+                return Ok(addr);
+            }
+        }
+        rto *= 2;
+    }
+    Err(anyhow!(
+        "{server} didn't respond to {MAX_REQUESTS} requests"
+    ))
+}
+
+/// Parses a response, returning the address it gives, or None if it isn't a response to the
+/// request with the given transaction ID.
+fn parse_response(resp: &[u8], transaction_id: &[u8; 12]) -> Result<Option<IpAddr>> {
+    if resp.len() < 20
+        || resp[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || resp[8..20] != transaction_id[..]
+    {
+        return Ok(None);
+    }
+    let msg_type = u16::from_be_bytes([resp[0], resp[1]]);
+    let mut attrs = Vec::new();
+    let mut rest = &resp[20..];
+    while rest.len() >= 4 {
+        let attr_type = u16::from_be_bytes([rest[0], rest[1]]);
+        let len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+        let value = rest
+            .get(4..4 + len)
+            .ok_or_else(|| anyhow!("response has a truncated attribute"))?;
+        attrs.push((attr_type, value));
+        // Attributes are padded to a multiple of 4 bytes.
+        rest = rest.get(4 + len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    let attr = |want| attrs.iter().find(|&&(attr_type, _)| attr_type == want);
+
+    match msg_type {
+        BINDING_SUCCESS => (),
+        BINDING_ERROR => {
+            let Some(&(_, [_, _, class, number, reason @ ..])) = attr(ERROR_CODE) else {
+                return Err(anyhow!("binding request failed"));
+            };
+            let code = u16::from(class & 0x7) * 100 + u16::from(*number);
+            let reason = String::from_utf8_lossy(reason);
+            return Err(anyhow!("binding request failed: {reason} (error {code})"));
+        }
+        msg_type => return Err(anyhow!("unexpected response type {msg_type:#06x}")),
+    }
+
+    // Servers predating RFC 5389 only give the address in the clear, which NATs may rewrite.
+    if let Some(&(_, value)) = attr(XOR_MAPPED_ADDRESS) {
+        let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend(transaction_id);
+        return parse_address(value, &mask).map(Some);
+    }
+    if let Some(&(_, value)) = attr(MAPPED_ADDRESS) {
+        return parse_address(value, &[0; 16]).map(Some);
+    }
+    Err(anyhow!("response has no address"))
+}
+
+/// Parses the value of an address attribute, whose address is XORed with `mask`.
+fn parse_address(value: &[u8], mask: &[u8]) -> Result<IpAddr> {
+    let unmask = |addr: &[u8]| -> Vec<u8> { addr.iter().zip(mask).map(|(a, m)| a ^ m).collect() };
+    match value {
+        [_, 0x01, _, _, addr @ ..] if addr.len() == 4 => {
+            let addr: [u8; 4] = unmask(addr).try_into().unwrap();
+            Ok(Ipv4Addr::from(addr).into())
+        }
+        [_, 0x02, _, _, addr @ ..] if addr.len() == 16 => {
+            let addr: [u8; 16] = unmask(addr).try_into().unwrap();
+            Ok(Ipv6Addr::from(addr).into())
+        }
+        _ => Err(anyhow!("response has a malformed address")),
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{backoff::Backoff, watchdog::Watchdog, worker::Worker};
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tracing::{error, info, warn, Instrument, Span};
+
+/// How often the supervisor checks on its workers.
+pub const SUPERVISE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long past its expected deadline a worker may go without completing a check before it is
+/// considered stalled. This must comfortably exceed the time a single check can take.
+const STALL_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// How long an aborted worker may take to actually stop. A worker that outlives this is blocking
+/// a runtime thread, which only restarting the process can fix.
+const ABORT_GRACE: Duration = Duration::from_secs(30);
+
+/// The minimum & maximum delays before restarting a failed worker.
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(10);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Heartbeat is used by a worker to tell its supervisor when it expects to next complete a check.
+pub struct Heartbeat(watch::Sender<Instant>);
+
+impl Heartbeat {
+    /// Returns a heartbeat which nothing watches, for a worker run without a supervisor.
+    pub fn unsupervised() -> Self {
+        Self(watch::channel(Instant::now()).0)
+    }
+
+    /// Notes that a check completed, and that the next check is expected to start by `deadline`.
+    pub fn expect_by(&self, deadline: Instant) {
+        self.0.send_replace(deadline + STALL_GRACE);
+    }
+}
+
+/// Supervisor runs workers, restarting (with backoff) any worker that panics, exits, or stalls.
+/// It pets the watchdog each time it checks on its workers.
+pub struct Supervisor {
+    watchdog: Watchdog,
+    slots: Vec<Slot>,
+}
+
+struct Slot {
+    span: Span,
+    make_worker: Box<dyn FnMut(Heartbeat) -> Worker + Send>,
+    task: Option<Task>,
+    restart_at: Instant,
+    backoff: Backoff,
+}
+
+struct Task {
+    handle: JoinHandle<()>,
+    deadline: watch::Receiver<Instant>,
+    aborted_at: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(watchdog: Watchdog) -> Self {
+        Self {
+            watchdog,
+            slots: Vec::new(),
+        }
+    }
+
+    /// Adds a worker, run in the given span. `make_worker` is called each time the worker is
+    /// (re)started.
+    pub fn add<F>(&mut self, span: Span, make_worker: F)
+    where
+        F: FnMut(Heartbeat) -> Worker + Send + 'static,
+    {
+        self.slots.push(Slot {
+            span,
+            make_worker: Box::new(make_worker),
+            task: None,
+            restart_at: Instant::now(),
+            backoff: Backoff::new(MIN_RESTART_DELAY, MAX_RESTART_DELAY),
+        });
+    }
+
+    /// Runs the supervisor. This only returns if a worker is stuck beyond the supervisor's
+    /// ability to recover it.
+    pub async fn run(&mut self) -> Result<()> {
+        let mut interval = time::interval(SUPERVISE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for slot in &mut self.slots {
+                slot.supervise().await?;
+            }
+            self.watchdog.pet();
+        }
+    }
+
+    /// Waits for workers, which have been asked to stop, to exit, aborting any still running after
+    /// `timeout`.
+    pub async fn stop(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for Slot { span, task, .. } in self.slots {
+            let Some(mut task) = task else {
+                continue;
+            };
+            if time::timeout_at(deadline, &mut task.handle).await.is_err() {
+                warn!(parent: &span, "Worker didn't stop in time, aborting");
+                task.handle.abort();
+            }
+        }
+    }
+}
+
+impl Slot {
+    async fn supervise(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let span = &self.span;
+        let Some(task) = &mut self.task else {
+            if now >= self.restart_at {
+                self.start(now);
+            }
+            return Ok(());
+        };
+
+        // A worker that has completed a check since we last looked is healthy.
+        if task.deadline.has_changed().unwrap_or(false) {
+            task.deadline.mark_unchanged();
+            self.backoff.reset();
+        }
+
+        // Workers only exit on their own when stopping, which happens after the supervisor has
+        // stopped running, so a finished worker has panicked (or otherwise died); a worker that has
+        // blown through its deadline is hung.
+        if task.handle.is_finished() {
+            match (&mut task.handle).await {
+                Err(err) if err.is_cancelled() => (), // we aborted it, and already logged why.
+                Err(err) => error!(parent: span, %err, "Worker panicked"),
+                Ok(()) => error!(parent: span, "Worker exited unexpectedly"),
+            }
+        } else if let Some(aborted_at) = task.aborted_at {
+            if now > aborted_at + ABORT_GRACE {
+                error!(parent: span, "Worker didn't stop after being aborted");
+                return Err(anyhow!("stuck worker"));
+            }
+            return Ok(());
+        } else if now > *task.deadline.borrow() {
+            error!(parent: span, "Worker stalled, aborting");
+            task.handle.abort();
+            task.aborted_at = Some(now);
+            return Ok(());
+        } else {
+            return Ok(());
+        }
+
+        self.task = None;
+        let delay = self.backoff.failure();
+        self.restart_at = now + delay;
+        info!(parent: &self.span, restart_in = ?delay, "Scheduled worker restart");
+        Ok(())
+    }
+
+    fn start(&mut self, now: Instant) {
+        let (deadline_tx, deadline) = watch::channel(now + STALL_GRACE);
+        let worker = (self.make_worker)(Heartbeat(deadline_tx));
+        let handle = tokio::spawn(worker.run().instrument(self.span.clone()));
+        self.task = Some(Task {
+            handle,
+            deadline,
+            aborted_at: None,
+        });
+    }
+}
@@ -0,0 +1,160 @@
+//! Output to the system log, for hosts (e.g. routers) where it, rather than stdout, is where logs
+//! are kept: either a syslog daemon, as RFC 5424 messages, or the systemd journal, via its native
+//! protocol. Each event is sent as its own datagram, with a priority given by its level: errors are
+//! `err`, warnings `warning`, notable events (e.g. IP changes) `notice`, & the rest `info` or
+//! `debug`.
+
+use std::{
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    process,
+};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::{
+    format::Writer,
+    time::{FormatTime, SystemTime},
+    MakeWriter,
+};
+
+/// The identifier messages are logged under.
+const IDENTIFIER: &str = "rnccd";
+
+/// The syslog facility messages are logged with: `daemon`.
+const FACILITY: u8 = 3;
+
+#[cfg(target_os = "macos")]
+const SYSLOG_SOCKET: &str = "/var/run/syslog";
+#[cfg(not(target_os = "macos"))]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// SystemLog writes events to the system log. It's used as the fmt layer's writer, so events are
+/// formatted as they would be on stdout (less the timestamp & level, which the log records).
+pub struct SystemLog {
+    socket: UnixDatagram,
+    path: &'static str,
+    journal: bool,
+}
+
+impl SystemLog {
+    /// Logs to a syslog daemon.
+    pub fn syslog() -> io::Result<Self> {
+        Self::new(false)
+    }
+
+    /// Logs to the systemd journal.
+    pub fn journal() -> io::Result<Self> {
+        Self::new(true)
+    }
+
+    fn new(journal: bool) -> io::Result<Self> {
+        let path = if journal {
+            JOURNAL_SOCKET
+        } else {
+            SYSLOG_SOCKET
+        };
+        // Fail now, rather than losing every message, if nothing's listening. Messages are then
+        // sent to the path, rather than over this connection, so that they get through after the
+        // daemon restarts.
+        UnixDatagram::unbound()?.connect(path).map_err(|err| {
+            io::Error::new(err.kind(), format!("couldn't connect to {path}: {err}"))
+        })?;
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            path,
+            journal,
+        })
+    }
+
+    /// Starts a message with the given syslog severity.
+    fn message(&self, severity: u8) -> Message<'_> {
+        Message {
+            log: self,
+            severity,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SystemLog {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.message(6)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.message(match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO if meta.target() == crate::NOTABLE_TARGET => 5,
+            Level::INFO => 6,
+            _ => 7,
+        })
+    }
+}
+
+/// Message collects a formatted event, which is sent when it's dropped.
+pub struct Message<'a> {
+    log: &'a SystemLog,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl Message<'_> {
+    /// Encodes the message as an RFC 5424 syslog message.
+    fn syslog(&self, msg: &[u8]) -> Vec<u8> {
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        // The hostname is left for the syslog daemon, which knows which host it's on, to fill in.
+        let mut datagram = format!(
+            "<{}>1 {timestamp} - {IDENTIFIER} {} - - ",
+            FACILITY * 8 + self.severity,
+            process::id()
+        )
+        .into_bytes();
+        datagram.extend_from_slice(msg);
+        datagram
+    }
+
+    /// Encodes the message as journal fields. The message is length-prefixed, since it may span
+    /// lines.
+    fn journal(&self, msg: &[u8]) -> Vec<u8> {
+        let mut datagram = format!(
+            "PRIORITY={}\nSYSLOG_IDENTIFIER={IDENTIFIER}\nMESSAGE\n",
+            self.severity
+        )
+        .into_bytes();
+        datagram.extend_from_slice(&(msg.len() as u64).to_le_bytes());
+        datagram.extend_from_slice(msg);
+        datagram.push(b'\n');
+        datagram
+    }
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        let msg = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+        if msg.is_empty() {
+            return;
+        }
+        let datagram = match self.log.journal {
+            true => self.journal(msg),
+            false => self.syslog(msg),
+        };
+        // Failures are ignored, since there's nowhere better to report them.
+        let _ = self.log.socket.send_to(&datagram, self.log.path);
+    }
+}
@@ -0,0 +1,111 @@
+//! Service notifications to systemd, for running as a `Type=notify` service: readiness, a status
+//! line for `systemctl status`, & watchdog pings (for `WatchdogSec=`). They're sent to the socket
+//! systemd gives in `$NOTIFY_SOCKET`; without it (i.e. when not run by systemd), nothing is sent.
+
+use crate::status::Status;
+use std::{env, process, time::Duration};
+use tokio::{sync::watch, time};
+use tracing::debug;
+
+/// How often workers' statuses are checked for changes to report.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends a notification (e.g. `READY=1`) to systemd, if it's listening. Failures are only logged,
+/// at debug level: systemd can't be told about them anyway.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = unix::send(&path, state) {
+            debug!(%err, state, "Couldn't notify systemd");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Returns how often systemd expects watchdog pings, if it does.
+pub fn watchdog_timeout() -> Option<Duration> {
+    // The watchdog may be meant for another process, e.g. one which exec'd us.
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Reports workers' statuses to systemd: `READY=1` once every worker has made a successful check
+/// (or is paused), & a status line giving each record's detected address whenever it changes.
+pub async fn report(statuses: Vec<watch::Receiver<Status>>) {
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let mut ready = false;
+    let mut reported = String::new();
+    let mut interval = time::interval(STATUS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let statuses: Vec<_> = statuses.iter().map(|rx| rx.borrow().clone()).collect();
+        let line: Vec<_> = statuses.iter().map(describe).collect();
+        let line = line.join("; ");
+        if line != reported {
+            notify(&format!("STATUS={line}"));
+            reported = line;
+        }
+        if !ready
+            && statuses
+                .iter()
+                .all(|status| status.paused || status.last_success.is_some())
+        {
+            notify("READY=1");
+            ready = true;
+        }
+    }
+}
+
+/// Describes a worker's status, e.g. `example.com: 203.0.113.7`.
+fn describe(status: &Status) -> String {
+    let mut desc = match status.detected_addr {
+        Some(addr) => format!("{}: {addr}", status.record),
+        None => format!("{}: not detected yet", status.record),
+    };
+    let failing = match (&status.last_error, status.last_success) {
+        (Some(err), Some(success)) => err.time > success,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    if status.paused {
+        desc.push_str(" (paused)");
+    } else if failing {
+        desc.push_str(" (failing)");
+    }
+    desc
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        ffi::OsStr,
+        io,
+        os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    };
+
+    /// Sends a datagram to the notification socket at `path`, which is abstract if it starts with
+    /// `@`.
+    pub fn send(path: &OsStr, state: &str) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)?;
+            }
+            _ => {
+                socket.send_to(state.as_bytes(), path)?;
+            }
+        }
+        Ok(())
+    }
+}
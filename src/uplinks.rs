@@ -0,0 +1,198 @@
+//! Detection over each of the uplinks (e.g. WAN links) of a host with more than one. Detection
+//! requests are made from each uplink's interface address, so that (with the source-based routing
+//! multi-WAN routers use) they leave via that uplink; a policy then picks whose address is used.
+
+use crate::{
+    config::{UplinkConfig, UplinkPolicy},
+    detect::{Detection, Detector},
+    interface, HttpSettings,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{task::JoinSet, time::Instant};
+use tracing::{debug, info, warn};
+
+/// With the `lowest-latency` policy, another uplink is only switched to if it answered faster than
+/// the active one by at least this fraction of the active one's latency, so that noise doesn't
+/// flip between uplinks of similar latency.
+const SWITCH_MARGIN: f64 = 0.25;
+
+/// Uplinks detects our IP address over each configured uplink.
+pub struct Uplinks {
+    uplinks: Vec<Arc<Uplink>>,
+    policy: UplinkPolicy,
+    http: HttpSettings,
+    active: Mutex<Option<usize>>, // the index of the uplink last used, if any.
+}
+
+struct Uplink {
+    interface: String,
+    detector: Detector,
+    client: Mutex<Option<(IpAddr, reqwest::Client)>>, // & the address it makes requests from.
+}
+
+impl Uplinks {
+    /// Creates the configured uplinks, whose HTTP detectors' clients are built with `http`.
+    pub fn new(cfgs: &[UplinkConfig], policy: UplinkPolicy, http: HttpSettings) -> Result<Self> {
+        let uplinks = cfgs
+            .iter()
+            .map(|cfg| {
+                let detector = Detector::new(cfg.detector.as_ref())?;
+                if !detector.is_http() && !detector.is_interface() {
+                    return Err(anyhow!(
+                        "uplink {}'s detector {} can't be bound to the uplink (use an HTTP or \
+                         interface detector)",
+                        cfg.interface,
+                        detector.url()
+                    ));
+                }
+                Ok(Arc::new(Uplink {
+                    interface: cfg.interface.clone(),
+                    detector,
+                    client: Mutex::new(None),
+                }))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            uplinks,
+            policy,
+            http,
+            active: Mutex::new(None),
+        })
+    }
+
+    pub fn detectors(&self) -> impl Iterator<Item = &Detector> {
+        self.uplinks.iter().map(|uplink| &uplink.detector)
+    }
+
+    /// Detects our address over the uplink picked by the policy.
+    pub async fn detect(&self) -> Result<Detection> {
+        let active = *self.active.lock().unwrap();
+        let (i, addr) = match self.policy {
+            UplinkPolicy::Primary => self.first_answer().await?,
+            UplinkPolicy::LowestLatency => self.fastest_answer(active).await?,
+        };
+        if active != Some(i) {
+            *self.active.lock().unwrap() = Some(i);
+            let uplink = &self.uplinks[i].interface;
+            match active {
+                None => info!(uplink, "Using uplink"),
+                Some(active) if i > active && matches!(self.policy, UplinkPolicy::Primary) => {
+                    warn!(
+                        target: crate::NOTABLE_TARGET,
+                        uplink,
+                        from = self.uplinks[active].interface,
+                        "Failing over to uplink"
+                    )
+                }
+                Some(active) => info!(
+                    target: crate::NOTABLE_TARGET,
+                    uplink,
+                    from = self.uplinks[active].interface,
+                    "Switching to uplink"
+                ),
+            }
+        }
+        Ok(Detection {
+            addr,
+            disagreements: BTreeMap::new(),
+        })
+    }
+
+    /// Detects over each uplink in turn, returning the first to answer & its answer.
+    async fn first_answer(&self) -> Result<(usize, Ipv4Addr)> {
+        let mut errs = Vec::new();
+        for (i, uplink) in self.uplinks.iter().enumerate() {
+            match uplink.detect(&self.http).await {
+                Ok((addr, _)) => return Ok((i, addr)),
+                Err(err) => {
+                    debug!(
+                        uplink = uplink.interface,
+                        err = format!("{err:#}"),
+                        "Detection over uplink failed, falling back to the next"
+                    );
+                    errs.push((i, err));
+                }
+            }
+        }
+        Err(self.every_failed(&errs))
+    }
+
+    /// Detects over every uplink at once, returning the one which answered fastest (unless the
+    /// active one wasn't much slower) & its answer.
+    async fn fastest_answer(&self, active: Option<usize>) -> Result<(usize, Ipv4Addr)> {
+        let mut tasks = JoinSet::new();
+        for (i, uplink) in self.uplinks.iter().enumerate() {
+            let (uplink, http) = (Arc::clone(uplink), self.http.clone());
+            tasks.spawn(async move { (i, uplink.detect(&http).await) });
+        }
+        let mut answers = BTreeMap::new();
+        let mut errs = Vec::new();
+        while let Some(rslt) = tasks.join_next().await {
+            match rslt? {
+                (i, Ok(answer)) => {
+                    answers.insert(i, answer);
+                }
+                (i, Err(err)) => {
+                    debug!(
+                        uplink = self.uplinks[i].interface,
+                        err = format!("{err:#}"),
+                        "Detection over uplink failed"
+                    );
+                    errs.push((i, err));
+                }
+            }
+        }
+        errs.sort_by_key(|&(i, _)| i);
+
+        let Some((&fastest, &(addr, latency))) =
+            answers.iter().min_by_key(|(_, (_, latency))| *latency)
+        else {
+            return Err(self.every_failed(&errs));
+        };
+        if let Some((active, &(active_addr, active_latency))) =
+            active.and_then(|active| Some((active, answers.get(&active)?)))
+        {
+            if latency.as_secs_f64() > active_latency.as_secs_f64() * (1.0 - SWITCH_MARGIN) {
+                return Ok((active, active_addr));
+            }
+        }
+        Ok((fastest, addr))
+    }
+
+    /// Returns the error of a detection for which every uplink failed, given each's index & error.
+    fn every_failed(&self, errs: &[(usize, anyhow::Error)]) -> anyhow::Error {
+        let msgs: Vec<_> = errs
+            .iter()
+            .map(|(i, err)| format!("{}: {err:#}", self.uplinks[*i].interface))
+            .collect();
+        anyhow!("detection over every uplink failed: {}", msgs.join("; "))
+    }
+}
+
+impl Uplink {
+    /// Detects our address over the uplink, also returning how long detection took.
+    async fn detect(&self, http: &HttpSettings) -> Result<(Ipv4Addr, Duration)> {
+        // The interface's address may change (e.g. by DHCP), so is looked up on every detection.
+        let local_addr = interface::local_address(&self.interface, false)?;
+        let client = {
+            let mut client = self.client.lock().unwrap();
+            match &*client {
+                Some((addr, client)) if *addr == local_addr => client.clone(),
+                _ => {
+                    let new_client = http.client(Some(local_addr));
+                    *client = Some((local_addr, new_client.clone()));
+                    new_client
+                }
+            }
+        };
+        let start = Instant::now();
+        let addr = self.detector.current_address(&client).await?;
+        Ok((addr, start.elapsed()))
+    }
+}
@@ -0,0 +1,49 @@
+use crate::systemd;
+use std::{
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+/// Watchdog exits the process (with a nonzero status, so that the service manager restarts it)
+/// if it isn't petted at least once per timeout. It runs on its own OS thread, so it keeps working
+/// even if the async runtime is wedged. If systemd's watchdog is enabled, petting also pings it.
+#[derive(Clone)]
+pub struct Watchdog {
+    last_pet: Arc<Mutex<Instant>>,
+    systemd: bool,
+}
+
+impl Watchdog {
+    pub fn start(timeout: Duration) -> Self {
+        let last_pet = Arc::new(Mutex::new(Instant::now()));
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn({
+                let last_pet = Arc::clone(&last_pet);
+                move || loop {
+                    thread::sleep(timeout / 4);
+                    let since_pet = last_pet.lock().unwrap().elapsed();
+                    if since_pet > timeout {
+                        error!(?since_pet, "Main loop is stuck, exiting");
+                        process::exit(1);
+                    }
+                }
+            })
+            .expect("Couldn't start watchdog thread");
+        Self {
+            last_pet,
+            systemd: systemd::watchdog_timeout().is_some(),
+        }
+    }
+
+    /// Notes that the main loop is still making progress.
+    pub fn pet(&self) {
+        *self.last_pet.lock().unwrap() = Instant::now();
+        if self.systemd {
+            systemd::notify("WATCHDOG=1");
+        }
+    }
+}
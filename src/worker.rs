@@ -0,0 +1,1377 @@
+use crate::{
+    backoff::{self, Backoff},
+    clock,
+    codes::{self, Code},
+    config::{CanaryConfig, ExecConfig, PropagationConfig, UpdatePolicy, VerifyDnsConfig},
+    counters::Counters,
+    detect::{self, Detector, Detectors},
+    dns,
+    faults::{Faults, Operation},
+    healthcheck::Healthcheck,
+    lockout::{self, Cooldowns, Lockout},
+    metrics::{Measurement, Metrics, Value},
+    nat64, netmon,
+    notify::{Event, Notification, Notifier},
+    propagation,
+    provider::{Provider, RecordType},
+    ratelimit::{Priority, RateLimiter},
+    state::StateUpdate,
+    status::{self, Action, ErrorStatus, Plan, ProviderStatus, Status},
+    supervisor::Heartbeat,
+    uplinks::Uplinks,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+/// Detections & updates taking longer than this are logged as slow, so that a slow detector or
+/// provider is noticed before its calls start timing out.
+const SLOW_CALL: Duration = Duration::from_secs(5);
+
+/// How often records' settings are checked for drift from the config. (This usually costs an API
+/// call per record, so is done much less often than checking our IP.)
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a detection is reused by other workers' checks (or, if shorter, half the interval).
+const DETECTION_REUSE: Duration = Duration::from_secs(10);
+
+/// How often the canary record is resolved while waiting for it to have the new address.
+const CANARY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A worker keeps a single domain/host entry up to date, with one or more providers. Each worker
+/// runs as its own task, with its own interval, backoff, and beliefs about what each provider
+/// thinks our IP is; changes that need to be persisted are sent to the state writer. A network
+/// change cuts short any backoff, since it likely means the cause of the failure has changed.
+pub struct Worker {
+    shared: Shared,
+    providers: Vec<ProviderSlot>,
+    interval: Duration,
+    backoff: Backoff,
+    pending_addr: Option<IpAddr>, // an update left pending by a previous run, if any.
+    heartbeat: Heartbeat,
+    paused: watch::Receiver<bool>,
+    labels: BTreeMap<String, String>,
+    propagation: Vec<JoinHandle<()>>, // propagation measurements of the latest update.
+    hooks: Vec<JoinHandle<()>>,       // runs of on_change hooks, which may not have finished.
+    last_reconcile: Option<Instant>,  // when records were last checked for drift, if ever.
+    last_verify: Option<Instant>,     // when records were last verified in DNS, if ever.
+    captive: bool,                    // whether detection last hit a captive portal.
+    new_addr: Option<(IpAddr, Instant)>, // a new address not yet updated to, & when first seen.
+    failing: Option<(Instant, u32)>,  // when checks started failing, & how many have since.
+    last_log_heartbeat: Instant,      // when the worker last logged that it's still running.
+    checks: u32,                      // checks made since then.
+    status: Arc<watch::Sender<Status>>,
+}
+
+struct ProviderSlot {
+    provider: Arc<dyn Provider>,
+    addr: Option<IpAddr>, // our belief about what the provider thinks our IP is.
+    failure: Option<Code>, // the code of the provider's ongoing failure to update, if notified.
+    failures: u32,        // consecutive failures to update the provider.
+    latency: Option<Duration>, // how long the latest update took, if any was made.
+}
+
+/// Resources shared by all workers.
+#[derive(Clone)]
+pub struct Shared {
+    pub client: reqwest::Client,
+    pub detector: Arc<Detectors>,
+    /// The uplinks our IPv4 address is detected over, if configured, in place of `detector`.
+    pub uplinks: Option<Arc<Uplinks>>,
+    /// The detector of our IPv6 address, if any records are AAAA records.
+    pub detector6: Option<Arc<Detector>>,
+    /// The latest detections, which workers share.
+    pub detections: Arc<Detections>,
+    pub state_updates: mpsc::Sender<(StateUpdate, Span)>,
+    pub network_changes: watch::Receiver<()>,
+    /// Signalled to check now, rather than waiting out the interval.
+    pub check_requests: watch::Receiver<()>,
+    /// Once true, workers finish any check in progress, then exit.
+    pub stop: watch::Receiver<bool>,
+    pub clock_jumps: watch::Receiver<clock::Jump>,
+    pub faults: Arc<Faults>,
+    pub metrics: Metrics,
+    pub notifier: Notifier,
+    pub rate_limiter: RateLimiter,
+    pub cooldowns: Cooldowns,
+    pub counters: Counters,
+    pub propagation: Option<Arc<PropagationConfig>>,
+    pub verify_dns: Option<Arc<VerifyDnsConfig>>,
+    pub canary: Option<Arc<CanaryConfig>>,
+    /// Through which the canary's worker lets the other workers' updates go ahead, if there's a
+    /// canary.
+    pub canary_gate: Option<Arc<CanaryGate>>,
+    /// The dead man's switch pinged after each check, if any.
+    pub healthcheck: Option<Arc<Healthcheck>>,
+    /// If set, records are never changed: each check reports what it would have done instead.
+    pub dry_run: bool,
+    /// If set, workers wait an interval before their first check, rather than checking at once.
+    pub delay_first_check: bool,
+    /// The maximum delay between checks while a worker is backing off from repeated failures.
+    pub max_backoff: Duration,
+    /// The longest a check may take, if not the check interval.
+    pub cycle_timeout: Option<Duration>,
+    /// How often to log that the worker is still running, if at all.
+    pub log_heartbeat: Option<Duration>,
+    /// Commands to run after the record is updated with a new address.
+    pub on_change: Arc<Vec<ExecConfig>>,
+    /// How many consecutive failures there must be before one is notified.
+    pub notify_after_failures: u32,
+    /// When to update records.
+    pub update_policy: UpdatePolicy,
+    /// How long a new address must last before records are updated, with `OnChangeStable`.
+    pub stable_for: Duration,
+}
+
+/// The channels through which a worker is controlled & observed.
+#[derive(Clone)]
+pub struct Channels {
+    /// While true, no checks are made.
+    pub paused: watch::Receiver<bool>,
+
+    /// Where the worker publishes its status.
+    pub status: Arc<watch::Sender<Status>>,
+}
+
+/// The latest detection of each type of address, shared by workers so that those checking at about
+/// the same time (as they do, sharing an interval) detect our address once between them, rather
+/// than multiplying detectors' traffic & the weight of each detection in their trust. Failures &
+/// disagreements are likewise notified of once.
+#[derive(Default)]
+pub struct Detections([tokio::sync::Mutex<Detection>; 2]);
+
+impl Detections {
+    fn of(&self, record_type: RecordType) -> &tokio::sync::Mutex<Detection> {
+        match record_type {
+            RecordType::A => &self.0[0],
+            RecordType::Aaaa => &self.0[1],
+        }
+    }
+}
+
+#[derive(Default)]
+struct Detection {
+    latest: Option<LatestDetection>,
+    disagreements: BTreeMap<String, Ipv4Addr>, // detectors' latest disagreements, by URL.
+    failure: Option<Code>, // the code of detection's ongoing failure, if notified.
+    failures: u32,         // consecutive failures to detect our address.
+}
+
+struct LatestDetection {
+    at: Instant,
+    rslt: Result<IpAddr, Arc<anyhow::Error>>,
+    latency: Duration,
+}
+
+/// Returns an error for a worker from a detection's error, which may be shared with others. Only
+/// its message, & whether it's a captive portal, are kept.
+fn shared_error(err: &anyhow::Error) -> anyhow::Error {
+    match detect::captive_portal(err) {
+        Some(portal) => anyhow::Error::new(portal.clone()),
+        None => anyhow!("{err:#}"),
+    }
+}
+
+/// Holds back updates of records other than the canary until the canary's worker has verified that
+/// the canary resolves to the new address. Only workers of the canary's type of record are held.
+pub struct CanaryGate {
+    gated: Vec<RecordType>,
+    verified: watch::Sender<Vec<IpAddr>>, // the latest address of each type verified, if any.
+}
+
+impl CanaryGate {
+    /// Creates a gate holding back updates of the given types of record.
+    pub fn new(gated: Vec<RecordType>) -> Self {
+        Self {
+            gated,
+            verified: watch::channel(Vec::new()).0,
+        }
+    }
+
+    /// Whether updates of the given type of record are held back.
+    fn gates(&self, record_type: RecordType) -> bool {
+        self.gated.contains(&record_type)
+    }
+
+    /// Lets updates to `addr` go ahead.
+    fn open(&self, addr: IpAddr) {
+        self.verified.send_if_modified(|verified| {
+            if verified.contains(&addr) {
+                return false;
+            }
+            verified.retain(|other| other.is_ipv4() != addr.is_ipv4());
+            verified.push(addr);
+            true
+        });
+    }
+
+    /// Waits until updates to `addr` may go ahead, returning false if they may not by `deadline`.
+    async fn wait(&self, addr: IpAddr, deadline: Instant) -> bool {
+        let mut verified = self.verified.subscribe();
+        let open = verified.wait_for(|verified| verified.contains(&addr));
+        let rslt = time::timeout_at(deadline, open).await;
+        matches!(rslt, Ok(Ok(_)))
+    }
+}
+
+impl Worker {
+    /// Creates a worker, updating the given providers, each paired with the address it is
+    /// believed to have. `labels` are attached to the worker's metrics.
+    pub fn new(
+        shared: Shared,
+        providers: Vec<(Arc<dyn Provider>, Option<IpAddr>)>,
+        interval: Duration,
+        pending_addr: Option<IpAddr>,
+        heartbeat: Heartbeat,
+        channels: Channels,
+        labels: BTreeMap<String, String>,
+    ) -> Self {
+        let backoff = Backoff::new(interval, shared.max_backoff);
+        Self {
+            shared,
+            providers: providers
+                .into_iter()
+                .map(|(provider, addr)| ProviderSlot {
+                    provider,
+                    addr,
+                    failure: None,
+                    failures: 0,
+                    latency: None,
+                })
+                .collect(),
+            interval,
+            backoff,
+            pending_addr,
+            heartbeat,
+            paused: channels.paused,
+            labels,
+            propagation: Vec::new(),
+            hooks: Vec::new(),
+            last_reconcile: None,
+            last_verify: None,
+            captive: false,
+            new_addr: None,
+            failing: None,
+            last_log_heartbeat: Instant::now(),
+            checks: 0,
+            status: channels.status,
+        }
+    }
+
+    /// Runs the worker until it's stopped: check IP every now and then, update if necessary.
+    pub async fn run(mut self) {
+        if self.shared.delay_first_check {
+            let until = Instant::now() + self.interval;
+            self.publish_status(*self.paused.borrow(), None, self.interval);
+            self.heartbeat.expect_by(until);
+            self.wait(until).await;
+        }
+        loop {
+            if *self.shared.stop.borrow() {
+                break;
+            }
+            let start = Instant::now();
+            self.shared.network_changes.mark_unchanged();
+            let cycle_span = info_span!("cycle", id = %CycleId::new());
+            let paused = *self.paused.borrow_and_update();
+            let (delay, rslt) = if paused {
+                debug!("Paused, skipping check");
+                (self.interval, None)
+            } else {
+                match self.check_and_ping().instrument(cycle_span.clone()).await {
+                    Ok(()) => {
+                        self.backoff.reset();
+                        if self.captive {
+                            self.captive = false;
+                            info!(
+                                parent: &cycle_span,
+                                "Past the captive portal, connectivity is back"
+                            );
+                        }
+                        if let Some((since, failures)) = self.failing.take() {
+                            info!(
+                                target: crate::NOTABLE_TARGET,
+                                parent: &cycle_span,
+                                failures,
+                                failing_for = ?since.elapsed(),
+                                "Check succeeded again"
+                            );
+                        }
+                        (self.interval, Some(Ok(())))
+                    }
+                    Err(err) if detect::captive_portal(&err).is_some() => {
+                        // Portals are local, so checking for our release from one is cheap: check
+                        // at the usual interval, & only log the first time.
+                        if !self.captive {
+                            self.captive = true;
+                            warn!(
+                                parent: &cycle_span,
+                                err = format!("{err:#}"),
+                                "Behind a captive portal, waiting for it to let us through"
+                            );
+                        } else {
+                            debug!(
+                                parent: &cycle_span,
+                                err = format!("{err:#}"),
+                                "Still behind a captive portal"
+                            );
+                        }
+                        (self.interval, Some(Err(err)))
+                    }
+                    Err(err) => {
+                        // Don't retry before a locked-out provider's cool-down ends.
+                        let delay = match backoff::is_permanent(&err) {
+                            true => self.backoff.permanent_failure(),
+                            false => self.backoff.failure(),
+                        };
+                        let delay = delay.max(self.cooldown());
+                        error!(
+                            parent: &cycle_span,
+                            err = format!("{err:#}"),
+                            retry_in = ?delay,
+                            "Check failed"
+                        );
+                        self.failing.get_or_insert((start, 0)).1 += 1;
+                        (delay, Some(Err(err)))
+                    }
+                }
+            };
+            if rslt.is_some() {
+                self.checks += 1;
+            }
+            self.log_heartbeat();
+            self.publish_status(paused, rslt, delay);
+            self.heartbeat.expect_by(start + delay);
+            self.wait(start + delay).await;
+        }
+        // Let hooks finish, rather than killing them as we exit.
+        for hook in self.hooks.drain(..) {
+            let _ = hook.await;
+        }
+    }
+
+    /// Logs that the worker is still running, if it's time to.
+    fn log_heartbeat(&mut self) {
+        let Some(interval) = self.shared.log_heartbeat else {
+            return;
+        };
+        if self.last_log_heartbeat.elapsed() < interval {
+            return;
+        }
+        info!(
+            checks = self.checks,
+            failing = self.failing.is_some(),
+            addr = ?self.providers[0].addr,
+            "Still running"
+        );
+        self.last_log_heartbeat = Instant::now();
+        self.checks = 0;
+    }
+
+    /// Runs a single check, pinging the healthcheck (if any) as it starts & finishes.
+    async fn check_and_ping(&mut self) -> Result<()> {
+        let healthcheck = self.shared.healthcheck.clone();
+        if let Some(healthcheck) = &healthcheck {
+            healthcheck.start().await;
+        }
+        let rslt = self.check_by_deadline().await;
+        if let Some(healthcheck) = &healthcheck {
+            healthcheck.finish(&rslt).await;
+        }
+        rslt
+    }
+
+    /// Runs a single check, rather than running forever.
+    pub async fn run_once(mut self) -> Result<()> {
+        if *self.paused.borrow() {
+            info!("Paused, skipping check");
+            return Ok(());
+        }
+        let cycle_span = info_span!("cycle", id = %CycleId::new());
+        let rslt = self.check_and_ping().instrument(cycle_span.clone()).await;
+        if let Err(err) = &rslt {
+            error!(parent: &cycle_span, err = format!("{err:#}"), "Check failed");
+        }
+        // Let hooks finish, rather than killing them as we exit.
+        for hook in self.hooks.drain(..) {
+            let _ = hook.await;
+        }
+        rslt
+    }
+
+    /// Waits for the next cycle, due at `until`. The wait is cut short by a network change while
+    /// backing off, by a requested check, by being stopped, by being paused or resumed, or by a
+    /// forward wall-clock jump.
+    async fn wait(&mut self, until: Instant) {
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(until) => return,
+                _ = netmon::changed(&mut self.shared.network_changes), if self.backoff.is_backing_off() => {
+                    info!("Network changed, retrying now");
+                    self.backoff.reset();
+                    return;
+                }
+                _ = stopped(&mut self.shared.stop) => return,
+                _ = check_requested(&mut self.shared.check_requests) => {
+                    info!("Check requested, checking now");
+                    return;
+                }
+                _ = pause_changed(&mut self.paused) => {
+                    // Check immediately on resume; on pause, the next iteration skips the check.
+                    self.backoff.reset();
+                    return;
+                }
+                jump = clock::jumped(&mut self.shared.clock_jumps) => {
+                    // The wait may have spanned a suspend, so may have run long: check now.
+                    if jump.forward {
+                        info!("Wall clock jumped forward, checking now");
+                        return;
+                    }
+                    // Otherwise, keep waiting, but correct when the next check is said to be due.
+                    let remaining = until.saturating_duration_since(Instant::now());
+                    self.status.send_modify(|st| {
+                        st.next_check = Some(status::unix_time(SystemTime::now() + remaining))
+                    });
+                }
+            }
+        }
+    }
+
+    /// Runs a check, aborting it (as failed) if it overruns the cycle timeout. Aborting is safe:
+    /// providers not yet updated are still believed to have the old address, so the next check
+    /// updates them.
+    async fn check_by_deadline(&mut self) -> Result<()> {
+        let timeout = self.shared.cycle_timeout.unwrap_or(self.interval);
+        match time::timeout(timeout, self.check()).await {
+            Ok(rslt) => rslt,
+            Err(elapsed) => Err(anyhow::Error::new(elapsed).context(format!(
+                "check didn't finish within {timeout:?}, aborted it"
+            ))),
+        }
+    }
+
+    async fn check(&mut self) -> Result<()> {
+        // If a previous run left an update pending, we know the providers are stale: push the
+        // pending address out first, without waiting on (possibly failing, or different) detection.
+        if let Some(pending_addr) = self.pending_addr.filter(|_| !self.shared.dry_run) {
+            info!(new_addr = ?pending_addr, "Resuming pending update");
+            self.update_providers(pending_addr).await?;
+            self.pending_addr = None;
+            self.send_state_update(StateUpdate::Addr(self.provider_ids(), pending_addr))
+                .await?;
+        }
+
+        // Figure out what our current IP is.
+        let current_addr = self
+            .detect()
+            .await
+            .context("couldn't get current IP address")?;
+        self.status
+            .send_modify(|st| st.detected_addr = Some(current_addr));
+        self.send_state_update(StateUpdate::Detected(current_addr))
+            .await?;
+        if self.shared.dry_run {
+            self.plan(current_addr).await;
+            return Ok(());
+        }
+
+        // Check that the providers really have the addresses we think, if it's time to.
+        if let Some(cfg) = self.shared.verify_dns.clone() {
+            let due = match (self.last_verify, cfg.interval) {
+                (None, _) => true,
+                (Some(last), Some(interval)) => last.elapsed() >= interval.0,
+                (Some(_), None) => false,
+            };
+            if due {
+                self.last_verify = Some(Instant::now());
+                self.verify_dns(cfg.resolver, current_addr).await?;
+            }
+        }
+
+        // Update IP in the providers if it differs (or as the update policy says). If the policy
+        // holds off a change, the records keep their address, so there's nothing more to do.
+        let changed = self
+            .providers
+            .iter()
+            .any(|slot| slot.addr != Some(current_addr));
+        match (self.should_update(current_addr, changed), changed) {
+            (true, true) => {
+                let old_addr = self.providers[0].addr;
+                info!(
+                    target: crate::NOTABLE_TARGET,
+                    old_addr = old_addr.map(field::display),
+                    new_addr = %current_addr,
+                    "Detected new IP, updating"
+                );
+                self.send_state_update(StateUpdate::Pending(self.provider_ids(), current_addr))
+                    .await?;
+                self.update_providers(current_addr).await?;
+                self.run_hooks(old_addr, current_addr);
+                self.shared.notifier.notify(Notification::new(
+                    Event::IpChanged {
+                        old_addr,
+                        new_addr: current_addr,
+                    },
+                    self.providers[0].provider.record().to_string(),
+                    self.labels.clone(),
+                ));
+            }
+            (true, false) => {
+                debug!(addr = ?current_addr, "Updating unchanged IP, per update_policy");
+                for slot in &mut self.providers {
+                    slot.addr = None;
+                }
+                self.update_providers(current_addr).await?;
+            }
+            (false, true) => return Ok(()),
+            (false, false) => (),
+        }
+
+        // If this worker's canary record already has the address, the others may update to it.
+        if let Some(gate) = &self.shared.canary_gate {
+            let canary = self.shared.canary.as_ref().expect("no canary");
+            if self.providers.iter().any(|slot| {
+                slot.provider.record() == canary.record && slot.addr == Some(current_addr)
+            }) {
+                gate.open(current_addr);
+            }
+        }
+
+        // Let the state writer know; it will update state on disk if it differs.
+        self.send_state_update(StateUpdate::Addr(self.provider_ids(), current_addr))
+            .await?;
+
+        // Every so often, correct any drift of the records' other settings (e.g. TTL).
+        if self
+            .last_reconcile
+            .is_none_or(|last| last.elapsed() >= RECONCILE_INTERVAL)
+        {
+            self.last_reconcile = Some(Instant::now());
+            self.reconcile_providers(current_addr).await;
+        }
+        Ok(())
+    }
+
+    /// Returns whether to update the providers to `addr` (which `changed` says differs from what
+    /// some have), per the update policy.
+    fn should_update(&mut self, addr: IpAddr, changed: bool) -> bool {
+        if !changed {
+            self.new_addr = None;
+        }
+        match self.shared.update_policy {
+            UpdatePolicy::OnChange => changed,
+            UpdatePolicy::Always => true,
+            UpdatePolicy::Never => {
+                if changed {
+                    debug!(addr = ?addr, "Not updating IP, as update_policy is never");
+                }
+                false
+            }
+            // A record whose address we don't know has nothing to keep, so is updated at once.
+            UpdatePolicy::OnChangeStable
+                if !changed || self.providers.iter().any(|slot| slot.addr.is_none()) =>
+            {
+                changed
+            }
+            UpdatePolicy::OnChangeStable => {
+                let since = match self.new_addr {
+                    Some((new_addr, since)) if new_addr == addr => since,
+                    _ => {
+                        info!(
+                            new_addr = ?addr,
+                            wait = ?self.shared.stable_for,
+                            "Detected new IP, waiting for it to be stable before updating"
+                        );
+                        let now = Instant::now();
+                        self.new_addr = Some((addr, now));
+                        now
+                    }
+                };
+                let stable_for = since.elapsed();
+                if stable_for < self.shared.stable_for {
+                    debug!(new_addr = ?addr, ?stable_for, "New IP isn't stable yet");
+                    return false;
+                }
+                self.new_addr = None;
+                true
+            }
+        }
+    }
+
+    /// Runs the `on_change` hooks in the background, in order, so that a slow hook can't hold up
+    /// checks. Failures are only logged.
+    fn run_hooks(&mut self, old_addr: Option<IpAddr>, new_addr: IpAddr) {
+        if self.shared.on_change.is_empty() {
+            return;
+        }
+        #[cfg(feature = "exec")]
+        {
+            let hooks = Arc::clone(&self.shared.on_change);
+            let provider = &self.providers[0].provider;
+            let vars = [
+                ("RNCCD_RECORD", provider.record().to_string()),
+                ("RNCCD_RECORD_TYPE", provider.record_type().to_string()),
+                (
+                    "RNCCD_OLD_ADDR",
+                    old_addr.map_or(String::new(), |addr| addr.to_string()),
+                ),
+                ("RNCCD_NEW_ADDR", new_addr.to_string()),
+            ];
+            self.hooks.retain(|hook| !hook.is_finished());
+            self.hooks.push(tokio::spawn(
+                async move {
+                    for hook in hooks.iter() {
+                        let command = hook.command.join(" ");
+                        match crate::exec::run(hook, &vars).await {
+                            Ok(output) => debug!(command, output = output.trim(), "Ran hook"),
+                            Err(err) => warn!(
+                                target: crate::NOTABLE_TARGET,
+                                command,
+                                err = format!("{err:#}"),
+                                "Hook failed"
+                            ),
+                        }
+                    }
+                }
+                .in_current_span(),
+            ));
+        }
+        #[cfg(not(feature = "exec"))]
+        let _ = (old_addr, new_addr);
+    }
+
+    /// Reports what a check would do about each provider's record, now that `detected_addr` has
+    /// been detected, in the logs & the worker's status.
+    async fn plan(&self, detected_addr: IpAddr) {
+        let mut plan = Vec::new();
+        for slot in &self.providers {
+            let provider = &slot.provider;
+            let dns_addrs = match net::lookup_host((provider.record(), 0)).await {
+                Ok(addrs) => Some(
+                    addrs
+                        .map(|addr| addr.ip())
+                        .filter(|&addr| provider.record_type().matches(addr))
+                        .collect::<Vec<_>>(),
+                ),
+                Err(err) => {
+                    debug!(record = provider.record(), %err, "Couldn't resolve record");
+                    None
+                }
+            };
+            let action = match slot.addr == Some(detected_addr) {
+                true => Action::None,
+                false => Action::Update,
+            };
+            info!(
+                provider = provider.id(),
+                ?dns_addrs,
+                known_addr = ?slot.addr,
+                ?detected_addr,
+                %action,
+                "Dry run, not changing record"
+            );
+            plan.push(Plan {
+                provider: provider.id().to_string(),
+                record: provider.record().to_string(),
+                dns_addrs,
+                known_addr: slot.addr,
+                detected_addr,
+                action,
+            });
+        }
+        self.status.send_modify(|st| st.plan = plan);
+    }
+
+    /// Reconciles what we believe each provider's record has with what it resolves to via
+    /// `resolver`: a record already resolving to `detected_addr` needn't be updated, & one which
+    /// resolves to something other than we believe must be. Records which can't be resolved are
+    /// left as they are.
+    async fn verify_dns(&mut self, resolver: IpAddr, detected_addr: IpAddr) -> Result<()> {
+        for i in 0..self.providers.len() {
+            let provider = Arc::clone(&self.providers[i].provider);
+            let known_addr = self.providers[i].addr;
+            let records = match provider.record_type() {
+                RecordType::A => dns::query_a(resolver, provider.record()).await,
+                RecordType::Aaaa => dns::query_aaaa(resolver, provider.record()).await,
+            };
+            let dns_addrs: Vec<IpAddr> = match records {
+                Ok(records) => records.iter().map(|record| record.addr).collect(),
+                Err(err) => {
+                    warn!(
+                        record = provider.record(),
+                        %resolver,
+                        err = format!("{err:#}"),
+                        "Couldn't verify record in DNS"
+                    );
+                    continue;
+                }
+            };
+            if dns_addrs.contains(&detected_addr) {
+                if known_addr != Some(detected_addr) {
+                    info!(
+                        provider = provider.id(),
+                        ?known_addr,
+                        ?dns_addrs,
+                        "Record already resolves to our IP, not updating it"
+                    );
+                    self.providers[i].addr = Some(detected_addr);
+                    self.send_state_update(StateUpdate::ProviderAddr(
+                        provider.id().to_string(),
+                        detected_addr,
+                    ))
+                    .await?;
+                }
+            } else if known_addr.is_some_and(|addr| !dns_addrs.contains(&addr)) {
+                warn!(
+                    target: crate::NOTABLE_TARGET,
+                    provider = provider.id(),
+                    ?known_addr,
+                    ?dns_addrs,
+                    "Record doesn't resolve to the IP we last set, it may have been changed \
+                     out-of-band; updating it"
+                );
+                self.providers[i].addr = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Corrects any drift of the providers' records from the config. Failures are logged, but
+    /// don't fail the check: the records still point at the right address.
+    async fn reconcile_providers(&self, addr: IpAddr) {
+        for slot in &self.providers {
+            let provider = &slot.provider;
+            if self
+                .shared
+                .cooldowns
+                .remaining(provider.account())
+                .is_some()
+            {
+                continue;
+            }
+            let Some(reconcile) = provider.reconcile(&self.shared.client, addr) else {
+                continue;
+            };
+            self.shared
+                .rate_limiter
+                .acquire(provider.account(), Priority::Routine)
+                .await;
+            match reconcile.await {
+                Ok(true) => info!(
+                    provider = provider.id(),
+                    "Corrected drift of record settings"
+                ),
+                Ok(false) => (),
+                Err(err) => {
+                    warn!(
+                        provider = provider.id(),
+                        err = format!("{err:#}"),
+                        "Couldn't check record settings for drift"
+                    );
+                    if let Some(lockout) = lockout::cause(&err) {
+                        let _ = self.lock_out(provider.as_ref(), lockout).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Detects our current address, or reuses another worker's recent detection of it. A worker
+    /// detecting holds up the others' detections of the same type of address, so that they reuse
+    /// its detection rather than making their own at the same time.
+    async fn detect(&mut self) -> Result<IpAddr> {
+        let detections = Arc::clone(&self.shared.detections);
+        let mut detection = detections.of(self.record_type()).lock().await;
+        let reuse_for = DETECTION_REUSE.min(self.interval / 2);
+        if let Some(latest) = &detection.latest {
+            if latest.at.elapsed() < reuse_for {
+                debug!(addr = ?latest.rslt.as_ref().ok(), "Reusing a recent detection");
+                let latency = status::millis(latest.latency);
+                self.status
+                    .send_modify(|st| st.detection_latency_ms = Some(latency));
+                return latest.rslt.clone().map_err(|err| shared_error(&err));
+            }
+        }
+
+        let start = Instant::now();
+        let mut disagreements = BTreeMap::new();
+        let rslt = async {
+            self.shared.faults.check(Operation::Detection)?;
+            let client = &self.shared.client;
+            match self.record_type() {
+                RecordType::A => {
+                    let detection = match &self.shared.uplinks {
+                        Some(uplinks) => uplinks.detect().await,
+                        None => self.shared.detector.detect(client).await,
+                    };
+                    let detection = match detection {
+                        Ok(detection) => detection,
+                        Err(err) => return Err(nat64::explain(err).await),
+                    };
+                    disagreements = detection.disagreements;
+                    anyhow::Ok(IpAddr::V4(detection.addr))
+                }
+                RecordType::Aaaa => {
+                    let detector = self.shared.detector6.as_ref();
+                    let detector = detector.ok_or_else(|| anyhow!("no IPv6 detector"))?;
+                    Ok(IpAddr::V6(detector.current_address6(client).await?))
+                }
+            }
+        }
+        .await;
+        let latency = start.elapsed();
+        self.status
+            .send_modify(|st| st.detection_latency_ms = Some(status::millis(latency)));
+        self.shared.metrics.record(Measurement::operation(
+            "detection",
+            self.tags(self.providers[0].provider.record(), None),
+            rslt.is_ok(),
+            latency,
+        ));
+        if latency > SLOW_CALL {
+            warn!(?latency, "IP address detection was slow");
+        }
+        if self.record_type() == RecordType::A {
+            self.send_state_update(StateUpdate::DetectorTrust(self.shared.detector.trust()))
+                .await?;
+        }
+        match &rslt {
+            Ok(addr) => {
+                debug!(?addr, ?latency, "Detected IP address");
+                detection.failure = None;
+                detection.failures = 0;
+                if let IpAddr::V4(addr) = *addr {
+                    self.note_disagreements(&mut detection.disagreements, addr, disagreements);
+                }
+            }
+            Err(err) => {
+                let code = codes::detection(err);
+                detection.failures += 1;
+                if detection.failures >= self.shared.notify_after_failures
+                    && detection.failure != Some(code)
+                {
+                    detection.failure = Some(code);
+                    self.shared.notifier.notify(
+                        Notification::new(
+                            Event::DetectionFailed {
+                                error: format!("{err:#}"),
+                            },
+                            self.providers[0].provider.record().to_string(),
+                            self.labels.clone(),
+                        )
+                        .with_code(code),
+                    );
+                }
+            }
+        }
+        let rslt = rslt.map_err(Arc::new);
+        detection.latest = Some(LatestDetection {
+            at: Instant::now(),
+            rslt: rslt.clone(),
+            latency,
+        });
+        rslt.map_err(|err| shared_error(&err))
+    }
+
+    /// Updates each provider that doesn't already have the given address. If there's a canary
+    /// record, it's updated first, & the others (including those of other workers) only once it
+    /// resolves to the address.
+    async fn update_providers(&mut self, addr: IpAddr) -> Result<()> {
+        // Measurements of a previous update's propagation are moot.
+        for handle in self.propagation.drain(..) {
+            handle.abort();
+        }
+        let canary = self.shared.canary.as_ref().and_then(|canary| {
+            self.providers
+                .iter()
+                .position(|slot| slot.provider.record() == canary.record)
+        });
+        let others: Vec<_> = (0..self.providers.len())
+            .filter(|&i| Some(i) != canary && self.providers[i].addr != Some(addr))
+            .collect();
+        let gate = self.shared.canary_gate.clone();
+        let gate = gate.filter(|gate| gate.gates(self.record_type()));
+        match (canary, gate) {
+            (Some(canary), gate) => {
+                self.update_slots(&[canary], addr).await?;
+                if !others.is_empty() || gate.is_some() {
+                    self.verify_canary(canary, addr).await?;
+                }
+                if let Some(gate) = gate {
+                    gate.open(addr);
+                }
+            }
+            (None, Some(gate)) => self.wait_for_canary(&gate, addr).await?,
+            (None, None) => (),
+        }
+        self.update_slots(&others, addr).await
+    }
+
+    /// Waits for the canary's worker to verify that the canary record resolves to `addr`, failing
+    /// if it hasn't within twice the canary's timeout (which leaves that worker time to update it).
+    async fn wait_for_canary(&self, gate: &CanaryGate, addr: IpAddr) -> Result<()> {
+        let canary = self.shared.canary.clone().expect("no canary");
+        let timeout = canary.timeout() * 2;
+        let deadline = Instant::now() + timeout;
+        debug!(record = canary.record, new_addr = %addr, "Waiting for the canary record");
+        self.heartbeat.expect_by(deadline);
+        if gate.wait(addr, deadline).await {
+            return Ok(());
+        }
+        warn!(
+            target: crate::NOTABLE_TARGET,
+            record = canary.record,
+            new_addr = %addr,
+            "Canary record wasn't verified to resolve to the new IP, not updating"
+        );
+        Err(anyhow!(
+            "canary record {} wasn't verified to resolve to {addr} within {timeout:?}",
+            canary.record
+        ))
+    }
+
+    /// Waits for the canary record (of the provider with the given index) to resolve to `addr`,
+    /// failing if it doesn't within the canary's timeout.
+    async fn verify_canary(&mut self, i: usize, addr: IpAddr) -> Result<()> {
+        let canary = self.shared.canary.clone().expect("no canary");
+        let provider = Arc::clone(&self.providers[i].provider);
+        let deadline = Instant::now() + canary.timeout();
+        loop {
+            let records = match provider.record_type() {
+                RecordType::A => dns::query_a(canary.resolver, provider.record()).await,
+                RecordType::Aaaa => dns::query_aaaa(canary.resolver, provider.record()).await,
+            };
+            match records {
+                Ok(records) if records.iter().any(|record| record.addr == addr) => {
+                    info!(
+                        record = provider.record(),
+                        "Canary record resolves to the new IP, updating the others"
+                    );
+                    return Ok(());
+                }
+                Ok(records) => {
+                    let dns_addrs: Vec<_> = records.iter().map(|record| record.addr).collect();
+                    debug!(
+                        record = provider.record(),
+                        ?dns_addrs,
+                        "Canary record doesn't resolve to the new IP yet"
+                    );
+                }
+                Err(err) => debug!(
+                    record = provider.record(),
+                    resolver = %canary.resolver,
+                    err = format!("{err:#}"),
+                    "Couldn't resolve canary record"
+                ),
+            }
+            if Instant::now() + CANARY_POLL_INTERVAL > deadline {
+                warn!(
+                    target: crate::NOTABLE_TARGET,
+                    record = provider.record(),
+                    new_addr = %addr,
+                    "Canary record didn't resolve to the new IP, not updating the others"
+                );
+                return Err(anyhow!(
+                    "canary record {} didn't resolve to {addr} within {:?}",
+                    provider.record(),
+                    canary.timeout()
+                ));
+            }
+            self.heartbeat
+                .expect_by(Instant::now() + CANARY_POLL_INTERVAL);
+            time::sleep(CANARY_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Updates each of the providers with the given indices which doesn't already have the given
+    /// address. A failure to update one provider doesn't stop the others from being updated.
+    async fn update_slots(&mut self, indices: &[usize], addr: IpAddr) -> Result<()> {
+        let mut failures = 0;
+        let mut permanent_failures = 0;
+        let mut updated_records = BTreeSet::new();
+        for &i in indices {
+            let slot = &self.providers[i];
+            if slot.addr == Some(addr) {
+                continue;
+            }
+            let provider = Arc::clone(&slot.provider);
+            if let Some(remaining) = self.shared.cooldowns.remaining(provider.account()) {
+                warn!(
+                    provider = provider.id(),
+                    account = provider.account(),
+                    ?remaining,
+                    "Account is locked out, not updating until its cool-down ends"
+                );
+                failures += 1;
+                continue;
+            }
+            let calls = provider.calls();
+            let mut retry_backoff = Backoff::new(calls.retry_delay(), Duration::MAX);
+            let mut retries = 0;
+            let (rslt, latency) = loop {
+                match self.update_provider(provider.as_ref(), addr).await {
+                    (Err(err), _)
+                        if retries < calls.retries
+                            && lockout::cause(&err).is_none()
+                            && !backoff::is_permanent(&err) =>
+                    {
+                        retries += 1;
+                        let delay = retry_backoff.failure();
+                        warn!(
+                            provider = provider.id(),
+                            err = format!("{err:#}"),
+                            retry_in = ?delay,
+                            "Couldn't update IP address, retrying"
+                        );
+                        self.heartbeat.expect_by(Instant::now() + delay);
+                        time::sleep(delay).await;
+                    }
+                    outcome => break outcome,
+                }
+            };
+            self.providers[i].latency = Some(latency);
+            self.send_state_update(StateUpdate::Counters(self.shared.counters.all()))
+                .await?;
+            match rslt {
+                Ok(()) => {
+                    info!(provider = provider.id(), ?latency, "Updated IP address");
+                    self.providers[i].failure = None;
+                    self.providers[i].failures = 0;
+                    // Sandbox records aren't served in public DNS, so never propagate.
+                    if !provider.is_sandbox() {
+                        updated_records.insert(provider.record().to_string());
+                    }
+                    self.providers[i].addr = Some(addr);
+                    self.send_state_update(StateUpdate::ProviderAddr(
+                        provider.id().to_string(),
+                        addr,
+                    ))
+                    .await?;
+                }
+                Err(err) => {
+                    if backoff::is_permanent(&err) {
+                        error!(
+                            target: crate::NOTABLE_TARGET,
+                            provider = provider.id(),
+                            account = provider.account(),
+                            err = format!("{err:#}"),
+                            "Couldn't update IP address, & retrying won't help until the config \
+                             is fixed; backing off to the maximum delay"
+                        );
+                        permanent_failures += 1;
+                    } else {
+                        error!(
+                            provider = provider.id(),
+                            account = provider.account(),
+                            err = format!("{err:#}"),
+                            "Couldn't update IP address"
+                        );
+                    }
+                    if let Some(lockout) = lockout::cause(&err) {
+                        self.lock_out(provider.as_ref(), lockout).await?;
+                    }
+                    let code = codes::update(&err);
+                    let slot = &mut self.providers[i];
+                    slot.failures += 1;
+                    if slot.failures >= self.shared.notify_after_failures
+                        && slot.failure != Some(code)
+                    {
+                        slot.failure = Some(code);
+                        self.shared.notifier.notify(
+                            Notification::new(
+                                Event::UpdateFailed {
+                                    provider: provider.id().to_string(),
+                                    error: format!("{err:#}"),
+                                },
+                                provider.record().to_string(),
+                                self.labels.clone(),
+                            )
+                            .with_code(code),
+                        );
+                    }
+                    failures += 1;
+                }
+            }
+        }
+        self.measure_propagation(updated_records, addr);
+        if failures > 0 {
+            let message = format!(
+                "couldn't update IP address with {failures} of {} providers",
+                indices.len()
+            );
+            // The check failed permanently only if every failure was permanent.
+            return Err(match permanent_failures == failures {
+                true => backoff::Permanent {
+                    code: Code::UpdateFailed,
+                    message,
+                }
+                .into(),
+                false => anyhow!(message),
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts a cool-down of the provider's account, which the provider has locked out, & notifies
+    /// of it.
+    async fn lock_out(&self, provider: &dyn Provider, lockout: &Lockout) -> Result<()> {
+        let cooldown = provider.calls().lockout_cooldown();
+        let (until, cooldowns) = self.shared.cooldowns.start(provider.account(), cooldown);
+        error!(
+            target: crate::NOTABLE_TARGET,
+            provider = provider.id(),
+            account = provider.account(),
+            reason = lockout.message,
+            ?cooldown,
+            "Provider locked out the account, not calling it with the account until the cool-down \
+             ends; updates will be missed meanwhile"
+        );
+        self.shared.notifier.notify(Notification::new(
+            Event::LockedOut {
+                account: provider.account().to_string(),
+                reason: lockout.message.clone(),
+                until,
+            },
+            provider.record().to_string(),
+            self.labels.clone(),
+        ));
+        self.send_state_update(StateUpdate::Cooldowns(cooldowns))
+            .await
+    }
+
+    /// Returns how long until any of the providers' accounts may be called again: the shortest
+    /// remaining cool-down, or zero if any account isn't cooling down.
+    fn cooldown(&self) -> Duration {
+        self.providers
+            .iter()
+            .map(|slot| {
+                let account = slot.provider.account();
+                self.shared.cooldowns.remaining(account).unwrap_or_default()
+            })
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Makes a single attempt at updating a provider, returning its result & latency.
+    async fn update_provider(
+        &self,
+        provider: &dyn Provider,
+        addr: IpAddr,
+    ) -> (Result<()>, Duration) {
+        self.shared
+            .rate_limiter
+            .acquire(provider.account(), Priority::Stale)
+            .await;
+        let start = Instant::now();
+        let rslt = async {
+            self.shared.faults.check(Operation::Update)?;
+            provider.update(&self.shared.client, addr).await
+        }
+        .await;
+        let latency = start.elapsed();
+        let totals = self.shared.counters.count(provider.id(), rslt.is_ok());
+        let mut measurement = Measurement::operation(
+            "update",
+            self.tags(provider.record(), Some(provider.id())),
+            rslt.is_ok(),
+            latency,
+        );
+        measurement.fields.extend([
+            ("updates_total", Value::Float(totals.updates as f64)),
+            ("failures_total", Value::Float(totals.failures as f64)),
+        ]);
+        self.shared.metrics.record(measurement);
+        if latency > SLOW_CALL {
+            warn!(provider = provider.id(), ?latency, "Update was slow");
+        }
+        (rslt, latency)
+    }
+
+    /// Starts measuring propagation of the given records' update to `addr`, if configured to.
+    fn measure_propagation(&mut self, records: BTreeSet<String>, addr: IpAddr) {
+        let Some(cfg) = &self.shared.propagation else {
+            return;
+        };
+        // Propagation is only measured for A records.
+        let IpAddr::V4(addr) = addr else {
+            return;
+        };
+        if records.is_empty() {
+            return;
+        }
+        for record in records {
+            self.propagation.push(propagation::spawn(
+                Arc::clone(cfg),
+                &self.shared,
+                self.tags(&record, None),
+                self.labels.clone(),
+                record,
+                addr,
+            ));
+        }
+    }
+
+    /// The IDs of the worker's providers.
+    fn provider_ids(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .map(|slot| slot.provider.id().to_string())
+            .collect()
+    }
+
+    /// The type of record the worker's providers update. (Each worker's providers all update the
+    /// same type of record.)
+    fn record_type(&self) -> RecordType {
+        self.providers[0].provider.record_type()
+    }
+
+    /// Publishes the worker's status after a cycle, which (unless paused) had the given result &
+    /// will be followed by another after `delay`.
+    fn publish_status(&self, paused: bool, rslt: Option<Result<()>>, delay: Duration) {
+        let now = SystemTime::now();
+        self.status.send_modify(|st| {
+            st.paused = paused;
+            st.providers = self
+                .providers
+                .iter()
+                .map(|slot| ProviderStatus {
+                    id: slot.provider.id().to_string(),
+                    addr: slot.addr,
+                    totals: self.shared.counters.get(slot.provider.id()),
+                    update_latency_ms: slot.latency.map(status::millis),
+                })
+                .collect();
+            match rslt {
+                Some(Ok(())) => st.last_success = Some(status::unix_time(now)),
+                Some(Err(err)) => {
+                    let err = ErrorStatus {
+                        time: status::unix_time(now),
+                        message: format!("{err:#}"),
+                    };
+                    if st.recent_errors.len() >= status::RECENT_ERRORS {
+                        st.recent_errors.remove(0);
+                    }
+                    st.recent_errors.push(err.clone());
+                    st.last_error = Some(err);
+                }
+                None => (),
+            }
+            st.next_check = Some(status::unix_time(now + delay));
+        });
+    }
+
+    /// Records detectors' disagreements about our address (`addr`), notifying if they've changed
+    /// since the last detection's (`previous`).
+    fn note_disagreements(
+        &self,
+        previous: &mut BTreeMap<String, Ipv4Addr>,
+        addr: Ipv4Addr,
+        disagreements: BTreeMap<String, Ipv4Addr>,
+    ) {
+        let record = self.providers[0].provider.record().to_string();
+        for (detector, detected) in &disagreements {
+            let mut tags = self.tags(&record, None);
+            tags.extend([
+                ("detector".to_string(), detector.clone()),
+                ("detected_addr".to_string(), detected.to_string()),
+                ("addr".to_string(), addr.to_string()),
+            ]);
+            self.shared.metrics.record(Measurement::new(
+                "detection_disagreement",
+                tags,
+                vec![("count", Value::Float(1.0))],
+            ));
+        }
+        if disagreements == *previous {
+            return;
+        }
+        if disagreements.is_empty() {
+            info!("Detectors agree again");
+        } else {
+            let detected: Vec<_> = disagreements
+                .iter()
+                .map(|(detector, detected)| format!("{detector}={detected}"))
+                .collect();
+            warn!(
+                target: crate::NOTABLE_TARGET,
+                ?addr,
+                disagreements = detected.join(","),
+                "Detectors disagreed about our IP"
+            );
+            self.shared.notifier.notify(Notification::new(
+                Event::DetectorsDisagreed {
+                    addr,
+                    disagreements: disagreements.clone(),
+                },
+                record,
+                self.labels.clone(),
+            ));
+        }
+        *previous = disagreements;
+    }
+
+    /// Returns the tags of a measurement about a record (& provider): those, then our labels.
+    fn tags(&self, record: &str, provider: Option<&str>) -> Vec<(String, String)> {
+        let mut tags = vec![("record".to_string(), record.to_string())];
+        tags.extend(provider.map(|id| ("provider".to_string(), id.to_string())));
+        tags.extend(self.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        tags
+    }
+
+    async fn send_state_update(&self, update: StateUpdate) -> Result<()> {
+        self.shared
+            .state_updates
+            .send((update, Span::current()))
+            .await
+            .map_err(|_| anyhow!("state writer is gone"))
+    }
+}
+
+/// Waits for workers to be stopped. If they can't be, this never returns.
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    if stop.wait_for(|&stop| stop).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Waits for a check to be requested. If checks can't be requested, this never returns.
+async fn check_requested(check_requests: &mut watch::Receiver<()>) {
+    if check_requests.changed().await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Waits for a worker's pause switch to change. If the switch is gone, this never returns.
+async fn pause_changed(paused: &mut watch::Receiver<bool>) {
+    if paused.changed().await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// CycleId identifies a single check cycle of a worker. It is attached (via a span) to all logging
+/// produced during the cycle, so that interleaved output from concurrent workers can be told apart.
+pub struct CycleId(u32);
+
+impl CycleId {
+    fn new() -> Self {
+        Self(fastrand::u32(..))
+    }
+}
+
+impl Display for CycleId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
@@ -0,0 +1,235 @@
+//! A small XML parser, handling as much of XML as providers' API responses use: elements,
+//! attributes, text (with entity & character references), CDATA sections, comments, processing
+//! instructions, & doctypes (without internal subsets, which are rejected). Namespace prefixes are
+//! dropped, so elements & attributes are known by their local names.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+
+/// How deeply elements may nest, so that a hostile document can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// An element of an XML document: its name, attributes, child elements, & text.
+pub struct Element {
+    pub name: String,
+    attrs: BTreeMap<String, String>,
+    pub children: Vec<Element>,
+    text: String, // the text directly within the element, but not its children.
+}
+
+impl Element {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(String::as_str)
+    }
+
+    /// The text directly within the element, with surrounding whitespace trimmed.
+    pub fn text(&self) -> &str {
+        self.text.trim()
+    }
+
+    /// Returns the first child element with the given name, if any.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Returns the text of the first child element with the given name, if any.
+    pub fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(Element::text)
+    }
+
+    /// Returns every element below this one with the given name, in document order.
+    pub fn descendants(&self, name: &str) -> Vec<&Element> {
+        let mut found = Vec::new();
+        for child in &self.children {
+            if child.name == name {
+                found.push(child);
+            }
+            found.extend(child.descendants(name));
+        }
+        found
+    }
+}
+
+/// Parses an XML document, returning its root element.
+pub fn parse(doc: &str) -> Result<Element> {
+    let mut parser = Parser {
+        rest: doc.trim_start_matches('\u{feff}'),
+    };
+    parser.skip_misc()?;
+    let root = parser.element(0)?;
+    parser.skip_misc()?;
+    if !parser.rest.is_empty() {
+        return Err(anyhow!("document has content after the root element"));
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    /// Skips whitespace, comments, processing instructions (e.g. the XML declaration), &
+    /// doctypes, as may surround the root element.
+    fn skip_misc(&mut self) -> Result<()> {
+        loop {
+            self.rest = self.rest.trim_start();
+            if self.rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest.starts_with("<!DOCTYPE") {
+                if self.skip_past(">")?.contains('[') {
+                    return Err(anyhow!("doctypes with internal subsets aren't supported"));
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Skips past the next occurrence of `end`, returning what came before it.
+    fn skip_past(&mut self, end: &str) -> Result<&'a str> {
+        let i = self
+            .rest
+            .find(end)
+            .ok_or_else(|| anyhow!("document ends before {end:?}"))?;
+        let skipped = &self.rest[..i];
+        self.rest = &self.rest[i + end.len()..];
+        Ok(skipped)
+    }
+
+    /// Parses a name, as written (i.e. with any namespace prefix).
+    fn name(&mut self) -> Result<&'a str> {
+        let len = self
+            .rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(self.rest.len());
+        if len == 0 {
+            return Err(anyhow!("expected a name at {:?}", excerpt(self.rest)));
+        }
+        let name = &self.rest[..len];
+        self.rest = &self.rest[len..];
+        Ok(name)
+    }
+
+    /// Expects `s` next (after any whitespace), & skips past it.
+    fn expect(&mut self, s: &str) -> Result<()> {
+        self.rest = self
+            .rest
+            .trim_start()
+            .strip_prefix(s)
+            .ok_or_else(|| anyhow!("expected {s:?} at {:?}", excerpt(self.rest)))?;
+        Ok(())
+    }
+
+    /// Parses an element, at the given depth of nesting.
+    fn element(&mut self, depth: usize) -> Result<Element> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("elements are nested too deeply"));
+        }
+        self.expect("<")?;
+        let name = self.name()?;
+        let mut element = Element {
+            name: local_name(name).to_string(),
+            attrs: BTreeMap::new(),
+            children: Vec::new(),
+            text: String::new(),
+        };
+
+        // Attributes: name="value" (or single-quoted).
+        loop {
+            self.rest = self.rest.trim_start();
+            if let Some(rest) = self.rest.strip_prefix("/>") {
+                self.rest = rest;
+                return Ok(element);
+            }
+            if let Some(rest) = self.rest.strip_prefix('>') {
+                self.rest = rest;
+                break;
+            }
+            let attr = self.name()?;
+            self.expect("=")?;
+            self.rest = self.rest.trim_start();
+            let quote = match self.rest.chars().next() {
+                Some('"') => "\"",
+                Some('\'') => "'",
+                _ => return Err(anyhow!("attribute {attr} has an unquoted value")),
+            };
+            self.rest = &self.rest[1..];
+            let value = unescape(self.skip_past(quote)?)?;
+            element.attrs.insert(local_name(attr).to_string(), value);
+        }
+
+        // Content, up to the closing tag.
+        loop {
+            if let Some(rest) = self.rest.strip_prefix("</") {
+                self.rest = rest;
+                let close = self.name()?;
+                if close != name {
+                    return Err(anyhow!("<{name}> is closed by </{close}>"));
+                }
+                self.expect(">")?;
+                return Ok(element);
+            } else if self.rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if let Some(rest) = self.rest.strip_prefix("<![CDATA[") {
+                self.rest = rest;
+                element.text.push_str(self.skip_past("]]>")?);
+            } else if self.rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest.starts_with('<') {
+                element.children.push(self.element(depth + 1)?);
+            } else if self.rest.is_empty() {
+                return Err(anyhow!("document ends before </{name}>"));
+            } else {
+                let len = self.rest.find('<').unwrap_or(self.rest.len());
+                element.text.push_str(&unescape(&self.rest[..len])?);
+                self.rest = &self.rest[len..];
+            }
+        }
+    }
+}
+
+/// Returns a name without its namespace prefix, if any.
+fn local_name(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+/// Replaces entity & character references with the characters they stand for.
+fn unescape(s: &str) -> Result<String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        unescaped.push_str(&rest[..i]);
+        let (reference, after) = rest[i + 1..]
+            .split_once(';')
+            .ok_or_else(|| anyhow!("unterminated reference at {:?}", excerpt(&rest[i..])))?;
+        let c = match reference {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match reference.strip_prefix('#') {
+                    Some(hex) if hex.starts_with('x') => u32::from_str_radix(&hex[1..], 16).ok(),
+                    Some(decimal) => decimal.parse().ok(),
+                    None => None,
+                };
+                code.and_then(char::from_u32)
+                    .with_context(|| format!("unknown reference &{reference};"))?
+            }
+        };
+        unescaped.push(c);
+        rest = after;
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+/// Returns the start of `s`, for error messages.
+fn excerpt(s: &str) -> &str {
+    let end = s.char_indices().nth(20).map_or(s.len(), |(i, _)| i);
+    &s[..end]
+}